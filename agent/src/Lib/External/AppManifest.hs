@@ -16,12 +16,13 @@ import           Lib.Types.Core
 import           Lib.Types.Emver
 import           Lib.Types.Emver.Orphans        ( )
 
-data ImageType = ImageTypeTar
+data ImageType = ImageTypeTar | ImageTypeOci
     deriving (Eq, Show)
 
 instance FromJSON ImageType where
     parseJSON = withText "Image Type" $ \case
         "tar" -> pure ImageTypeTar
+        "oci" -> pure ImageTypeOci
         wat   -> fail $ "Unknown Image Type: " <> toS wat
 
 data OnionVersion = OnionV2 | OnionV3
@@ -106,8 +107,15 @@ lanUiAvailable AppManifest {..} = any id $ fmap portMapEntryLan appManifestPortM
     Just (Custom 80 ) -> True
     _                 -> False
 
+-- | Reads both manifest versions appmgr writes. V1 declares its ports as named interfaces and its
+-- mount under volumes. `appmgr info` reports the latest version without its compat tag.
 instance FromJSON AppManifest where
     parseJSON = withObject "App Manifest " $ \o -> do
+        isV1 <- o .:? "compat" >>= \case
+            Just ("v0" :: Text) -> pure False
+            Just "v1"           -> pure True
+            Just other          -> fail $ "Unknown Compat Version: " <> toS other
+            Nothing             -> pure $ HM.member "volumes" o
         appManifestId             <- o .: "id"
         appManifestVersion        <- o .: "version"
         appManifestTitle          <- o .: "title"
@@ -116,16 +124,18 @@ instance FromJSON AppManifest where
         appManifestDescShort      <- o .: "description" >>= (.: "short")
         appManifestDescLong       <- o .: "description" >>= (.: "long")
         appManifestReleaseNotes   <- o .: "release-notes"
-        appManifestPortMapping    <- o .: "ports"
+        appManifestPortMapping    <- if isV1
+            then HM.elems <$> (o .:? "interfaces" .!= (HM.empty :: HM.HashMap Text PortMapEntry))
+            else o .: "ports"
         appManifestImageType      <- o .: "image" >>= (.: "type")
-        appManifestMount          <- o .: "mount"
-        appManifestAssets         <- o .: "assets" >>= traverse parseJSON
+        appManifestMount          <- if isV1 then o .: "volumes" >>= (.: "mount") else o .: "mount"
+        appManifestAssets         <- o .:? "assets" .!= []
         appManifestOnionVersion   <- o .: "hidden-service-version"
         appManifestDependencies   <- o .:? "dependencies" .!= HM.empty >>= traverse parseDepInfo
         appManifestUninstallAlert <- o .:? "uninstall-alert"
         appManifestRestoreAlert   <- o .:? "restore-alert"
         appManifestStartAlert     <- o .:? "start-alert"
-        appManifestActions        <- o .: "actions"
+        appManifestActions        <- o .:? "actions" .!= []
         pure $ AppManifest { .. }
         where parseDepInfo = withObject "Dep Info" $ (.: "version")
 
@@ -198,11 +198,14 @@ type Manifest = Some1 ManifestStructure
 data ManifestStructure (n :: Nat) where
     ManifestV0 ::{ manifestTitle :: Text
         } -> ManifestStructure 0
+    ManifestV1 ::{ manifestV1Title :: Text
+        } -> ManifestStructure 1
 
 instance FromJSON (Some1 ManifestStructure) where
     parseJSON = withObject "app manifest" $ \o -> do
         o .: "compat" >>= \t -> case (t :: Text) of
             "v0"  -> some1 <$> parseJSON @(ManifestStructure 0) (Object o)
+            "v1"  -> some1 <$> parseJSON @(ManifestStructure 1) (Object o)
             other -> fail $ "Unknown Compat Version" <> unpack other
 
 instance FromJSON (ManifestStructure 0) where
@@ -210,6 +213,11 @@ instance FromJSON (ManifestStructure 0) where
         manifestTitle <- o .: "title"
         pure $ ManifestV0 { .. }
 
+instance FromJSON (ManifestStructure 1) where
+    parseJSON = withObject "manifest v1" $ \o -> do
+        manifestV1Title <- o .: "title"
+        pure $ ManifestV1 { .. }
+
 torrcBase :: SystemPath
 torrcBase = "/root/appmgr/tor/torrc"
 
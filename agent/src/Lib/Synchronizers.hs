@@ -98,7 +98,7 @@ parseKernelVersion = do
     pure $ KernelVersion (Version (major', minor', patch', 0)) arch
 
 synchronizer :: Synchronizer
-synchronizer = sync_0_2_13
+synchronizer = sync_0_2_14
 {-# INLINE synchronizer #-}
 
 sync_0_2_13 :: Synchronizer
@@ -130,6 +130,37 @@ sync_0_2_13 = Synchronizer
     , syncRemoveDefaultNginxCfg
     ]
 
+sync_0_2_14 :: Synchronizer
+sync_0_2_14 = Synchronizer
+    "0.2.14"
+    [ syncCreateAgentTmp
+    , syncCreateSshDir
+    , syncRemoveAvahiSystemdDependency
+    , syncInstallLibAvahi
+    , syncInstallAppMgr
+    , syncFullUpgrade
+    , sync32BitKernel
+    , syncInstallNginx
+    , syncWriteNginxConf
+    , syncInstallDuplicity
+    , syncInstallExfatFuse
+    , syncInstallExfatUtils
+    , syncUpgradeTor
+    , syncInstallAmbassadorUI
+    , syncOpenHttpPorts
+    , syncUpgradeLifeline
+    , syncPrepSslRootCaDir
+    , syncPrepSslIntermediateCaDir
+    , syncPersistLogs
+    , syncConvertEcdsaCerts
+    , syncInstallEject
+    , syncDropCertificateUniqueness
+    , syncRemoveDefaultNginxCfg
+    , syncSupervisorService
+    , syncInstallNftables
+    , syncFirewallService
+    ]
+
 syncCreateAgentTmp :: SyncOp
 syncCreateAgentTmp = SyncOp "Create Agent Tmp Dir" check migrate False
     where
@@ -220,6 +251,15 @@ syncInstallDuplicity = SyncOp "Install duplicity" check migrate False
             shell "apt-get update"
             shell "apt-get install -y duplicity"
 
+-- appmgr loads the firewall rules between apps with nft
+syncInstallNftables :: SyncOp
+syncInstallNftables = SyncOp "Install nftables" check migrate False
+    where
+        check   = liftIO . run $ fmap isNothing (shell [i|which nft || true|] $| conduit await)
+        migrate = liftIO . run $ do
+            shell "apt-get update"
+            shell "apt-get install -y nftables"
+
 syncInstallExfatFuse :: SyncOp
 syncInstallExfatFuse = SyncOp "Install exfat-fuse" check migrate False
     where
@@ -568,6 +608,51 @@ replaceDerivativeCerts = do
     liftIO $ renameDirectory sslDirTmp sslDir
     liftIO $ systemCtl RestartService "nginx" $> ()
 
+-- replaces the restarter timer, which ran `appmgr repair-app-status` every minute
+-- named after appmgr so as not to clash with the unit of debian's supervisor package
+syncSupervisorService :: SyncOp
+syncSupervisorService = SyncOp "Install AppMgr Supervisor Service" check migrate False
+    where
+        wanted = decodeUtf8 $(embedFile "config/appmgr-supervisor.service")
+        servicePath :: SystemPath
+        servicePath = "/etc/systemd/system/appmgr-supervisor.service"
+        check = do
+            base   <- asks $ appFilesystemBase . appSettings
+            exists <- liftIO $ doesPathExist (toS $ servicePath `relativeTo` base)
+            if exists
+                then (/= wanted) <$> liftIO (readFile (toS $ servicePath `relativeTo` base))
+                else pure True
+        migrate = do
+            base <- asks $ appFilesystemBase . appSettings
+            liftIO $ callCommand "systemctl stop restarter.timer || true"
+            removeFileIfExists . toS $ "/etc/systemd/system/timers.target.wants/restarter.timer" `relativeTo` base
+            removeFileIfExists . toS $ "/etc/systemd/system/restarter.timer" `relativeTo` base
+            removeFileIfExists . toS $ "/etc/systemd/system/restarter.service" `relativeTo` base
+            liftIO $ writeFile (toS $ servicePath `relativeTo` base) wanted
+            void $ liftIO systemCtlDaemonReload
+            liftIO $ callCommand "systemctl enable appmgr-supervisor.service"
+            void . liftIO $ systemCtl RestartService "appmgr-supervisor"
+
+-- nftables forgets the rules between apps on reboot, so they are regenerated and loaded at boot
+syncFirewallService :: SyncOp
+syncFirewallService = SyncOp "Install AppMgr Firewall Service" check migrate False
+    where
+        wanted = decodeUtf8 $(embedFile "config/appmgr-firewall.service")
+        servicePath :: SystemPath
+        servicePath = "/etc/systemd/system/appmgr-firewall.service"
+        check = do
+            base   <- asks $ appFilesystemBase . appSettings
+            exists <- liftIO $ doesPathExist (toS $ servicePath `relativeTo` base)
+            if exists
+                then (/= wanted) <$> liftIO (readFile (toS $ servicePath `relativeTo` base))
+                else pure True
+        migrate = do
+            base <- asks $ appFilesystemBase . appSettings
+            liftIO $ writeFile (toS $ servicePath `relativeTo` base) wanted
+            void $ liftIO systemCtlDaemonReload
+            liftIO $ callCommand "systemctl enable appmgr-firewall.service"
+            void . liftIO $ systemCtl RestartService "appmgr-firewall"
+
 syncRestarterService :: SyncOp
 syncRestarterService = SyncOp "Install Restarter Service" check migrate True
     where
@@ -8,7 +8,12 @@ if [ "$0" != "./build-portable.sh" ]; then
 	exit 1
 fi
 
-alias 'rust-musl-builder'='docker run --rm -it -v "$HOME"/.cargo/registry:/root/.cargo/registry -v "$(pwd)":/home/rust/src messense/rust-musl-cross:x86_64-musl'
+alias 'rust-musl-builder'='docker run --rm -it -e REGISTRY_KEY -v "$HOME"/.cargo/registry:/root/.cargo/registry -v "$(pwd)":/home/rust/src messense/rust-musl-cross:x86_64-musl'
+
+if [ -z "$REGISTRY_KEY" ]; then
+	>&2 echo "REGISTRY_KEY must be set to the registry's public signing key"
+	exit 1
+fi
 
 cd ..
 rust-musl-builder sh -c "(cd appmgr && cargo build --release --target=x86_64-unknown-linux-musl --features=portable,production --no-default-features)"
@@ -8,7 +8,12 @@ if [ "$0" != "./build-prod.sh" ]; then
 	exit 1
 fi
 
-alias 'rust-arm-builder'='docker run --rm -it -v "$HOME/.cargo/registry":/root/.cargo/registry -v "$(pwd)":/home/rust/src start9/rust-arm-cross:latest'
+alias 'rust-arm-builder'='docker run --rm -it -e REGISTRY_KEY -v "$HOME/.cargo/registry":/root/.cargo/registry -v "$(pwd)":/home/rust/src start9/rust-arm-cross:latest'
+
+if [ -z "$REGISTRY_KEY" ]; then
+	>&2 echo "REGISTRY_KEY must be set to the registry's public signing key"
+	exit 1
+fi
 
 cd ..
 rust-arm-builder sh -c "(cd appmgr && cargo build --release --features=production)"
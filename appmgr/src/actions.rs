@@ -1,11 +1,8 @@
-use std::os::unix::process::ExitStatusExt;
-use std::process::Stdio;
-
 use linear_map::set::LinearSet;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error as IoError};
 use yajrc::RpcError;
 
 use crate::apps::DockerStatus;
+use crate::runtime::CreateOptions;
 
 pub const STATUS_NOT_ALLOWED: i32 = -2;
 pub const INVALID_COMMAND: i32 = -3;
@@ -23,24 +20,6 @@ pub struct Action {
     pub command: Vec<String>,
 }
 
-async fn tee<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
-    mut r: R,
-    mut w: W,
-) -> Result<Vec<u8>, IoError> {
-    let mut res = Vec::new();
-    let mut buf = vec![0; 2048];
-    let mut bytes;
-    while {
-        bytes = r.read(&mut buf).await?;
-        bytes != 0
-    } {
-        res.extend_from_slice(&buf[..bytes]);
-        w.write_all(&buf[..bytes]).await?;
-    }
-    w.flush().await?;
-    Ok(res)
-}
-
 impl Action {
     pub async fn perform(&self, app_id: &str) -> Result<String, RpcError> {
         let man = crate::apps::manifest(app_id)
@@ -62,53 +41,36 @@ impl Action {
                 data: None,
             });
         }
-        let mut cmd = if status == DockerStatus::Running {
-            let mut cmd = tokio::process::Command::new("docker");
-            cmd.arg("exec").arg(&app_id).args(&self.command);
-            cmd
+        let runtime = crate::runtime::get();
+        let output = if status == DockerStatus::Running {
+            runtime.exec(app_id, &self.command).await
         } else {
-            let mut cmd = tokio::process::Command::new("docker");
             let entrypoint = self.command.get(0).ok_or_else(|| RpcError {
                 code: INVALID_COMMAND,
                 message: "Command Cannot Be Empty".to_owned(),
                 data: None,
             })?;
-            cmd.arg("run")
-                .arg("--rm")
-                .arg("--name")
-                .arg(format!("{}_{}", app_id, self.id))
-                .arg("--mount")
-                .arg(format!(
-                    "type=bind,src={}/{},dst={}",
-                    crate::VOLUMES,
-                    app_id,
-                    man.mount.display()
-                ))
-                .arg("--entrypoint")
-                .arg(entrypoint)
-                .arg(format!("start9/{}", app_id))
-                .args(&self.command[1..]);
-            // TODO: 0.3.0: net, tor, shm
-            cmd
-        };
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
-        let mut child = cmd.spawn()?;
-
-        let (stdout, stderr) = futures::try_join!(
-            tee(child.stdout.take().unwrap(), tokio::io::sink()),
-            tee(child.stderr.take().unwrap(), tokio::io::sink())
-        )?;
-
-        let status = child.wait().await?;
-        if status.success() {
-            String::from_utf8(stdout).map_err(From::from)
+            runtime
+                .run(&CreateOptions {
+                    name: format!("{}_{}", app_id, self.id),
+                    image: format!("start9/{}", app_id),
+                    mounts: crate::install::volume_mounts(&man),
+                    tmpfs: crate::install::tmpfs_mounts(&man),
+                    entrypoint: Some(entrypoint.clone()),
+                    command: self.command[1..].to_vec(),
+                    // TODO: 0.3.0: net, tor, shm
+                    ..Default::default()
+                })
+                .await
+        }
+        .map_err(failure::Error::from)
+        .map_err(failure::Error::compat)?;
+        if output.success() {
+            String::from_utf8(output.stdout).map_err(From::from)
         } else {
             Err(RpcError {
-                code: status
-                    .code()
-                    .unwrap_or_else(|| status.signal().unwrap_or(0) + 128),
-                message: String::from_utf8(stderr)?,
+                code: output.code,
+                message: String::from_utf8(output.stderr)?,
                 data: None,
             })
         }
@@ -4,9 +4,10 @@ use linear_map::{set::LinearSet, LinearMap};
 use rand::SeedableRng;
 
 use crate::dependencies::AppDependencies;
+use crate::health::Health;
 use crate::manifest::{Manifest, ManifestLatest};
-use crate::util::Apply;
-use crate::util::{from_yaml_async_reader, PersistencePath, YamlUpdateHandle};
+use crate::runtime::ContainerStatus;
+use crate::util::{from_yaml_async_reader, PersistencePath};
 use crate::Error;
 use crate::ResultExt as _;
 
@@ -25,6 +26,10 @@ fn not(b: &bool) -> bool {
     !b
 }
 
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct AppInfo {
@@ -44,6 +49,16 @@ pub struct AppInfo {
 #[serde(rename_all = "kebab-case")]
 pub struct AppStatus {
     pub status: DockerStatus,
+    /// only for running apps that declare health checks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<Health>,
+    #[serde(skip_serializing_if = "LinearMap::is_empty")]
+    pub failing_checks: LinearMap<String, String>,
+    /// times the app has stopped without being asked to
+    #[serde(skip_serializing_if = "is_zero")]
+    pub crash_count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_exit_code: Option<i32>,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -71,108 +86,102 @@ pub struct AppInfoFull {
 }
 
 pub async fn list_info() -> Result<LinearMap<String, AppInfo>, Error> {
-    let apps_path = PersistencePath::from_ref("apps.yaml");
-    let mut f = match apps_path.maybe_read(false).await.transpose()? {
-        Some(a) => a,
-        None => return Ok(LinearMap::new()),
-    };
-    from_yaml_async_reader(&mut *f).await
-}
-
-pub async fn list_info_mut() -> Result<YamlUpdateHandle<LinearMap<String, AppInfo>>, Error> {
-    let apps_path = PersistencePath::from_ref("apps.yaml");
-    YamlUpdateHandle::new_or_default(apps_path).await
+    crate::db::get_or_default(crate::db::APPS).await
 }
 
 pub async fn add(id: &str, info: AppInfo) -> Result<(), failure::Error> {
-    let mut apps = list_info_mut().await?;
+    let mut db = crate::db::transaction().await?;
+    let mut apps: LinearMap<String, AppInfo> = db.get_or_default(crate::db::APPS)?;
     apps.insert(id.to_string(), info);
-    apps.commit().await?;
+    db.put(crate::db::APPS, &apps)?;
+    db.commit().await?;
     Ok(())
 }
 
-pub async fn set_configured(id: &str, configured: bool) -> Result<(), Error> {
-    let mut apps = list_info_mut().await?;
-    let mut app = apps
+/// Applies `f` to the info of `id` within `db`.
+pub fn update_info<F: FnOnce(&mut AppInfo)>(
+    db: &mut crate::db::Transaction,
+    id: &str,
+    f: F,
+) -> Result<(), Error> {
+    let mut apps: LinearMap<String, AppInfo> = db.get_or_default(crate::db::APPS)?;
+    let app = apps
         .get_mut(id)
         .ok_or_else(|| failure::format_err!("App Not Installed: {}", id))
         .with_code(crate::error::NOT_FOUND)?;
-    app.configured = configured;
-    apps.commit().await?;
-    Ok(())
+    f(app);
+    db.put(crate::db::APPS, &apps)
+}
+
+pub async fn set_configured(id: &str, configured: bool) -> Result<(), Error> {
+    let mut db = crate::db::transaction().await?;
+    update_info(&mut db, id, |app| app.configured = configured)?;
+    db.commit().await
 }
 
 pub async fn set_needs_restart(id: &str, needs_restart: bool) -> Result<(), Error> {
-    let mut apps = list_info_mut().await?;
-    let mut app = apps
-        .get_mut(id)
-        .ok_or_else(|| failure::format_err!("App Not Installed: {}", id))
-        .with_code(crate::error::NOT_FOUND)?;
-    app.needs_restart = needs_restart;
-    apps.commit().await?;
-    Ok(())
+    let mut db = crate::db::transaction().await?;
+    update_info(&mut db, id, |app| app.needs_restart = needs_restart)?;
+    db.commit().await
 }
 
 pub async fn set_recoverable(id: &str, recoverable: bool) -> Result<(), Error> {
-    let mut apps = list_info_mut().await?;
-    let mut app = apps
-        .get_mut(id)
-        .ok_or_else(|| failure::format_err!("App Not Installed: {}", id))
-        .with_code(crate::error::NOT_FOUND)?;
-    app.recoverable = recoverable;
-    apps.commit().await?;
-    Ok(())
+    let mut db = crate::db::transaction().await?;
+    update_info(&mut db, id, |app| app.recoverable = recoverable)?;
+    db.commit().await
 }
 
 pub async fn remove(id: &str) -> Result<(), failure::Error> {
-    let mut apps = list_info_mut().await?;
+    let mut db = crate::db::transaction().await?;
+    let mut apps: LinearMap<String, AppInfo> = db.get_or_default(crate::db::APPS)?;
     apps.remove(id);
-    apps.commit().await?;
+    db.put(crate::db::APPS, &apps)?;
+    db.commit().await?;
     Ok(())
 }
 
 pub async fn status(id: &str, remap_crashed: bool) -> Result<AppStatus, Error> {
-    let output = std::process::Command::new("docker")
-        .args(&["inspect", id, "--format", "{{.State.Status}}"])
-        .stdout(std::process::Stdio::piped())
-        .stderr(match log::max_level() {
-            log::LevelFilter::Error => std::process::Stdio::null(),
-            _ => std::process::Stdio::inherit(),
-        })
-        .spawn()?
-        .wait_with_output()?;
-    crate::ensure_code!(
-        output.status.success(),
-        crate::error::DOCKER_ERROR,
-        "{}: Docker Error: {}",
-        id,
-        std::str::from_utf8(&output.stderr).no_code()?
-    );
-    let status = std::str::from_utf8(&output.stdout).no_code()?;
-    Ok(AppStatus {
-        status: match status.trim() {
-            "running" => DockerStatus::Running,
-            "restarting" => DockerStatus::Restarting,
-            "removing" => DockerStatus::Removing,
-            "dead" => DockerStatus::Dead,
-            "exited"
-                if remap_crashed && {
-                    let path = PersistencePath::from_ref("running.yaml");
-                    if let Some(mut f) = path.maybe_read(false).await.transpose()? {
-                        let running: Vec<String> = from_yaml_async_reader(&mut *f).await?;
-                        running.iter().filter(|a| a.as_str() == id).next().is_some()
-                    } else {
-                        false
-                    }
-                } =>
+    let state = crate::runtime::get().inspect(id).await?;
+    let mut res = AppStatus {
+        status: match state.status {
+            ContainerStatus::Running => DockerStatus::Running,
+            ContainerStatus::Restarting => DockerStatus::Restarting,
+            ContainerStatus::Removing => DockerStatus::Removing,
+            ContainerStatus::Dead => DockerStatus::Dead,
+            ContainerStatus::Exited
+                if remap_crashed
+                    && crate::db::get_or_default::<LinearSet<String>>(crate::db::RUNNING)
+                        .await?
+                        .contains(id) =>
             {
                 DockerStatus::Restarting
             }
-            "created" | "exited" => DockerStatus::Stopped,
-            "paused" => DockerStatus::Paused,
-            _ => Err(format_err!("unknown status: {}", status))?,
+            ContainerStatus::Created | ContainerStatus::Exited => DockerStatus::Stopped,
+            ContainerStatus::Paused => DockerStatus::Paused,
         },
-    })
+        health: None,
+        failing_checks: LinearMap::new(),
+        crash_count: 0,
+        last_exit_code: None,
+    };
+    match crate::supervisor::crash_record(id).await {
+        Ok(record) => {
+            res.crash_count = record.crash_count;
+            res.last_exit_code = record.last_exit_code;
+        }
+        Err(e) => log::warn!("Failed to read crash record of {}: {}", id, e),
+    }
+    if res.status == DockerStatus::Running {
+        match crate::health::report(id, &state.started_at).await {
+            Ok(Some(report)) => {
+                res.health = Some(report.health);
+                res.failing_checks = report.failing;
+            }
+            Ok(None) => (),
+            Err(e) => log::warn!("Failed to read health of {}: {}", id, e),
+        }
+    }
+    Ok(res)
 }
 
 pub async fn manifest(id: &str) -> Result<ManifestLatest, Error> {
@@ -202,54 +211,39 @@ pub async fn config(id: &str) -> Result<AppConfig, Error> {
         crate::util::from_yaml_async_reader(&mut *rules.read(false).await?)
             .await
             .no_code()?;
-    let config = PersistencePath::from_ref("apps")
-        .join(id)
-        .join("config.yaml");
-    let config: Option<crate::config::Config> = match config
-        .maybe_read(false)
-        .await
-        .transpose()?
-        .map(|mut f| async move { from_yaml_async_reader(&mut *f).await })
-        .apply(OptionFuture::from)
-        .await
-    {
-        Some(Ok(cfg)) => Some(cfg),
-        #[cfg(not(feature = "production"))]
-        Some(Err(e)) => return Err(e),
-        _ => {
-            let volume_config = std::path::Path::new(crate::VOLUMES)
-                .join(id)
-                .join("start9")
-                .join("config.yaml");
-            if volume_config.exists() {
-                let cfg_path = config.path();
-                tokio::fs::copy(&volume_config, &cfg_path)
-                    .await
-                    .with_context(|e| {
-                        format!(
-                            "{}: {} -> {}",
-                            e,
-                            volume_config.display(),
-                            cfg_path.display()
-                        )
-                    })
-                    .with_code(crate::error::FILESYSTEM_ERROR)?;
-                let mut f = tokio::fs::File::open(&volume_config)
-                    .await
-                    .with_context(|e| format!("{}: {}", e, volume_config.display()))
-                    .with_code(crate::error::FILESYSTEM_ERROR)?;
-                match from_yaml_async_reader(&mut f).await {
-                    Ok(a) => Some(a),
-                    #[cfg(not(feature = "production"))]
-                    Err(e) => return Err(e),
-                    #[cfg(feature = "production")]
-                    _ => None,
+    let config: Option<crate::config::Config> =
+        match crate::db::get(&crate::db::config_key(id)).await {
+            Ok(Some(cfg)) => Some(cfg),
+            #[cfg(not(feature = "production"))]
+            Err(e) => return Err(e),
+            _ => {
+                let volume_config = crate::context::get()
+                    .volumes
+                    .join(id)
+                    .join("start9")
+                    .join("config.yaml");
+                if volume_config.exists() {
+                    let mut f = tokio::fs::File::open(&volume_config)
+                        .await
+                        .with_context(|e| format!("{}: {}", e, volume_config.display()))
+                        .with_code(crate::error::FILESYSTEM_ERROR)?;
+                    match from_yaml_async_reader::<crate::config::Config, _>(&mut f).await {
+                        Ok(a) => {
+                            let mut db = crate::db::transaction().await?;
+                            db.put(&crate::db::config_key(id), &a)?;
+                            db.commit().await?;
+                            Some(a)
+                        }
+                        #[cfg(not(feature = "production"))]
+                        Err(e) => return Err(e),
+                        #[cfg(feature = "production")]
+                        _ => None,
+                    }
+                } else {
+                    None
                 }
-            } else {
-                None
             }
-        }
-    };
+        };
     Ok(AppConfig {
         spec,
         rules,
@@ -8,6 +8,7 @@ use futures::TryStreamExt;
 use rand::Rng;
 use serde::Serialize;
 
+use crate::progress::Phase;
 use crate::util::from_yaml_async_reader;
 use crate::util::to_yaml_async_writer;
 use crate::util::Invoke;
@@ -37,10 +38,10 @@ pub async fn create_backup<P: AsRef<Path>>(
     let metadata_path = path.join("metadata.yaml");
     let pw_path = path.join("password");
     let data_path = path.join("data");
+    let volumes_path = path.join("volumes");
     let tor_path = path.join("tor");
-    let volume_path = Path::new(crate::VOLUMES).join(app_id);
-    let hidden_service_path =
-        Path::new(crate::tor::HIDDEN_SERVICE_DIR_ROOT).join(format!("app-{}", app_id));
+    let volume_path = crate::context::get().volumes.join(app_id);
+    let hidden_service_path = crate::context::get().hidden_service_dir(app_id);
 
     if pw_path.exists() {
         use tokio::io::AsyncReadExt;
@@ -94,6 +95,7 @@ pub async fn create_backup<P: AsRef<Path>>(
     };
     let running = status.status == crate::apps::DockerStatus::Running;
     if running {
+        crate::progress::step(Phase::Backup, "pausing app").await;
         crate::control::pause_app(&app_id).await?;
     }
     let mut data_cmd = tokio::process::Command::new("duplicity");
@@ -107,12 +109,32 @@ pub async fn create_backup<P: AsRef<Path>>(
             data_cmd.arg(format!("--exclude={}", volume_path.join(exclude).display()));
         }
     }
+    crate::progress::step(Phase::Backup, "backing up data").await;
     let data_res = data_cmd
         .env("PASSPHRASE", password)
         .arg(volume_path)
         .arg(format!("file://{}", data_path.display()))
         .invoke("Duplicity")
         .await;
+    let manifest = crate::apps::manifest(app_id).await?;
+    let mut volumes_res: Result<(), failure::Error> = Ok(());
+    for (name, volume) in &manifest.volumes.named {
+        if !volume.backed_up() {
+            continue;
+        }
+        crate::progress::step(Phase::Backup, format!("backing up volume {}", name)).await;
+        volumes_res = tokio::process::Command::new("duplicity")
+            .env("PASSPHRASE", password)
+            .arg(crate::context::get().named_volumes(app_id).join(name))
+            .arg(format!("file://{}", volumes_path.join(name).display()))
+            .invoke("Duplicity")
+            .await
+            .map(|_| ());
+        if volumes_res.is_err() {
+            break;
+        }
+    }
+    crate::progress::step(Phase::Backup, "backing up tor keys").await;
     let tor_res = tokio::process::Command::new("duplicity")
         .env("PASSPHRASE", password)
         .arg(hidden_service_path)
@@ -120,6 +142,7 @@ pub async fn create_backup<P: AsRef<Path>>(
         .invoke("Duplicity")
         .await;
     if running {
+        crate::progress::step(Phase::Backup, "resuming app").await;
         if crate::apps::info(&app_id).await?.needs_restart {
             crate::control::restart_app(&app_id).await?;
         } else {
@@ -127,7 +150,9 @@ pub async fn create_backup<P: AsRef<Path>>(
         }
     }
     data_res?;
+    volumes_res?;
     tor_res?;
+    crate::progress::complete(Phase::Backup).await;
 
     Ok(())
 }
@@ -146,10 +171,10 @@ pub async fn restore_backup<P: AsRef<Path>>(
     let metadata_path = path.join("metadata.yaml");
     let pw_path = path.join("password");
     let data_path = path.join("data");
+    let volumes_path = path.join("volumes");
     let tor_path = path.join("tor");
-    let volume_path = Path::new(crate::VOLUMES).join(app_id);
-    let hidden_service_path =
-        Path::new(crate::tor::HIDDEN_SERVICE_DIR_ROOT).join(format!("app-{}", app_id));
+    let volume_path = crate::context::get().volumes.join(app_id);
+    let hidden_service_path = crate::context::get().hidden_service_dir(app_id);
 
     if pw_path.exists() {
         use tokio::io::AsyncReadExt;
@@ -168,6 +193,7 @@ pub async fn restore_backup<P: AsRef<Path>>(
     let status = crate::apps::status(app_id, false).await?;
     let running = status.status == crate::apps::DockerStatus::Running;
     if running {
+        crate::progress::step(Phase::Restore, "stopping app").await;
         crate::control::stop_app(app_id, true, false).await?;
     }
 
@@ -185,6 +211,7 @@ pub async fn restore_backup<P: AsRef<Path>>(
         .arg(format!("file://{}", tor_path.display()))
         .arg(&hidden_service_path);
 
+    crate::progress::step(Phase::Restore, "restoring data").await;
     let (data_output, tor_output) = try_join!(data_cmd.status(), tor_cmd.status())?;
     crate::ensure_code!(
         data_output.success(),
@@ -197,24 +224,47 @@ pub async fn restore_backup<P: AsRef<Path>>(
         "Duplicity Error"
     );
 
-    // Fix the tor address in apps.yaml
-    let mut yhdl = crate::apps::list_info_mut().await?;
-    if let Some(app_info) = yhdl.get_mut(app_id) {
-        app_info.tor_address = Some(crate::tor::read_tor_address(app_id, None).await?);
+    let manifest = crate::apps::manifest(app_id).await?;
+    for (name, volume) in &manifest.volumes.named {
+        // a volume that was not backed up is left as it is
+        if !volume.backed_up() || !volumes_path.join(name).exists() {
+            continue;
+        }
+        crate::progress::step(Phase::Restore, format!("restoring volume {}", name)).await;
+        let volume_output = tokio::process::Command::new("duplicity")
+            .env("PASSPHRASE", password)
+            .arg("--force")
+            .arg(format!("file://{}", volumes_path.join(name).display()))
+            .arg(crate::context::get().named_volumes(app_id).join(name))
+            .status()
+            .await?;
+        crate::ensure_code!(
+            volume_output.success(),
+            crate::error::GENERAL_ERROR,
+            "Duplicity Error"
+        );
     }
-    yhdl.commit().await?;
+
+    // Fix the tor address in the app list
+    let tor_address = crate::tor::read_tor_address(app_id, None).await?;
+    let mut db = crate::db::transaction().await?;
+    crate::apps::update_info(&mut db, app_id, |app| app.tor_address = Some(tor_address))?;
+    db.commit().await?;
 
     tokio::fs::copy(
         metadata_path,
-        Path::new(crate::VOLUMES)
+        crate::context::get()
+            .volumes
             .join(app_id)
             .join("start9")
             .join("restore.yaml"),
     )
     .await?;
 
+    crate::progress::step(Phase::Restore, "restoring configuration").await;
     // Attempt to configure the service with the config coming from restoration
-    let cfg_path = Path::new(crate::VOLUMES)
+    let cfg_path = crate::context::get()
+        .volumes
         .join(app_id)
         .join("start9")
         .join("config.yaml");
@@ -225,6 +275,7 @@ pub async fn restore_backup<P: AsRef<Path>>(
         }
     }
 
+    crate::progress::step(Phase::Restore, "restarting services").await;
     crate::tor::restart().await?;
     // Delete the fullchain certificate, so it can be regenerated with the restored tor pubkey address
     PersistencePath::from_ref("apps")
@@ -232,10 +283,7 @@ pub async fn restore_backup<P: AsRef<Path>>(
         .join("cert-local.fullchain.crt.pem")
         .delete()
         .await?;
-    crate::tor::write_lan_services(
-        &crate::tor::services_map(&PersistencePath::from_ref(crate::SERVICES_YAML)).await?,
-    )
-    .await?;
+    crate::tor::write_lan_services(&crate::tor::services_map().await?).await?;
     let svc_exit = std::process::Command::new("service")
         .args(&["nginx", "reload"])
         .status()?;
@@ -248,6 +296,7 @@ pub async fn restore_backup<P: AsRef<Path>>(
             .or_else(|| { svc_exit.signal().map(|a| 128 + a) })
             .unwrap_or(0)
     );
+    crate::progress::complete(Phase::Restore).await;
 
     Ok(())
 }
@@ -257,7 +306,7 @@ pub async fn backup_to_partition(
     app_id: &str,
     password: &str,
 ) -> Result<(), Error> {
-    let backup_mount_path = Path::new(crate::BACKUP_MOUNT_POINT);
+    let backup_mount_path = crate::context::get().backup_mount_point.clone();
     let guard = crate::disks::MountGuard::new(logicalname, &backup_mount_path).await?;
     let backup_dir_path = backup_mount_path.join(crate::BACKUP_DIR).join(app_id);
     tokio::fs::create_dir_all(&backup_dir_path).await?;
@@ -274,7 +323,7 @@ pub async fn restore_from_partition(
     app_id: &str,
     password: &str,
 ) -> Result<(), Error> {
-    let backup_mount_path = Path::new(crate::BACKUP_MOUNT_POINT);
+    let backup_mount_path = crate::context::get().backup_mount_point.clone();
     let guard = crate::disks::MountGuard::new(logicalname, &backup_mount_path).await?;
     let backup_dir_path = backup_mount_path.join(crate::BACKUP_DIR).join(app_id);
 
@@ -0,0 +1,132 @@
+use std::str::FromStr;
+
+use async_compression::tokio_03::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder};
+use tokio::io::{AsyncRead, BufReader};
+
+use crate::Error;
+use crate::ResultExt as _;
+
+pub type Reader<'a> = Box<dyn AsyncRead + Unpin + Send + 'a>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+impl FromStr for Compression {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format_err!("Unknown Compression: {}", s)).no_code(),
+        }
+    }
+}
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+impl Compression {
+    pub fn encode<'a, R: AsyncRead + Unpin + Send + 'a>(self, r: R) -> Reader<'a> {
+        match self {
+            Compression::None => Box::new(r),
+            Compression::Gzip => Box::new(GzipEncoder::new(BufReader::new(r))),
+            Compression::Zstd => Box::new(ZstdEncoder::new(BufReader::new(r))),
+        }
+    }
+
+    /// Decoders keep reading past the end of the compressed stream until their input ends, so
+    /// readers that check the input once it is exhausted see all of it.
+    pub fn decode<'a, R: AsyncRead + Unpin + Send + 'a>(self, r: R) -> Reader<'a> {
+        match self {
+            Compression::None => Box::new(r),
+            Compression::Gzip => {
+                let mut decoder = GzipDecoder::new(BufReader::new(r));
+                decoder.multiple_members(true);
+                Box::new(decoder)
+            }
+            Compression::Zstd => {
+                let mut decoder = ZstdDecoder::new(BufReader::new(r));
+                decoder.multiple_members(true);
+                Box::new(decoder)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    const COMPRESSIONS: &[Compression] = &[Compression::None, Compression::Gzip, Compression::Zstd];
+
+    async fn encode(compression: Compression, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        compression
+            .encode(data)
+            .read_to_end(&mut out)
+            .await
+            .unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn test_multi_member_round_trip() {
+        for compression in COMPRESSIONS {
+            let mut encoded = encode(*compression, b"first member, ").await;
+            encoded.extend(encode(*compression, b"second member").await);
+            let mut decoded = Vec::new();
+            compression
+                .decode(&encoded[..])
+                .read_to_end(&mut decoded)
+                .await
+                .unwrap();
+            assert_eq!(decoded, b"first member, second member", "{}", compression);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_mismatch() {
+        for compression in COMPRESSIONS {
+            let bytes = crate::test_util::package(
+                &format!("compression-{}", compression),
+                &[("entry", b"contents of the entry", *compression)],
+            )
+            .await;
+            let mut pkg = crate::s9pk::Reader::new(std::io::Cursor::new(bytes))
+                .await
+                .unwrap();
+            pkg.toc.0.get_mut("entry").unwrap().sha256 = format!("{:064x}", 0);
+            let mut decoded = Vec::new();
+            let err = pkg
+                .entry("entry")
+                .await
+                .unwrap()
+                .read_to_end(&mut decoded)
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.kind(),
+                std::io::ErrorKind::InvalidData,
+                "{}",
+                compression
+            );
+        }
+    }
+}
@@ -1,5 +1,4 @@
 use std::borrow::Cow;
-use std::path::Path;
 use std::time::Duration;
 
 use failure::ResultExt as _;
@@ -19,7 +18,7 @@ pub mod spec;
 pub mod util;
 pub mod value;
 
-pub use rules::{ConfigRuleEntry, ConfigRuleEntryWithSuggestions};
+pub use rules::{ConfigExpr, ConfigRuleEntry, ConfigRuleEntryWithSuggestions};
 pub use spec::{ConfigSpec, Defaultable};
 use util::NumRange;
 pub use value::Config;
@@ -114,6 +113,9 @@ pub struct ConfigurationRes {
     pub changed: LinearMap<String, Config>,
     pub needs_restart: LinearSet<String>,
     pub stopped: LinearMap<String, TaggedDependencyError>,
+    /// dependents whose configuration no longer satisfies their spec or rules
+    #[serde(skip)]
+    pub unconfigured: LinearSet<String>,
 }
 
 // returns apps with changed configurations
@@ -172,19 +174,17 @@ pub async fn configure(
             let rules_path = PersistencePath::from_ref("apps")
                 .join(name)
                 .join("config_rules.yaml");
-            let config_path = PersistencePath::from_ref("apps")
-                .join(name)
-                .join("config.yaml");
             let spec: ConfigSpec =
                 from_yaml_async_reader(&mut *spec_path.read(false).await?).await?;
             let rules: Vec<ConfigRuleEntry> =
                 from_yaml_async_reader(&mut *rules_path.read(false).await?).await?;
-            let old_config: Option<Config> =
-                if let Some(mut f) = config_path.maybe_read(false).await.transpose()? {
-                    Some(from_yaml_async_reader(&mut *f).await?)
-                } else {
-                    None
-                };
+            // an app reached again through its dependents is compared against the configuration
+            // this call is about to save for it
+            let saved = res.changed.contains_key(name) || (info.configured && !info.recoverable);
+            let old_config: Option<Config> = match res.changed.get(name) {
+                Some(cfg) => Some(cfg.clone()),
+                None => crate::db::get(&crate::db::config_key(name)).await?,
+            };
             let mut config = if let Some(cfg) = config {
                 cfg
             } else {
@@ -207,9 +207,7 @@ pub async fn configure(
                     .with_code(crate::error::CFG_RULES_VIOLATION)?;
             }
             match old_config {
-                Some(old) if &old == &config && info.configured && !info.recoverable => {
-                    return Ok(config)
-                }
+                Some(old) if &old == &config && saved => return Ok(config),
                 _ => (),
             };
             res.changed.insert(name.to_owned(), config.clone());
@@ -239,9 +237,7 @@ pub async fn configure(
                         if e.code == Some(crate::error::CFG_RULES_VIOLATION)
                             || e.code == Some(crate::error::CFG_SPEC_VIOLATION)
                         {
-                            if !dry_run {
-                                crate::apps::set_configured(&dependent, false).await?;
-                            }
+                            res.unconfigured.insert(dependent.clone());
                             handle_broken_dependent(
                                 name,
                                 dependent,
@@ -263,33 +259,8 @@ pub async fn configure(
                     }
                 }
             }
-            if !dry_run {
-                let mut file = config_path.write(None).await?;
-                to_yaml_async_writer(file.as_mut(), &config).await?;
-                file.commit().await?;
-                let volume_config = Path::new(crate::VOLUMES)
-                    .join(name)
-                    .join("start9")
-                    .join("config.yaml");
-                tokio::fs::copy(config_path.path(), &volume_config)
-                    .await
-                    .with_context(|e| {
-                        format!(
-                            "{}: {} -> {}",
-                            e,
-                            config_path.path().display(),
-                            volume_config.display()
-                        )
-                    })
-                    .with_code(crate::error::FILESYSTEM_ERROR)?;
-                crate::apps::set_configured(name, true).await?;
-                crate::apps::set_recoverable(name, false).await?;
-            }
             if crate::apps::status(name, false).await?.status != crate::apps::DockerStatus::Stopped
             {
-                if !dry_run {
-                    crate::apps::set_needs_restart(name, true).await?;
-                }
                 res.needs_restart.insert(name.to_string());
             }
             Ok(config)
@@ -298,21 +269,42 @@ pub async fn configure(
     }
     let mut res = ConfigurationRes::default();
     configure_rec(name, config, timeout, dry_run, &mut res).await?;
+    if !dry_run {
+        // every app changed by this configuration is saved together
+        let mut db = crate::db::transaction().await?;
+        for (id, config) in &res.changed {
+            db.put(&crate::db::config_key(id), config)?;
+            crate::apps::update_info(&mut db, id, |app| {
+                app.configured = true;
+                app.recoverable = false;
+            })?;
+        }
+        for id in &res.unconfigured {
+            crate::apps::update_info(&mut db, id, |app| app.configured = false)?;
+        }
+        for id in &res.needs_restart {
+            crate::apps::update_info(&mut db, id, |app| app.needs_restart = true)?;
+        }
+        db.commit().await?;
+        for (id, config) in &res.changed {
+            let volume_config = crate::context::get()
+                .volumes
+                .join(id)
+                .join("start9")
+                .join("config.yaml");
+            let mut file = tokio::fs::File::create(&volume_config)
+                .await
+                .with_context(|e| format!("{}: {}", e, volume_config.display()))
+                .with_code(crate::error::FILESYSTEM_ERROR)?;
+            to_yaml_async_writer(&mut file, config).await?;
+        }
+    }
     Ok(res)
 }
 
 pub async fn remove(name: &str) -> Result<(), crate::Error> {
-    let config_path = PersistencePath::from_ref("apps")
-        .join(name)
-        .join("config.yaml")
-        .path();
-    if config_path.exists() {
-        tokio::fs::remove_file(&config_path)
-            .await
-            .with_context(|e| format!("{}: {}", e, config_path.display()))
-            .with_code(crate::error::FILESYSTEM_ERROR)?;
-    }
-    let volume_config = Path::new(crate::VOLUMES)
+    let volume_config = crate::context::get()
+        .volumes
         .join(name)
         .join("start9")
         .join("config.yaml");
@@ -322,6 +314,8 @@ pub async fn remove(name: &str) -> Result<(), crate::Error> {
             .with_context(|e| format!("{}: {}", e, volume_config.display()))
             .with_code(crate::error::FILESYSTEM_ERROR)?;
     }
-    crate::apps::set_configured(name, false).await?;
-    Ok(())
+    let mut db = crate::db::transaction().await?;
+    db.remove(&crate::db::config_key(name));
+    crate::apps::update_info(&mut db, name, |app| app.configured = false)?;
+    db.commit().await
 }
@@ -101,6 +101,42 @@ impl serde::ser::Serialize for ConfigRule {
         serializer.serialize_str(&self.src)
     }
 }
+/// An expression of the rule language that evaluates to a value of the config, e.g.
+/// `"http://" + 'rpc.user + ":" + 'rpc.password + "@localhost"`.
+#[derive(Clone)]
+pub struct ConfigExpr {
+    pub src: String,
+    pub compiled: Arc<CompiledExpr<Value>>,
+}
+impl std::fmt::Debug for ConfigExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConfigExpr")
+            .field("src", &self.src)
+            .field("compiled", &"Fn(&Config, &Config) -> Value")
+            .finish()
+    }
+}
+impl<'de> serde::de::Deserialize<'de> for ConfigExpr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let src = String::deserialize(deserializer)?;
+        let compiled = compile_expr(&src).map_err(serde::de::Error::custom)?;
+        Ok(ConfigExpr {
+            src,
+            compiled: Arc::new(compiled),
+        })
+    }
+}
+impl serde::ser::Serialize for ConfigExpr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        serializer.serialize_str(&self.src)
+    }
+}
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ConfigRuleEntry {
     pub rule: ConfigRule,
@@ -17,7 +17,6 @@ use super::{MatchError, NoMatchWithPath, TimeoutError};
 
 use crate::config::ConfigurationError;
 use crate::manifest::ManifestLatest;
-use crate::util::PersistencePath;
 
 // Config Value Specifications
 #[async_trait]
@@ -1377,8 +1376,7 @@ impl AppPointerSpec {
                     .unwrap_or(Value::Null))
             }
             AppPointerSpecVariants::TorKey => {
-                let services_path = PersistencePath::from_ref(crate::SERVICES_YAML);
-                let service_map = crate::tor::services_map(&services_path)
+                let service_map = crate::tor::services_map()
                     .await
                     .map_err(ConfigurationError::SystemError)?;
                 let service =
@@ -1397,8 +1395,7 @@ impl AppPointerSpec {
                 )
             }
             AppPointerSpecVariants::LanAddress => {
-                let services_path = PersistencePath::from_ref(crate::SERVICES_YAML);
-                let mut service_map = crate::tor::services_map(&services_path)
+                let mut service_map = crate::tor::services_map()
                     .await
                     .map_err(ConfigurationError::SystemError)?;
                 let service = service_map.map.remove(&self.app_id);
@@ -1842,32 +1839,37 @@ mod test {
                 config: Vec::new(),
             },
         );
-        spec.validate(&crate::manifest::ManifestV0 {
-            id: "test-app".to_owned(),
-            version: "0.1.0".parse().unwrap(),
-            title: "Test App".to_owned(),
-            description: crate::manifest::Description {
-                short: "A test app.".to_owned(),
-                long: "A super cool test app for testing".to_owned(),
-            },
-            release_notes: "Some things changed".to_owned(),
-            ports: Vec::new(),
-            image: crate::manifest::ImageConfig::Tar,
-            shm_size_mb: None,
-            mount: "/root".parse().unwrap(),
-            public: None,
-            shared: None,
-            has_instructions: false,
-            os_version_required: ">=0.2.5".parse().unwrap(),
-            os_version_recommended: ">=0.2.5".parse().unwrap(),
-            assets: Vec::new(),
-            hidden_service_version: crate::tor::HiddenServiceVersion::V3,
-            dependencies: deps,
-            extra: LinearMap::new(),
-            install_alert: None,
-            restore_alert: None,
-            uninstall_alert: None,
-        })
+        spec.validate(
+            &crate::manifest::ManifestV0 {
+                id: "test-app".to_owned(),
+                version: "0.1.0".parse().unwrap(),
+                title: "Test App".to_owned(),
+                description: crate::manifest::Description {
+                    short: "A test app.".to_owned(),
+                    long: "A super cool test app for testing".to_owned(),
+                },
+                release_notes: "Some things changed".to_owned(),
+                ports: Vec::new(),
+                image: crate::manifest::ImageConfig::Tar { arch: Vec::new() },
+                shm_size_mb: None,
+                mount: "/root".parse().unwrap(),
+                public: None,
+                shared: None,
+                has_instructions: false,
+                os_version_required: ">=0.2.5".parse().unwrap(),
+                os_version_recommended: ">=0.2.5".parse().unwrap(),
+                assets: Vec::new(),
+                hidden_service_version: crate::tor::HiddenServiceVersion::V3,
+                dependencies: deps,
+                extra: LinearMap::new(),
+                actions: Vec::new(),
+                install_alert: None,
+                restore_alert: None,
+                uninstall_alert: None,
+                start_alert: None,
+            }
+            .into(),
+        )
         .unwrap();
         let config = spec
             .gen(&mut rand::rngs::StdRng::from_entropy(), &None)
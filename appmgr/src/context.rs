@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use failure::ResultExt as _;
+
+use crate::Error;
+use crate::ResultExt as _;
+
+pub const DEFAULT_CONFIG: &'static str = "/etc/appmgr/config.yaml";
+
+lazy_static::lazy_static! {
+    static ref CONTEXT: RwLock<Arc<Context>> = RwLock::new(Arc::new(
+        Context::default().under_env_root()
+    ));
+}
+
+/// The context appmgr runs in: the defaults, moved under `APPMGR_ROOT` if it is set, unless
+/// replaced with `set`.
+pub fn get() -> Arc<Context> {
+    CONTEXT.read().unwrap().clone()
+}
+
+pub fn set(context: Context) {
+    *CONTEXT.write().unwrap() = Arc::new(context);
+}
+
+/// Where appmgr keeps its state and finds the system files it manages.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct Context {
+    pub persistence_dir: PathBuf,
+    /// app data volumes, one directory per app
+    pub volumes: PathBuf,
+    pub tmp_dir: PathBuf,
+    pub backup_mount_point: PathBuf,
+    /// the torrc tor reads, rewritten from `tor_rc` whenever hidden services change
+    pub etc_tor_rc: PathBuf,
+    pub hidden_service_dir_root: PathBuf,
+    pub nginx_services_conf: PathBuf,
+    /// the certificate authority LAN certificates are signed with
+    pub ca_dir: PathBuf,
+}
+impl Default for Context {
+    fn default() -> Self {
+        Context {
+            persistence_dir: "/root/appmgr".into(),
+            volumes: "/root/volumes".into(),
+            tmp_dir: "/root/tmp/appmgr".into(),
+            backup_mount_point: "/mnt/backup_drive".into(),
+            etc_tor_rc: "/etc/tor/torrc".into(),
+            hidden_service_dir_root: "/var/lib/tor".into(),
+            nginx_services_conf: "/etc/nginx/sites-available/start9-services.conf".into(),
+            ca_dir: "/root/agent/ca".into(),
+        }
+    }
+}
+impl Context {
+    /// Reads the context from the file named by `APPMGR_CONFIG`, or from `DEFAULT_CONFIG` if
+    /// it exists, then moves it under `APPMGR_ROOT` if that is set. Paths the file leaves out
+    /// keep their defaults.
+    pub fn load() -> Result<Self, Error> {
+        let path = std::env::var_os("APPMGR_CONFIG")
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG)).filter(|a| a.exists()));
+        let context = match path {
+            Some(path) => {
+                let file = std::fs::File::open(&path)
+                    .with_context(|e| format!("{}: {}", path.display(), e))
+                    .with_code(crate::error::FILESYSTEM_ERROR)?;
+                serde_yaml::from_reader(file)
+                    .with_context(|e| format!("{}: {}", path.display(), e))
+                    .with_code(crate::error::SERDE_ERROR)?
+            }
+            None => Context::default(),
+        };
+        Ok(context.under_env_root())
+    }
+
+    fn under_env_root(self) -> Self {
+        match std::env::var_os("APPMGR_ROOT") {
+            Some(root) => self.under(root),
+            None => self,
+        }
+    }
+
+    /// Moves every path under `root`, e.g. to run appmgr in a sandbox directory.
+    pub fn under<P: AsRef<Path>>(self, root: P) -> Self {
+        let root = root.as_ref();
+        let rebase = |p: PathBuf| root.join(p.strip_prefix("/").unwrap_or(&p));
+        Context {
+            persistence_dir: rebase(self.persistence_dir),
+            volumes: rebase(self.volumes),
+            tmp_dir: rebase(self.tmp_dir),
+            backup_mount_point: rebase(self.backup_mount_point),
+            etc_tor_rc: rebase(self.etc_tor_rc),
+            hidden_service_dir_root: rebase(self.hidden_service_dir_root),
+            nginx_services_conf: rebase(self.nginx_services_conf),
+            ca_dir: rebase(self.ca_dir),
+        }
+    }
+
+    /// The torrc appmgr's hidden services are appended to.
+    pub fn tor_rc(&self) -> PathBuf {
+        self.persistence_dir.join("tor").join("torrc")
+    }
+
+    /// Where the named volumes of `app_id` are kept, one directory each.
+    pub fn named_volumes(&self, app_id: &str) -> PathBuf {
+        self.volumes.join(format!("{}.volumes", app_id))
+    }
+
+    pub fn hidden_service_dir(&self, app_id: &str) -> PathBuf {
+        self.hidden_service_dir_root.join(format!("app-{}", app_id))
+    }
+
+    pub fn control_lock(&self, app_id: &str) -> String {
+        format!(
+            "{}",
+            self.persistence_dir
+                .join("apps")
+                .join(app_id)
+                .join("control.lock")
+                .display()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_under() {
+        let ctx = Context::default().under("/tmp/sandbox");
+        assert_eq!(ctx.persistence_dir, Path::new("/tmp/sandbox/root/appmgr"));
+        assert_eq!(ctx.etc_tor_rc, Path::new("/tmp/sandbox/etc/tor/torrc"));
+        assert_eq!(
+            ctx.tor_rc(),
+            Path::new("/tmp/sandbox/root/appmgr/tor/torrc")
+        );
+    }
+}
@@ -1,49 +1,25 @@
-use std::path::Path;
-
 use futures::future::{BoxFuture, FutureExt};
 use linear_map::{set::LinearSet, LinearMap};
 
 use crate::dependencies::{DependencyError, TaggedDependencyError};
-use crate::util::{from_yaml_async_reader, PersistencePath, YamlUpdateHandle};
 use crate::Error;
 
 pub async fn start_app(name: &str, update_metadata: bool) -> Result<(), Error> {
-    let lock = crate::util::lock_file(
-        format!(
-            "{}",
-            Path::new(crate::PERSISTENCE_DIR)
-                .join("apps")
-                .join(name)
-                .join("control.lock")
-                .display()
-        ),
-        true,
-    )
-    .await?;
+    let lock = crate::util::lock_file(crate::context::get().control_lock(name), true).await?;
     let status = crate::apps::status(name, false).await?.status;
     if status == crate::apps::DockerStatus::Stopped {
         if update_metadata {
             crate::config::configure(name, None, None, false).await?;
             crate::dependencies::update_binds(name).await?;
         }
-        crate::apps::set_needs_restart(name, false).await?;
-        let mut running = YamlUpdateHandle::<LinearSet<String>>::new_or_default(
-            PersistencePath::from_ref("running.yaml"),
-        )
-        .await?;
-        let output = tokio::process::Command::new("docker")
-            .args(&["start", name])
-            .stdout(std::process::Stdio::null())
-            .output()
-            .await?;
-        crate::ensure_code!(
-            output.status.success(),
-            crate::error::DOCKER_ERROR,
-            "Failed to Start Application: {}",
-            std::str::from_utf8(&output.stderr).unwrap_or("Unknown Error")
-        );
+        crate::env::refresh(name).await?;
+        crate::runtime::get().start(name).await?;
+        let mut db = crate::db::transaction().await?;
+        crate::apps::update_info(&mut db, name, |app| app.needs_restart = false)?;
+        let mut running: LinearSet<String> = db.get_or_default(crate::db::RUNNING)?;
         running.insert(name.to_owned());
-        running.commit().await?;
+        db.put(crate::db::RUNNING, &running)?;
+        db.commit().await?;
     } else if status == crate::apps::DockerStatus::Paused {
         resume_app(name).await?;
     }
@@ -61,36 +37,14 @@ pub async fn stop_app(
         stop_dependents(name, dry_run, DependencyError::NotRunning, &mut res).await?;
     }
     if !dry_run {
-        let lock = crate::util::lock_file(
-            format!(
-                "{}",
-                Path::new(crate::PERSISTENCE_DIR)
-                    .join("apps")
-                    .join(name)
-                    .join("control.lock")
-                    .display()
-            ),
-            true,
-        )
-        .await?;
-        let mut running = YamlUpdateHandle::<LinearSet<String>>::new_or_default(
-            PersistencePath::from_ref("running.yaml"),
-        )
-        .await?;
+        let lock = crate::util::lock_file(crate::context::get().control_lock(name), true).await?;
         log::info!("Stopping {}", name);
-        let output = tokio::process::Command::new("docker")
-            .args(&["stop", "-t", "25", name])
-            .stdout(std::process::Stdio::null())
-            .output()
-            .await?;
-        crate::ensure_code!(
-            output.status.success(),
-            crate::error::DOCKER_ERROR,
-            "Failed to Stop Application: {}",
-            std::str::from_utf8(&output.stderr).unwrap_or("Unknown Error")
-        );
+        crate::runtime::get().stop(name, 25).await?;
+        let mut db = crate::db::transaction().await?;
+        let mut running: LinearSet<String> = db.get_or_default(crate::db::RUNNING)?;
         running.remove(name);
-        running.commit().await?;
+        db.put(crate::db::RUNNING, &running)?;
+        db.commit().await?;
         crate::util::unlock(lock).await?;
     }
     Ok(res)
@@ -149,89 +103,15 @@ pub async fn restart_app(name: &str) -> Result<(), Error> {
 }
 
 pub async fn pause_app(name: &str) -> Result<(), Error> {
-    let lock = crate::util::lock_file(
-        format!(
-            "{}",
-            Path::new(crate::PERSISTENCE_DIR)
-                .join("apps")
-                .join(name)
-                .join("control.lock")
-                .display()
-        ),
-        true,
-    )
-    .await?;
-    let output = tokio::process::Command::new("docker")
-        .args(&["pause", name])
-        .stdout(std::process::Stdio::null())
-        .output()
-        .await?;
-    crate::ensure_code!(
-        output.status.success(),
-        crate::error::DOCKER_ERROR,
-        "Failed to Pause Application: {}",
-        std::str::from_utf8(&output.stderr).unwrap_or("Unknown Error")
-    );
-
+    let lock = crate::util::lock_file(crate::context::get().control_lock(name), true).await?;
+    crate::runtime::get().pause(name).await?;
     crate::util::unlock(lock).await?;
     Ok(())
 }
 
 pub async fn resume_app(name: &str) -> Result<(), Error> {
-    let lock = crate::util::lock_file(
-        format!(
-            "{}",
-            Path::new(crate::PERSISTENCE_DIR)
-                .join("apps")
-                .join(name)
-                .join("control.lock")
-                .display()
-        ),
-        true,
-    )
-    .await?;
-    let output = tokio::process::Command::new("docker")
-        .args(&["unpause", name])
-        .stdout(std::process::Stdio::null())
-        .output()
-        .await?;
-    crate::ensure_code!(
-        output.status.success(),
-        crate::error::DOCKER_ERROR,
-        "Failed to Resume Application: {}",
-        std::str::from_utf8(&output.stderr).unwrap_or("Unknown Error")
-    );
+    let lock = crate::util::lock_file(crate::context::get().control_lock(name), true).await?;
+    crate::runtime::get().resume(name).await?;
     crate::util::unlock(lock).await?;
     Ok(())
 }
-
-pub async fn repair_app_status() -> Result<(), Error> {
-    let mut running_file = PersistencePath::from_ref("running.yaml")
-        .maybe_read(false)
-        .await
-        .transpose()?;
-    let running: Vec<String> = if let Some(f) = running_file.as_mut() {
-        from_yaml_async_reader::<_, &mut tokio::fs::File>(f).await?
-    } else {
-        Vec::new()
-    };
-    for name in running {
-        let lock = crate::util::lock_file(
-            format!(
-                "{}",
-                Path::new(crate::PERSISTENCE_DIR)
-                    .join("apps")
-                    .join(&name)
-                    .join("control.lock")
-                    .display()
-            ),
-            true,
-        )
-        .await?;
-        if crate::apps::status(&name, false).await?.status == crate::apps::DockerStatus::Stopped {
-            start_app(&name, true).await?;
-        }
-        crate::util::unlock(lock).await?;
-    }
-    Ok(())
-}
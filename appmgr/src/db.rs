@@ -0,0 +1,387 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use failure::ResultExt as _;
+use file_lock::FileLock;
+use linear_map::set::LinearSet;
+use linear_map::LinearMap;
+
+use crate::util::{to_yaml_async_writer, PersistencePath};
+use crate::Error;
+use crate::ResultExt as _;
+
+/// The file appmgr's state is kept in, relative to the persistence directory.
+pub const DB: &'static str = "db.yaml";
+
+/// the version of appmgr the state was last migrated to
+pub const VERSION: &'static str = "version";
+/// `LinearMap<String, apps::AppInfo>`
+pub const APPS: &'static str = "apps";
+/// `LinearSet<String>` of the apps that should be running
+pub const RUNNING: &'static str = "running";
+/// `tor::ServicesMap`
+pub const SERVICES: &'static str = "services";
+
+/// The key of the `config::Config` of `app_id`.
+pub fn config_key(app_id: &str) -> String {
+    format!("configs/{}", app_id)
+}
+
+/// The key of the `manifest::Resources` the user has set on `app_id`, in place of its manifest's.
+pub fn resources_key(app_id: &str) -> String {
+    format!("resources/{}", app_id)
+}
+
+/// The key of the environment the container of `app_id` was last created with.
+pub fn env_key(app_id: &str) -> String {
+    format!("env/{}", app_id)
+}
+
+/// The key of the `health::HealthState` the supervisor last stored for `app_id`.
+pub fn health_key(app_id: &str) -> String {
+    format!("health/{}", app_id)
+}
+
+type Data = LinearMap<String, serde_yaml::Value>;
+
+fn path() -> PersistencePath {
+    PersistencePath::from_ref(DB)
+}
+
+pub async fn exists() -> bool {
+    path().exists().await
+}
+
+async fn load() -> Result<Data, Error> {
+    let path = path().path();
+    match tokio::fs::read(&path).await {
+        Ok(buf) if buf.is_empty() => Ok(Data::new()),
+        Ok(buf) => serde_yaml::from_slice(&buf)
+            .with_context(|e| format!("{}: {}", path.display(), e))
+            .with_code(crate::error::SERDE_ERROR),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Data::new()),
+        Err(e) => Err(e)
+            .with_context(|e| format!("{}: {}", path.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR),
+    }
+}
+
+fn decode<T: serde::de::DeserializeOwned>(
+    key: &str,
+    value: Option<&serde_yaml::Value>,
+) -> Result<Option<T>, Error> {
+    value
+        .map(|v| {
+            serde_yaml::from_value(v.clone())
+                .with_context(|e| format!("{}: {}", key, e))
+                .with_code(crate::error::SERDE_ERROR)
+        })
+        .transpose()
+}
+
+type Open = Arc<Mutex<Option<Data>>>;
+
+tokio::task_local! {
+    /// The state as changed by the outermost transaction whose `scope` the task is running in.
+    static OPEN: Open;
+}
+
+fn open() -> Option<Open> {
+    OPEN.try_with(|open| open.clone())
+        .ok()
+        .filter(|open| open.lock().unwrap().is_some())
+}
+
+/// Reads `key` as last committed, without waiting for a transaction in progress. Within the
+/// `scope` of a transaction, reads see its changes.
+pub async fn get<T: serde::de::DeserializeOwned>(key: &str) -> Result<Option<T>, Error> {
+    if let Some(open) = open() {
+        if let Some(data) = open.lock().unwrap().as_ref() {
+            return decode(key, data.get(key));
+        }
+    }
+    decode(key, load().await?.get(key))
+}
+
+pub async fn get_or_default<T: serde::de::DeserializeOwned + Default>(
+    key: &str,
+) -> Result<T, Error> {
+    Ok(get(key).await?.unwrap_or_default())
+}
+
+/// Changes to the state that are committed together, or not at all if the transaction is dropped
+/// or appmgr dies before `commit` returns.
+///
+/// A transaction begun within the `scope` of another is nested in it: its changes are committed
+/// into the outer transaction, and only reach the file if that one commits too. This is how an
+/// install makes the changes of every step it goes through at once. Transactions begun anywhere
+/// else, such as in another task, are not affected by it.
+pub struct Transaction {
+    /// shared with the transactions nested in this one, and `None` once the outermost one is
+    /// committed or dropped
+    open: Open,
+    /// held by an outermost transaction from `transaction`, for as long as it is open
+    lock: Option<FileLock>,
+    /// the state as an outermost transaction from `transaction_unlocked` found it
+    base: Option<Data>,
+    /// the changes of a nested transaction, `None` for keys it removes. The outermost one changes
+    /// the shared state directly, since dropping it discards that anyway.
+    changes: Option<LinearMap<String, Option<serde_yaml::Value>>>,
+}
+
+fn nested(open: Open) -> Transaction {
+    Transaction {
+        open,
+        lock: None,
+        base: None,
+        changes: Some(LinearMap::new()),
+    }
+}
+
+/// Begins a transaction, holding the lock on the file until it is committed or dropped so that
+/// only one process changes the state at a time.
+pub async fn transaction() -> Result<Transaction, Error> {
+    if let Some(open) = open() {
+        return Ok(nested(open));
+    }
+    let lock = path().lock(true).await?;
+    let data = load().await?;
+    Ok(Transaction {
+        open: Arc::new(Mutex::new(Some(data))),
+        lock: Some(lock),
+        base: None,
+        changes: None,
+    })
+}
+
+/// Begins a transaction that only takes the lock on the file to commit, for one that runs too long
+/// to keep other processes waiting, such as an install. Committing it fails if another
+/// transaction changed a key it changes since it began.
+pub async fn transaction_unlocked() -> Result<Transaction, Error> {
+    if let Some(open) = open() {
+        return Ok(nested(open));
+    }
+    let data = load().await?;
+    Ok(Transaction {
+        open: Arc::new(Mutex::new(Some(data.clone()))),
+        lock: None,
+        base: Some(data),
+        changes: None,
+    })
+}
+
+impl Transaction {
+    fn with_data<T, F: FnOnce(&mut Data) -> Result<T, Error>>(&self, f: F) -> Result<T, Error> {
+        match self.open.lock().unwrap().as_mut() {
+            Some(data) => f(data),
+            None => Err(failure::format_err!(
+                "Transaction Outlived The One It Was Nested In"
+            ))
+            .with_code(crate::error::GENERAL_ERROR),
+        }
+    }
+
+    /// Runs `f` with the transactions it begins nested in this one, and its reads seeing the
+    /// changes of this one.
+    pub async fn scope<F: Future>(&self, f: F) -> F::Output {
+        OPEN.scope(self.open.clone(), f).await
+    }
+
+    pub fn get<T: serde::de::DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Error> {
+        if let Some(change) = self.changes.as_ref().and_then(|c| c.get(key)) {
+            return decode(key, change.as_ref());
+        }
+        self.with_data(|data| decode(key, data.get(key)))
+    }
+
+    pub fn get_or_default<T: serde::de::DeserializeOwned + Default>(
+        &self,
+        key: &str,
+    ) -> Result<T, Error> {
+        Ok(self.get(key)?.unwrap_or_default())
+    }
+
+    pub fn put<T: serde::Serialize>(&mut self, key: &str, value: &T) -> Result<(), Error> {
+        let value = serde_yaml::to_value(value)
+            .with_context(|e| format!("{}: {}", key, e))
+            .with_code(crate::error::SERDE_ERROR)?;
+        match &mut self.changes {
+            Some(changes) => {
+                changes.insert(key.to_owned(), Some(value));
+                Ok(())
+            }
+            None => self.with_data(|data| {
+                data.insert(key.to_owned(), value);
+                Ok(())
+            }),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        match &mut self.changes {
+            Some(changes) => {
+                changes.insert(key.to_owned(), None);
+            }
+            None => {
+                if let Some(data) = self.open.lock().unwrap().as_mut() {
+                    data.remove(key);
+                }
+            }
+        }
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>, Error> {
+        let mut keys = self.with_data(|data| Ok(data.keys().cloned().collect::<Vec<_>>()))?;
+        if let Some(changes) = &self.changes {
+            keys.retain(|k| !matches!(changes.get(k), Some(None)));
+            for (k, v) in changes {
+                if v.is_some() && !keys.contains(k) {
+                    keys.push(k.clone());
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    pub async fn commit(mut self) -> Result<(), Error> {
+        if let Some(changes) = &mut self.changes {
+            let changes = std::mem::take(changes);
+            return self.with_data(|data| {
+                for (key, value) in changes {
+                    match value {
+                        Some(value) => data.insert(key, value),
+                        None => data.remove(&key),
+                    };
+                }
+                Ok(())
+            });
+        }
+        let mut data = self.with_data(|data| Ok(data.clone()))?;
+        let lock = match self.lock.take() {
+            Some(lock) => lock,
+            None => path().lock(true).await?,
+        };
+        if let Some(base) = &self.base {
+            let changed = base
+                .keys()
+                .chain(data.keys())
+                .filter(|key| base.get(*key) != data.get(*key))
+                .cloned()
+                .collect::<LinearSet<_>>();
+            let mut current = load().await?;
+            for key in changed {
+                crate::ensure_code!(
+                    current.get(&key) == base.get(&key),
+                    crate::error::GENERAL_ERROR,
+                    "Database Changed During Transaction: {}",
+                    key
+                );
+                match data.remove(&key) {
+                    Some(value) => current.insert(key, value),
+                    None => current.remove(&key),
+                };
+            }
+            data = current;
+        }
+        let mut file = path().write(Some(lock)).await?;
+        to_yaml_async_writer(&mut *file, &data).await?;
+        file.commit().await?;
+        *self.open.lock().unwrap() = None;
+        Ok(())
+    }
+}
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        // the outermost transaction discards the shared state, and the changes of every
+        // transaction nested in it, unless it has been committed
+        if self.changes.is_none() {
+            *self.open.lock().unwrap() = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn committed<T: serde::de::DeserializeOwned>(key: &str) -> Option<T> {
+        decode(key, load().await.unwrap().get(key)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_nested() {
+        let (_guard, root) = crate::test_util::sandbox("db-nested").await;
+        let mut outer = transaction().await.unwrap();
+        outer.put("a", &1).unwrap();
+        outer
+            .scope(async {
+                let mut inner = transaction().await.unwrap();
+                assert_eq!(inner.get::<u32>("a").unwrap(), Some(1));
+                inner.put("b", &2).unwrap();
+                assert_eq!(get::<u32>("b").await.unwrap(), None);
+                inner.commit().await.unwrap();
+                assert_eq!(get::<u32>("b").await.unwrap(), Some(2));
+                let mut dropped = transaction().await.unwrap();
+                dropped.put("c", &3).unwrap();
+            })
+            .await;
+        // outside of its scope, only what is committed is read
+        assert_eq!(get::<u32>("b").await.unwrap(), None);
+        outer.commit().await.unwrap();
+        assert_eq!(committed::<u32>("a").await, Some(1));
+        assert_eq!(committed::<u32>("b").await, Some(2));
+        assert_eq!(committed::<u32>("c").await, None);
+
+        // dropping the outer transaction discards what was committed into it
+        let outer = transaction().await.unwrap();
+        outer
+            .scope(async {
+                let mut inner = transaction().await.unwrap();
+                inner.remove("a");
+                inner.put("d", &4).unwrap();
+                inner.commit().await.unwrap();
+                assert_eq!(get::<u32>("a").await.unwrap(), None);
+            })
+            .await;
+        drop(outer);
+        assert_eq!(get::<u32>("a").await.unwrap(), Some(1));
+        assert_eq!(get::<u32>("d").await.unwrap(), None);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unlocked() {
+        let (_guard, root) = crate::test_util::sandbox("db-unlocked").await;
+        let mut outer = transaction_unlocked().await.unwrap();
+        outer.put("a", &1).unwrap();
+        // another task is not nested in it, nor kept waiting by it
+        outer
+            .scope(async {
+                tokio::spawn(async {
+                    let mut db = transaction().await.unwrap();
+                    assert_eq!(db.get::<u32>("a").unwrap(), None);
+                    db.put("b", &2).unwrap();
+                    db.commit().await.unwrap();
+                })
+                .await
+                .unwrap();
+            })
+            .await;
+        assert_eq!(outer.get::<u32>("b").unwrap(), None);
+        outer.commit().await.unwrap();
+        assert_eq!(committed::<u32>("a").await, Some(1));
+        assert_eq!(committed::<u32>("b").await, Some(2));
+
+        // changed by another since it began
+        let mut outer = transaction_unlocked().await.unwrap();
+        outer.put("a", &3).unwrap();
+        let mut db = transaction().await.unwrap();
+        db.put("a", &4).unwrap();
+        db.commit().await.unwrap();
+        assert!(outer.commit().await.is_err());
+        assert_eq!(committed::<u32>("a").await, Some(4));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,697 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt as _;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::Error;
+use crate::ResultExt as _;
+
+pub const MAGIC: &'static [u8; 4] = b"S9DL";
+pub const VERSION: u8 = 3;
+/// The base package is matched in blocks of this size. Data that does not line up with a whole
+/// block of the base is sent as is.
+pub const BLOCK_SIZE: usize = 64 * 1024;
+const MAX_HEADER_LEN: u32 = 1024 * 1024;
+/// Literal runs are flushed at this length so the diff does not hold the whole target in memory.
+const MAX_LITERAL: usize = 4 * 1024 * 1024;
+
+const OP_COPY: u8 = 0;
+const OP_LITERAL: u8 = 1;
+const OP_END: u8 = 2;
+
+/// Identifies the two packages a delta goes between.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeltaHeader {
+    pub id: String,
+    pub base: emver::Version,
+    /// hex encoded SHA-256 of the base s9pk
+    pub base_sha256: String,
+    pub version: emver::Version,
+    /// hex encoded SHA-256 of the s9pk the delta produces
+    pub sha256: String,
+    /// hex encoded SHA-256 of the expanded target, which the operations must rebuild
+    pub expanded_sha256: String,
+    /// the uncompressed length of every entry of the target, in payload order
+    pub lengths: Vec<u64>,
+}
+
+/// A delta is laid out as `MAGIC`, `VERSION`, the length of the CBOR encoded `DeltaHeader` as a
+/// big endian u32, the header, and then a list of operations that rebuild the expanded target
+/// package front to back:
+///  - `OP_COPY`, offset and length as big endian u64s: copy that range of the expanded base
+///  - `OP_LITERAL`, length as a big endian u64, then that many bytes: copy them as is
+///  - `OP_END`
+/// Packages are compared expanded, with every entry decompressed (see `expand`), since a small
+/// change to an entry rewrites most of its compressed bytes. The rebuilt target is compressed
+/// again entry by entry, and the result is checked against the header before it is used, so a
+/// delta can never produce anything but the exact package it was made from. Nothing guarantees
+/// that another build of a compressor writes the same bytes, so a rebuilt package can fail that
+/// check even though the delta was applied correctly: callers fall back to the full package.
+pub async fn diff<P: AsRef<Path>, Q: AsRef<Path>, O: AsRef<Path>>(
+    base: P,
+    target: Q,
+    output: O,
+) -> Result<(), Error> {
+    let base = base.as_ref();
+    let target = target.as_ref();
+    let output = output.as_ref();
+    let base_manifest = crate::s9pk::open(base)
+        .await?
+        .manifest()
+        .await?
+        .into_latest();
+    let target_manifest = crate::s9pk::open(target)
+        .await?
+        .manifest()
+        .await?
+        .into_latest();
+    crate::ensure_code!(
+        base_manifest.id == target_manifest.id,
+        crate::error::GENERAL_ERROR,
+        "Packages Are For Different Apps: {} and {}",
+        base_manifest.id,
+        target_manifest.id
+    );
+    log::info!(
+        "Creating delta of {} from {} to {}.",
+        target_manifest.id,
+        base_manifest.version,
+        target_manifest.version
+    );
+    let expanded_base = scratch_path(output, "base");
+    let expanded_target = scratch_path(output, "target");
+    let res = async {
+        log::info!("Expanding {}.", base.display());
+        expand(base, &expanded_base).await?;
+        log::info!("Expanding {}.", target.display());
+        let lengths = expand(target, &expanded_target).await?;
+        let header = DeltaHeader {
+            id: target_manifest.id,
+            base: base_manifest.version,
+            base_sha256: crate::integrity::hash_file(base).await?,
+            version: target_manifest.version,
+            sha256: crate::integrity::hash_file(target).await?,
+            expanded_sha256: crate::integrity::hash_file(&expanded_target).await?,
+            lengths,
+        };
+        diff_expanded(&header, &expanded_base, &expanded_target, output).await
+    }
+    .await;
+    remove_scratch(&expanded_base).await;
+    remove_scratch(&expanded_target).await;
+    res?;
+    log::info!("Delta written to {}.", output.display());
+    Ok(())
+}
+
+/// A file beside `output` to hold an expanded package while a delta is made or applied.
+fn scratch_path(output: &Path, name: &str) -> PathBuf {
+    let mut file_name = output.file_name().unwrap_or_default().to_owned();
+    file_name.push(format!(".{}.expanded", name));
+    output.with_file_name(file_name)
+}
+
+async fn remove_scratch(path: &Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            log::warn!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Writes the package at `path` to `output` as its header and table of contents, the
+/// uncompressed contents of its entries in payload order, and its signature section. Returns the
+/// uncompressed length of every entry.
+async fn expand(path: &Path, output: &Path) -> Result<Vec<u64>, Error> {
+    let mut raw = tokio::fs::File::open(path)
+        .await
+        .with_context(|e| format!("{}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let mut pkg = crate::s9pk::open(path).await?;
+    let mut out = tokio::fs::File::create(output)
+        .await
+        .with_context(|e| format!("{}: {}", output.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    tokio::io::copy(&mut (&mut raw).take(pkg.payload_start()), &mut out).await?;
+    let mut lengths = Vec::new();
+    let mut pos = 0;
+    for (name, entry) in pkg.toc.0.clone() {
+        crate::ensure_code!(
+            entry.offset == pos,
+            crate::error::GENERAL_ERROR,
+            "Package File Invalid or Corrupted: unexpected offset for {}",
+            name
+        );
+        lengths.push(tokio::io::copy(&mut pkg.entry(&name).await?, &mut out).await?);
+        pos += entry.length;
+    }
+    crate::ensure_code!(
+        pos == pkg.payload_len(),
+        crate::error::GENERAL_ERROR,
+        "Package File Invalid or Corrupted: unlisted data after {} bytes",
+        pos
+    );
+    raw.seek(SeekFrom::Start(pkg.payload_start() + pkg.payload_len()))
+        .await?;
+    tokio::io::copy(&mut raw, &mut out).await?;
+    out.flush().await?;
+    Ok(lengths)
+}
+
+/// Reverses `expand`, compressing every entry of the expanded package at `path` as its table of
+/// contents says.
+async fn compress(path: &Path, lengths: &[u64], output: &Path) -> Result<(), Error> {
+    let mut f = tokio::fs::File::open(path)
+        .await
+        .with_context(|e| format!("{}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let mut out = tokio::fs::File::create(output)
+        .await
+        .with_context(|e| format!("{}: {}", output.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let mut header = [0; crate::s9pk::HEADER_LEN as usize];
+    f.read_exact(&mut header).await?;
+    let toc_len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+    crate::ensure_code!(
+        toc_len <= crate::s9pk::MAX_TOC_LEN,
+        crate::error::GENERAL_ERROR,
+        "Delta File Invalid or Corrupted: invalid table of contents length"
+    );
+    let mut toc = vec![0; toc_len as usize];
+    f.read_exact(&mut toc).await?;
+    out.write_all(&header).await?;
+    out.write_all(&toc).await?;
+    let toc: crate::s9pk::Toc =
+        serde_cbor::from_slice(&toc).with_code(crate::error::SERDE_ERROR)?;
+    crate::ensure_code!(
+        toc.0.len() == lengths.len(),
+        crate::error::GENERAL_ERROR,
+        "Delta File Invalid or Corrupted: {} entries, {} lengths",
+        toc.0.len(),
+        lengths.len()
+    );
+    for (entry, length) in toc.0.values().zip(lengths) {
+        let mut contents = (&mut f).take(*length);
+        tokio::io::copy(&mut entry.compression.encode(&mut contents), &mut out).await?;
+        crate::ensure_code!(
+            contents.limit() == 0,
+            crate::error::GENERAL_ERROR,
+            "Delta File Invalid or Corrupted: unexpected end of data"
+        );
+    }
+    tokio::io::copy(&mut f, &mut out).await?;
+    out.flush().await?;
+    out.sync_all().await?;
+    Ok(())
+}
+
+/// Writes the delta from the expanded package at `base` to the one at `target`.
+async fn diff_expanded(
+    header: &DeltaHeader,
+    base: &Path,
+    target: &Path,
+    output: &Path,
+) -> Result<(), Error> {
+    let blocks = index_blocks(base).await?;
+
+    let mut out = tokio::fs::File::create(output)
+        .await
+        .with_context(|e| format!("{}: {}", output.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let header_cbor = serde_cbor::to_vec(header).with_code(crate::error::SERDE_ERROR)?;
+    out.write_all(MAGIC).await?;
+    out.write_all(&[VERSION]).await?;
+    out.write_all(&(header_cbor.len() as u32).to_be_bytes())
+        .await?;
+    out.write_all(&header_cbor).await?;
+
+    let mut f = tokio::fs::File::open(target)
+        .await
+        .with_context(|e| format!("{}: {}", target.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let mut ops = OpWriter::new(out);
+    let mut data: Vec<u8> = Vec::new();
+    let mut eof = false;
+    // `data[lit..pos]` is not found in the base, `data[pos..pos + BLOCK_SIZE]` is the window
+    // being looked up
+    let mut lit = 0;
+    let mut pos = 0;
+    let mut weak: Option<Rolling> = None;
+    loop {
+        while !eof && data.len() <= pos + BLOCK_SIZE {
+            let start = data.len();
+            data.resize(start + 64 * crate::BUFFER_SIZE, 0);
+            let n = f.read(&mut data[start..]).await?;
+            data.truncate(start + n);
+            eof = n == 0;
+        }
+        if data.len() < pos + BLOCK_SIZE {
+            break;
+        }
+        let window = &data[pos..pos + BLOCK_SIZE];
+        let rolling = *weak.get_or_insert_with(|| Rolling::new(window));
+        let found = blocks.get(&rolling.digest()).and_then(|candidates| {
+            let strong = Sha256::digest(window);
+            candidates
+                .iter()
+                .find(|(_, s)| s[..] == strong[..])
+                .map(|(offset, _)| *offset)
+        });
+        if let Some(offset) = found {
+            ops.literal(&data[lit..pos]).await?;
+            ops.copy(offset, BLOCK_SIZE as u64).await?;
+            pos += BLOCK_SIZE;
+            lit = pos;
+            weak = None;
+        } else if data.len() > pos + BLOCK_SIZE {
+            weak = Some(rolling.roll(data[pos], data[pos + BLOCK_SIZE]));
+            pos += 1;
+            if pos - lit >= MAX_LITERAL {
+                ops.literal(&data[lit..pos]).await?;
+                lit = pos;
+            }
+        } else {
+            break;
+        }
+        if lit >= MAX_LITERAL {
+            data.drain(..lit);
+            pos -= lit;
+            lit = 0;
+        }
+    }
+    ops.literal(&data[lit..]).await?;
+    let mut out = ops.finish().await?;
+    out.flush().await?;
+    Ok(())
+}
+
+/// Maps the weak checksum of every whole block of the file at `path` to the offset and SHA-256
+/// of the blocks that have it.
+async fn index_blocks(path: &Path) -> Result<HashMap<u32, Vec<(u64, Vec<u8>)>>, Error> {
+    let mut f = tokio::fs::File::open(path)
+        .await
+        .with_context(|e| format!("{}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let mut blocks: HashMap<u32, Vec<(u64, Vec<u8>)>> = HashMap::new();
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut offset = 0;
+    loop {
+        let mut filled = 0;
+        while filled < BLOCK_SIZE {
+            let n = f.read(&mut block[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled < BLOCK_SIZE {
+            break;
+        }
+        blocks
+            .entry(Rolling::new(&block).digest())
+            .or_default()
+            .push((offset, Sha256::digest(&block).to_vec()));
+        offset += BLOCK_SIZE as u64;
+    }
+    Ok(blocks)
+}
+
+/// An rsync style checksum that can be moved along a byte at a time.
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+}
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, x) in data.iter().enumerate() {
+            a = a.wrapping_add(*x as u32);
+            b = b.wrapping_add(((data.len() - i) as u32).wrapping_mul(*x as u32));
+        }
+        Rolling { a, b }
+    }
+
+    fn roll(self, out: u8, new: u8) -> Self {
+        let a = self.a.wrapping_sub(out as u32).wrapping_add(new as u32);
+        let b = self
+            .b
+            .wrapping_sub((BLOCK_SIZE as u32).wrapping_mul(out as u32))
+            .wrapping_add(a);
+        Rolling { a, b }
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Writes delta operations, joining copies of adjacent ranges of the base.
+struct OpWriter<W> {
+    inner: W,
+    copy: Option<(u64, u64)>,
+}
+impl<W: AsyncWrite + Unpin> OpWriter<W> {
+    fn new(inner: W) -> Self {
+        OpWriter { inner, copy: None }
+    }
+
+    async fn copy(&mut self, offset: u64, length: u64) -> Result<(), Error> {
+        match &mut self.copy {
+            Some((start, len)) if *start + *len == offset => *len += length,
+            _ => {
+                self.flush_copy().await?;
+                self.copy = Some((offset, length));
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush_copy(&mut self) -> Result<(), Error> {
+        if let Some((offset, length)) = self.copy.take() {
+            self.inner.write_all(&[OP_COPY]).await?;
+            self.inner.write_all(&offset.to_be_bytes()).await?;
+            self.inner.write_all(&length.to_be_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn literal(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flush_copy().await?;
+        self.inner.write_all(&[OP_LITERAL]).await?;
+        self.inner
+            .write_all(&(data.len() as u64).to_be_bytes())
+            .await?;
+        self.inner.write_all(data).await?;
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<W, Error> {
+        self.flush_copy().await?;
+        self.inner.write_all(&[OP_END]).await?;
+        Ok(self.inner)
+    }
+}
+
+pub async fn read_header<R: AsyncRead + Unpin>(r: &mut R) -> Result<DeltaHeader, Error> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic).await?;
+    crate::ensure_code!(
+        &magic == MAGIC,
+        crate::error::GENERAL_ERROR,
+        "Delta File Invalid or Corrupted: not a delta"
+    );
+    let version = r.read_u8().await?;
+    crate::ensure_code!(
+        version == VERSION,
+        crate::error::VERSION_INCOMPATIBLE,
+        "Unsupported Delta Format Version: {}",
+        version
+    );
+    let len = r.read_u32().await?;
+    crate::ensure_code!(
+        len <= MAX_HEADER_LEN,
+        crate::error::GENERAL_ERROR,
+        "Delta File Invalid or Corrupted: invalid header length"
+    );
+    let mut header = vec![0; len as usize];
+    r.read_exact(&mut header).await?;
+    serde_cbor::from_slice(&header).with_code(crate::error::SERDE_ERROR)
+}
+
+/// Rebuilds the target package of `delta` from `base`, writing it to `output`. Fails without
+/// touching `output` unless `base` is the package the delta was made against, and fails if the
+/// result is not byte for byte the package the delta was made from, either because the delta is
+/// corrupt or because the entries compressed differently here.
+pub async fn apply<P: AsRef<Path>, Q: AsRef<Path>, O: AsRef<Path>>(
+    base: P,
+    delta: Q,
+    output: O,
+) -> Result<DeltaHeader, Error> {
+    let base = base.as_ref();
+    let delta = delta.as_ref();
+    let output = output.as_ref();
+    let mut d = tokio::io::BufReader::new(
+        tokio::fs::File::open(delta)
+            .await
+            .with_context(|e| format!("{}: {}", delta.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?,
+    );
+    let header = read_header(&mut d).await?;
+    crate::ensure_code!(
+        crate::integrity::hash_file(base).await? == header.base_sha256,
+        crate::error::GENERAL_ERROR,
+        "Delta Is Not Based On {}",
+        base.display()
+    );
+    log::info!(
+        "Applying delta of {} from {} to {}.",
+        header.id,
+        header.base,
+        header.version
+    );
+    let expanded_base = scratch_path(output, "base");
+    let expanded_target = scratch_path(output, "target");
+    let res = async {
+        expand(base, &expanded_base).await?;
+        apply_expanded(&mut d, &expanded_base, &expanded_target).await?;
+        crate::ensure_code!(
+            crate::integrity::hash_file(&expanded_target).await? == header.expanded_sha256,
+            crate::error::GENERAL_ERROR,
+            "Delta File Invalid or Corrupted: result does not match"
+        );
+        compress(&expanded_target, &header.lengths, output).await
+    }
+    .await;
+    remove_scratch(&expanded_base).await;
+    remove_scratch(&expanded_target).await;
+    res?;
+    crate::ensure_code!(
+        crate::integrity::hash_file(output).await? == header.sha256,
+        crate::error::GENERAL_ERROR,
+        "Delta Result Compressed Differently Than {}@{}",
+        header.id,
+        header.version
+    );
+    Ok(header)
+}
+
+/// Runs the operations read from `d` against the expanded package at `base`.
+async fn apply_expanded<R: AsyncRead + Unpin>(
+    d: &mut R,
+    base: &Path,
+    output: &Path,
+) -> Result<(), Error> {
+    let mut b = tokio::fs::File::open(base)
+        .await
+        .with_context(|e| format!("{}: {}", base.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let mut out = tokio::fs::File::create(output)
+        .await
+        .with_context(|e| format!("{}: {}", output.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    loop {
+        match d.read_u8().await? {
+            OP_COPY => {
+                let offset = d.read_u64().await?;
+                let length = d.read_u64().await?;
+                b.seek(SeekFrom::Start(offset)).await?;
+                copy_exact(&mut b, &mut out, length).await?;
+            }
+            OP_LITERAL => {
+                let length = d.read_u64().await?;
+                copy_exact(d, &mut out, length).await?;
+            }
+            OP_END => break,
+            op => {
+                return Err(format_err!(
+                    "Delta File Invalid or Corrupted: unknown operation {}",
+                    op
+                ))
+                .with_code(crate::error::GENERAL_ERROR)
+            }
+        }
+    }
+    out.flush().await?;
+    Ok(())
+}
+
+/// Copies exactly `length` bytes from `r` to `w`.
+async fn copy_exact<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    r: &mut R,
+    w: &mut W,
+    length: u64,
+) -> Result<(), Error> {
+    let mut buf = vec![0; 64 * crate::BUFFER_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let len = (buf.len() as u64).min(remaining) as usize;
+        let n = r.read(&mut buf[..len]).await?;
+        crate::ensure_code!(
+            n > 0,
+            crate::error::GENERAL_ERROR,
+            "Delta File Invalid or Corrupted: unexpected end of data"
+        );
+        w.write_all(&buf[..n]).await?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::Keypair;
+
+    use super::*;
+    use crate::compression::Compression;
+
+    fn manifest(version: &str) -> Vec<u8> {
+        let manifest: crate::manifest::Manifest = serde_yaml::from_str(&format!(
+            "compat: v0
+id: hello
+version: {}
+title: Hello
+description:
+  short: Says hello
+  long: Says hello over http
+release-notes: notes
+ports: []
+image:
+  type: tar
+mount: /root
+",
+            version
+        ))
+        .unwrap();
+        serde_cbor::to_vec(&manifest).unwrap()
+    }
+
+    /// Bytes that barely compress, so the compressed entry is about as large as its contents.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    async fn package(
+        dir: &Path,
+        name: &str,
+        version: &str,
+        image: &[u8],
+        key: &Keypair,
+    ) -> PathBuf {
+        let path = dir.join(format!("{}.s9pk", name));
+        let bytes = crate::test_util::package(
+            &format!("delta-{}", name),
+            &[
+                (crate::s9pk::MANIFEST, &manifest(version), Compression::None),
+                ("image.tar", image, Compression::Gzip),
+            ],
+        )
+        .await;
+        std::fs::write(&path, bytes).unwrap();
+        crate::signing::sign_file(&path, key).await.unwrap();
+        path
+    }
+
+    /// A base package, and a target in which one byte of the image has changed.
+    async fn packages(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let dir = crate::test_util::scratch_dir(&format!("delta-{}", name));
+        let key = Keypair::generate(&mut rand::rngs::OsRng);
+        let mut image = noise(1024 * 1024, 1);
+        let base = package(&dir, "base", "0.1.0", &image, &key).await;
+        image[512 * 1024] ^= 1;
+        let target = package(&dir, "target", "0.1.1", &image, &key).await;
+        (dir, base, target)
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let (dir, base, target) = packages("round-trip").await;
+        let delta = dir.join("delta");
+        diff(&base, &target, &delta).await.unwrap();
+        let out = dir.join("out.s9pk");
+        let header = apply(&base, &delta, &out).await.unwrap();
+        let (target_len, delta_len) = (
+            std::fs::metadata(&target).unwrap().len(),
+            std::fs::metadata(&delta).unwrap().len(),
+        );
+        let (rebuilt, expected) = (
+            std::fs::read(&out).unwrap(),
+            std::fs::read(&target).unwrap(),
+        );
+        let scratch = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(header.id, "hello");
+        assert!(rebuilt == expected);
+        // a one byte change costs about a block, not the whole compressed image
+        assert!(
+            delta_len < target_len / 4,
+            "{} of {}",
+            delta_len,
+            target_len
+        );
+        // base, target, the delta and the result: no expanded packages left over
+        assert_eq!(scratch, 4);
+    }
+
+    #[tokio::test]
+    async fn test_base_mismatch() {
+        let (dir, base, target) = packages("base-mismatch").await;
+        let delta = dir.join("delta");
+        diff(&base, &target, &delta).await.unwrap();
+        let out = dir.join("out.s9pk");
+        let res = apply(&target, &delta, &out).await;
+        let touched = out.exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(res.is_err());
+        assert!(!touched);
+    }
+
+    /// Rewrites the header of the delta at `path`, keeping its operations.
+    async fn rewrite_header<F: FnOnce(&mut DeltaHeader)>(path: &Path, f: F) {
+        let bytes = std::fs::read(path).unwrap();
+        let mut r = &bytes[..];
+        let mut header = read_header(&mut r).await.unwrap();
+        f(&mut header);
+        let header_cbor = serde_cbor::to_vec(&header).unwrap();
+        let mut tampered = Vec::new();
+        tampered.extend_from_slice(MAGIC);
+        tampered.push(VERSION);
+        tampered.extend_from_slice(&(header_cbor.len() as u32).to_be_bytes());
+        tampered.extend_from_slice(&header_cbor);
+        tampered.extend_from_slice(r);
+        std::fs::write(path, &tampered).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_result_mismatch() {
+        let (dir, base, target) = packages("result-mismatch").await;
+        let delta = dir.join("delta");
+        diff(&base, &target, &delta).await.unwrap();
+        // the same operations, claiming to rebuild a different package
+        rewrite_header(&delta, |h| h.expanded_sha256 = format!("{:064x}", 0)).await;
+        let corrupt = apply(&base, &delta, &dir.join("out.s9pk")).await;
+        // rebuilt correctly, but compressed to other bytes than the registry's package
+        diff(&base, &target, &delta).await.unwrap();
+        rewrite_header(&delta, |h| h.sha256 = format!("{:064x}", 0)).await;
+        let compressed = apply(&base, &delta, &dir.join("out.s9pk")).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(format!("{}", corrupt.unwrap_err().failure).contains("Invalid or Corrupted"));
+        assert!(format!("{}", compressed.unwrap_err().failure).contains("Compressed Differently"));
+    }
+}
@@ -1,9 +1,7 @@
-use std::borrow::Cow;
-use std::path::Path;
-
 use emver::{Version, VersionRange};
 use linear_map::LinearMap;
 use rand::SeedableRng;
+use std::borrow::Cow;
 
 use crate::config::{Config, ConfigRuleEntryWithSuggestions, ConfigSpec};
 use crate::manifest::ManifestLatest;
@@ -20,6 +18,7 @@ pub enum DependencyError {
         received: Version,
     }, // { "incorrect-version": { "expected": "0.1.0", "received": "^0.2.0" } }
     ConfigUnsatisfied(Vec<String>), // { "config-unsatisfied": ["Bitcoin Core must have pruning set to manual."] }
+    Unhealthy(Vec<String>),         // { "unhealthy": ["rpc: connection refused"] }
     PointerUpdateError(String), // { "pointer-update-error": "Bitcoin Core RPC Port must not be 18332" }
     Other(String),              // { "other": "Well fuck." }
 }
@@ -37,6 +36,7 @@ impl std::fmt::Display for DependencyError {
             ConfigUnsatisfied(rules) => {
                 write!(f, "Configuration Rule(s) Violated: {}", rules.join(", "))
             }
+            Unhealthy(checks) => write!(f, "Unhealthy: {}", checks.join(", ")),
             PointerUpdateError(e) => write!(f, "Pointer Update Caused {}", e),
             Other(e) => write!(f, "System Error: {}", e),
         }
@@ -115,11 +115,19 @@ impl DepInfo {
         if !errors.is_empty() {
             return Ok(Err(DependencyError::ConfigUnsatisfied(errors)));
         }
-        if crate::apps::status(dependency_id, false).await?.status
-            != crate::apps::DockerStatus::Running
-        {
+        let status = crate::apps::status(dependency_id, false).await?;
+        if status.status != crate::apps::DockerStatus::Running {
             return Ok(Err(DependencyError::NotRunning));
         }
+        if status.health == Some(crate::health::Health::Unhealthy) {
+            return Ok(Err(DependencyError::Unhealthy(
+                status
+                    .failing_checks
+                    .into_iter()
+                    .map(|(name, e)| format!("{}: {}", name, e))
+                    .collect(),
+            )));
+        }
         Ok(Ok(()))
     }
 }
@@ -210,14 +218,18 @@ pub async fn update_binds(dependent_id: &str) -> Result<(), Error> {
     for (dependency_id, info, dependency_manifest) in
         dependency_manifests.into_iter().filter_map(|a| a)
     {
-        match (dependency_manifest.public, info.mount_public) {
+        match (dependency_manifest.volumes.public, info.mount_public) {
             (Some(public), true) => {
-                let public_path = Path::new(crate::VOLUMES).join(&dependency_id).join(public);
+                let public_path = crate::context::get()
+                    .volumes
+                    .join(&dependency_id)
+                    .join(public);
                 if let Ok(metadata) = tokio::fs::metadata(&public_path).await {
                     if metadata.is_dir() {
                         crate::disks::bind(
                             public_path,
-                            Path::new(crate::VOLUMES)
+                            crate::context::get()
+                                .volumes
                                 .join(&dependent_id)
                                 .join("start9")
                                 .join("public")
@@ -230,9 +242,10 @@ pub async fn update_binds(dependent_id: &str) -> Result<(), Error> {
             }
             _ => (),
         }
-        match (dependency_manifest.shared, info.mount_shared) {
+        match (dependency_manifest.volumes.shared, info.mount_shared) {
             (Some(shared), true) => {
-                let shared_path = Path::new(crate::VOLUMES)
+                let shared_path = crate::context::get()
+                    .volumes
                     .join(&dependency_id)
                     .join(shared)
                     .join(dependent_id); // namespaced by dependent
@@ -241,7 +254,8 @@ pub async fn update_binds(dependent_id: &str) -> Result<(), Error> {
                     if metadata.is_dir() {
                         crate::disks::bind(
                             shared_path,
-                            Path::new(crate::VOLUMES)
+                            crate::context::get()
+                                .volumes
                                 .join(&dependent_id)
                                 .join("start9")
                                 .join("shared")
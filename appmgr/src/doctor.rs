@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::os::unix::process::ExitStatusExt;
+
+use linear_map::set::LinearSet;
+
+use crate::tor::{LanOptions, ServicesMap};
+use crate::util::PersistencePath;
+use crate::Error;
+
+/// A way in which appmgr's state and the system it manages have drifted apart.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "type")]
+pub enum Problem {
+    /// listed as running, but not installed
+    StaleRunning {
+        app_id: String,
+    },
+    /// reserved in the services map, but assigned to no service
+    UnownedIp {
+        ip: Ipv4Addr,
+    },
+    SharedIp {
+        ip: Ipv4Addr,
+        app_ids: Vec<String>,
+    },
+    MissingMetadata {
+        app_id: String,
+    },
+    MissingVolume {
+        app_id: String,
+    },
+    MissingService {
+        app_id: String,
+    },
+    MissingContainer {
+        app_id: String,
+    },
+    MissingImage {
+        app_id: String,
+    },
+    /// created from an app image, but not the container of an installed app
+    OrphanedContainer {
+        name: String,
+    },
+    /// an app image that is not the image of an installed app
+    OrphanedImage {
+        image: String,
+    },
+    /// the torrc does not declare exactly the hidden services in the services map
+    TorrcOutOfDate,
+    MissingHiddenService {
+        app_id: String,
+    },
+    TorAddressMismatch {
+        app_id: String,
+        recorded: Option<String>,
+        actual: String,
+    },
+    /// the nginx services conf does not proxy exactly the LAN interfaces in the services map
+    NginxConfOutOfDate,
+    MissingCert {
+        app_id: String,
+    },
+}
+impl Problem {
+    /// Whether `fix` can repair this without losing data. The others need a reinstall, or a
+    /// decision only the user can make.
+    pub fn fixable(&self) -> bool {
+        match self {
+            Problem::SharedIp { .. }
+            | Problem::MissingMetadata { .. }
+            | Problem::MissingVolume { .. }
+            | Problem::MissingService { .. }
+            | Problem::MissingContainer { .. }
+            | Problem::MissingImage { .. } => false,
+            _ => true,
+        }
+    }
+}
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::StaleRunning { app_id } => {
+                write!(f, "{} is marked running but is not installed", app_id)
+            }
+            Problem::UnownedIp { ip } => write!(f, "{} is reserved but assigned to no app", ip),
+            Problem::SharedIp { ip, app_ids } => {
+                write!(f, "{} is assigned to {}", ip, app_ids.join(", "))
+            }
+            Problem::MissingMetadata { app_id } => write!(f, "{} has no manifest", app_id),
+            Problem::MissingVolume { app_id } => write!(f, "{} has no volume", app_id),
+            Problem::MissingService { app_id } => write!(f, "{} has no tor service", app_id),
+            Problem::MissingContainer { app_id } => write!(f, "{} has no container", app_id),
+            Problem::MissingImage { app_id } => write!(f, "{} has no image", app_id),
+            Problem::OrphanedContainer { name } => {
+                write!(f, "container {} belongs to no installed app", name)
+            }
+            Problem::OrphanedImage { image } => {
+                write!(f, "image {} belongs to no installed app", image)
+            }
+            Problem::TorrcOutOfDate => write!(f, "torrc is out of date"),
+            Problem::MissingHiddenService { app_id } => {
+                write!(f, "{} has no tor hidden service directory", app_id)
+            }
+            Problem::TorAddressMismatch {
+                app_id,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "{} is recorded at {} but tor serves it at {}",
+                app_id,
+                recorded
+                    .as_ref()
+                    .map(|a| a.as_str())
+                    .unwrap_or("no address"),
+                actual
+            ),
+            Problem::NginxConfOutOfDate => write!(f, "nginx services conf is out of date"),
+            Problem::MissingCert { app_id } => write!(f, "{} has no LAN certificate", app_id),
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Finding {
+    #[serde(flatten)]
+    pub problem: Problem,
+    pub fixable: bool,
+    pub fixed: bool,
+    /// why the fix failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Cross-checks the app database against docker, tor, nginx and the filesystem, repairing what
+/// can be repaired safely if `fix` is set.
+pub async fn doctor(fix: bool) -> Result<Vec<Finding>, Error> {
+    let mut findings: Vec<Finding> = diagnose()
+        .await?
+        .into_iter()
+        .map(|problem| Finding {
+            fixable: problem.fixable(),
+            problem,
+            fixed: false,
+            error: None,
+        })
+        .collect();
+    if fix {
+        // nginx conf and certificates are all written at once
+        let mut lan_fixed: Option<Result<(), String>> = None;
+        for finding in findings.iter_mut().filter(|f| f.fixable) {
+            let res = match &finding.problem {
+                Problem::NginxConfOutOfDate | Problem::MissingCert { .. } => {
+                    if lan_fixed.is_none() {
+                        lan_fixed = Some(fix_lan().await.map_err(|e| format!("{}", e)));
+                    }
+                    lan_fixed.clone().unwrap()
+                }
+                problem => repair(problem).await.map_err(|e| format!("{}", e)),
+            };
+            match res {
+                Ok(()) => finding.fixed = true,
+                Err(e) => {
+                    log::error!("Failed to fix \"{}\": {}", finding.problem, e);
+                    finding.error = Some(e)
+                }
+            }
+        }
+    }
+    Ok(findings)
+}
+
+/// Finds every problem, in the order they are safe to fix in.
+pub async fn diagnose() -> Result<Vec<Problem>, Error> {
+    let ctx = crate::context::get();
+    let mut problems = Vec::new();
+    let apps = crate::apps::list_info().await?;
+    let running: LinearSet<String> = crate::db::get_or_default(crate::db::RUNNING).await?;
+    let services = crate::tor::services_map().await?;
+
+    for id in running {
+        if !apps.contains_key(&id) {
+            problems.push(Problem::StaleRunning { app_id: id });
+        }
+    }
+    let mut owners: BTreeMap<Ipv4Addr, Vec<String>> = BTreeMap::new();
+    for (id, service) in &services.map {
+        owners.entry(service.ip).or_default().push(id.clone());
+    }
+    for ip in &services.ips {
+        if !owners.contains_key(ip) {
+            problems.push(Problem::UnownedIp { ip: *ip });
+        }
+    }
+    for (ip, mut app_ids) in owners {
+        if app_ids.len() > 1 {
+            app_ids.sort();
+            problems.push(Problem::SharedIp { ip, app_ids });
+        }
+    }
+
+    let runtime = crate::runtime::get();
+    let containers = runtime.list_containers().await?;
+    let images = runtime.list_images().await?;
+    for id in apps.keys() {
+        if !PersistencePath::from_ref("apps")
+            .join(id)
+            .join("manifest.yaml")
+            .exists()
+            .await
+        {
+            problems.push(Problem::MissingMetadata { app_id: id.clone() });
+        }
+        if !ctx.volumes.join(id).exists() {
+            problems.push(Problem::MissingVolume { app_id: id.clone() });
+        }
+        if !services.map.contains_key(id) {
+            problems.push(Problem::MissingService { app_id: id.clone() });
+        }
+        if !containers.iter().any(|(name, _)| name == id) {
+            problems.push(Problem::MissingContainer { app_id: id.clone() });
+        }
+        if !images.contains(&app_image(id)) {
+            problems.push(Problem::MissingImage { app_id: id.clone() });
+        }
+    }
+    for (name, image) in containers {
+        if image.starts_with("start9/") && !apps.contains_key(&name) {
+            problems.push(Problem::OrphanedContainer { name });
+        }
+    }
+    for image in images {
+        if image.starts_with("start9/") && !apps.keys().any(|id| image == app_image(id)) {
+            problems.push(Problem::OrphanedImage { image });
+        }
+    }
+
+    let torrc = tokio::fs::read_to_string(&ctx.etc_tor_rc)
+        .await
+        .unwrap_or_default();
+    let base = tokio::fs::read_to_string(ctx.tor_rc())
+        .await
+        .unwrap_or_default();
+    if !torrc.starts_with(&base) || torrc_services(&torrc) != expected_torrc_services(&services) {
+        problems.push(Problem::TorrcOutOfDate);
+    }
+    for (id, info) in &apps {
+        match services.map.get(id) {
+            Some(service) if !service.ports.is_empty() => (),
+            _ => continue,
+        }
+        let hostname_path = ctx.hidden_service_dir(id).join("hostname");
+        match tokio::fs::read_to_string(&hostname_path).await {
+            Ok(hostname) => {
+                let actual = hostname.trim().to_owned();
+                if info.tor_address.is_some() && info.tor_address.as_ref() != Some(&actual) {
+                    problems.push(Problem::TorAddressMismatch {
+                        app_id: id.clone(),
+                        recorded: info.tor_address.clone(),
+                        actual,
+                    });
+                }
+            }
+            Err(_) => problems.push(Problem::MissingHiddenService { app_id: id.clone() }),
+        }
+    }
+
+    let nginx_conf = tokio::fs::read_to_string(&ctx.nginx_services_conf)
+        .await
+        .unwrap_or_default();
+    if proxy_targets(&nginx_conf) != expected_proxy_targets(&services) {
+        problems.push(Problem::NginxConfOutOfDate);
+    }
+    for (id, service) in &services.map {
+        if service
+            .ports
+            .iter()
+            .any(|p| matches!(p.lan, Some(LanOptions::Standard)))
+        {
+            let dir = PersistencePath::from_ref("apps").join(id);
+            if !dir.join("cert-local.fullchain.crt.pem").exists().await
+                || !dir.join("cert-local.key.pem").exists().await
+            {
+                problems.push(Problem::MissingCert { app_id: id.clone() });
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+fn app_image(id: &str) -> String {
+    format!("start9/{}:latest", id)
+}
+
+/// The `HiddenServicePort` lines of every `HiddenServiceDir` in a torrc.
+fn torrc_services(torrc: &str) -> BTreeMap<String, BTreeSet<String>> {
+    let mut res: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let mut dir = None;
+    for line in torrc.lines().map(str::trim) {
+        if let Some(d) = line.strip_prefix("HiddenServiceDir ") {
+            let d = d.trim().trim_end_matches('/').to_owned();
+            res.entry(d.clone()).or_default();
+            dir = Some(d);
+        } else if line.starts_with("HiddenServicePort ") {
+            if let Some(dir) = &dir {
+                res.entry(dir.clone()).or_default().insert(line.to_owned());
+            }
+        }
+    }
+    res
+}
+
+/// What `torrc_services` reads from the torrc `tor::write_services` writes.
+fn expected_torrc_services(services: &ServicesMap) -> BTreeMap<String, BTreeSet<String>> {
+    let ctx = crate::context::get();
+    services
+        .map
+        .iter()
+        .filter(|(_, service)| !service.ports.is_empty())
+        .map(|(id, service)| {
+            (
+                format!("{}", ctx.hidden_service_dir(id).display()),
+                service
+                    .ports
+                    .iter()
+                    .map(|port| {
+                        format!(
+                            "HiddenServicePort {} {}:{}",
+                            port.tor, service.ip, port.internal
+                        )
+                    })
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// The targets of every `proxy_pass` in an nginx conf.
+fn proxy_targets(conf: &str) -> BTreeSet<String> {
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("proxy_pass "))
+        .map(|target| target.trim_end_matches(';').trim().to_owned())
+        .collect()
+}
+
+/// What `proxy_targets` reads from the conf `tor::write_lan_services` writes.
+fn expected_proxy_targets(services: &ServicesMap) -> BTreeSet<String> {
+    services
+        .map
+        .values()
+        .flat_map(|service| {
+            service
+                .ports
+                .iter()
+                .filter(|port| port.lan.is_some())
+                .map(move |port| format!("http://{}:{}/", service.ip, port.internal))
+        })
+        .collect()
+}
+
+async fn repair(problem: &Problem) -> Result<(), Error> {
+    match problem {
+        Problem::StaleRunning { app_id } => {
+            let mut db = crate::db::transaction().await?;
+            let mut running: LinearSet<String> = db.get_or_default(crate::db::RUNNING)?;
+            running.remove(app_id);
+            db.put(crate::db::RUNNING, &running)?;
+            db.commit().await
+        }
+        Problem::UnownedIp { ip } => {
+            let mut db = crate::db::transaction().await?;
+            let mut services: ServicesMap = db.get_or_default(crate::db::SERVICES)?;
+            services.ips.remove(ip);
+            db.put(crate::db::SERVICES, &services)?;
+            db.commit().await
+        }
+        Problem::OrphanedContainer { name } => {
+            log::info!("Removing container {}.", name);
+            crate::runtime::get().rm(name, true).await
+        }
+        Problem::OrphanedImage { image } => {
+            log::info!("Removing image {}.", image);
+            crate::runtime::get().rmi(image).await
+        }
+        Problem::TorrcOutOfDate => crate::tor::reload().await,
+        Problem::MissingHiddenService { app_id } => {
+            // tor creates a new one, under a new address
+            crate::tor::change_key(app_id, None).await
+        }
+        Problem::TorAddressMismatch { app_id, actual, .. } => {
+            let mut db = crate::db::transaction().await?;
+            crate::apps::update_info(&mut db, app_id, |app| {
+                app.tor_address = Some(actual.clone())
+            })?;
+            db.commit().await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Rewrites the nginx services conf, generating any missing certificates, and reloads nginx.
+async fn fix_lan() -> Result<(), Error> {
+    crate::tor::write_lan_services(&crate::tor::services_map().await?).await?;
+    log::info!("Reloading Nginx.");
+    let svc_exit = std::process::Command::new("service")
+        .args(&["nginx", "reload"])
+        .status()?;
+    crate::ensure_code!(
+        svc_exit.success(),
+        crate::error::GENERAL_ERROR,
+        "Failed to Reload Nginx: {}",
+        svc_exit
+            .code()
+            .or_else(|| { svc_exit.signal().map(|a| 128 + a) })
+            .unwrap_or(0)
+    );
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::borrow::Cow;
+
+use linear_map::LinearMap;
+
+use crate::config::value::Value;
+use crate::config::Config;
+use crate::manifest::ManifestLatest;
+use crate::Error;
+
+/// The variables appmgr sets itself, which the manifest cannot declare.
+pub const RESERVED: &'static [&'static str] = &["TOR_ADDRESS", "TOR_KEY"];
+
+/// Renders a config value as the value of an environment variable: strings as they are, lists
+/// and objects as json, and null as no value at all.
+fn render(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Null => None,
+        v => serde_json::to_string(v).ok(),
+    }
+}
+
+/// The variables `manifest` declares, evaluated against the saved config of the app and those of
+/// its dependencies.
+pub async fn templated(manifest: &ManifestLatest) -> Result<LinearMap<String, String>, Error> {
+    let config: Config = crate::db::get_or_default(&crate::db::config_key(&manifest.id)).await?;
+    let mut dep_configs = LinearMap::new();
+    for dep_id in manifest.dependencies.0.keys() {
+        if let Some(dep_config) = crate::db::get::<Config>(&crate::db::config_key(dep_id)).await? {
+            dep_configs.insert(dep_id.clone(), dep_config);
+        }
+    }
+    let mut cfgs = LinearMap::new();
+    cfgs.insert(manifest.id.as_str(), Cow::Borrowed(&config));
+    for (dep_id, dep_config) in &dep_configs {
+        cfgs.insert(dep_id.as_str(), Cow::Borrowed(dep_config));
+    }
+    Ok(manifest
+        .env
+        .iter()
+        .filter_map(|(key, expr)| {
+            render(&(expr.compiled)(&config, &cfgs)).map(|value| (key.clone(), value))
+        })
+        .collect())
+}
+
+/// The whole environment of the container of `manifest`: its templated variables, and the
+/// address and key of its hidden service if it has one.
+pub async fn environment(manifest: &ManifestLatest) -> Result<LinearMap<String, String>, Error> {
+    let mut env = templated(manifest).await?;
+    if !manifest.ports().is_empty() {
+        let tor = async {
+            Ok::<_, Error>((
+                crate::tor::read_tor_address(&manifest.id, None).await?,
+                crate::tor::read_tor_key(&manifest.id, manifest.hidden_service_version, None)
+                    .await?,
+            ))
+        };
+        match tor.await {
+            Ok((tor_addr, tor_key)) => {
+                env.insert("TOR_ADDRESS".to_owned(), tor_addr);
+                env.insert("TOR_KEY".to_owned(), tor_key);
+            }
+            Err(e) => log::warn!("Starting {} without its tor address: {}", manifest.id, e),
+        }
+    }
+    Ok(env)
+}
+
+/// Recreates the container of `id`, which must be stopped, if its environment has changed since
+/// it was created, e.g. because the app or one of its dependencies was reconfigured.
+pub async fn refresh(id: &str) -> Result<(), Error> {
+    let manifest = crate::apps::manifest(id).await?;
+    let created_with: Option<LinearMap<String, String>> =
+        crate::db::get(&crate::db::env_key(id)).await?;
+    let up_to_date = match created_with {
+        Some(created_with) => created_with == environment(&manifest).await?,
+        // created before environments were recorded, with nothing that can have changed
+        None => manifest.env.is_empty(),
+    };
+    if up_to_date {
+        return Ok(());
+    }
+    log::info!("Recreating container {} with its new environment.", id);
+    crate::runtime::get().rm(id, false).await?;
+    crate::install::create_container(&manifest).await
+}
@@ -0,0 +1,193 @@
+use std::fmt::Write as _;
+
+use linear_map::LinearMap;
+
+use crate::tor::ServicesMap;
+use crate::util::PersistencePath;
+use crate::Error;
+
+/// The nftables table appmgr owns, replaced as a whole on every reload.
+pub const TABLE: &'static str = "appmgr";
+/// the `start9` docker network every app is on
+pub const SUBNET: &'static str = "172.18.0.0/16";
+/// The rule set last loaded, relative to the persistence directory.
+pub const RULES: &'static str = "firewall.nft";
+
+/// The rule set that lets each app reach the ports of the apps it depends on, over TCP or UDP,
+/// and no other app.
+/// `dependencies` maps each installed app to the apps it declares as dependencies.
+///
+/// Traffic between containers on the same bridge only passes the forward hook while docker keeps
+/// `br_netfilter` loaded, which it does for its own rules. Traffic to and from the host, e.g.
+/// from tor or nginx, and to the internet is left alone.
+pub fn rules(services: &ServicesMap, dependencies: &LinearMap<String, Vec<String>>) -> String {
+    let mut dependents: Vec<(&String, &Vec<String>)> = dependencies.iter().collect();
+    dependents.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut out = String::new();
+    // declaring the table first makes the delete succeed when it does not exist yet
+    writeln!(out, "table inet {}", TABLE).unwrap();
+    writeln!(out, "delete table inet {}", TABLE).unwrap();
+    writeln!(out, "table inet {} {{", TABLE).unwrap();
+    writeln!(out, "\tchain forward {{").unwrap();
+    writeln!(
+        out,
+        "\t\ttype filter hook forward priority filter; policy accept;"
+    )
+    .unwrap();
+    writeln!(out, "\t\tct state established,related accept").unwrap();
+    for (dependent, deps) in dependents {
+        let src = match services.map.get(dependent) {
+            Some(a) => a,
+            None => continue,
+        };
+        let mut deps: Vec<&String> = deps.iter().collect();
+        deps.sort();
+        deps.dedup();
+        for dep in deps {
+            let dst = match services.map.get(dep) {
+                Some(a) => a,
+                None => continue,
+            };
+            let mut ports: Vec<u16> = dst.ports.iter().map(|p| p.internal).collect();
+            ports.sort();
+            ports.dedup();
+            if ports.is_empty() {
+                continue;
+            }
+            let ports = ports
+                .iter()
+                .map(|p| format!("{}", p))
+                .collect::<Vec<_>>()
+                .join(", ");
+            // manifests do not say which protocol a port is for, so both are let through
+            for proto in &["tcp", "udp"] {
+                writeln!(
+                    out,
+                    "\t\tip saddr {} ip daddr {} {} dport {{ {} }} accept comment \"{} -> {}\"",
+                    src.ip, dst.ip, proto, ports, dependent, dep
+                )
+                .unwrap();
+            }
+        }
+    }
+    writeln!(out, "\t\tip saddr {} ip daddr {} drop", SUBNET, SUBNET).unwrap();
+    writeln!(out, "\t}}").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// The declared dependencies of every installed app, left out if they are not installed.
+pub async fn dependencies() -> Result<LinearMap<String, Vec<String>>, Error> {
+    let installed = crate::apps::list_info().await?;
+    let mut res = LinearMap::new();
+    for id in installed.keys() {
+        let manifest = crate::apps::manifest(id).await?;
+        res.insert(
+            id.clone(),
+            manifest
+                .dependencies
+                .0
+                .keys()
+                .filter(|dep| installed.contains_key(*dep))
+                .cloned()
+                .collect(),
+        );
+    }
+    Ok(res)
+}
+
+pub async fn current() -> Result<String, Error> {
+    Ok(rules(
+        &crate::tor::services_map().await?,
+        &dependencies().await?,
+    ))
+}
+
+/// Regenerates the rule set from the installed apps and loads it in place of the last one.
+pub async fn reload() -> Result<(), Error> {
+    let rules = current().await?;
+    log::info!("Writing firewall rules to {}.", RULES);
+    let path = PersistencePath::from_ref(RULES);
+    let mut file = path.write(None).await?;
+    {
+        use tokio::io::AsyncWriteExt;
+        file.write_all(rules.as_bytes()).await?;
+    }
+    file.commit().await?;
+    log::info!("Loading firewall rules.");
+    let output = tokio::process::Command::new("nft")
+        .arg("-f")
+        .arg(path.path())
+        .output()
+        .await?;
+    crate::ensure_code!(
+        output.status.success(),
+        crate::error::GENERAL_ERROR,
+        "Failed to Load Firewall Rules: {}",
+        std::str::from_utf8(&output.stderr)
+            .unwrap_or("Unknown Error")
+            .trim()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::tor::{PortMapping, Service};
+
+    fn service(ip: [u8; 4], ports: &[u16]) -> Service {
+        Service {
+            ip: Ipv4Addr::from(ip),
+            ports: ports
+                .iter()
+                .map(|p| PortMapping {
+                    internal: *p,
+                    tor: *p,
+                    lan: None,
+                })
+                .collect(),
+            hidden_service_version: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let mut services = ServicesMap::default();
+        services.map.insert(
+            "bitcoind".to_owned(),
+            service([172, 18, 0, 2], &[8333, 8332]),
+        );
+        services
+            .map
+            .insert("lnd".to_owned(), service([172, 18, 0, 3], &[9735]));
+        services
+            .map
+            .insert("rtl".to_owned(), service([172, 18, 0, 4], &[80]));
+        let mut dependencies = LinearMap::new();
+        dependencies.insert("rtl".to_owned(), vec!["lnd".to_owned()]);
+        dependencies.insert("lnd".to_owned(), vec!["bitcoind".to_owned()]);
+        dependencies.insert("bitcoind".to_owned(), vec![]);
+        // not installed, so without a service
+        dependencies.insert("electrs".to_owned(), vec!["bitcoind".to_owned()]);
+        assert_eq!(
+            rules(&services, &dependencies),
+            "table inet appmgr\n\
+             delete table inet appmgr\n\
+             table inet appmgr {\n\
+             \tchain forward {\n\
+             \t\ttype filter hook forward priority filter; policy accept;\n\
+             \t\tct state established,related accept\n\
+             \t\tip saddr 172.18.0.3 ip daddr 172.18.0.2 tcp dport { 8332, 8333 } accept comment \"lnd -> bitcoind\"\n\
+             \t\tip saddr 172.18.0.3 ip daddr 172.18.0.2 udp dport { 8332, 8333 } accept comment \"lnd -> bitcoind\"\n\
+             \t\tip saddr 172.18.0.4 ip daddr 172.18.0.3 tcp dport { 9735 } accept comment \"rtl -> lnd\"\n\
+             \t\tip saddr 172.18.0.4 ip daddr 172.18.0.3 udp dport { 9735 } accept comment \"rtl -> lnd\"\n\
+             \t\tip saddr 172.18.0.0/16 ip daddr 172.18.0.0/16 drop\n\
+             \t}\n\
+             }\n"
+        );
+    }
+}
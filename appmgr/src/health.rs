@@ -0,0 +1,217 @@
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use linear_map::LinearMap;
+use tokio_compat_02::FutureExt;
+
+use crate::manifest::{HealthCheck, Probe};
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Health {
+    /// some check has not passed yet, and none has run out of retries
+    Starting,
+    Healthy,
+    /// some check has failed more times in a row than it allows
+    Unhealthy,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CheckState {
+    last_run: u64,
+    /// consecutive failures, not counting those during the grace period
+    failures: u32,
+    passed: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+/// Results of an app's health checks, kept in the db under `db::health_key` by the supervisor.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthState {
+    /// docker's start time of the container the results are for
+    started_at: String,
+    /// when appmgr first saw this container running, which the grace periods count from
+    first_seen: u64,
+    checks: LinearMap<String, CheckState>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HealthReport {
+    pub health: Health,
+    /// the last error of every check that has not passed since its last failure
+    pub failing: LinearMap<String, String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|a| a.as_secs())
+        .unwrap_or(0)
+}
+
+/// Runs the health checks of `id` that are due and stores their results. `started_at`
+/// identifies the run of the container being checked: results from earlier runs are discarded.
+///
+/// Only the supervisor calls this: everything else reads the stored results through `report`.
+pub async fn check(id: &str, started_at: &str) -> Result<(), Error> {
+    let manifest = crate::apps::manifest(id).await?;
+    if manifest.health_checks.is_empty() {
+        return Ok(());
+    }
+    let ip = crate::tor::services_map()
+        .await?
+        .map
+        .get(id)
+        .map(|svc| svc.ip);
+    let mut state: HealthState = crate::db::get_or_default(&crate::db::health_key(id)).await?;
+    let now = now();
+    if state.started_at != started_at {
+        state = HealthState {
+            started_at: started_at.to_owned(),
+            first_seen: now,
+            checks: LinearMap::new(),
+        };
+    }
+    let due: Vec<(&String, &HealthCheck)> = manifest
+        .health_checks
+        .iter()
+        .filter(|(name, check)| {
+            state
+                .checks
+                .get(*name)
+                .map(|s| s.last_run + check.interval_secs <= now)
+                .unwrap_or(true)
+        })
+        .collect();
+    if due.is_empty() {
+        return Ok(());
+    }
+    // probes can take up to their timeout, so the db is only locked to store their results
+    let results = futures::future::join_all(due.iter().map(|(_, check)| {
+        probe(
+            id,
+            ip,
+            &check.probe,
+            Duration::from_secs(check.timeout_secs),
+        )
+    }))
+    .await;
+    for ((name, check), res) in due.into_iter().zip(results) {
+        let in_grace = now < state.first_seen + check.grace_period_secs;
+        let check_state = state
+            .checks
+            .entry(name.clone())
+            .or_insert_with(CheckState::default);
+        check_state.last_run = now;
+        match res {
+            Ok(()) => {
+                check_state.failures = 0;
+                check_state.passed = true;
+                check_state.last_error = None;
+            }
+            Err(e) => {
+                log::info!("Health check {} of {} failed: {}", name, id, e);
+                if !in_grace {
+                    check_state.failures += 1;
+                }
+                check_state.last_error = Some(e);
+            }
+        }
+    }
+    let mut db = crate::db::transaction().await?;
+    if !db
+        .get_or_default::<LinearMap<String, crate::apps::AppInfo>>(crate::db::APPS)?
+        .contains_key(id)
+    {
+        // removed while it was being probed
+        return Ok(());
+    }
+    db.put(&crate::db::health_key(id), &state)?;
+    db.commit().await
+}
+
+/// Reports the health of `id` from the results last stored by the supervisor, or `None` if it
+/// declares no checks. Checks that have not run since the container started count as not passed.
+pub async fn report(id: &str, started_at: &str) -> Result<Option<HealthReport>, Error> {
+    let manifest = crate::apps::manifest(id).await?;
+    if manifest.health_checks.is_empty() {
+        return Ok(None);
+    }
+    let state: HealthState = crate::db::get_or_default(&crate::db::health_key(id)).await?;
+    let mut report = HealthReport {
+        health: Health::Healthy,
+        failing: LinearMap::new(),
+    };
+    for (name, check) in &manifest.health_checks {
+        let check_state = if state.started_at == started_at {
+            state.checks.get(name).cloned().unwrap_or_default()
+        } else {
+            CheckState::default()
+        };
+        if let Some(e) = &check_state.last_error {
+            report.failing.insert(name.clone(), e.clone());
+        }
+        if check_state.failures >= check.retries {
+            report.health = Health::Unhealthy;
+        } else if !check_state.passed && report.health == Health::Healthy {
+            report.health = Health::Starting;
+        }
+    }
+    Ok(Some(report))
+}
+
+async fn probe(
+    id: &str,
+    ip: Option<Ipv4Addr>,
+    probe: &Probe,
+    timeout: Duration,
+) -> Result<(), String> {
+    let ip = || ip.ok_or_else(|| format!("{} has no IP address", id));
+    match probe {
+        Probe::Command { command } => {
+            let runtime = crate::runtime::get();
+            match tokio::time::timeout(timeout, runtime.exec(id, command)).await {
+                Err(_) => Err("timed out".to_owned()),
+                Ok(Err(e)) => Err(format!("{}", e)),
+                Ok(Ok(output)) if output.success() => Ok(()),
+                Ok(Ok(output)) => Err(format!(
+                    "exited with {}: {}",
+                    output.code,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+            }
+        }
+        Probe::Http { port, path } => {
+            let url = format!("http://{}:{}{}", ip()?, port, path);
+            let client = reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .map_err(|e| format!("{}", e))?;
+            let res = client
+                .get(&url)
+                .send()
+                .compat()
+                .await
+                .map_err(|e| format!("{}", e))?;
+            if res.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!("GET {} returned {}", path, res.status()))
+            }
+        }
+        Probe::Tcp { port } => {
+            match tokio::time::timeout(timeout, tokio::net::TcpStream::connect((ip()?, *port)))
+                .await
+            {
+                Err(_) => Err("timed out".to_owned()),
+                Ok(Err(e)) => Err(format!("{}", e)),
+                Ok(Ok(_)) => Ok(()),
+            }
+        }
+    }
+}
@@ -8,7 +8,7 @@ use futures::future::{BoxFuture, FutureExt};
 use linear_map::LinearMap;
 
 use crate::inspect::info_full;
-use crate::manifest::{Description, ManifestLatest};
+use crate::manifest::{Description, ManifestLatest, Privilege};
 use crate::{Error, ResultExt};
 
 #[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
@@ -31,6 +31,12 @@ pub struct VersionInfo {
     pub os_version_required: VersionRange,
     pub os_version_recommended: VersionRange,
     pub install_alert: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub arch: Vec<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub privileges: Vec<Privilege>,
 }
 
 const NULL_VERSION: Version = Version::new(0, 0, 0, 0);
@@ -54,6 +60,8 @@ impl AppIndex {
                 os_version_required: manifest.os_version_required,
                 os_version_recommended: manifest.os_version_recommended,
                 install_alert: manifest.install_alert,
+                arch: manifest.image.arch().to_vec(),
+                privileges: manifest.security.privileges(),
             });
             entry
                 .version_info
@@ -71,6 +79,8 @@ impl AppIndex {
                         os_version_required: manifest.os_version_required,
                         os_version_recommended: manifest.os_version_recommended,
                         install_alert: manifest.install_alert,
+                        arch: manifest.image.arch().to_vec(),
+                        privileges: manifest.security.privileges(),
                     }],
                     icon_type: "png".to_owned(), // TODO
                 },
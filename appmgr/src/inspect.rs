@@ -1,11 +1,7 @@
 use std::path::Path;
 
-use failure::ResultExt as _;
-use futures::stream::StreamExt;
-use tokio_tar as tar;
-
 use crate::config::{ConfigRuleEntry, ConfigSpec};
-use crate::manifest::{Manifest, ManifestLatest};
+use crate::manifest::{ManifestLatest, Privilege};
 use crate::util::from_cbor_async_reader;
 use crate::version::VersionT;
 use crate::Error;
@@ -27,6 +23,13 @@ pub struct AppInfoFull {
 pub struct AppInfo {
     pub title: String,
     pub version: emver::Version,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub arch: Vec<String>,
+    /// what the app may do on the device, to be shown before it is installed
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub privileges: Vec<Privilege>,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -43,27 +46,9 @@ pub async fn info_full<P: AsRef<Path>>(
 ) -> Result<AppInfoFull, Error> {
     let p = path.as_ref();
     log::info!("Opening file.");
-    let r = tokio::fs::File::open(p)
-        .await
-        .with_context(|e| format!("{}: {}", p.display(), e))
-        .with_code(crate::error::FILESYSTEM_ERROR)?;
-    log::info!("Extracting archive.");
-    let mut pkg = tar::Archive::new(r);
-    let mut entries = pkg.entries()?;
-    log::info!("Opening manifest from archive.");
-    let manifest = entries
-        .next()
-        .await
-        .ok_or(crate::install::Error::CorruptedPkgFile("missing manifest"))
-        .no_code()??;
-    crate::ensure_code!(
-        manifest.path()?.to_str() == Some("manifest.cbor"),
-        crate::error::GENERAL_ERROR,
-        "Package File Invalid or Corrupted"
-    );
-    log::trace!("Deserializing manifest.");
-    let manifest: Manifest = from_cbor_async_reader(manifest).await?;
-    let manifest = manifest.into_latest();
+    let mut pkg = crate::s9pk::open(p).await?;
+    log::info!("Reading manifest from archive.");
+    let manifest = pkg.manifest().await?.into_latest();
     crate::ensure_code!(
         crate::version::Current::new()
             .semver()
@@ -76,39 +61,15 @@ pub async fn info_full<P: AsRef<Path>>(
         info: AppInfo {
             title: manifest.title.clone(),
             version: manifest.version.clone(),
+            arch: manifest.image.arch().to_vec(),
+            privileges: manifest.security.privileges(),
         },
         manifest: if with_manifest { Some(manifest) } else { None },
         config: if with_config {
-            log::info!("Opening config spec from archive.");
-            let spec = entries
-                .next()
-                .await
-                .ok_or(crate::install::Error::CorruptedPkgFile(
-                    "missing config spec",
-                ))
-                .no_code()??;
-            crate::ensure_code!(
-                spec.path()?.to_str() == Some("config_spec.cbor"),
-                crate::error::GENERAL_ERROR,
-                "Package File Invalid or Corrupted"
-            );
-            log::trace!("Deserializing config spec.");
-            let spec = from_cbor_async_reader(spec).await?;
-            log::info!("Opening config rules from archive.");
-            let rules = entries
-                .next()
-                .await
-                .ok_or(crate::install::Error::CorruptedPkgFile(
-                    "missing config rules",
-                ))
-                .no_code()??;
-            crate::ensure_code!(
-                rules.path()?.to_str() == Some("config_rules.cbor"),
-                crate::error::GENERAL_ERROR,
-                "Package File Invalid or Corrupted"
-            );
-            log::trace!("Deserializing config rules.");
-            let rules = from_cbor_async_reader(rules).await?;
+            log::info!("Reading config spec from archive.");
+            let spec = from_cbor_async_reader(pkg.entry(crate::s9pk::CONFIG_SPEC).await?).await?;
+            log::info!("Reading config rules from archive.");
+            let rules = from_cbor_async_reader(pkg.entry(crate::s9pk::CONFIG_RULES).await?).await?;
             Some(AppConfig { spec, rules })
         } else {
             None
@@ -119,27 +80,9 @@ pub async fn info_full<P: AsRef<Path>>(
 pub async fn print_instructions<P: AsRef<Path>>(path: P) -> Result<(), Error> {
     let p = path.as_ref();
     log::info!("Opening file.");
-    let r = tokio::fs::File::open(p)
-        .await
-        .with_context(|e| format!("{}: {}", p.display(), e))
-        .with_code(crate::error::FILESYSTEM_ERROR)?;
-    log::info!("Extracting archive.");
-    let mut pkg = tar::Archive::new(r);
-    let mut entries = pkg.entries()?;
-    log::info!("Opening manifest from archive.");
-    let manifest = entries
-        .next()
-        .await
-        .ok_or(crate::install::Error::CorruptedPkgFile("missing manifest"))
-        .no_code()??;
-    crate::ensure_code!(
-        manifest.path()?.to_str() == Some("manifest.cbor"),
-        crate::error::GENERAL_ERROR,
-        "Package File Invalid or Corrupted"
-    );
-    log::trace!("Deserializing manifest.");
-    let manifest: Manifest = from_cbor_async_reader(manifest).await?;
-    let manifest = manifest.into_latest();
+    let mut pkg = crate::s9pk::open(p).await?;
+    log::info!("Reading manifest from archive.");
+    let manifest = pkg.manifest().await?.into_latest();
     crate::ensure_code!(
         crate::version::Current::new()
             .semver()
@@ -148,36 +91,17 @@ pub async fn print_instructions<P: AsRef<Path>>(path: P) -> Result<(), Error> {
         "AppMgr Version Not Compatible: needs {}",
         manifest.os_version_required
     );
-    entries
-        .next()
-        .await
-        .ok_or(crate::install::Error::CorruptedPkgFile(
-            "missing config spec",
-        ))
-        .no_code()??;
-    entries
-        .next()
-        .await
-        .ok_or(crate::install::Error::CorruptedPkgFile(
-            "missing config rules",
-        ))
-        .no_code()??;
 
     if manifest.has_instructions {
         use tokio::io::AsyncWriteExt;
 
-        let mut instructions = entries
-            .next()
-            .await
-            .ok_or(crate::install::Error::CorruptedPkgFile(
-                "missing instructions",
-            ))
-            .no_code()??;
-
         let mut stdout = tokio::io::stdout();
-        tokio::io::copy(&mut instructions, &mut stdout)
-            .await
-            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        tokio::io::copy(
+            &mut pkg.entry(crate::s9pk::INSTRUCTIONS).await?,
+            &mut stdout,
+        )
+        .await
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
         stdout
             .flush()
             .await
@@ -1,6 +1,6 @@
-use std::borrow::Cow;
-use std::ffi::{OsStr, OsString};
-use std::marker::Unpin;
+use std::ffi::OsStr;
+use std::future::Future;
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{
@@ -11,16 +11,20 @@ use std::task::Context;
 use std::task::Poll;
 use std::time::Duration;
 
+use async_trait::async_trait;
 use failure::ResultExt as _;
-use futures::stream::StreamExt;
+use futures::future::{BoxFuture, FutureExt as _};
 use futures::stream::TryStreamExt;
 use tokio::io::AsyncWriteExt;
-use tokio::io::{AsyncRead, ReadBuf};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
 use tokio_compat_02::FutureExt;
-use tokio_tar as tar;
 
 use crate::config::{ConfigRuleEntry, ConfigSpec};
-use crate::manifest::{ImageConfig, Manifest, ManifestV0};
+use crate::manifest::{ImageConfig, Manifest, ManifestLatest, VolumeKind};
+use crate::progress::Phase;
+use crate::runtime::{ContainerStatus, CreateOptions, Mount, Tmpfs};
+use crate::s9pk::Source;
+use crate::signing::SIGNATURE_SECTION_LEN;
 use crate::util::{from_cbor_async_reader, to_yaml_async_writer, AsyncCompat, PersistencePath};
 use crate::version::VersionT;
 use crate::ResultExt as _;
@@ -33,26 +37,12 @@ pub enum Error {
     InvalidFileName,
 }
 
+/// Installs a version of `name` from the registry. The package is kept in the download cache,
+/// where a later update can find it as the base of a delta.
 pub async fn install_name(name_version: &str, use_cache: bool) -> Result<(), crate::Error> {
-    let name = name_version.split("@").next().unwrap();
-    let tmp_path = Path::new(crate::TMP_DIR).join(format!("{}.s9pk", name));
-    if !use_cache || !tmp_path.exists() {
-        download_name(name_version).await?;
-    }
-    install_path(
-        &tmp_path
-            .as_os_str()
-            .to_str()
-            .ok_or(Error::InvalidFileName)
-            .with_code(crate::error::FILESYSTEM_ERROR)?,
-        Some(name),
-    )
-    .await?;
-    tokio::fs::remove_file(&tmp_path)
-        .await
-        .with_context(|e| format!("{}: {}", tmp_path.display(), e))
-        .with_code(crate::error::FILESYSTEM_ERROR)?;
-    Ok(())
+    let (name, version) = resolve(name_version).await?;
+    let path = download_version(name, &version, use_cache).await?;
+    install_path(&path, Some(name)).await
 }
 
 struct CountingReader<R: AsyncRead>(pub R, pub Arc<AtomicU64>);
@@ -76,44 +66,366 @@ where
     }
 }
 
-pub async fn download_name(name_version: &str) -> Result<PathBuf, crate::Error> {
+impl<R> AsyncSeek for CountingReader<R>
+where
+    R: AsyncRead + AsyncSeek,
+{
+    fn start_seek(self: Pin<&mut Self>, pos: SeekFrom) -> std::io::Result<()> {
+        unsafe { self.map_unchecked_mut(|a| &mut a.0) }.start_seek(pos)
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        unsafe { self.map_unchecked_mut(|a| &mut a.0) }.poll_complete(cx)
+    }
+}
+
+#[async_trait]
+impl<R: Source> Source for CountingReader<R> {
+    async fn finish(&mut self) -> Result<(), crate::Error> {
+        self.0.finish().await
+    }
+}
+
+/// The most the download cache may hold. Downloads of the apps least recently downloaded are
+/// evicted first.
+pub const DOWNLOAD_CACHE_LIMIT: u64 = 1024 * 1024 * 1024;
+
+/// Downloaded packages, stored as `<id>/<version>/<sha256>.s9pk`. Only the most recent download
+/// of each app is kept, and only while the cache is within `DOWNLOAD_CACHE_LIMIT`.
+pub fn download_cache() -> PathBuf {
+    crate::context::get().tmp_dir.join("cache")
+}
+
+/// Checks that a downloaded package is intact and is `version` of `id`.
+async fn check_download(
+    path: &Path,
+    id: &str,
+    version: &emver::Version,
+) -> Result<(), crate::Error> {
+    let mut pkg = crate::s9pk::open(path).await?;
+    let manifest = pkg.manifest().await?.into_latest();
+    crate::ensure_code!(
+        manifest.id == id && &manifest.version == version,
+        crate::error::REGISTRY_ERROR,
+        "Downloaded Package Does Not Match: expected {}@{}, got {}@{}",
+        id,
+        version,
+        manifest.id,
+        manifest.version
+    );
+    crate::integrity::check(&mut pkg).await
+}
+
+/// Looks up a cached download of `version` of `id`, removing any that no longer match the hash
+/// they were stored under.
+async fn cached(id: &str, version: &emver::Version) -> Result<Option<PathBuf>, crate::Error> {
+    let dir = download_cache().join(id).join(format!("{}", version));
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let hash = match path.file_stem().and_then(|a| a.to_str()) {
+            Some(hash) if path.extension() == Some(OsStr::new("s9pk")) => hash.to_owned(),
+            _ => continue,
+        };
+        if crate::integrity::hash_file(&path).await? == hash
+            && check_download(&path, id, version).await.is_ok()
+        {
+            return Ok(Some(path));
+        }
+        log::warn!("Removing invalid cached download {}.", path.display());
+        remove_path(&path).await?;
+    }
+    Ok(None)
+}
+
+/// Splits `name@range` and asks the registry which version of `name` satisfies it.
+async fn resolve(name_version: &str) -> Result<(&str, emver::Version), crate::Error> {
     let mut split = name_version.split("@");
     let name = split.next().unwrap();
-    let req: Option<emver::VersionRange> = split.next().map(|a| a.parse()).transpose().no_code()?;
-    if let Some(req) = req {
-        download(
-            &format!("{}/{}.s9pk?spec={}", &*crate::APP_REGISTRY_URL, name, req),
-            Some(name),
-        )
+    let req: emver::VersionRange = split
+        .next()
+        .map(|a| a.parse())
+        .transpose()
+        .no_code()?
+        .unwrap_or_else(emver::VersionRange::any);
+    let version = crate::registry::version(name, &req).await?;
+    Ok((name, version))
+}
+
+fn registry_url(name: &str, version: &emver::Version) -> String {
+    format!(
+        "{}/{}.s9pk?spec={}",
+        &*crate::APP_REGISTRY_URL,
+        name,
+        emver::VersionRange::exactly(version.clone())
+    )
+}
+
+fn delta_url(name: &str, base: &emver::Version, version: &emver::Version) -> String {
+    format!(
+        "{}/delta/{}?spec={}&base={}",
+        &*crate::APP_REGISTRY_URL,
+        name,
+        emver::VersionRange::exactly(version.clone()),
+        base
+    )
+}
+
+pub async fn download_name(name_version: &str, use_cache: bool) -> Result<PathBuf, crate::Error> {
+    let (name, version) = resolve(name_version).await?;
+    download_version(name, &version, use_cache).await
+}
+
+pub async fn download_version(
+    name: &str,
+    version: &emver::Version,
+    use_cache: bool,
+) -> Result<PathBuf, crate::Error> {
+    if use_cache {
+        if let Some(path) = cached(name, version).await? {
+            log::info!("Using cached download {}.", path.display());
+            return Ok(path);
+        }
+    }
+    let part = download_dir(name, version).await?.join("download.part");
+    download_to(&registry_url(name, version), &part).await?;
+    store_download(&part, name, version).await
+}
+
+/// Downloads `version` of `name` for an update from `base`. If the download of `base` is still
+/// cached, only a delta between the two is fetched. Otherwise, or if the registry has no usable
+/// delta, or if the delta does not rebuild the registry's package byte for byte, the full package
+/// is downloaded.
+pub async fn download_update(
+    name: &str,
+    base: &emver::Version,
+    version: &emver::Version,
+) -> Result<PathBuf, crate::Error> {
+    if let Some(path) = cached(name, version).await? {
+        log::info!("Using cached download {}.", path.display());
+        return Ok(path);
+    }
+    match download_delta(name, base, version).await {
+        Ok(Some(path)) => return Ok(path),
+        Ok(None) => (),
+        Err(e) => log::warn!("Delta update failed, downloading full package: {}", e),
+    }
+    download_version(name, version, false).await
+}
+
+async fn download_delta(
+    name: &str,
+    base: &emver::Version,
+    version: &emver::Version,
+) -> Result<Option<PathBuf>, crate::Error> {
+    let base_path = match cached(name, base).await? {
+        Some(path) => path,
+        None => {
+            log::info!("No download of {}@{} cached, skipping delta.", name, base);
+            return Ok(None);
+        }
+    };
+    let dir = download_dir(name, version).await?;
+    let delta_part = dir.join("delta.part");
+    match download_to(&delta_url(name, base, version), &delta_part).await {
+        // including a 404 from a registry that does not serve deltas at all
+        Err(e) if e.code == Some(crate::error::REGISTRY_ERROR) => {
+            log::info!("No delta available from {}: {}", base, e);
+            remove_path(&delta_part).await?;
+            return Ok(None);
+        }
+        res => res?,
+    }
+    let part = dir.join("download.part");
+    crate::progress::step(Phase::Download, "applying delta").await;
+    let res = crate::delta::apply(&base_path, &delta_part, &part).await;
+    remove_path(&delta_part).await?;
+    if let Err(e) = res {
+        log::warn!("Delta from {} not usable: {}", base, e);
+        remove_path(&part).await?;
+        return Ok(None);
+    }
+    store_download(&part, name, version).await.map(Some)
+}
+
+async fn download_dir(name: &str, version: &emver::Version) -> Result<PathBuf, crate::Error> {
+    let dir = download_cache().join(name).join(format!("{}", version));
+    tokio::fs::create_dir_all(&dir)
         .await
-    } else {
-        download(
-            &format!("{}/{}.s9pk", &*crate::APP_REGISTRY_URL, name),
-            Some(name),
-        )
+        .with_context(|e| format!("{}: {}", dir.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    Ok(dir)
+}
+
+/// Checks a finished download of `version` of `name` and moves it into the cache, evicting
+/// any other downloads of `name`.
+async fn store_download(
+    part: &Path,
+    name: &str,
+    version: &emver::Version,
+) -> Result<PathBuf, crate::Error> {
+    if let Err(e) = check_download(part, name, version).await {
+        remove_path(part).await?;
+        return Err(e);
+    }
+    let app_cache = download_cache().join(name);
+    let dir = app_cache.join(format!("{}", version));
+    let path = dir.join(format!("{}.s9pk", crate::integrity::hash_file(part).await?));
+    tokio::fs::rename(part, &path)
         .await
+        .with_context(|e| format!("{}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let mut versions = tokio::fs::read_dir(&app_cache).await?;
+    while let Some(entry) = versions.next_entry().await? {
+        if entry.path() != dir {
+            log::info!("Removing cached download {}.", entry.path().display());
+            remove_path(entry.path()).await?;
+        }
+    }
+    let mut downloads = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = downloads.next_entry().await? {
+        if entry.path() != path && entry.path().extension() == Some(OsStr::new("s9pk")) {
+            remove_path(entry.path()).await?;
+        }
+    }
+    prune_download_cache(name).await?;
+    Ok(path)
+}
+
+/// Evicts the downloads of apps other than `keep`, oldest first, until the cache is within
+/// `DOWNLOAD_CACHE_LIMIT`.
+async fn prune_download_cache(keep: &str) -> Result<(), crate::Error> {
+    let mut apps = Vec::new();
+    let mut total = 0;
+    let mut entries = tokio::fs::read_dir(download_cache()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let mut size = 0;
+        let mut modified = std::time::SystemTime::UNIX_EPOCH;
+        let mut dirs = vec![entry.path()];
+        while let Some(dir) = dirs.pop() {
+            let mut files = tokio::fs::read_dir(&dir).await?;
+            while let Some(file) = files.next_entry().await? {
+                let metadata = file.metadata().await?;
+                if metadata.is_dir() {
+                    dirs.push(file.path());
+                } else {
+                    size += metadata.len();
+                    modified = modified.max(metadata.modified()?);
+                }
+            }
+        }
+        total += size;
+        if entry.file_name() != OsStr::new(keep) {
+            apps.push((modified, size, entry.path()));
+        }
     }
+    apps.sort();
+    for (_, size, path) in apps {
+        if total <= DOWNLOAD_CACHE_LIMIT {
+            break;
+        }
+        log::info!("Evicting cached download {}.", path.display());
+        remove_path(&path).await?;
+        total -= size;
+    }
+    Ok(())
+}
+
+/// Removes every cached download of `name`.
+pub async fn clear_download_cache(name: &str) -> Result<(), crate::Error> {
+    remove_path(download_cache().join(name)).await
 }
 
 pub async fn download(url: &str, name: Option<&str>) -> Result<PathBuf, crate::Error> {
+    tokio::fs::create_dir_all(&crate::context::get().tmp_dir).await?;
+    let tmp_file_path = crate::context::get()
+        .tmp_dir
+        .join(&format!("{}.s9pk", name.unwrap_or("download")));
+    let part = tmp_file_path.with_extension("s9pk.part");
+    download_to(url, &part).await?;
+    tokio::fs::rename(&part, &tmp_file_path)
+        .await
+        .with_context(|e| format!("{}: {}", tmp_file_path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    Ok(tmp_file_path)
+}
+
+const DOWNLOAD_RETRIES: u64 = 5;
+
+/// Downloads `url` to `dst`, resuming from whatever an earlier attempt left there. Interrupted
+/// transfers are retried a few times before giving up.
+async fn download_to(url: &str, dst: &Path) -> Result<(), crate::Error> {
     let url = reqwest::Url::parse(url).no_code()?;
     log::info!("Downloading {}.", url.as_str());
-    let response = reqwest::get(url)
+    let mut attempt = 0;
+    loop {
+        match download_range(&url, dst).await {
+            Err(e) if e.code == Some(crate::error::NETWORK_ERROR) && attempt < DOWNLOAD_RETRIES => {
+                attempt += 1;
+                log::warn!(
+                    "Download interrupted, retrying ({}/{}): {}",
+                    attempt,
+                    DOWNLOAD_RETRIES,
+                    e
+                );
+                tokio::time::sleep(Duration::from_secs(attempt)).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+async fn download_range(url: &reqwest::Url, dst: &Path) -> Result<(), crate::Error> {
+    let etag_path = dst.with_extension("etag");
+    let offset = tokio::fs::metadata(dst).await.map(|m| m.len()).unwrap_or(0);
+    let mut request = reqwest::Client::new().get(url.clone());
+    if offset > 0 {
+        log::info!("Resuming download at {}KiB.", offset / 1024);
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+        // the partial download is only extended if it is still the same file on the server
+        if let Ok(etag) = tokio::fs::read_to_string(&etag_path).await {
+            request = request.header(reqwest::header::IF_RANGE, etag);
+        }
+    }
+    let response = request
+        .send()
         .compat()
         .await
-        .with_code(crate::error::NETWORK_ERROR)?
+        .with_code(crate::error::NETWORK_ERROR)?;
+    if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        remove_path(dst).await?;
+        return Err(format_err!("Partial Download Invalid: {}", dst.display()))
+            .with_code(crate::error::NETWORK_ERROR);
+    }
+    let response = response
         .error_for_status()
         .with_code(crate::error::REGISTRY_ERROR)?;
-    tokio::fs::create_dir_all(crate::TMP_DIR).await?;
-    let tmp_file_path =
-        Path::new(crate::TMP_DIR).join(&format!("{}.s9pk", name.unwrap_or("download")));
-    let mut f = tokio::fs::File::create(&tmp_file_path).await?;
+    let resumed = if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        offset
+    } else {
+        0
+    };
+    match response.headers().get(reqwest::header::ETAG) {
+        Some(etag) => tokio::fs::write(&etag_path, etag.as_bytes()).await?,
+        None => remove_path(&etag_path).await?,
+    }
+    let mut f = if resumed > 0 {
+        tokio::fs::OpenOptions::new().append(true).open(dst).await
+    } else {
+        tokio::fs::File::create(dst).await
+    }
+    .with_context(|e| format!("{}: {}", dst.display(), e))
+    .with_code(crate::error::FILESYSTEM_ERROR)?;
     let len: Option<u64> = response.content_length().map(|a| {
         log::info!("{}KiB to download.", a / 1024);
-        a
+        a + resumed
     });
     let done = Arc::new(AtomicBool::new(false));
-    let counter = Arc::new(AtomicU64::new(0));
+    let counter = Arc::new(AtomicU64::new(resumed));
+    let poll_handle = crate::progress::track(Phase::Download, counter.clone(), len, done.clone());
     let mut reader = CountingReader(
         AsyncCompat(
             response
@@ -127,34 +439,28 @@ pub async fn download(url: &str, name: Option<&str>) -> Result<PathBuf, crate::E
     let download_handle = tokio::spawn(async move {
         let res = tokio::io::copy(&mut reader, &mut f).await;
         done_handle.store(true, atomic::Ordering::SeqCst);
-        res
-    });
-    let poll_handle = tokio::spawn(async move {
-        loop {
-            let is_done = done.load(atomic::Ordering::SeqCst);
-            let downloaded_bytes = counter.load(atomic::Ordering::SeqCst);
-            if !*crate::QUIET.read().await {
-                if let Some(len) = len {
-                    print!("\rDownloading... {}%", downloaded_bytes * 100 / len);
-                } else {
-                    print!("\rDownloading... {}KiB", downloaded_bytes / 1024);
-                }
-            }
-            if is_done {
-                break;
-            }
-            tokio::time::sleep(Duration::from_millis(10)).await;
-        }
-        if !*crate::QUIET.read().await {
-            println!("\rDownloading... 100%");
-        }
+        res?;
+        f.flush().await?;
+        f.sync_all().await
     });
-    download_handle.await.unwrap()?;
-    poll_handle.await.unwrap();
-    Ok(tmp_file_path)
+    let res = download_handle.await.unwrap();
+    let downloaded = poll_handle.await.unwrap();
+    res.with_code(crate::error::NETWORK_ERROR)?;
+    remove_path(&etag_path).await?;
+    crate::progress::complete_bytes(Phase::Download, downloaded, len).await;
+    Ok(())
 }
 
+/// Installs the package at `url` as it downloads. If the server cannot send the signature of the
+/// package ahead of it, or the connection drops partway through, the install is rolled back and
+/// the package is downloaded to a temporary file instead, so the transfer can be resumed.
 pub async fn install_url(url: &str, name: Option<&str>) -> Result<(), crate::Error> {
+    match install_stream(url, name).await {
+        Err(e) if e.code == Some(crate::error::NETWORK_ERROR) => {
+            log::warn!("Streaming install failed, downloading instead: {}", e);
+        }
+        res => return res,
+    }
     let tmp_file_path = download(url, name).await?;
     install_path(&tmp_file_path, name).await?;
     tokio::fs::remove_file(&tmp_file_path)
@@ -164,6 +470,110 @@ pub async fn install_url(url: &str, name: Option<&str>) -> Result<(), crate::Err
     Ok(())
 }
 
+/// Fetches the signature section at the end of the package at `url` on its own, with the length
+/// of the package. Fails with `NETWORK_ERROR` if the server does not serve byte ranges.
+async fn fetch_signature(
+    url: &reqwest::Url,
+) -> Result<([u8; SIGNATURE_SECTION_LEN as usize], u64), crate::Error> {
+    let response = reqwest::Client::new()
+        .get(url.clone())
+        .header(
+            reqwest::header::RANGE,
+            format!("bytes=-{}", SIGNATURE_SECTION_LEN),
+        )
+        .send()
+        .compat()
+        .await
+        .with_code(crate::error::NETWORK_ERROR)?
+        .error_for_status()
+        .with_code(crate::error::REGISTRY_ERROR)?;
+    // Content-Range: bytes <first>-<last>/<length>
+    let len = response
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)
+        .filter(|_| response.status() == reqwest::StatusCode::PARTIAL_CONTENT)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.rsplit('/').next())
+        .and_then(|len| len.parse::<u64>().ok())
+        .ok_or_else(|| format_err!("Registry Does Not Serve Byte Ranges"))
+        .with_code(crate::error::NETWORK_ERROR)?;
+    let body = response
+        .bytes()
+        .compat()
+        .await
+        .with_code(crate::error::NETWORK_ERROR)?;
+    let mut section = [0; SIGNATURE_SECTION_LEN as usize];
+    crate::ensure_code!(
+        body.len() == section.len() && len >= crate::s9pk::HEADER_LEN + SIGNATURE_SECTION_LEN,
+        crate::error::SIGNATURE_ERROR,
+        "Package Is Not Signed"
+    );
+    section.copy_from_slice(&body);
+    Ok((section, len))
+}
+
+/// Installs the package at `url` straight from the response. The signature is fetched first, and
+/// checked against the table of contents before anything is installed. Each entry is then checked
+/// against the table of contents as it arrives: the read that completes an entry fails if it does
+/// not match, and the install is rolled back.
+async fn install_stream(url: &str, name: Option<&str>) -> Result<(), crate::Error> {
+    let url = reqwest::Url::parse(url).no_code()?;
+    log::info!("Starting streaming install from {}.", url.as_str());
+    let (section, len) = fetch_signature(&url).await?;
+    let sig = crate::signing::parse_signature(&section)?
+        .ok_or_else(|| format_err!("Package Is Not Signed"))
+        .with_code(crate::error::SIGNATURE_ERROR)?;
+    let response = reqwest::get(url)
+        .compat()
+        .await
+        .with_code(crate::error::NETWORK_ERROR)?
+        .error_for_status()
+        .with_code(crate::error::REGISTRY_ERROR)?;
+    let done = Arc::new(AtomicBool::new(false));
+    let counter = Arc::new(AtomicU64::new(0));
+    let stream = crate::s9pk::Stream::new(
+        CountingReader(
+            AsyncCompat(
+                response
+                    .bytes_stream()
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+                    .into_async_read(),
+            ),
+            counter.clone(),
+        ),
+        len,
+        section,
+    );
+    let interrupted = stream.interrupted();
+    let end = stream.end();
+    let poll_handle =
+        crate::progress::track(Phase::Install, counter.clone(), Some(len), done.clone());
+    let res = async {
+        let mut pkg = crate::s9pk::Reader::from_stream(stream, end).await?;
+        log::info!("Verifying package signature.");
+        crate::progress::step(Phase::Install, "verifying package signature").await;
+        crate::signing::verify_digest(&sig, pkg.digest()).await?;
+        crate::integrity::check_layout(&pkg)?;
+        install(&mut pkg, name).await
+    }
+    .await;
+    done.store(true, atomic::Ordering::SeqCst);
+    let installed = poll_handle.await.unwrap();
+    match res {
+        Err(e) if interrupted.load(atomic::Ordering::SeqCst) => Err(crate::Error {
+            failure: e.failure,
+            code: Some(crate::error::NETWORK_ERROR),
+        }),
+        Ok(()) => {
+            crate::progress::complete_bytes(Phase::Install, installed, Some(len)).await;
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// Installs the package at `path`. The file is opened once, and its signature and entry hashes
+/// are checked through that handle before anything is installed from it.
 pub async fn install_path<P: AsRef<Path>>(p: P, name: Option<&str>) -> Result<(), crate::Error> {
     let path = p.as_ref();
     log::info!(
@@ -173,72 +583,219 @@ pub async fn install_path<P: AsRef<Path>>(p: P, name: Option<&str>) -> Result<()
             .ok_or(Error::InvalidFileName)
             .no_code()?
     );
-    let file = tokio::fs::File::open(&path)
+    let mut file = tokio::fs::File::open(&path)
         .await
         .with_context(|e| format!("{}: {}", path.display(), e))
         .with_code(crate::error::FILESYSTEM_ERROR)?;
+    log::info!("Verifying package signature.");
+    crate::progress::step(Phase::Install, "verifying package signature").await;
+    crate::signing::verify(&mut file).await?;
+    log::info!("Verifying package integrity.");
+    crate::progress::step(Phase::Install, "verifying package integrity").await;
+    crate::integrity::check(&mut crate::s9pk::Reader::new(&mut file).await?).await?;
     let len = file.metadata().await?.len();
     let done = Arc::new(AtomicBool::new(false));
     let counter = Arc::new(AtomicU64::new(0));
-    let done_handle = done.clone();
-    let name_clone = name.map(|a| a.to_owned());
-    let counter_clone = counter.clone();
-    let poll_handle = tokio::spawn(async move {
-        loop {
-            let is_done = done.load(atomic::Ordering::SeqCst);
-            let installed_bytes = counter.load(atomic::Ordering::SeqCst);
-            if !*crate::QUIET.read().await {
-                print!("\rInstalling... {}%", installed_bytes * 100 / len);
+    let poll_handle =
+        crate::progress::track(Phase::Install, counter.clone(), Some(len), done.clone());
+    let res = match crate::s9pk::Reader::new(CountingReader(file, counter)).await {
+        Ok(mut pkg) => install(&mut pkg, name).await,
+        Err(e) => Err(e),
+    };
+    done.store(true, atomic::Ordering::SeqCst);
+    let installed = poll_handle.await.unwrap();
+    if res.is_ok() {
+        crate::progress::complete_bytes(Phase::Install, installed, Some(len)).await;
+    }
+    res
+}
+
+async fn install<R: Source>(
+    pkg: &mut crate::s9pk::Reader<R>,
+    name: Option<&str>,
+) -> Result<(), crate::Error> {
+    log::info!("Reading manifest from archive.");
+    let manifest = pkg.manifest().await.no_code()?.into_latest();
+    install_v1(manifest, pkg, name).await
+}
+
+type Action = (String, BoxFuture<'static, Result<(), crate::Error>>);
+
+/// Compensating actions recorded while an install is staged. If staging fails, the rollback
+/// actions are run in reverse order to return the system to its pre-install state. If it
+/// succeeds, the commit actions discard whatever was kept around to make rollback possible.
+#[derive(Default)]
+pub struct Transaction {
+    rollback: Vec<Action>,
+    commit: Vec<Action>,
+}
+impl Transaction {
+    pub fn on_rollback<
+        D: Into<String>,
+        F: Future<Output = Result<(), crate::Error>> + Send + 'static,
+    >(
+        &mut self,
+        description: D,
+        action: F,
+    ) {
+        self.rollback.push((description.into(), action.boxed()));
+    }
+
+    pub fn on_commit<
+        D: Into<String>,
+        F: Future<Output = Result<(), crate::Error>> + Send + 'static,
+    >(
+        &mut self,
+        description: D,
+        action: F,
+    ) {
+        self.commit.push((description.into(), action.boxed()));
+    }
+
+    /// Creates `path` and any missing parents, removing the outermost created directory on
+    /// rollback.
+    pub async fn create_dir_all<P: AsRef<Path>>(&mut self, path: P) -> Result<(), crate::Error> {
+        let path = path.as_ref();
+        let created = path
+            .ancestors()
+            .take_while(|a| !a.exists())
+            .last()
+            .map(|a| a.to_owned());
+        tokio::fs::create_dir_all(path)
+            .await
+            .with_context(|e| format!("{}: {}", path.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        if let Some(created) = created {
+            self.on_rollback(format!("remove {}", created.display()), async move {
+                remove_path(&created).await
+            });
+        }
+        Ok(())
+    }
+
+    /// Moves `path` to `backup`, moving it back on rollback and deleting the backup on commit.
+    pub async fn stash<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        backup: PathBuf,
+    ) -> Result<(), crate::Error> {
+        let path = path.as_ref().to_owned();
+        remove_path(&backup).await?;
+        tokio::fs::rename(&path, &backup)
+            .await
+            .with_context(|e| format!("mv {} {}: {}", path.display(), backup.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        let backup_clone = backup.clone();
+        self.on_rollback(format!("restore {}", path.display()), async move {
+            remove_path(&path).await?;
+            tokio::fs::rename(&backup_clone, &path)
+                .await
+                .with_context(|e| {
+                    format!("mv {} {}: {}", backup_clone.display(), path.display(), e)
+                })
+                .with_code(crate::error::FILESYSTEM_ERROR)
+        });
+        self.on_commit(format!("remove {}", backup.display()), async move {
+            remove_path(&backup).await
+        });
+        Ok(())
+    }
+
+    pub async fn commit(self) {
+        for (description, action) in self.commit {
+            log::trace!("Committing: {}.", description);
+            if let Err(e) = action.await {
+                log::warn!("Failed to {}: {}", description, e);
             }
-            if is_done {
-                break;
+        }
+    }
+
+    pub async fn rollback(self) {
+        for (description, action) in self.rollback.into_iter().rev() {
+            log::info!("Rolling back: {}.", description);
+            if let Err(e) = action.await {
+                log::error!("Failed to {}: {}", description, e);
             }
-            tokio::time::sleep(Duration::from_millis(10)).await;
         }
-        if !*crate::QUIET.read().await {
-            println!("\rInstalling... 100%");
+    }
+}
+
+/// The data volume of `manifest` and its named volumes, but for those kept in memory.
+pub fn volume_mounts(manifest: &ManifestLatest) -> Vec<Mount> {
+    let ctx = crate::context::get();
+    let mut mounts = vec![Mount {
+        src: ctx.volumes.join(&manifest.id),
+        dst: manifest.volumes.mount.clone(),
+        readonly: false,
+    }];
+    for (name, volume) in &manifest.volumes.named {
+        if volume.kind != VolumeKind::Tmpfs {
+            mounts.push(Mount {
+                src: ctx.named_volumes(&manifest.id).join(name),
+                dst: volume.mount.clone(),
+                readonly: false,
+            });
         }
-    });
-    let reader = CountingReader(file, counter_clone);
-    let res = install(reader, name_clone.as_ref().map(|a| a.as_str())).await;
-    done_handle.store(true, atomic::Ordering::SeqCst);
-    res?;
-    poll_handle.await.unwrap();
-    if !*crate::QUIET.read().await {
-        println!("Complete.");
     }
-    Ok(())
+    mounts
 }
 
-pub async fn install<R: AsyncRead + Unpin + Send + Sync>(
-    r: R,
-    name: Option<&str>,
-) -> Result<(), crate::Error> {
-    log::info!("Extracting archive.");
-    let mut pkg = tar::Archive::new(r);
-    let mut entries = pkg.entries()?;
-    log::info!("Opening manifest from archive.");
-    let manifest = entries
-        .next()
-        .await
-        .ok_or(Error::CorruptedPkgFile("missing manifest"))
-        .no_code()??;
-    crate::ensure_code!(
-        manifest.path()?.to_str() == Some("manifest.cbor"),
-        crate::error::GENERAL_ERROR,
-        "Package File Invalid or Corrupted"
-    );
-    log::trace!("Deserializing manifest.");
-    let manifest: Manifest = from_cbor_async_reader(manifest).await.no_code()?;
-    match manifest {
-        Manifest::V0(m) => install_v0(m, entries, name).await?,
+pub fn tmpfs_mounts(manifest: &ManifestLatest) -> Vec<Tmpfs> {
+    manifest
+        .volumes
+        .named
+        .values()
+        .filter(|volume| volume.kind == VolumeKind::Tmpfs)
+        .map(|volume| Tmpfs {
+            dst: volume.mount.clone(),
+            size_mb: volume.size_mb,
+        })
+        .collect()
+}
+
+/// Creates the container of an installed app, and records the environment it was created with.
+pub async fn create_container(manifest: &ManifestLatest) -> Result<(), crate::Error> {
+    let ip = crate::tor::services_map()
+        .await?
+        .map
+        .get(&manifest.id)
+        .map(|svc| svc.ip)
+        .ok_or_else(|| format_err!("{} has no address on the network", manifest.id))
+        .with_code(crate::error::NOT_FOUND)?;
+    let env = crate::env::environment(manifest).await?;
+    crate::runtime::get()
+        .create(&CreateOptions {
+            name: manifest.id.clone(),
+            image: format!("start9/{}:latest", manifest.id),
+            mounts: volume_mounts(manifest),
+            tmpfs: tmpfs_mounts(manifest),
+            network: Some(("start9".to_owned(), ip)),
+            env: env.clone(),
+            resources: crate::resources::effective(&manifest.id, &manifest.resources).await?,
+            security: manifest.security.clone(),
+            ..Default::default()
+        })
+        .await?;
+    let mut db = crate::db::transaction().await?;
+    db.put(&crate::db::env_key(&manifest.id), &env)?;
+    db.commit().await
+}
+
+async fn remove_path<P: AsRef<Path>>(path: P) -> Result<(), crate::Error> {
+    let path = path.as_ref();
+    let res = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(path).await,
+        Ok(_) => tokio::fs::remove_file(path).await,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
     };
-    Ok(())
+    res.with_context(|e| format!("rm {}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)
 }
 
-pub async fn install_v0<R: AsyncRead + Unpin + Send + Sync>(
-    manifest: ManifestV0,
-    mut entries: tar::Entries<R>,
+async fn install_v1<R: Source>(
+    manifest: ManifestLatest,
+    pkg: &mut crate::s9pk::Reader<R>,
     name: Option<&str>,
 ) -> Result<(), crate::Error> {
     crate::ensure_code!(
@@ -256,324 +813,670 @@ pub async fn install_v0<R: AsyncRead + Unpin + Send + Sync>(
             "Package Name Does Not Match Expected"
         );
     }
+    manifest.image.host_arch()?;
+
+    let mut tx = Transaction::default();
+    // every step commits its changes to the db into this one, which is dropped before rolling
+    // back, so that the db is left as it was. It does not keep other processes from the db while
+    // the image loads.
+    let db = crate::db::transaction_unlocked().await?;
+    let staged = async {
+        db.scope(async {
+            stage_v1(&manifest, pkg, &mut tx).await?;
+            pkg.finish().await
+        })
+        .await?;
+        db.commit().await
+    };
+    if let Err(e) = staged.await {
+        log::error!("Install of {} failed, rolling back: {}", manifest.id, e);
+        crate::progress::step(Phase::Install, "rolling back").await;
+        tx.rollback().await;
+        return Err(e);
+    }
+    tx.commit().await;
+    if let Err(e) = crate::oci::prune().await {
+        log::warn!("Failed to prune image blobs: {}", e);
+    }
+
+    // Binding dependency volumes and restarting dependents act on other apps, so they happen
+    // once the install is committed.
+    crate::dependencies::update_binds(&manifest.id).await?;
+    for (dep_id, dep_info) in manifest.dependencies.0 {
+        if dep_info.mount_shared
+            && crate::apps::list_info().await?.get(&dep_id).is_some()
+            && crate::apps::manifest(&dep_id)
+                .await?
+                .volumes
+                .shared
+                .is_some()
+        {
+            match crate::apps::status(&dep_id, false).await?.status {
+                crate::apps::DockerStatus::Stopped => (),
+                crate::apps::DockerStatus::Running => crate::control::restart_app(&dep_id).await?,
+                _ => crate::apps::set_needs_restart(&dep_id, true).await?,
+            }
+        }
+    }
+
+    Ok(())
+}
 
+async fn stage_v1<R: AsyncRead + AsyncSeek + Unpin + Send>(
+    manifest: &ManifestLatest,
+    pkg: &mut crate::s9pk::Reader<R>,
+    tx: &mut Transaction,
+) -> Result<(), crate::Error> {
+    // registered first so that it runs last, once the services and manifests it is generated
+    // from are restored
+    tx.on_rollback("restore firewall rules", crate::firewall::reload());
     log::info!(
         "Creating metadata directory: {}/apps/{}",
-        crate::PERSISTENCE_DIR,
+        crate::context::get().persistence_dir.display(),
         manifest.id
     );
     let app_dir = PersistencePath::from_ref("apps").join(&manifest.id);
     let app_dir_path = app_dir.path();
     if app_dir_path.exists() {
-        tokio::fs::remove_dir_all(&app_dir_path).await?;
+        tx.stash(
+            &app_dir_path,
+            PersistencePath::from_ref("apps")
+                .join(format!("{}.rollback", manifest.id))
+                .path(),
+        )
+        .await?;
     }
-    tokio::fs::create_dir_all(&app_dir_path).await?;
+    tx.create_dir_all(&app_dir_path).await?;
 
-    let (ip, tor_addr, tor_key) = crate::tor::set_svc(
+    let prev_svc = crate::tor::services_map()
+        .await?
+        .map
+        .get(&manifest.id)
+        .cloned();
+    let id = manifest.id.clone();
+    tx.on_rollback("restore tor hidden service", async move {
+        match prev_svc {
+            Some(svc) => crate::tor::set_svc(
+                &id,
+                crate::tor::NewService {
+                    ports: svc.ports,
+                    hidden_service_version: svc.hidden_service_version,
+                },
+            )
+            .await
+            .map(|_| ()),
+            None => crate::tor::rm_svc(&id).await,
+        }
+    });
+    let (_, tor_addr, _) = crate::tor::set_svc(
         &manifest.id,
         crate::tor::NewService {
-            ports: manifest.ports.clone(),
+            ports: manifest.ports(),
             hidden_service_version: manifest.hidden_service_version,
         },
     )
     .await?;
 
-    let recoverable = Path::new(crate::VOLUMES).join(&manifest.id).exists();
+    let volume = crate::context::get().volumes.join(&manifest.id);
+    let recoverable = volume.exists();
 
-    log::info!("Creating volume {}/{}.", crate::VOLUMES, manifest.id);
-    tokio::fs::create_dir_all(Path::new(crate::VOLUMES).join(&manifest.id)).await?;
+    log::info!("Creating volume {}.", volume.display());
+    tx.create_dir_all(&volume).await?;
 
     let _lock = app_dir.lock(true).await?;
     log::info!("Saving manifest.");
+    crate::progress::step(Phase::Install, "saving metadata").await;
     let mut manifest_out = app_dir.join("manifest.yaml").write(None).await?;
-    to_yaml_async_writer(&mut *manifest_out, &Manifest::V0(manifest.clone())).await?;
+    to_yaml_async_writer(&mut *manifest_out, &Manifest::V1(manifest.clone())).await?;
     manifest_out.commit().await?;
-    log::info!("Opening config spec from archive.");
-    let config_spec = entries
-        .next()
-        .await
-        .ok_or(Error::CorruptedPkgFile("missing config spec"))
-        .no_code()??;
-    crate::ensure_code!(
-        config_spec.path()?.to_str() == Some("config_spec.cbor"),
-        crate::error::GENERAL_ERROR,
-        "Package File Invalid or Corrupted"
-    );
-    log::trace!("Deserializing config spec.");
-    let config_spec: ConfigSpec = from_cbor_async_reader(config_spec).await?;
+    log::info!("Reading config spec from archive.");
+    let config_spec: ConfigSpec =
+        from_cbor_async_reader(pkg.entry(crate::s9pk::CONFIG_SPEC).await?).await?;
     log::info!("Saving config spec.");
     let mut config_spec_out = app_dir.join("config_spec.yaml").write(None).await?;
     to_yaml_async_writer(&mut *config_spec_out, &config_spec).await?;
     config_spec_out.commit().await?;
-    log::info!("Opening config rules from archive.");
-    let config_rules = entries
-        .next()
-        .await
-        .ok_or(Error::CorruptedPkgFile("missing config rules"))
-        .no_code()??;
-    crate::ensure_code!(
-        config_rules.path()?.to_str() == Some("config_rules.cbor"),
-        crate::error::GENERAL_ERROR,
-        "Package File Invalid or Corrupted"
-    );
-    log::trace!("Deserializing config rules.");
-    let config_rules: Vec<ConfigRuleEntry> = from_cbor_async_reader(config_rules).await?;
+    log::info!("Reading config rules from archive.");
+    let config_rules: Vec<ConfigRuleEntry> =
+        from_cbor_async_reader(pkg.entry(crate::s9pk::CONFIG_RULES).await?).await?;
     log::info!("Saving config rules.");
     let mut config_rules_out = app_dir.join("config_rules.yaml").write(None).await?;
     to_yaml_async_writer(&mut *config_rules_out, &config_rules).await?;
     config_rules_out.commit().await?;
     if manifest.has_instructions {
-        log::info!("Opening instructions from archive.");
-        let mut instructions = entries
-            .next()
-            .await
-            .ok_or(Error::CorruptedPkgFile("missing config rules"))
-            .no_code()??;
-        crate::ensure_code!(
-            instructions.path()?.to_str() == Some("instructions.md"),
-            crate::error::GENERAL_ERROR,
-            "Package File Invalid or Corrupted"
-        );
         log::info!("Saving instructions.");
         let mut instructions_out = app_dir.join("instructions.md").write(None).await?;
-        tokio::io::copy(&mut instructions, &mut *instructions_out)
-            .await
-            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        tokio::io::copy(
+            &mut pkg.entry(crate::s9pk::INSTRUCTIONS).await?,
+            &mut *instructions_out,
+        )
+        .await
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
         instructions_out.commit().await?;
     }
 
     log::info!("Copying over assets.");
-    for asset in manifest.assets.iter() {
-        let dst_path = Path::new(crate::VOLUMES)
-            .join(&manifest.id)
-            .join(&asset.dst);
+    crate::progress::step(Phase::Install, "copying assets").await;
+    let assets_backup = crate::context::get()
+        .volumes
+        .join(format!("{}.rollback", manifest.id));
+    remove_path(&assets_backup).await?;
+    let assets_backup_clone = assets_backup.clone();
+    tx.on_commit(format!("remove {}", assets_backup.display()), async move {
+        remove_path(&assets_backup_clone).await
+    });
+    for (idx, asset) in manifest.assets.iter().enumerate() {
+        let dst_path = crate::pack::join_path(&volume, &asset.dst).no_code()?;
         log::info!("Copying {} to {}", asset.src.display(), dst_path.display());
         let src_path = Path::new(&asset.src);
-        log::info!("Opening {} from archive.", src_path.display());
-        let mut src = entries
-            .next()
-            .await
-            .ok_or(Error::CorruptedPkgFile("missing asset"))
-            .no_code()??;
+        crate::pack::validate_path(src_path).no_code()?;
+        let files = pkg.toc.files(&crate::s9pk::asset_entry(src_path));
         crate::ensure_code!(
-            src.path()? == src_path,
+            !files.is_empty(),
             crate::error::GENERAL_ERROR,
-            "Package File Invalid or Corrupted"
+            "Package File Invalid or Corrupted: missing asset {}",
+            src_path.display()
         );
         let dst_path_file = dst_path.join(src_path);
         if dst_path_file.exists() && !asset.overwrite {
             log::info!("{} already exists, skipping.", dst_path_file.display());
         } else {
             if dst_path_file.exists() {
-                if dst_path_file.is_dir() {
-                    tokio::fs::remove_dir_all(&dst_path_file)
-                        .await
-                        .with_context(|e| format!("{}: {}", dst_path_file.display(), e))
-                        .with_code(crate::error::FILESYSTEM_ERROR)?;
-                } else {
-                    tokio::fs::remove_file(&dst_path_file)
-                        .await
-                        .with_context(|e| format!("{}: {}", dst_path_file.display(), e))
-                        .with_code(crate::error::FILESYSTEM_ERROR)?;
-                }
+                tx.create_dir_all(&assets_backup).await?;
+                tx.stash(&dst_path_file, assets_backup.join(idx.to_string()))
+                    .await?;
+            } else {
+                let dst = dst_path_file.clone();
+                tx.on_rollback(format!("remove {}", dst.display()), async move {
+                    remove_path(&dst).await
+                });
             }
-            src.unpack_in(&dst_path).await?;
-            if src.header().entry_type().is_dir() {
-                loop {
-                    let mut file = entries
-                        .next()
-                        .await
-                        .ok_or(Error::CorruptedPkgFile("missing asset"))
-                        .no_code()??;
-                    if file
-                        .path()?
-                        .starts_with(format!("APPMGR_DIR_END:{}", asset.src.display()))
-                    {
-                        break;
-                    } else {
-                        file.unpack_in(&dst_path).await?;
-                    }
-                }
+            tx.create_dir_all(&dst_path).await?;
+            for file in files {
+                let dst =
+                    dst_path.join(Path::new(&file).strip_prefix(crate::s9pk::ASSETS).unwrap());
+                pkg.unpack(&file, dst).await?;
             }
         }
     }
 
-    let tag = match &manifest.image {
-        ImageConfig::Tar => {
-            let image_name = format!("start9/{}", manifest.id);
-            let tag = format!("{}:latest", image_name);
-            if tokio::process::Command::new("docker")
-                .arg("images")
-                .arg("-q")
-                .arg(&image_name)
-                .output()
-                .await?
-                .stdout
-                .len()
-                > 0
-            {
-                tokio::process::Command::new("docker")
-                    .arg("stop")
-                    .arg(&manifest.id)
-                    .spawn()?
-                    .wait()
-                    .await?;
-                tokio::process::Command::new("docker")
-                    .arg("rm")
-                    .arg(&manifest.id)
-                    .spawn()?
-                    .wait()
-                    .await?;
-                crate::ensure_code!(
-                    tokio::process::Command::new("docker")
-                        .arg("rmi")
-                        .arg(&image_name)
-                        .output()
-                        .await?
-                        .status
-                        .success(),
-                    crate::error::DOCKER_ERROR,
-                    "Failed to Remove Existing Image"
-                )
-            }
-            log::info!("Opening image.tar from archive.");
-            let mut image = entries
-                .next()
-                .await
-                .ok_or(Error::CorruptedPkgFile("missing image.tar"))
-                .no_code()??;
-            let image_path = image.path()?;
-            if image_path != Path::new("image.tar") {
-                return Err(crate::Error::from(format_err!(
-                    "Package File Invalid or Corrupted: expected image.tar, got {}",
-                    image_path.display()
-                )));
-            }
-            log::info!(
-                "Loading docker image start9/{} from image.tar.",
-                manifest.id
-            );
-            let mut child = tokio::process::Command::new("docker")
-                .arg("load")
-                .stdin(std::process::Stdio::piped())
-                .stdout(std::process::Stdio::inherit())
-                .stderr(match log::max_level() {
-                    log::LevelFilter::Error => std::process::Stdio::null(),
-                    _ => std::process::Stdio::inherit(),
-                })
-                .spawn()?;
-            let mut child_in = child.stdin.take().unwrap();
-            tokio::io::copy(&mut image, &mut child_in).await?;
-            child_in.flush().await?;
-            child_in.shutdown().await?;
-            drop(child_in);
-            crate::ensure_code!(
-                child.wait().await?.success(),
-                crate::error::DOCKER_ERROR,
-                "Failed to Load Docker Image From Tar"
+    let image_name = format!("start9/{}", manifest.id);
+    let tag = format!("{}:latest", image_name);
+    let rollback_tag = format!("{}:rollback", image_name);
+    let rollback_container = format!("{}.rollback", manifest.id);
+    let runtime = crate::runtime::get();
+    if runtime.image_exists(&image_name).await? {
+        log::info!("Setting aside existing image and container.");
+        runtime.rm(&rollback_container, true).await.ok();
+        runtime.rmi(&rollback_tag).await.ok();
+        let running = runtime
+            .inspect(&manifest.id)
+            .await
+            .map(|state| state.status == ContainerStatus::Running)
+            .unwrap_or(false);
+        if running {
+            runtime.stop(&manifest.id, 10).await?;
+            let id = manifest.id.clone();
+            tx.on_rollback(format!("start {}", id), async move {
+                crate::runtime::get().start(&id).await
+            });
+        }
+        if runtime
+            .rename(&manifest.id, &rollback_container)
+            .await
+            .is_ok()
+        {
+            let id = manifest.id.clone();
+            let rollback_container_clone = rollback_container.clone();
+            tx.on_rollback(format!("restore container {}", id), async move {
+                crate::runtime::get()
+                    .rename(&rollback_container_clone, &id)
+                    .await
+            });
+            tx.on_commit(
+                format!("remove container {}", rollback_container),
+                async move {
+                    crate::runtime::get()
+                        .rm(&rollback_container, false)
+                        .await
+                        .ok();
+                    Ok(())
+                },
             );
-            tag
         }
-    };
+        runtime.tag(&tag, &rollback_tag).await?;
+        let (tag_clone, rollback_tag_clone) = (tag.clone(), rollback_tag.clone());
+        tx.on_rollback(format!("restore image {}", tag), async move {
+            let runtime = crate::runtime::get();
+            runtime.tag(&rollback_tag_clone, &tag_clone).await?;
+            runtime.rmi(&rollback_tag_clone).await.ok();
+            Ok(())
+        });
+        tx.on_commit(format!("remove image {}", rollback_tag), async move {
+            crate::runtime::get().rmi(&rollback_tag).await.ok();
+            Ok(())
+        });
+        runtime.rmi(&tag).await?;
+    }
+    let (id, tag_clone) = (manifest.id.clone(), tag.clone());
+    tx.on_rollback(format!("remove image {}", tag), async move {
+        let runtime = crate::runtime::get();
+        runtime.rm(&id, true).await.ok();
+        runtime.rmi(&tag_clone).await.ok();
+        Ok(())
+    });
+    let host_arch = manifest.image.host_arch()?;
+    match &manifest.image {
+        ImageConfig::Tar { .. } => {
+            let name = ImageConfig::tar_name(host_arch);
+            log::info!("Loading docker image start9/{} from {}.", manifest.id, name);
+            crate::progress::step(Phase::Install, "loading docker image").await;
+            // the tags are checked as the image goes by, so that it is only read once
+            runtime
+                .load(&mut crate::pack::CheckedImage::new(
+                    pkg.entry(&name).await?,
+                    &manifest.id,
+                ))
+                .await?;
+        }
+        ImageConfig::Oci { .. } => {
+            crate::progress::step(Phase::Install, "storing image layers").await;
+            let index = crate::oci::install(pkg, host_arch, tx).await?;
+            log::info!("Saving image index.");
+            let mut index_out = app_dir.join("image-index.yaml").write(None).await?;
+            to_yaml_async_writer(&mut *index_out, &index).await?;
+            index_out.commit().await?;
+            log::info!("Loading docker image {} from OCI layout.", tag);
+            crate::progress::step(Phase::Install, "loading docker image").await;
+            crate::oci::load(&index, &tag).await?;
+        }
+    }
     log::info!("Creating docker container: {} from {}.", manifest.id, tag);
-    let volume_arg = format!(
-        "type=bind,src={}/{},dst={}",
-        crate::VOLUMES,
-        manifest.id,
-        manifest.mount.display()
-    );
-    let mut args = vec![
-        Cow::Borrowed(OsStr::new("create")),
-        Cow::Borrowed(OsStr::new("--restart")),
-        Cow::Borrowed(OsStr::new("no")),
-        Cow::Borrowed(OsStr::new("--name")),
-        Cow::Borrowed(OsStr::new(&manifest.id)),
-        Cow::Borrowed(OsStr::new("--mount")),
-        Cow::Borrowed(OsStr::new(&volume_arg)),
-        Cow::Borrowed(OsStr::new("--net")),
-        Cow::Borrowed(OsStr::new("start9")),
-        Cow::Borrowed(OsStr::new("--ip")),
-        Cow::Owned(OsString::from(format!("{}", ip))),
-    ];
-    if let (Some(ref tor_addr), Some(ref tor_key)) = (&tor_addr, &tor_key) {
-        args.extend(
-            std::iter::empty()
-                .chain(std::iter::once(Cow::Borrowed(OsStr::new("--env"))))
-                .chain(std::iter::once(Cow::Owned(OsString::from(format!(
-                    "TOR_ADDRESS={}",
-                    tor_addr
-                )))))
-                .chain(std::iter::once(Cow::Borrowed(OsStr::new("--env"))))
-                .chain(std::iter::once(Cow::Owned(OsString::from(format!(
-                    "TOR_KEY={}",
-                    tor_key
-                ))))),
-        );
+    crate::progress::step(Phase::Install, "creating docker container").await;
+    for (name, volume) in &manifest.volumes.named {
+        if volume.kind != VolumeKind::Tmpfs {
+            tx.create_dir_all(crate::context::get().named_volumes(&manifest.id).join(name))
+                .await?;
+        }
     }
-    if let Some(shm_size_mb) = manifest.shm_size_mb {
-        args.push(Cow::Borrowed(OsStr::new("--shm-size")));
-        args.push(Cow::Owned(OsString::from(format!("{}m", shm_size_mb))));
+    create_container(manifest).await?;
+    tx.create_dir_all(volume.join("start9")).await?;
+    if let Some(public) = &manifest.volumes.public {
+        tx.create_dir_all(volume.join(public)).await?;
     }
-    args.push(Cow::Borrowed(OsStr::new(&tag)));
-    crate::ensure_code!(
-        std::process::Command::new("docker")
-            .args(&args)
-            .stdout(std::process::Stdio::null())
-            .stderr(match log::max_level() {
-                log::LevelFilter::Error => std::process::Stdio::null(),
-                _ => std::process::Stdio::inherit(),
-            })
-            .status()?
-            .success(),
-        crate::error::DOCKER_ERROR,
-        "Failed to Create Docker Container"
-    );
-    tokio::fs::create_dir_all(Path::new(crate::VOLUMES).join(&manifest.id).join("start9")).await?;
-    if let Some(public) = manifest.public {
-        tokio::fs::create_dir_all(Path::new(crate::VOLUMES).join(&manifest.id).join(public))
-            .await?;
+    if let Some(shared) = &manifest.volumes.shared {
+        tx.create_dir_all(volume.join(shared)).await?;
     }
-    if let Some(shared) = manifest.shared {
-        tokio::fs::create_dir_all(Path::new(crate::VOLUMES).join(&manifest.id).join(shared))
-            .await?;
+    if let Some((uid, gid)) = manifest.security.ids() {
+        log::info!("Giving volumes to uid {}.", uid);
+        let mut paths = vec![volume.clone()];
+        for (name, named) in &manifest.volumes.named {
+            if named.kind != VolumeKind::Tmpfs {
+                paths.push(crate::context::get().named_volumes(&manifest.id).join(name));
+            }
+        }
+        for path in paths {
+            nix::unistd::chown(
+                &path,
+                Some(nix::unistd::Uid::from_raw(uid)),
+                gid.map(nix::unistd::Gid::from_raw),
+            )
+            .with_context(|e| format!("chown {}: {}", path.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        }
     }
     log::info!("Updating app list.");
+    let config = crate::apps::config(&manifest.id).await?;
     crate::apps::add(
         &manifest.id,
         crate::apps::AppInfo {
             title: manifest.title.clone(),
             version: manifest.version.clone(),
             tor_address: tor_addr.clone(),
-            configured: false,
+            configured: config
+                .config
+                .as_ref()
+                .map(|cfg| config.spec.matches(cfg).is_ok())
+                .unwrap_or(false),
             recoverable,
             needs_restart: false,
         },
     )
     .await?;
-    let config = crate::apps::config(&manifest.id).await?;
-    if let Some(cfg) = config.config {
-        if config.spec.matches(&cfg).is_ok() {
-            crate::apps::set_configured(&manifest.id, true).await?;
-        }
-    } else {
+    if config.config.is_none() {
         let empty_config = crate::config::Config::default();
         if config.spec.matches(&empty_config).is_ok() {
             crate::config::configure(&manifest.id, Some(empty_config), None, false).await?;
         }
     }
-    crate::dependencies::update_binds(&manifest.id).await?;
-    for (dep_id, dep_info) in manifest.dependencies.0 {
-        if dep_info.mount_shared
-            && crate::apps::list_info().await?.get(&dep_id).is_some()
-            && crate::apps::manifest(&dep_id).await?.shared.is_some()
-        {
-            match crate::apps::status(&dep_id, false).await?.status {
-                crate::apps::DockerStatus::Stopped => (),
-                crate::apps::DockerStatus::Running => crate::control::restart_app(&dep_id).await?,
-                _ => crate::apps::set_needs_restart(&dep_id, true).await?,
+    log::info!("Updating firewall.");
+    crate::progress::step(Phase::Install, "updating firewall").await;
+    crate::firewall::reload().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use linear_map::LinearMap;
+
+    use super::*;
+    use crate::compression::Compression;
+    use crate::dependencies::DependencyError;
+    use crate::runtime::{FakeRuntime, Runtime};
+
+    const BITCOIND: &str = "
+compat: v1
+id: bitcoind
+version: 0.21.0
+title: Bitcoin Core
+description: {short: a node, long: a bitcoin node}
+release-notes: none
+image: {type: tar}
+interfaces: {rpc: {internal: 8332, tor: 8332}}
+volumes: {mount: /root/.bitcoin}
+env: {RPC_USER: \"'rpcuser\"}
+";
+
+    const LND: &str = "
+compat: v1
+id: lnd
+version: 0.11.0
+title: LND
+description: {short: a node, long: a lightning node}
+release-notes: none
+image: {type: tar}
+interfaces: {grpc: {internal: 10009, tor: 10009}}
+volumes: {mount: /root/.lnd}
+dependencies:
+  bitcoind:
+    version: '>=0.21.0'
+    optional: ~
+    description: ~
+    config:
+      - rule: \"'rpcuser = \\\"bitcoin\\\"\"
+        description: RPC user must be bitcoin
+        suggestions: []
+";
+
+    fn bitcoind_spec() -> serde_json::Value {
+        serde_json::json!({
+            "rpcuser": {
+                "name": "RPC Username",
+                "type": "string",
+                "description": "rpc username",
+                "nullable": false,
+                "default": "bitcoin"
             }
-        }
+        })
     }
 
-    Ok(())
+    async fn package(manifest: &str, spec: serde_json::Value, image_tag: &str) -> Vec<u8> {
+        let manifest: Manifest = serde_yaml::from_str(manifest).unwrap();
+        let spec: ConfigSpec = serde_json::from_value(spec).unwrap();
+        crate::test_util::package(
+            &format!("install-{}", manifest.clone().into_latest().id),
+            &[
+                (
+                    crate::s9pk::MANIFEST,
+                    &serde_cbor::to_vec(&manifest).unwrap(),
+                    Compression::None,
+                ),
+                (
+                    crate::s9pk::CONFIG_SPEC,
+                    &serde_cbor::to_vec(&spec).unwrap(),
+                    Compression::None,
+                ),
+                (
+                    crate::s9pk::CONFIG_RULES,
+                    &serde_cbor::to_vec(&Vec::<ConfigRuleEntry>::new()).unwrap(),
+                    Compression::None,
+                ),
+                (
+                    "image.tar",
+                    &crate::test_util::image_archive(image_tag).await,
+                    Compression::None,
+                ),
+            ],
+        )
+        .await
+    }
+
+    async fn install_package(bytes: Vec<u8>) -> Result<(), crate::Error> {
+        let mut pkg = crate::s9pk::Reader::new(Cursor::new(bytes)).await?;
+        install(&mut pkg, None).await
+    }
+
+    /// Installs `bytes` as a stream that ends in `section`, read in order as a download would be.
+    async fn stream_package(
+        mut bytes: Vec<u8>,
+        section: [u8; SIGNATURE_SECTION_LEN as usize],
+        fetched: [u8; SIGNATURE_SECTION_LEN as usize],
+    ) -> Result<(), crate::Error> {
+        bytes.extend_from_slice(&section);
+        let len = bytes.len() as u64;
+        let stream = crate::s9pk::Stream::new(Cursor::new(bytes), len, fetched);
+        let end = stream.end();
+        let mut pkg = crate::s9pk::Reader::from_stream(stream, end).await?;
+        crate::integrity::check_layout(&pkg)?;
+        install(&mut pkg, None).await
+    }
+
+    async fn setup(
+        name: &str,
+    ) -> (
+        tokio::sync::MutexGuard<'static, ()>,
+        PathBuf,
+        Arc<FakeRuntime>,
+    ) {
+        let (guard, root) = crate::test_util::sandbox(name).await;
+        crate::test_util::fake_system(&root);
+        let runtime = Arc::new(FakeRuntime::default());
+        crate::runtime::set(runtime.clone());
+        (guard, root, runtime)
+    }
+
+    #[tokio::test]
+    async fn test_install() {
+        let (_guard, root, runtime) = setup("install-flow").await;
+        install_package(package(BITCOIND, bitcoind_spec(), "start9/bitcoind:latest").await)
+            .await
+            .unwrap();
+        let info = crate::apps::info("bitcoind").await.unwrap();
+        assert_eq!(info.tor_address.as_deref(), Some("app-bitcoind.onion"));
+        // its config has no defaults an empty one would satisfy
+        assert!(!info.configured);
+        let container = runtime.containers().remove("bitcoind").unwrap();
+        assert_eq!(container.state.status, ContainerStatus::Created);
+        assert_eq!(
+            container.options.env.get("TOR_ADDRESS").map(|a| a.as_str()),
+            Some("app-bitcoind.onion")
+        );
+        assert!(crate::tor::services_map()
+            .await
+            .unwrap()
+            .map
+            .contains_key("bitcoind"));
+        assert!(
+            PersistencePath::from_ref(crate::tor::SERVICES_YAML)
+                .exists()
+                .await
+        );
+        assert!(
+            PersistencePath::from_ref(crate::firewall::RULES)
+                .exists()
+                .await
+        );
+
+        // tagged as another app, so it fails to load once its hidden service is staged
+        let res =
+            install_package(package(LND, serde_json::json!({}), "start9/bitcoind:latest").await)
+                .await;
+        assert!(res.is_err());
+        assert!(!crate::apps::list_info().await.unwrap().contains_key("lnd"));
+        assert!(!crate::tor::services_map()
+            .await
+            .unwrap()
+            .map
+            .contains_key("lnd"));
+        assert!(
+            crate::db::get::<LinearMap<String, String>>(&crate::db::env_key("lnd"))
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(!runtime.containers().contains_key("lnd"));
+        assert!(!PersistencePath::from_ref("apps").join("lnd").exists().await);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_install_stream() {
+        let (_guard, root, runtime) = setup("install-stream").await;
+        let section = [7; SIGNATURE_SECTION_LEN as usize];
+        // the section has changed since it was fetched, so nothing is committed
+        let res = stream_package(
+            package(BITCOIND, bitcoind_spec(), "start9/bitcoind:latest").await,
+            section,
+            [8; SIGNATURE_SECTION_LEN as usize],
+        )
+        .await;
+        assert_eq!(res.unwrap_err().code, Some(crate::error::SIGNATURE_ERROR));
+        assert!(!crate::apps::list_info()
+            .await
+            .unwrap()
+            .contains_key("bitcoind"));
+        assert!(!runtime.containers().contains_key("bitcoind"));
+
+        stream_package(
+            package(BITCOIND, bitcoind_spec(), "start9/bitcoind:latest").await,
+            section,
+            section,
+        )
+        .await
+        .unwrap();
+        assert!(crate::apps::list_info()
+            .await
+            .unwrap()
+            .contains_key("bitcoind"));
+        assert!(runtime.containers().contains_key("bitcoind"));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_configure() {
+        let (_guard, root, runtime) = setup("configure-flow").await;
+        install_package(package(BITCOIND, bitcoind_spec(), "start9/bitcoind:latest").await)
+            .await
+            .unwrap();
+        let res = crate::config::configure("bitcoind", None, None, false)
+            .await
+            .unwrap();
+        assert!(res.changed.contains_key("bitcoind"));
+        assert!(crate::apps::info("bitcoind").await.unwrap().configured);
+        assert_eq!(
+            crate::apps::config("bitcoind")
+                .await
+                .unwrap()
+                .config
+                .unwrap()
+                .0
+                .get("rpcuser"),
+            Some(&crate::config::value::Value::String("bitcoin".to_owned()))
+        );
+        assert!(crate::context::get()
+            .volumes
+            .join("bitcoind")
+            .join("start9")
+            .join("config.yaml")
+            .exists());
+
+        // created before it had a config, so it is recreated with the new environment
+        crate::control::start_app("bitcoind", true).await.unwrap();
+        let container = runtime.containers().remove("bitcoind").unwrap();
+        assert_eq!(container.state.status, ContainerStatus::Running);
+        assert_eq!(
+            container.options.env.get("RPC_USER").map(|a| a.as_str()),
+            Some("bitcoin")
+        );
+
+        let mut config = crate::apps::config("bitcoind")
+            .await
+            .unwrap()
+            .config
+            .unwrap();
+        config.0.insert(
+            "rpcuser".to_owned(),
+            crate::config::value::Value::String("satoshi".to_owned()),
+        );
+        let res = crate::config::configure("bitcoind", Some(config), None, false)
+            .await
+            .unwrap();
+        assert!(res.needs_restart.contains("bitcoind"));
+        assert!(crate::apps::info("bitcoind").await.unwrap().needs_restart);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dependencies() {
+        let (_guard, root, runtime) = setup("dependencies-flow").await;
+        install_package(package(BITCOIND, bitcoind_spec(), "start9/bitcoind:latest").await)
+            .await
+            .unwrap();
+        crate::config::configure("bitcoind", None, None, false)
+            .await
+            .unwrap();
+        install_package(package(LND, serde_json::json!({}), "start9/lnd:latest").await)
+            .await
+            .unwrap();
+        assert!(crate::apps::info("lnd").await.unwrap().configured);
+        assert_eq!(
+            crate::apps::dependents("bitcoind", false).await.unwrap(),
+            vec!["lnd".to_owned()].into_iter().collect()
+        );
+        let firewall = crate::firewall::current().await.unwrap();
+        assert!(firewall.contains("tcp dport { 8332 } accept comment \"lnd -> bitcoind\""));
+
+        let deps = crate::apps::dependencies("lnd", true).await.unwrap();
+        assert!(matches!(
+            deps.0["bitcoind"].error,
+            Some(DependencyError::NotRunning)
+        ));
+        crate::control::start_app("bitcoind", true).await.unwrap();
+        let deps = crate::apps::dependencies("lnd", true).await.unwrap();
+        assert!(deps.0["bitcoind"].error.is_none());
+        crate::control::start_app("lnd", true).await.unwrap();
+
+        // breaking the rule lnd sets on bitcoind's config stops lnd
+        let mut config = crate::apps::config("bitcoind")
+            .await
+            .unwrap()
+            .config
+            .unwrap();
+        config.0.insert(
+            "rpcuser".to_owned(),
+            crate::config::value::Value::String("satoshi".to_owned()),
+        );
+        let res = crate::config::configure("bitcoind", Some(config), None, false)
+            .await
+            .unwrap();
+        assert!(matches!(
+            res.stopped["lnd"].error,
+            DependencyError::ConfigUnsatisfied(_)
+        ));
+        assert_eq!(
+            runtime.inspect("lnd").await.unwrap().status,
+            ContainerStatus::Exited
+        );
+        let running: linear_map::set::LinearSet<String> =
+            crate::db::get_or_default(crate::db::RUNNING).await.unwrap();
+        assert!(running.contains("bitcoind") && !running.contains("lnd"));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
 }
@@ -0,0 +1,70 @@
+use std::path::Path;
+
+use failure::ResultExt as _;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek};
+
+use crate::s9pk::Reader;
+use crate::Error;
+use crate::ResultExt as _;
+
+/// The hex encoded SHA-256 of the file at `path`.
+pub async fn hash_file<P: AsRef<Path>>(path: P) -> Result<String, Error> {
+    let path = path.as_ref();
+    let mut f = tokio::fs::File::open(path)
+        .await
+        .with_context(|e| format!("{}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * crate::BUFFER_SIZE];
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Checks that the table of contents accounts for the whole payload: its entries have relative
+/// names, and are laid out back to back in table of contents order, with nothing after them.
+/// Nothing is read, so this can be done before the entries of a stream arrive.
+pub fn check_layout<R: AsyncRead + AsyncSeek + Unpin + Send>(pkg: &Reader<R>) -> Result<(), Error> {
+    let mut pos = 0;
+    for (name, entry) in &pkg.toc.0 {
+        crate::ensure_code!(
+            crate::pack::validate_path(name).is_ok(),
+            crate::error::GENERAL_ERROR,
+            "Package File Invalid or Corrupted: invalid entry name {}",
+            name
+        );
+        crate::ensure_code!(
+            entry.offset == pos,
+            crate::error::GENERAL_ERROR,
+            "Package File Invalid or Corrupted: unexpected offset for {}",
+            name
+        );
+        pos += entry.length;
+    }
+    crate::ensure_code!(
+        pos == pkg.payload_len(),
+        crate::error::GENERAL_ERROR,
+        "Package File Invalid or Corrupted: unlisted data after {} bytes",
+        pos
+    );
+    Ok(())
+}
+
+/// Hashes every entry of the package and compares the result against its table of contents.
+/// Fails if any entry does not match, or if the payload holds anything but the listed entries.
+pub async fn check<R: AsyncRead + AsyncSeek + Unpin + Send>(
+    pkg: &mut Reader<R>,
+) -> Result<(), Error> {
+    check_layout(pkg)?;
+    for name in pkg.toc.0.keys().cloned().collect::<Vec<_>>() {
+        log::trace!("Hashing {}.", name);
+        tokio::io::copy(&mut pkg.raw(&name).await?, &mut tokio::io::sink()).await?;
+    }
+    Ok(())
+}
@@ -37,8 +37,8 @@ pub async fn enable_lan() -> Result<(), Error> {
         for (app_id, app_info) in app_list {
             let man = crate::apps::manifest(&app_id).await?;
             if man
-                .ports
-                .iter()
+                .interfaces
+                .values()
                 .filter(|p| p.lan.is_some())
                 .next()
                 .is_none()
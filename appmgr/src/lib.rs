@@ -3,12 +3,11 @@ extern crate failure;
 #[macro_use]
 extern crate pest_derive;
 
-pub const TOR_RC: &'static str = "/root/appmgr/tor/torrc";
-pub const SERVICES_YAML: &'static str = "tor/services.yaml";
-pub const VOLUMES: &'static str = "/root/volumes";
-pub const PERSISTENCE_DIR: &'static str = "/root/appmgr";
-pub const TMP_DIR: &'static str = "/root/tmp/appmgr";
-pub const BACKUP_MOUNT_POINT: &'static str = "/mnt/backup_drive";
+pub const TRUSTED_KEYS_YAML: &'static str = "trusted-keys.yaml";
+/// the name the registry's signing key is trusted under
+pub const REGISTRY_KEY_NAME: &'static str = "start9-registry";
+/// the registry's signing key, which must be given as `REGISTRY_KEY` when building
+pub const REGISTRY_KEY: &'static str = env!("REGISTRY_KEY");
 pub const BACKUP_DIR: &'static str = "Embassy Backups";
 pub const BUFFER_SIZE: usize = 1024;
 pub const HOST_IP: [u8; 4] = [172, 18, 0, 1];
@@ -23,21 +22,39 @@ lazy_static::lazy_static! {
 pub mod actions;
 pub mod apps;
 pub mod backup;
+pub mod compression;
 pub mod config;
+pub mod context;
 pub mod control;
+pub mod db;
+pub mod delta;
 pub mod dependencies;
 pub mod disks;
+pub mod doctor;
+pub mod env;
 pub mod error;
+pub mod firewall;
+pub mod health;
 pub mod index;
 pub mod inspect;
 pub mod install;
+pub mod integrity;
 #[cfg(feature = "avahi")]
 pub mod lan;
 pub mod logs;
 pub mod manifest;
+pub mod oci;
 pub mod pack;
+pub mod progress;
 pub mod registry;
 pub mod remove;
+pub mod resources;
+pub mod runtime;
+pub mod s9pk;
+pub mod signing;
+pub mod supervisor;
+#[cfg(test)]
+pub mod test_util;
 pub mod tor;
 pub mod update;
 pub mod util;
@@ -48,7 +65,7 @@ pub use control::{restart_app, start_app, stop_app, stop_dependents};
 pub use error::{Error, ResultExt};
 pub use install::{install_name, install_path, install_url};
 pub use logs::{logs, notifications, stats, LogOptions};
-pub use pack::{pack, verify};
+pub use pack::{pack, unpack, verify};
 pub use remove::remove;
 pub use update::update;
 pub use version::{init, self_update};
@@ -1,7 +1,3 @@
-use std::borrow::Cow;
-use std::ffi::{OsStr, OsString};
-use std::path::Path;
-
 use failure::ResultExt as _;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
@@ -107,38 +103,19 @@ pub async fn logs<A: AsRef<str>, B: AsRef<str>>(
     name: &str,
     options: LogOptions<A, B>,
 ) -> Result<(), Error> {
-    let mut args = vec![Cow::Borrowed(OsStr::new("logs"))];
-    if options.details {
-        args.push(Cow::Borrowed(OsStr::new("--details")));
-    }
-    if options.follow {
-        args.push(Cow::Borrowed(OsStr::new("-f")));
-    }
-    if let Some(since) = options.since.as_ref() {
-        args.push(Cow::Borrowed(OsStr::new("--since")));
-        args.push(Cow::Borrowed(OsStr::new(since.as_ref())));
-    }
-    if let Some(until) = options.until.as_ref() {
-        args.push(Cow::Borrowed(OsStr::new("--until")));
-        args.push(Cow::Borrowed(OsStr::new(until.as_ref())));
-    }
-    if let Some(tail) = options.tail {
-        args.push(Cow::Borrowed(OsStr::new("--tail")));
-        args.push(Cow::Owned(OsString::from(format!("{}", tail))));
-    }
-    if options.timestamps {
-        args.push(Cow::Borrowed(OsStr::new("-t")));
-    }
-    args.push(Cow::Borrowed(OsStr::new(name)));
-    crate::ensure_code!(
-        std::process::Command::new("docker")
-            .args(args.into_iter())
-            .status()?
-            .success(),
-        crate::error::DOCKER_ERROR,
-        "Failed to Collect Logs from Docker"
-    );
-    Ok(())
+    crate::runtime::get()
+        .logs(
+            name,
+            &LogOptions {
+                details: options.details,
+                follow: options.follow,
+                since: options.since.as_ref().map(|a| a.as_ref()),
+                until: options.until.as_ref().map(|a| a.as_ref()),
+                tail: options.tail,
+                timestamps: options.timestamps,
+            },
+        )
+        .await
 }
 
 pub async fn notifications(id: &str) -> Result<Vec<Notification>, Error> {
@@ -149,7 +126,8 @@ pub async fn notifications(id: &str) -> Result<Vec<Notification>, Error> {
         }
     }
     match tokio::fs::rename(
-        Path::new(crate::VOLUMES)
+        crate::context::get()
+            .volumes
             .join(id)
             .join("start9")
             .join("notifications.log"),
@@ -178,7 +156,8 @@ pub async fn stats(id: &str) -> Result<serde_yaml::Value, Error> {
         }
     }
     match tokio::fs::copy(
-        Path::new(crate::VOLUMES)
+        crate::context::get()
+            .volumes
             .join(id)
             .join("start9")
             .join("stats.yaml"),
@@ -22,11 +22,13 @@ async fn main() {
 
 async fn inner_main() -> Result<(), Error> {
     simple_logging::log_to_stderr(log::LevelFilter::Info);
+    context::set(context::Context::load()?);
     #[cfg(not(feature = "portable"))]
     {
-        if !Path::new(crate::PERSISTENCE_DIR).join(".lock").exists() {
-            tokio::fs::create_dir_all(crate::PERSISTENCE_DIR).await?;
-            tokio::fs::File::create(Path::new(crate::PERSISTENCE_DIR).join(".lock")).await?;
+        let persistence_dir = &context::get().persistence_dir;
+        if !persistence_dir.join(".lock").exists() {
+            tokio::fs::create_dir_all(persistence_dir).await?;
+            tokio::fs::File::create(persistence_dir.join(".lock")).await?;
         }
     }
     let q = *QUIET.read().await;
@@ -50,6 +52,13 @@ async fn inner_main() -> Result<(), Error> {
                 .help("Sets verbosity level")
                 .multiple(true),
         )
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .help("Sets how progress of long operations is reported"),
+        )
         .subcommand(SubCommand::with_name("semver").about("Prints semantic version and exits"))
         .subcommand(SubCommand::with_name("git-info").about("Prints git version info and exits"))
         .subcommand(
@@ -62,12 +71,118 @@ async fn inner_main() -> Result<(), Error> {
                         .takes_value(true)
                         .default_value("app.s9pk"),
                 )
+                .arg(
+                    Arg::with_name("key")
+                        .short("k")
+                        .long("key")
+                        .takes_value(true)
+                        .default_value("developer.key")
+                        .help("Path to the developer key to sign the package with"),
+                )
+                .arg(
+                    Arg::with_name("compression")
+                        .short("z")
+                        .long("compression")
+                        .takes_value(true)
+                        .possible_values(&["none", "gzip", "zstd"])
+                        .default_value("none")
+                        .help("Compression to apply to the package payload"),
+                )
+                .arg(
+                    Arg::with_name("deterministic")
+                        .short("d")
+                        .long("deterministic")
+                        .help(
+                            "Normalize file modes so identical sources produce identical packages",
+                        ),
+                )
                 .arg(
                     Arg::with_name("PATH")
                         .help("Path to the folder containing the application data")
                         .required(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("unpack")
+                .about("Extracts an application package back into its source folder")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .default_value("app"),
+                )
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("Path to the s9pk file to unpack")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delta")
+                .about("Creates a delta that updates one version of a package to another")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .default_value("app.s9pk.delta"),
+                )
+                .arg(
+                    Arg::with_name("BASE")
+                        .help("Path to the s9pk to update from")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("TARGET")
+                        .help("Path to the s9pk to update to")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("Generates a new developer key for signing packages")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .default_value("developer.key"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trust")
+                .about("Manages the keys trusted to sign application packages")
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Trusts packages signed by a key")
+                        .arg(
+                            Arg::with_name("NAME")
+                                .help("Name to trust the key under")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("PUBKEY")
+                                .help("Public key to trust")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .alias("rm")
+                        .about("Stops trusting a key")
+                        .arg(
+                            Arg::with_name("NAME")
+                                .help("Name of the key to stop trusting")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .alias("ls")
+                        .about("Lists trusted keys"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("verify")
                 .about("Verifies an application package")
@@ -459,6 +574,18 @@ async fn inner_main() -> Result<(), Error> {
                 )
                 .subcommand(SubCommand::with_name("reload").about("Reloads the tor configuration")),
         )
+        .subcommand(
+            SubCommand::with_name("firewall")
+                .about("Manages the firewall between apps")
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Prints the nftables rules generated for the installed apps"),
+                )
+                .subcommand(
+                    SubCommand::with_name("reload")
+                        .about("Regenerates and loads the firewall rules"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("info")
                 .about("Prints information about an installed app")
@@ -743,6 +870,71 @@ async fn inner_main() -> Result<(), Error> {
                         .help("Output as yaml"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("resources")
+                .about("Show or change the resource limits of an app")
+                .arg(
+                    Arg::with_name("ID")
+                        .help("ID of the application to limit")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("memory-limit")
+                        .long("memory-limit")
+                        .takes_value(true)
+                        .help("Most memory the app may use, in MB"),
+                )
+                .arg(
+                    Arg::with_name("memory-reservation")
+                        .long("memory-reservation")
+                        .takes_value(true)
+                        .help("Memory the app is kept from being squeezed below when memory runs low, in MB"),
+                )
+                .arg(
+                    Arg::with_name("cpu-shares")
+                        .long("cpu-shares")
+                        .takes_value(true)
+                        .help("Weight of the app against others when the CPU is contended"),
+                )
+                .arg(
+                    Arg::with_name("cpus")
+                        .long("cpus")
+                        .takes_value(true)
+                        .help("Most CPUs the app may use, e.g. 1.5"),
+                )
+                .arg(
+                    Arg::with_name("pids-limit")
+                        .long("pids-limit")
+                        .takes_value(true)
+                        .help("Most processes and threads the app may run"),
+                )
+                .arg(
+                    Arg::with_name("reset")
+                        .long("reset")
+                        .help("Returns limits not given to those of the app's manifest"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .conflicts_with("yaml")
+                        .long("json")
+                        .short("j")
+                        .help("Output as json"),
+                )
+                .arg(
+                    Arg::with_name("pretty")
+                        .requires("json")
+                        .long("pretty")
+                        .short("p")
+                        .help("Pretty print output"),
+                )
+                .arg(
+                    Arg::with_name("yaml")
+                        .conflicts_with("json")
+                        .long("yaml")
+                        .short("y")
+                        .help("Output as yaml"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("disks")
                 .about("Manage external disks")
@@ -829,7 +1021,38 @@ async fn inner_main() -> Result<(), Error> {
                 ),
         )
         .subcommand(
-            SubCommand::with_name("repair-app-status").about("Restarts crashed apps"), // TODO: remove
+            SubCommand::with_name("doctor")
+                .about("Checks the app database against docker, tor, nginx and the filesystem")
+                .arg(
+                    Arg::with_name("fix")
+                        .long("fix")
+                        .help("Repairs the problems that can be repaired without losing data"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .conflicts_with("yaml")
+                        .long("json")
+                        .short("j")
+                        .help("Output as json"),
+                )
+                .arg(
+                    Arg::with_name("pretty")
+                        .requires("json")
+                        .long("pretty")
+                        .short("p")
+                        .help("Pretty print output"),
+                )
+                .arg(
+                    Arg::with_name("yaml")
+                        .conflicts_with("json")
+                        .long("yaml")
+                        .short("y")
+                        .help("Output as yaml"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("supervise")
+                .about("Restarts apps that stop on their own, according to their restart policies"),
         )
         .subcommand(
             SubCommand::with_name("actions")
@@ -851,6 +1074,9 @@ async fn inner_main() -> Result<(), Error> {
         3 => log::LevelFilter::Debug,
         _ => log::LevelFilter::Trace,
     });
+    if let Some(progress) = matches.value_of("progress") {
+        crate::progress::set_output(progress.parse()?);
+    }
 
     match matches.subcommand() {
         ("semver", _) => {
@@ -1198,6 +1424,19 @@ async fn inner_main() -> Result<(), Error> {
                 std::process::exit(1);
             }
         },
+        #[cfg(not(feature = "portable"))]
+        ("firewall", Some(sub_m)) => match sub_m.subcommand() {
+            ("show", Some(_)) => {
+                print!("{}", crate::firewall::current().await?);
+            }
+            ("reload", Some(_)) => {
+                crate::firewall::reload().await?;
+            }
+            _ => {
+                println!("{}", sub_m.usage());
+                std::process::exit(1);
+            }
+        },
         #[cfg(feature = "avahi")]
         #[cfg(not(feature = "portable"))]
         ("lan", Some(sub_m)) => match sub_m.subcommand() {
@@ -1513,6 +1752,110 @@ async fn inner_main() -> Result<(), Error> {
             }
         }
         #[cfg(not(feature = "portable"))]
+        ("resources", Some(sub_m)) => {
+            let id = sub_m.value_of("ID").unwrap();
+            let overrides = appmgrlib::manifest::Resources {
+                shm_size_mb: None,
+                memory_limit_mb: sub_m
+                    .value_of("memory-limit")
+                    .map(|a| a.parse())
+                    .transpose()
+                    .no_code()?,
+                memory_reservation_mb: sub_m
+                    .value_of("memory-reservation")
+                    .map(|a| a.parse())
+                    .transpose()
+                    .no_code()?,
+                cpu_shares: sub_m
+                    .value_of("cpu-shares")
+                    .map(|a| a.parse())
+                    .transpose()
+                    .no_code()?,
+                cpus: sub_m
+                    .value_of("cpus")
+                    .map(|a| a.parse())
+                    .transpose()
+                    .no_code()?,
+                pids_limit: sub_m
+                    .value_of("pids-limit")
+                    .map(|a| a.parse())
+                    .transpose()
+                    .no_code()?,
+            };
+            let reset = sub_m.is_present("reset");
+            let res = if reset || overrides != Default::default() {
+                resources::set(id, &overrides, reset).await?
+            } else {
+                resources::get(id).await?
+            };
+            if sub_m.is_present("json") {
+                if sub_m.is_present("pretty") {
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&res).with_code(crate::error::SERDE_ERROR)?
+                    );
+                } else {
+                    println!(
+                        "{}",
+                        serde_json::to_string(&res).with_code(crate::error::SERDE_ERROR)?
+                    );
+                }
+            } else if sub_m.is_present("yaml") {
+                println!(
+                    "{}",
+                    serde_yaml::to_string(&res).with_code(crate::error::SERDE_ERROR)?
+                );
+            } else {
+                use prettytable::{Cell, Row, Table};
+                let mut table = Table::new();
+                table.add_row(Row::new(vec![
+                    Cell::new("LIMIT"),
+                    Cell::new("VALUE"),
+                    Cell::new("SET BY"),
+                ]));
+                let limits = vec![
+                    (
+                        "shm-size-mb",
+                        res.limits.shm_size_mb.map(|a| format!("{}", a)),
+                        false,
+                    ),
+                    (
+                        "memory-limit-mb",
+                        res.limits.memory_limit_mb.map(|a| format!("{}", a)),
+                        res.overrides.memory_limit_mb.is_some(),
+                    ),
+                    (
+                        "memory-reservation-mb",
+                        res.limits.memory_reservation_mb.map(|a| format!("{}", a)),
+                        res.overrides.memory_reservation_mb.is_some(),
+                    ),
+                    (
+                        "cpu-shares",
+                        res.limits.cpu_shares.map(|a| format!("{}", a)),
+                        res.overrides.cpu_shares.is_some(),
+                    ),
+                    (
+                        "cpus",
+                        res.limits.cpus.map(|a| format!("{}", a)),
+                        res.overrides.cpus.is_some(),
+                    ),
+                    (
+                        "pids-limit",
+                        res.limits.pids_limit.map(|a| format!("{}", a)),
+                        res.overrides.pids_limit.is_some(),
+                    ),
+                ];
+                for (name, value, overridden) in limits {
+                    table.add_row(Row::new(vec![
+                        Cell::new(name),
+                        Cell::new(value.as_ref().map(|a| a.as_str()).unwrap_or("unlimited")),
+                        Cell::new(if overridden { "user" } else { "manifest" }),
+                    ]));
+                }
+                table.print(&mut std::io::stdout())?;
+            }
+        }
+        #[cfg(not(feature = "portable"))]
         ("disks", Some(sub_m)) => match sub_m.subcommand() {
             ("show", Some(sub_sub_m)) | ("list", Some(sub_sub_m)) | ("ls", Some(sub_sub_m)) => {
                 let info = disks::list().await?;
@@ -1573,8 +1916,53 @@ async fn inner_main() -> Result<(), Error> {
             }
         },
         #[cfg(not(feature = "portable"))]
-        ("repair-app-status", _) => {
-            control::repair_app_status().await?;
+        ("doctor", Some(sub_m)) => {
+            let fix = sub_m.is_present("fix");
+            let res = doctor::doctor(fix).await?;
+            if sub_m.is_present("json") {
+                if sub_m.is_present("pretty") {
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&res).with_code(crate::error::SERDE_ERROR)?
+                    );
+                } else {
+                    println!(
+                        "{}",
+                        serde_json::to_string(&res).with_code(crate::error::SERDE_ERROR)?
+                    );
+                }
+            } else if sub_m.is_present("yaml") {
+                println!(
+                    "{}",
+                    serde_yaml::to_string(&res).with_code(crate::error::SERDE_ERROR)?
+                );
+            } else if !res.is_empty() {
+                use prettytable::{Cell, Row, Table};
+                let mut table = Table::new();
+                let mut heading = vec![Cell::new("PROBLEM"), Cell::new("FIXABLE")];
+                if fix {
+                    heading.push(Cell::new("FIXED"));
+                }
+                table.add_row(Row::new(heading));
+                for finding in res {
+                    let mut row = vec![
+                        Cell::new(&format!("{}", finding.problem)),
+                        Cell::new(&format!("{}", finding.fixable)),
+                    ];
+                    if fix {
+                        row.push(Cell::new(&match finding.error {
+                            Some(e) => e,
+                            None => format!("{}", finding.fixed),
+                        }));
+                    }
+                    table.add_row(Row::new(row));
+                }
+                table.print(&mut std::io::stdout())?;
+            }
+        }
+        #[cfg(not(feature = "portable"))]
+        ("supervise", _) => {
+            supervisor::run().await?;
         }
         #[cfg(not(feature = "portable"))]
         ("actions", Some(sub_m)) => {
@@ -1608,9 +1996,52 @@ async fn inner_main() -> Result<(), Error> {
             pack(
                 sub_m.value_of("PATH").unwrap(),
                 sub_m.value_of("output").unwrap(),
+                sub_m.value_of("key").unwrap(),
+                sub_m.value_of("compression").unwrap().parse()?,
+                sub_m.is_present("deterministic"),
             )
             .await?
         }
+        ("unpack", Some(sub_m)) => {
+            unpack(
+                sub_m.value_of("PATH").unwrap(),
+                sub_m.value_of("output").unwrap(),
+            )
+            .await?
+        }
+        ("delta", Some(sub_m)) => {
+            crate::delta::diff(
+                sub_m.value_of("BASE").unwrap(),
+                sub_m.value_of("TARGET").unwrap(),
+                sub_m.value_of("output").unwrap(),
+            )
+            .await?
+        }
+        ("keygen", Some(sub_m)) => {
+            let pubkey = crate::signing::keygen(sub_m.value_of("output").unwrap()).await?;
+            println!("{}", crate::signing::encode_key(&pubkey));
+        }
+        ("trust", Some(sub_m)) => match sub_m.subcommand() {
+            ("add", Some(sub_sub_m)) => {
+                crate::signing::trust(
+                    sub_sub_m.value_of("NAME").unwrap(),
+                    &crate::signing::decode_key(sub_sub_m.value_of("PUBKEY").unwrap())?,
+                )
+                .await?
+            }
+            ("remove", Some(sub_sub_m)) | ("rm", Some(sub_sub_m)) => {
+                crate::signing::distrust(sub_sub_m.value_of("NAME").unwrap()).await?
+            }
+            ("list", _) | ("ls", _) => {
+                for (name, key) in crate::signing::trust_store().await?.0 {
+                    println!("{}: {}", name, key);
+                }
+            }
+            _ => {
+                println!("{}", sub_m.usage());
+                std::process::exit(1);
+            }
+        },
         ("verify", Some(sub_m)) => verify(sub_m.value_of("PATH").unwrap()).await?,
         ("inspect", Some(sub_m)) => match sub_m.subcommand() {
             ("info", Some(sub_sub_m)) => {
@@ -3,11 +3,12 @@ use std::path::PathBuf;
 use linear_map::LinearMap;
 
 use crate::actions::Action;
+use crate::config::ConfigExpr;
 use crate::dependencies::Dependencies;
 use crate::tor::HiddenServiceVersion;
 use crate::tor::PortMapping;
 
-pub type ManifestLatest = ManifestV0;
+pub type ManifestLatest = ManifestV1;
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct Description {
@@ -19,7 +20,60 @@ pub struct Description {
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum ImageConfig {
-    Tar,
+    Tar {
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        arch: Vec<String>,
+    },
+    Oci {
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        arch: Vec<String>,
+    },
+}
+impl ImageConfig {
+    /// Architectures the package carries an image for, named as in `uname -m`. Empty for
+    /// single-architecture packages, which install on any host.
+    pub fn arch(&self) -> &[String] {
+        match self {
+            ImageConfig::Tar { arch } => arch,
+            ImageConfig::Oci { arch } => arch,
+        }
+    }
+
+    /// One entry per image the package carries: every declared architecture, or a single
+    /// `None` for single-architecture packages.
+    pub fn images(&self) -> Vec<Option<&str>> {
+        if self.arch().is_empty() {
+            vec![None]
+        } else {
+            self.arch().iter().map(|a| Some(a.as_str())).collect()
+        }
+    }
+
+    /// The archive entry holding the docker image for `arch`.
+    pub fn tar_name(arch: Option<&str>) -> String {
+        match arch {
+            Some(arch) => format!("image.{}.tar", arch),
+            None => "image.tar".to_owned(),
+        }
+    }
+
+    /// The architecture to install on this host, or `None` for single-architecture packages.
+    pub fn host_arch(&self) -> Result<Option<&str>, crate::Error> {
+        if self.arch().is_empty() {
+            return Ok(None);
+        }
+        let host = std::env::consts::ARCH;
+        crate::ensure_code!(
+            self.arch().iter().any(|a| a == host),
+            crate::error::GENERAL_ERROR,
+            "Package Does Not Support {}: built for {}",
+            host,
+            self.arch().join(", ")
+        );
+        Ok(Some(host))
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -72,16 +126,522 @@ pub struct ManifestV0 {
     pub extra: LinearMap<String, serde_yaml::Value>,
 }
 
+/// Where the app's data volume, `<volumes>/<id>`, is mounted, and which of its subdirectories
+/// dependents may mount.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct Volumes {
+    pub mount: PathBuf,
+    /// mounted read only by dependents that ask for it
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public: Option<PathBuf>,
+    /// mounted read-write by dependents that ask for it, in a subdirectory per dependent
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared: Option<PathBuf>,
+    /// volumes of their own, mounted beside the data volume, by name
+    #[serde(default)]
+    #[serde(skip_serializing_if = "LinearMap::is_empty")]
+    pub named: LinearMap<String, NamedVolume>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VolumeKind {
+    Data,
+    /// anything the app can rebuild
+    Cache,
+    Logs,
+    /// kept in memory, and lost whenever the container stops
+    Tmpfs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackupPolicy {
+    Include,
+    Exclude,
+}
+
+/// A volume kept in `<volumes>/<id>.volumes/<name>`, or in memory for a tmpfs.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct NamedVolume {
+    #[serde(rename = "type")]
+    pub kind: VolumeKind,
+    /// where it is mounted in the container
+    pub mount: PathBuf,
+    /// whether it is backed up, by default only if it holds data
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup: Option<BackupPolicy>,
+    /// the most a tmpfs may hold
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_mb: Option<usize>,
+}
+impl NamedVolume {
+    pub fn backed_up(&self) -> bool {
+        match self.backup {
+            Some(policy) => policy == BackupPolicy::Include,
+            None => self.kind == VolumeKind::Data,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum Probe {
+    /// run in the container with `docker exec`, healthy if it exits 0
+    Command { command: Vec<String> },
+    /// healthy if a GET of `path` on `port` returns a 2xx status
+    Http {
+        port: u16,
+        #[serde(default = "Probe::default_path")]
+        path: String,
+    },
+    /// healthy if `port` accepts a connection
+    Tcp { port: u16 },
+}
+impl Probe {
+    fn default_path() -> String {
+        "/".to_owned()
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthCheck {
+    #[serde(flatten)]
+    pub probe: Probe,
+    #[serde(default = "HealthCheck::default_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "HealthCheck::default_timeout")]
+    pub timeout_secs: u64,
+    /// how long after starting failures are not held against the app
+    #[serde(default)]
+    pub grace_period_secs: u64,
+    /// consecutive failures before the app is unhealthy
+    #[serde(default = "HealthCheck::default_retries")]
+    pub retries: u32,
+}
+impl HealthCheck {
+    fn default_interval() -> u64 {
+        30
+    }
+    fn default_timeout() -> u64 {
+        10
+    }
+    fn default_retries() -> u32 {
+        3
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartCondition {
+    Never,
+    /// only if the app exits with a non-zero code
+    OnFailure,
+    Always,
+}
+
+/// What the supervisor does when an app that should be running stops on its own.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct RestartPolicy {
+    #[serde(default = "RestartPolicy::default_condition")]
+    pub condition: RestartCondition,
+    /// consecutive restarts before giving up, unlimited if unset
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    /// the delay before the first restart, doubled for each consecutive one
+    #[serde(default = "RestartPolicy::default_backoff_initial")]
+    pub backoff_initial_secs: u64,
+    #[serde(default = "RestartPolicy::default_backoff_max")]
+    pub backoff_max_secs: u64,
+    /// how long the app must stay up for its earlier crashes to stop counting as consecutive
+    #[serde(default = "RestartPolicy::default_reset_after")]
+    pub reset_after_secs: u64,
+}
+impl RestartPolicy {
+    fn default_condition() -> RestartCondition {
+        RestartCondition::OnFailure
+    }
+    fn default_backoff_initial() -> u64 {
+        1
+    }
+    fn default_backoff_max() -> u64 {
+        300
+    }
+    fn default_reset_after() -> u64 {
+        600
+    }
+}
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            condition: RestartPolicy::default_condition(),
+            max_retries: None,
+            backoff_initial_secs: RestartPolicy::default_backoff_initial(),
+            backoff_max_secs: RestartPolicy::default_backoff_max(),
+            reset_after_secs: RestartPolicy::default_reset_after(),
+        }
+    }
+}
+
+/// Limits on what the container of an app may use, unlimited if unset. All but `shm_size_mb` can
+/// be overridden by the user, see `resources`.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct Resources {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shm_size_mb: Option<usize>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_limit_mb: Option<usize>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_reservation_mb: Option<usize>,
+    /// relative weight against other apps when the CPU is contended, docker's default is 1024
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_shares: Option<u64>,
+    /// the most CPUs the app may use, e.g. 1.5
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<f64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pids_limit: Option<u64>,
+}
+impl Resources {
+    /// docker refuses memory limits below this
+    pub const MIN_MEMORY_MB: usize = 6;
+
+    /// These limits, with those set in `overrides` in their place.
+    pub fn overlay(&self, overrides: &Resources) -> Resources {
+        Resources {
+            shm_size_mb: overrides.shm_size_mb.or(self.shm_size_mb),
+            memory_limit_mb: overrides.memory_limit_mb.or(self.memory_limit_mb),
+            memory_reservation_mb: overrides
+                .memory_reservation_mb
+                .or(self.memory_reservation_mb),
+            cpu_shares: overrides.cpu_shares.or(self.cpu_shares),
+            cpus: overrides.cpus.or(self.cpus),
+            pids_limit: overrides.pids_limit.or(self.pids_limit),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        if let Some(shm_size_mb) = self.shm_size_mb {
+            ensure!(shm_size_mb > 0, "Shared Memory Size Cannot Be Zero");
+        }
+        if let Some(memory_limit_mb) = self.memory_limit_mb {
+            ensure!(
+                memory_limit_mb >= Self::MIN_MEMORY_MB,
+                "Memory Limit Must Be At Least {}MB",
+                Self::MIN_MEMORY_MB
+            );
+        }
+        if let Some(memory_reservation_mb) = self.memory_reservation_mb {
+            ensure!(
+                memory_reservation_mb > 0,
+                "Memory Reservation Cannot Be Zero"
+            );
+            ensure!(
+                self.memory_limit_mb
+                    .map(|limit| memory_reservation_mb <= limit)
+                    .unwrap_or(true),
+                "Memory Reservation Cannot Exceed Memory Limit"
+            );
+        }
+        if let Some(cpu_shares) = self.cpu_shares {
+            ensure!(cpu_shares >= 2, "CPU Shares Must Be At Least 2");
+        }
+        if let Some(cpus) = self.cpus {
+            ensure!(
+                cpus.is_finite() && cpus > 0.0,
+                "CPUs Must Be A Positive Number"
+            );
+        }
+        if let Some(pids_limit) = self.pids_limit {
+            ensure!(pids_limit > 0, "PIDs Limit Cannot Be Zero");
+        }
+        Ok(())
+    }
+}
+
+/// How the container of an app is confined. Unset, it runs with docker's defaults: as the image's
+/// user, with docker's default capabilities, on a writable root filesystem. A read only root
+/// filesystem pairs with a tmpfs volume for scratch data.
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct Security {
+    /// by name or uid, which also makes it the owner of the app's volumes
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// by name or gid, only with `user`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// capabilities as docker names them, e.g. `NET_ADMIN`, or `ALL`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cap_drop: Vec<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cap_add: Vec<String>,
+    #[serde(default)]
+    pub read_only_rootfs: bool,
+    #[serde(default)]
+    pub no_new_privileges: bool,
+}
+impl Security {
+    /// capabilities that give an app a way out of its container or control of the host
+    pub const RISKY_CAPABILITIES: &'static [&'static str] = &[
+        "ALL",
+        "BPF",
+        "DAC_READ_SEARCH",
+        "MAC_ADMIN",
+        "MAC_OVERRIDE",
+        "NET_ADMIN",
+        "SYS_ADMIN",
+        "SYS_BOOT",
+        "SYS_MODULE",
+        "SYS_PTRACE",
+        "SYS_RAWIO",
+        "SYS_TIME",
+    ];
+
+    /// `--user` as docker takes it.
+    pub fn user_spec(&self) -> Option<String> {
+        self.user.as_ref().map(|user| match &self.group {
+            Some(group) => format!("{}:{}", user, group),
+            None => user.clone(),
+        })
+    }
+
+    /// The numeric ids the app runs as, if it runs as a uid.
+    pub fn ids(&self) -> Option<(u32, Option<u32>)> {
+        let uid = self.user.as_ref()?.parse().ok()?;
+        Some((uid, self.group.as_ref().and_then(|g| g.parse().ok())))
+    }
+
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        ensure!(
+            self.group.is_none() || self.user.is_some(),
+            "Group Cannot Be Set Without User"
+        );
+        for cap in self.cap_add.iter().chain(&self.cap_drop) {
+            ensure!(
+                !cap.is_empty()
+                    && cap
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'),
+                "Invalid Capability: {}",
+                cap
+            );
+        }
+        for cap in &self.cap_add {
+            ensure!(
+                !self.cap_drop.contains(cap),
+                "Capability Both Added And Dropped: {}",
+                cap
+            );
+        }
+        Ok(())
+    }
+
+    /// What the app may do that a fully confined container could not.
+    pub fn privileges(&self) -> Vec<Privilege> {
+        let mut privileges = Vec::new();
+        match self.user.as_ref().map(|a| a.as_str()) {
+            None | Some("root") | Some("0") => privileges.push(Privilege::RunsAsRoot),
+            _ => (),
+        }
+        for cap in &self.cap_add {
+            let name = cap.trim_start_matches("CAP_");
+            privileges.push(Privilege::Capability {
+                name: name.to_owned(),
+                risky: Self::RISKY_CAPABILITIES.contains(&name),
+            });
+        }
+        if !self.read_only_rootfs {
+            privileges.push(Privilege::WritableRootfs);
+        }
+        if !self.no_new_privileges {
+            privileges.push(Privilege::NewPrivileges);
+        }
+        privileges
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum Privilege {
+    /// including when the user is left to the image, which is most often root
+    RunsAsRoot,
+    Capability {
+        name: String,
+        risky: bool,
+    },
+    WritableRootfs,
+    /// setuid binaries and the like can raise the privileges of its processes
+    NewPrivileges,
+}
+impl Privilege {
+    pub fn risky(&self) -> bool {
+        match self {
+            Privilege::Capability { risky, .. } => *risky,
+            _ => false,
+        }
+    }
+}
+impl std::fmt::Display for Privilege {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Privilege::RunsAsRoot => write!(f, "runs as root"),
+            Privilege::Capability { name, .. } => write!(f, "has capability {}", name),
+            Privilege::WritableRootfs => write!(f, "can write to its root filesystem"),
+            Privilege::NewPrivileges => write!(f, "can gain new privileges"),
+        }
+    }
+}
+
+/// Unlike V0, which keeps keys it does not know in `extra`, V1 rejects them, so a misspelled
+/// key fails the pack instead of being silently ignored.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct ManifestV1 {
+    pub id: String,
+    pub version: emver::Version,
+    pub title: String,
+    pub description: Description,
+    pub release_notes: String,
+    #[serde(default)]
+    pub install_alert: Option<String>,
+    #[serde(default)]
+    pub uninstall_alert: Option<String>,
+    #[serde(default)]
+    pub restore_alert: Option<String>,
+    #[serde(default)]
+    pub start_alert: Option<String>,
+    #[serde(default)]
+    pub has_instructions: bool,
+    #[serde(default = "emver::VersionRange::any")]
+    pub os_version_required: emver::VersionRange,
+    #[serde(default = "emver::VersionRange::any")]
+    pub os_version_recommended: emver::VersionRange,
+    pub image: ImageConfig,
+    /// the ports the app serves, by name
+    #[serde(default)]
+    pub interfaces: LinearMap<String, PortMapping>,
+    #[serde(default)]
+    pub hidden_service_version: HiddenServiceVersion,
+    pub volumes: Volumes,
+    /// environment variables of the container, evaluated against the app's config and those of
+    /// its dependencies. A variable that evaluates to null is left unset. The container is
+    /// recreated when the app next starts after they change, so anything the app keeps outside
+    /// its volume is lost then.
+    #[serde(default)]
+    pub env: LinearMap<String, ConfigExpr>,
+    #[serde(default)]
+    pub health_checks: LinearMap<String, HealthCheck>,
+    #[serde(default)]
+    pub resources: Resources,
+    #[serde(default)]
+    pub security: Security,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+    #[serde(default)]
+    pub dependencies: Dependencies,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+impl ManifestV1 {
+    pub fn ports(&self) -> Vec<PortMapping> {
+        self.interfaces.values().cloned().collect()
+    }
+}
+impl From<ManifestV0> for ManifestV1 {
+    fn from(m: ManifestV0) -> Self {
+        ManifestV1 {
+            id: m.id,
+            version: m.version,
+            title: m.title,
+            description: m.description,
+            release_notes: m.release_notes,
+            install_alert: m.install_alert,
+            uninstall_alert: m.uninstall_alert,
+            restore_alert: m.restore_alert,
+            start_alert: m.start_alert,
+            has_instructions: m.has_instructions,
+            os_version_required: m.os_version_required,
+            os_version_recommended: m.os_version_recommended,
+            image: m.image,
+            interfaces: m
+                .ports
+                .into_iter()
+                .map(|p| (format!("port-{}", p.internal), p))
+                .collect(),
+            hidden_service_version: m.hidden_service_version,
+            volumes: Volumes {
+                mount: m.mount,
+                public: m.public,
+                shared: m.shared,
+                named: LinearMap::new(),
+            },
+            env: LinearMap::new(),
+            health_checks: LinearMap::new(),
+            resources: Resources {
+                shm_size_mb: m.shm_size_mb,
+                ..Default::default()
+            },
+            security: Security::default(),
+            restart_policy: RestartPolicy::default(),
+            assets: m.assets,
+            dependencies: m.dependencies,
+            actions: m.actions,
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(tag = "compat")]
 #[serde(rename_all = "lowercase")]
 pub enum Manifest {
     V0(ManifestV0),
+    V1(ManifestV1),
 }
 impl Manifest {
     pub fn into_latest(self) -> ManifestLatest {
         match self {
-            Manifest::V0(m) => m,
+            Manifest::V0(m) => m.into(),
+            Manifest::V1(m) => m,
+        }
+    }
+
+    /// Keys of a V0 manifest that no version of appmgr reads. They are dropped by
+    /// `into_latest`.
+    pub fn unrecognized_keys(&self) -> Vec<&str> {
+        match self {
+            Manifest::V0(m) => m.extra.keys().map(|a| a.as_str()).collect(),
+            Manifest::V1(_) => Vec::new(),
         }
     }
 }
@@ -0,0 +1,468 @@
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt as _;
+use linear_map::{set::LinearSet, LinearMap};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncWriteExt};
+use tokio_tar as tar;
+
+use crate::install::Transaction;
+use crate::s9pk::Reader;
+use crate::util::{from_json_async_reader, from_yaml_async_reader, PersistencePath};
+use crate::Error;
+use crate::ResultExt as _;
+
+pub const LAYOUT_DIR: &'static str = "image";
+pub const MEDIA_TYPE_IMAGE_MANIFEST: &'static str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_DOCKER_MANIFEST: &'static str =
+    "application/vnd.docker.distribution.manifest.v2+json";
+pub const ANNOTATION_IMAGE_NAME: &'static str = "io.containerd.image.name";
+pub const ANNOTATION_REF_NAME: &'static str = "org.opencontainers.image.ref.name";
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "LinearMap::is_empty")]
+    pub annotations: LinearMap<String, String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    #[serde(flatten)]
+    pub extra: LinearMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(flatten)]
+    pub extra: LinearMap<String, serde_json::Value>,
+}
+
+/// Maps `uname -m` style architecture names, as used in manifests, to their OCI equivalents.
+pub fn oci_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "armv7" | "armv7l" => "arm",
+        a => a,
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    pub schema_version: u32,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<Descriptor>,
+    #[serde(flatten)]
+    pub extra: LinearMap<String, serde_json::Value>,
+}
+impl ImageIndex {
+    /// The image manifest to install for `arch`. Single-architecture packages carry exactly
+    /// one image, multi-architecture packages pick theirs by platform.
+    pub fn image(&self, arch: Option<&str>) -> Result<&Descriptor, Error> {
+        let image = if let Some(arch) = arch {
+            self.manifests
+                .iter()
+                .find(|m| {
+                    m.platform.as_ref().map(|p| p.architecture.as_str()) == Some(oci_arch(arch))
+                })
+                .ok_or_else(|| format_err!("OCI Index Has No Manifest For {}", arch))
+                .with_code(crate::error::GENERAL_ERROR)?
+        } else {
+            crate::ensure_code!(
+                self.manifests.len() == 1,
+                crate::error::GENERAL_ERROR,
+                "OCI Index Must Contain Exactly One Manifest, Found {}",
+                self.manifests.len()
+            );
+            &self.manifests[0]
+        };
+        check_media_type(image)?;
+        Ok(image)
+    }
+
+    /// A copy of this index listing only `image`.
+    pub fn with_image(&self, image: Descriptor) -> Self {
+        ImageIndex {
+            manifests: vec![image],
+            ..self.clone()
+        }
+    }
+}
+
+fn check_media_type(image: &Descriptor) -> Result<(), Error> {
+    crate::ensure_code!(
+        image.media_type == MEDIA_TYPE_IMAGE_MANIFEST
+            || image.media_type == MEDIA_TYPE_DOCKER_MANIFEST,
+        crate::error::GENERAL_ERROR,
+        "Unsupported OCI Manifest Media Type: {}",
+        image.media_type
+    );
+    Ok(())
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageManifest {
+    pub schema_version: u32,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+    #[serde(flatten)]
+    pub extra: LinearMap<String, serde_json::Value>,
+}
+
+/// The manifest `docker load` reads from a `docker save` style archive. Writing it next to the
+/// OCI layout lets daemons that predate OCI archive support load the same stream.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    repo_tags: Vec<String>,
+    layers: Vec<String>,
+}
+
+pub fn digest_hex(digest: &str) -> Result<&str, Error> {
+    let hex = digest
+        .strip_prefix("sha256:")
+        .filter(|h| h.len() == 64 && h.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| format_err!("Invalid Digest: {}", digest))
+        .with_code(crate::error::GENERAL_ERROR)?;
+    Ok(hex)
+}
+
+pub fn blob_path<P: AsRef<Path>>(root: P, digest: &str) -> Result<PathBuf, Error> {
+    Ok(root
+        .as_ref()
+        .join("blobs")
+        .join("sha256")
+        .join(digest_hex(digest)?))
+}
+
+pub fn blob_entry_path(digest: &str) -> Result<String, Error> {
+    Ok(format!(
+        "{}/blobs/sha256/{}",
+        LAYOUT_DIR,
+        digest_hex(digest)?
+    ))
+}
+
+/// Local content-addressed blob store shared by every installed app.
+pub fn store() -> PathBuf {
+    PersistencePath::from_ref("oci").path()
+}
+
+pub async fn read_manifest<P: AsRef<Path>>(
+    root: P,
+    descriptor: &Descriptor,
+) -> Result<ImageManifest, Error> {
+    let path = blob_path(root, &descriptor.digest)?;
+    let f = tokio::fs::File::open(&path)
+        .await
+        .with_context(|e| format!("{}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    from_json_async_reader(f).await
+}
+
+/// Every blob reachable from `index`, image manifests first so readers of a packed layout learn
+/// which configs and layers to expect before they arrive. Blobs shared between manifests are
+/// listed once.
+pub async fn blobs<P: AsRef<Path>>(root: P, index: &ImageIndex) -> Result<Vec<Descriptor>, Error> {
+    let root = root.as_ref();
+    let mut res = Vec::new();
+    let mut seen = LinearSet::new();
+    let mut rest = Vec::new();
+    for image in &index.manifests {
+        check_media_type(image)?;
+        let manifest = read_manifest(root, image).await?;
+        if seen.insert(image.digest.clone()) {
+            res.push(image.clone());
+        }
+        rest.push(manifest.config);
+        rest.extend(manifest.layers);
+    }
+    res.extend(rest.into_iter().filter(|b| seen.insert(b.digest.clone())));
+    Ok(res)
+}
+
+pub async fn read_index<R: AsyncRead + AsyncSeek + Unpin + Send>(
+    pkg: &mut Reader<R>,
+) -> Result<ImageIndex, Error> {
+    from_json_async_reader(pkg.entry(&format!("{}/index.json", LAYOUT_DIR)).await?).await
+}
+
+/// Sorts `blobs` into the order they are stored in the package, so they can be read from a
+/// stream without seeking backwards.
+fn sort_packed<R: AsyncRead + AsyncSeek + Unpin + Send>(
+    pkg: &Reader<R>,
+    blobs: &mut Vec<Descriptor>,
+) -> Result<(), Error> {
+    let mut offsets = LinearMap::new();
+    for blob in blobs.iter() {
+        let offset = pkg.get(&blob_entry_path(&blob.digest)?)?.offset;
+        offsets.insert(blob.digest.clone(), offset);
+    }
+    blobs.sort_by_key(|b| offsets[&b.digest]);
+    Ok(())
+}
+
+/// Reads every image manifest the index references, keyed by digest, as stored.
+async fn read_packed_manifests<R: AsyncRead + AsyncSeek + Unpin + Send>(
+    pkg: &mut Reader<R>,
+    index: &ImageIndex,
+) -> Result<LinearMap<String, Vec<u8>>, Error> {
+    let mut seen = LinearSet::new();
+    let mut images: Vec<Descriptor> = index
+        .manifests
+        .iter()
+        .filter(|a| seen.insert(a.digest.clone()))
+        .cloned()
+        .collect();
+    sort_packed(pkg, &mut images)?;
+    let mut res = LinearMap::new();
+    for image in images {
+        check_media_type(&image)?;
+        let mut raw = Vec::new();
+        pkg.entry(&blob_entry_path(&image.digest)?)
+            .await?
+            .read_to_end(&mut raw)
+            .await?;
+        res.insert(image.digest, raw);
+    }
+    Ok(res)
+}
+
+fn parse_manifest(raw: &[u8]) -> Result<ImageManifest, Error> {
+    serde_json::from_slice(raw)
+        .map_err(failure::Error::from)
+        .with_code(crate::error::SERDE_ERROR)
+}
+
+/// Checks the OCI image layout packed under `image/` in an s9pk: every blob the index
+/// references must be present, and nothing else may be. Returns the index.
+pub async fn verify<R: AsyncRead + AsyncSeek + Unpin + Send>(
+    pkg: &mut Reader<R>,
+) -> Result<ImageIndex, Error> {
+    Ok(verify_layout(pkg).await?.0)
+}
+
+/// `verify`, also returning the image manifests that were read along the way.
+async fn verify_layout<R: AsyncRead + AsyncSeek + Unpin + Send>(
+    pkg: &mut Reader<R>,
+) -> Result<(ImageIndex, LinearMap<String, Vec<u8>>), Error> {
+    let index = read_index(pkg).await?;
+    let manifests = read_packed_manifests(pkg, &index).await?;
+    let mut expected = LinearSet::new();
+    expected.insert(format!("{}/oci-layout", LAYOUT_DIR));
+    expected.insert(format!("{}/index.json", LAYOUT_DIR));
+    for (digest, raw) in &manifests {
+        let manifest = parse_manifest(raw)?;
+        expected.insert(blob_entry_path(digest)?);
+        for blob in std::iter::once(&manifest.config).chain(&manifest.layers) {
+            expected.insert(blob_entry_path(&blob.digest)?);
+        }
+    }
+    for name in pkg.toc.dir(LAYOUT_DIR) {
+        crate::ensure_code!(
+            expected.remove(name),
+            crate::error::GENERAL_ERROR,
+            "Package File Invalid or Corrupted: unexpected {}",
+            name
+        );
+    }
+    if let Some(missing) = expected.iter().next() {
+        return Err(format_err!(
+            "Package File Invalid or Corrupted: missing {}",
+            missing
+        ))
+        .with_code(crate::error::GENERAL_ERROR);
+    }
+    Ok((index, manifests))
+}
+
+/// Copies a blob into the local store, checking it against its digest. Blobs already in the
+/// store are left alone.
+async fn store_blob<R: AsyncRead + Unpin>(
+    digest: &str,
+    mut r: R,
+    tx: &mut Transaction,
+) -> Result<(), Error> {
+    let path = blob_path(store(), digest)?;
+    if path.exists() {
+        log::info!("Blob {} already present, skipping.", digest);
+        return Ok(());
+    }
+    log::info!("Storing blob {}.", digest);
+    tx.create_dir_all(path.parent().unwrap()).await?;
+    let tmp_path = path.with_extension("tmp");
+    let mut f = tokio::fs::File::create(&tmp_path)
+        .await
+        .with_context(|e| format!("{}: {}", tmp_path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * crate::BUFFER_SIZE];
+    loop {
+        let n = r.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        f.write_all(&buf[..n]).await?;
+    }
+    f.flush().await?;
+    f.sync_all().await?;
+    drop(f);
+    if format!("{:x}", hasher.finalize()) != digest_hex(digest)? {
+        tokio::fs::remove_file(&tmp_path).await?;
+        return Err(format_err!("Blob Does Not Match Digest: {}", digest))
+            .with_code(crate::error::GENERAL_ERROR);
+    }
+    tokio::fs::rename(&tmp_path, &path)
+        .await
+        .with_context(|e| format!("{}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    tx.on_rollback(format!("remove {}", path.display()), async move {
+        tokio::fs::remove_file(&path)
+            .await
+            .with_context(|e| format!("{}: {}", path.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)
+    });
+    Ok(())
+}
+
+/// Copies the image for `arch` from the package into the local store, skipping blobs that an
+/// installed app already provided. Returns an index listing only the installed image.
+pub async fn install<R: AsyncRead + AsyncSeek + Unpin + Send>(
+    pkg: &mut Reader<R>,
+    arch: Option<&str>,
+    tx: &mut Transaction,
+) -> Result<ImageIndex, Error> {
+    let (index, manifests) = verify_layout(pkg).await?;
+    let image = index.image(arch)?.clone();
+    let raw = &manifests[&image.digest];
+    let manifest = parse_manifest(raw)?;
+    store_blob(&image.digest, &raw[..], tx).await?;
+    let mut seen = LinearSet::new();
+    let mut blobs: Vec<Descriptor> = std::iter::once(manifest.config)
+        .chain(manifest.layers)
+        .filter(|b| seen.insert(b.digest.clone()))
+        .collect();
+    sort_packed(pkg, &mut blobs)?;
+    for blob in blobs {
+        let name = blob_entry_path(&blob.digest)?;
+        store_blob(&blob.digest, pkg.entry(&name).await?, tx).await?;
+    }
+    Ok(index.with_image(image))
+}
+
+/// Streams the image of a single-image index from the local store into `docker load`, tagged
+/// as `tag`.
+pub async fn load(index: &ImageIndex, tag: &str) -> Result<(), Error> {
+    index.image(None)?;
+    let root = store();
+    let blobs = blobs(&root, index).await?;
+    let mut index = index.clone();
+    let image = &mut index.manifests[0];
+    image
+        .annotations
+        .insert(ANNOTATION_IMAGE_NAME.to_owned(), tag.to_owned());
+    image.annotations.insert(
+        ANNOTATION_REF_NAME.to_owned(),
+        tag.rsplit(":").next().unwrap().to_owned(),
+    );
+    let docker_manifest = vec![DockerManifest {
+        config: format!("blobs/sha256/{}", digest_hex(&blobs[1].digest)?),
+        repo_tags: vec![tag.to_owned()],
+        layers: blobs[2..]
+            .iter()
+            .map(|l| Ok(format!("blobs/sha256/{}", digest_hex(&l.digest)?)))
+            .collect::<Result<_, Error>>()?,
+    }];
+    let files = vec![
+        (
+            "oci-layout",
+            serde_json::to_vec(&serde_json::json!({ "imageLayoutVersion": "1.0.0" }))
+                .with_code(crate::error::SERDE_ERROR)?,
+        ),
+        (
+            "index.json",
+            serde_json::to_vec(&index).with_code(crate::error::SERDE_ERROR)?,
+        ),
+        (
+            "manifest.json",
+            serde_json::to_vec(&docker_manifest).with_code(crate::error::SERDE_ERROR)?,
+        ),
+    ];
+    // the archive is built as the runtime reads it, never on disk
+    let (archive_in, mut archive_out) = tokio::io::duplex(64 * 1024);
+    let write_archive = async move {
+        let mut out = tar::Builder::new(archive_in);
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            out.append_data(&mut header, name, std::io::Cursor::new(data))
+                .await?;
+        }
+        for blob in &blobs {
+            out.append_path_with_name(
+                blob_path(&root, &blob.digest)?,
+                format!("blobs/sha256/{}", digest_hex(&blob.digest)?),
+            )
+            .await?;
+        }
+        let mut archive_in = out.into_inner().await?;
+        archive_in.shutdown().await?;
+        Ok::<_, Error>(())
+    };
+    let runtime = crate::runtime::get();
+    futures::try_join!(write_archive, runtime.load(&mut archive_out))?;
+    Ok(())
+}
+
+/// Removes every blob in the local store not referenced by an installed app's image index.
+pub async fn prune() -> Result<(), Error> {
+    let root = store();
+    let blob_dir = root.join("blobs").join("sha256");
+    if !blob_dir.exists() {
+        return Ok(());
+    }
+    let mut referenced = LinearSet::new();
+    for (id, _) in crate::apps::list_info().await? {
+        let index_path = PersistencePath::from_ref("apps")
+            .join(&id)
+            .join("image-index.yaml");
+        if let Some(mut f) = index_path.maybe_read(false).await.transpose()? {
+            let index: ImageIndex = from_yaml_async_reader(&mut *f).await?;
+            for blob in blobs(&root, &index).await? {
+                referenced.insert(digest_hex(&blob.digest)?.to_owned());
+            }
+        }
+    }
+    let mut blob_files = tokio::fs::read_dir(&blob_dir).await?;
+    while let Some(blob) = blob_files.next_entry().await? {
+        if !blob
+            .file_name()
+            .to_str()
+            .map(|name| referenced.contains(name))
+            .unwrap_or(false)
+        {
+            log::info!("Removing unused blob {}.", blob.path().display());
+            tokio::fs::remove_file(blob.path())
+                .await
+                .with_context(|e| format!("{}: {}", blob.path().display(), e))
+                .with_code(crate::error::FILESYSTEM_ERROR)?;
+        }
+    }
+    Ok(())
+}
@@ -1,16 +1,22 @@
 use std::borrow::Cow;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use failure::ResultExt;
-use futures::stream::StreamExt;
 use linear_map::LinearMap;
 use rand::SeedableRng;
-use tokio_tar as tar;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 
+use crate::compression::Compression;
 use crate::config::{ConfigRuleEntry, ConfigSpec};
-use crate::manifest::{ImageConfig, Manifest};
-use crate::util::{from_cbor_async_reader, from_json_async_reader, from_yaml_async_reader};
+use crate::manifest::{BackupPolicy, ImageConfig, Manifest, ManifestLatest, Probe, VolumeKind};
+use crate::s9pk;
+use crate::util::{
+    from_cbor_async_reader, from_json_async_reader, from_yaml_async_reader, to_yaml_async_writer,
+};
 use crate::version::VersionT;
+use crate::ResultExt as _;
 
 #[derive(Clone, Debug, Fail)]
 pub enum Error {
@@ -22,7 +28,13 @@ pub enum Error {
     InvalidOutputPath(String),
 }
 
-pub async fn pack(path: &str, output: &str) -> Result<(), failure::Error> {
+pub async fn pack(
+    path: &str,
+    output: &str,
+    key: &str,
+    compression: Compression,
+    deterministic: bool,
+) -> Result<(), failure::Error> {
     let path = Path::new(path.trim_end_matches("/"));
     let output = Path::new(output);
     log::info!(
@@ -32,8 +44,10 @@ pub async fn pack(path: &str, output: &str) -> Result<(), failure::Error> {
             .ok_or_else(|| Error::InvalidDirectoryName(format!("{}", path.display())))?,
         output.display(),
     );
-    let out_file = tokio::fs::File::create(output).await?;
-    let mut out = tar::Builder::new(out_file);
+    log::info!("Loading developer key from {}.", key);
+    let keypair = crate::signing::load_keypair(key).await?;
+    let mut out =
+        crate::s9pk::Writer::new(output.with_extension("s9pk.tmp"), deterministic).await?;
     log::info!("Reading {}/manifest.yaml.", path.display());
     let manifest: Manifest = crate::util::from_yaml_async_reader(
         tokio::fs::File::open(path.join("manifest.yaml"))
@@ -42,15 +56,13 @@ pub async fn pack(path: &str, output: &str) -> Result<(), failure::Error> {
     )
     .await?;
     log::info!("Writing manifest to archive.");
-    let bin_manifest = serde_cbor::to_vec(&manifest)?;
-    let mut manifest_header = tar::Header::new_gnu();
-    manifest_header.set_size(bin_manifest.len() as u64);
-    out.append_data(
-        &mut manifest_header,
-        "manifest.cbor",
-        std::io::Cursor::new(bin_manifest),
+    out.append_bytes(
+        s9pk::MANIFEST,
+        &serde_cbor::to_vec(&manifest)?,
+        Compression::None,
     )
     .await?;
+    warn_unrecognized(&manifest);
     let manifest = manifest.into_latest();
     ensure!(
         crate::version::Current::new()
@@ -67,13 +79,10 @@ pub async fn pack(path: &str, output: &str) -> Result<(), failure::Error> {
     )
     .await?;
     log::info!("Writing config spec to archive.");
-    let bin_config_spec = serde_cbor::to_vec(&config_spec)?;
-    let mut config_spec_header = tar::Header::new_gnu();
-    config_spec_header.set_size(bin_config_spec.len() as u64);
-    out.append_data(
-        &mut config_spec_header,
-        "config_spec.cbor",
-        std::io::Cursor::new(bin_config_spec),
+    out.append_bytes(
+        s9pk::CONFIG_SPEC,
+        &serde_cbor::to_vec(&config_spec)?,
+        compression,
     )
     .await?;
     log::info!("Reading {}/config_rules.yaml.", path.display());
@@ -84,57 +93,535 @@ pub async fn pack(path: &str, output: &str) -> Result<(), failure::Error> {
     )
     .await?;
     log::info!("Writing config rules to archive.");
-    let bin_config_rules = serde_cbor::to_vec(&config_rules)?;
-    let mut config_rules_header = tar::Header::new_gnu();
-    config_rules_header.set_size(bin_config_rules.len() as u64);
-    out.append_data(
-        &mut config_rules_header,
-        "config_rules.cbor",
-        std::io::Cursor::new(bin_config_rules),
+    out.append_bytes(
+        s9pk::CONFIG_RULES,
+        &serde_cbor::to_vec(&config_rules)?,
+        compression,
     )
     .await?;
     if manifest.has_instructions {
         log::info!("Packing instructions.md");
-        out.append_path_with_name(path.join("instructions.md"), "instructions.md")
-            .await?;
+        out.append_file(
+            s9pk::INSTRUCTIONS,
+            path.join("instructions.md"),
+            compression,
+        )
+        .await?;
     }
     log::info!("Copying over assets.");
     for asset in &manifest.assets {
-        let src_path = Path::new("assets").join(&asset.src);
-        log::info!("Reading {}/{}.", path.display(), src_path.display());
+        validate_path(&asset.src)?;
+        let src_path = s9pk::asset_entry(&asset.src);
+        log::info!("Reading {}/{}.", path.display(), src_path);
         let file_path = path.join(&src_path);
-        let src = tokio::fs::File::open(&file_path)
+        let metadata = tokio::fs::metadata(&file_path)
             .await
-            .with_context(|e| format!("{}: {}", e, src_path.display()))?;
-        log::info!("Writing {} to archive.", src_path.display());
-        if src.metadata().await?.is_dir() {
-            out.append_dir_all(&asset.src, &file_path).await?;
-            let mut h = tar::Header::new_gnu();
-            h.set_size(0);
-            h.set_path(format!("APPMGR_DIR_END:{}", asset.src.display()))?;
-            h.set_cksum();
-            out.append(&h, tokio::io::empty()).await?;
+            .with_context(|e| format!("{}: {}", e, src_path))?;
+        log::info!("Writing {} to archive.", src_path);
+        if metadata.is_dir() {
+            out.append_dir_all(src_path.into(), file_path, compression)
+                .await?;
         } else {
-            out.append_path_with_name(&file_path, &asset.src).await?;
+            out.append_file(&src_path, &file_path, compression).await?;
         }
     }
-    match manifest.image {
-        ImageConfig::Tar => {
-            log::info!("Reading {}/image.tar.", path.display());
-            let image = tokio::fs::File::open(path.join("image.tar"))
-                .await
-                .with_context(|e| format!("{}: image.tar", e))?;
-            log::info!("Writing image.tar to archive.");
-            let mut header = tar::Header::new_gnu();
-            header.set_size(image.metadata().await?.len());
-            out.append_data(&mut header, "image.tar", image).await?;
+    match &manifest.image {
+        ImageConfig::Tar { .. } => {
+            for arch in manifest.image.images() {
+                let name = ImageConfig::tar_name(arch);
+                log::info!("Writing {}/{} to archive.", path.display(), name);
+                out.append_file(&name, path.join(&name), compression)
+                    .await?;
+            }
+        }
+        ImageConfig::Oci { .. } => {
+            let layout = path.join(crate::oci::LAYOUT_DIR);
+            log::info!("Reading {}.", layout.display());
+            let index: crate::oci::ImageIndex = from_json_async_reader(
+                tokio::fs::File::open(layout.join("index.json"))
+                    .await
+                    .with_context(|e| format!("{}: {}/index.json", e, crate::oci::LAYOUT_DIR))?,
+            )
+            .await?;
+            for arch in manifest.image.images() {
+                index.image(arch)?;
+            }
+            log::info!("Writing OCI layout to archive.");
+            for file in &["oci-layout", "index.json"] {
+                let name = format!("{}/{}", crate::oci::LAYOUT_DIR, file);
+                out.append_file(&name, layout.join(file), Compression::None)
+                    .await?;
+            }
+            for blob in crate::oci::blobs(&layout, &index).await? {
+                log::info!("Writing {} to archive.", blob.digest);
+                out.append_file(
+                    &crate::oci::blob_entry_path(&blob.digest)?,
+                    crate::oci::blob_path(&layout, &blob.digest)?,
+                    Compression::None,
+                )
+                .await?;
+            }
+        }
+    }
+    log::info!("Writing table of contents and payload.");
+    let mut out_file = tokio::fs::File::create(output).await?;
+    out.finish(&mut out_file).await?;
+    out_file.sync_all().await?;
+    drop(out_file);
+    log::info!(
+        "Signing archive with {}.",
+        crate::signing::encode_key(&keypair.public)
+    );
+    crate::signing::sign_file(output, &keypair).await?;
+
+    Ok(())
+}
+
+async fn write_yaml<T: serde::Serialize>(path: &Path, value: &T) -> Result<(), failure::Error> {
+    log::info!("Writing {}.", path.display());
+    let mut f = tokio::fs::File::create(path)
+        .await
+        .with_context(|e| format!("{}: {}", e, path.display()))?;
+    to_yaml_async_writer(&mut f, value).await?;
+    f.sync_all().await?;
+    Ok(())
+}
+
+/// Reverses `pack`, writing the source directory of the package at `path` to `output`.
+/// Packing the result again reproduces the same entries.
+pub async fn unpack(path: &str, output: &str) -> Result<(), failure::Error> {
+    let path = Path::new(path);
+    let output = Path::new(output.trim_end_matches("/"));
+    ensure!(!output.exists(), "{} Already Exists", output.display());
+    log::info!(
+        "Starting unpack of {} to {}.",
+        path.display(),
+        output.display()
+    );
+    log::info!("Opening file.");
+    let mut pkg = s9pk::open(path).await?;
+    log::info!("Verifying entry hashes.");
+    crate::integrity::check(&mut pkg).await?;
+    tokio::fs::create_dir_all(output)
+        .await
+        .with_context(|e| format!("{}: {}", e, output.display()))?;
+    let manifest = pkg.manifest().await?;
+    write_yaml(&output.join("manifest.yaml"), &manifest).await?;
+    let manifest = manifest.into_latest();
+    let config_spec: ConfigSpec =
+        from_cbor_async_reader(pkg.entry(s9pk::CONFIG_SPEC).await?).await?;
+    write_yaml(&output.join("config_spec.yaml"), &config_spec).await?;
+    let config_rules: Vec<ConfigRuleEntry> =
+        from_cbor_async_reader(pkg.entry(s9pk::CONFIG_RULES).await?).await?;
+    write_yaml(&output.join("config_rules.yaml"), &config_rules).await?;
+    if manifest.has_instructions {
+        log::info!("Writing {}/instructions.md.", output.display());
+        pkg.unpack(s9pk::INSTRUCTIONS, output.join("instructions.md"))
+            .await?;
+    }
+    log::info!("Copying over assets.");
+    for asset in &manifest.assets {
+        log::info!(
+            "Writing {}/assets/{}.",
+            output.display(),
+            asset.src.display()
+        );
+        validate_path(&asset.src)?;
+        for file in pkg.toc.files(&s9pk::asset_entry(&asset.src)) {
+            pkg.unpack(&file, join_path(output, &file)?).await?;
         }
     }
-    out.into_inner().await?;
+    let images = match &manifest.image {
+        ImageConfig::Tar { .. } => manifest
+            .image
+            .images()
+            .into_iter()
+            .map(ImageConfig::tar_name)
+            .collect(),
+        ImageConfig::Oci { .. } => pkg
+            .toc
+            .dir(crate::oci::LAYOUT_DIR)
+            .cloned()
+            .collect::<Vec<_>>(),
+    };
+    for name in images {
+        log::info!("Writing {}/{}.", output.display(), name);
+        pkg.unpack(&name, join_path(output, &name)?).await?;
+    }
 
     Ok(())
 }
 
+/// Warns about V0 manifest keys that would be dropped on install. They are usually typos, or
+/// settings that V1 declares under a different name.
+fn warn_unrecognized(manifest: &Manifest) {
+    for key in manifest.unrecognized_keys() {
+        log::warn!("Unrecognized Manifest Key Will Be Ignored: {}", key);
+    }
+}
+
+fn warn_privileges(manifest: &ManifestLatest) {
+    for privilege in manifest.security.privileges() {
+        if privilege.risky() {
+            log::warn!(
+                "App Requests Risky Privilege: {} {}",
+                manifest.id,
+                privilege
+            );
+        }
+    }
+}
+
+/// The tags of the images in the `docker save` archive `image`, as `docker load` would apply them.
+pub async fn image_tags<R: AsyncRead + Unpin + Send>(
+    mut image: R,
+) -> Result<Vec<String>, crate::Error> {
+    let mut scan = TarScan::default();
+    let mut buf = vec![0; 64 * crate::BUFFER_SIZE];
+    loop {
+        let n = image.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        scan.feed(&buf[..n]).no_code()?;
+    }
+    scan.tags().no_code()
+}
+
+/// The tags listed in the `manifest.json` of a `docker save` archive. Docker matches the keys of
+/// the manifest to `RepoTags` without regard to case, so every key it would match counts.
+fn manifest_tags(manifest: &[u8]) -> Result<Vec<String>, failure::Error> {
+    fn fold(key: &str) -> String {
+        key.chars()
+            .map(|c| match c {
+                '\u{17f}' => 's',
+                c => c.to_ascii_lowercase(),
+            })
+            .collect()
+    }
+    let images: Vec<serde_json::Map<String, serde_json::Value>> = serde_json::from_slice(manifest)
+        .with_context(|e| format!("Invalid Image Manifest: {}", e))?;
+    let mut tags = Vec::new();
+    for image in images {
+        for (key, value) in image {
+            if fold(&key) == "repotags" && !value.is_null() {
+                tags.extend(
+                    serde_json::from_value::<Vec<String>>(value)
+                        .with_context(|e| format!("Invalid Image Manifest: {}", e))?,
+                );
+            }
+        }
+    }
+    Ok(tags)
+}
+
+const TAR_BLOCK: usize = 512;
+/// Bounds what is kept of the `manifest.json`, long names and extended headers of an image.
+const TAR_CAPTURE_LIMIT: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TarCapture {
+    Manifest,
+    LongName,
+    Pax,
+}
+
+/// Follows the entries of a tar archive as it is read, keeping the contents of `manifest.json`
+/// the way `docker load` would find it after extracting the archive.
+#[derive(Debug, Default)]
+struct TarScan {
+    /// how much of the archive has been fed
+    pos: u64,
+    /// the header block being read
+    header: Vec<u8>,
+    /// how much of the current entry is left to read, padding included
+    remaining: u64,
+    capture: Option<(TarCapture, u64, Vec<u8>)>,
+    /// the name a GNU long name or PAX header gives the entry that follows it
+    next_name: Option<String>,
+    /// where the end of archive marker starts
+    end: Option<u64>,
+    manifest: Option<Vec<u8>>,
+}
+impl TarScan {
+    fn feed(&mut self, mut data: &[u8]) -> Result<(), failure::Error> {
+        while !data.is_empty() && self.end.is_none() {
+            let n = if self.remaining > 0 {
+                let n = (self.remaining.min(data.len() as u64)) as usize;
+                if let Some((_, len, captured)) = &mut self.capture {
+                    let want = (*len as usize).saturating_sub(captured.len()).min(n);
+                    captured.extend_from_slice(&data[..want]);
+                }
+                self.remaining -= n as u64;
+                n
+            } else {
+                let n = (TAR_BLOCK - self.header.len()).min(data.len());
+                self.header.extend_from_slice(&data[..n]);
+                n
+            };
+            self.pos += n as u64;
+            data = &data[n..];
+            if self.header.len() == TAR_BLOCK {
+                self.read_header()?;
+            }
+            if self.remaining == 0 && self.header.is_empty() {
+                self.finish_entry();
+            }
+        }
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> Result<(), failure::Error> {
+        let block = std::mem::take(&mut self.header);
+        if block.iter().all(|b| *b == 0) {
+            self.end = Some(self.pos - TAR_BLOCK as u64);
+            return Ok(());
+        }
+        let checksum: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(i, b)| if (148..156).contains(&i) { b' ' } else { *b } as u64)
+            .sum();
+        ensure!(
+            tar_octal(&block[148..156]) == Some(checksum),
+            "Image Archive Is Not A Valid Tar Archive"
+        );
+        let size = tar_octal(&block[124..136])
+            .ok_or_else(|| format_err!("Image Archive Is Not A Valid Tar Archive"))?;
+        let name = match self.next_name.take() {
+            Some(name) => name,
+            None => {
+                let mut name = tar_str(&block[..100]);
+                if &block[257..262] == b"ustar" && block[345] != 0 {
+                    name = format!("{}/{}", tar_str(&block[345..500]), name);
+                }
+                name
+            }
+        };
+        let typeflag = block[156];
+        let capture = match typeflag {
+            b'L' => Some(TarCapture::LongName),
+            b'x' => Some(TarCapture::Pax),
+            _ if is_manifest_json(&name) => {
+                ensure!(
+                    typeflag == b'0' || typeflag == 0 || typeflag == b'7',
+                    "Image Manifest Is Not A Regular File"
+                );
+                Some(TarCapture::Manifest)
+            }
+            _ => None,
+        };
+        if capture.is_some() {
+            ensure!(
+                size <= TAR_CAPTURE_LIMIT,
+                "Image Archive Entry Too Large: {}",
+                name
+            );
+        }
+        self.capture = capture.map(|c| (c, size, Vec::new()));
+        self.remaining = (size + TAR_BLOCK as u64 - 1) / TAR_BLOCK as u64 * TAR_BLOCK as u64;
+        Ok(())
+    }
+
+    fn finish_entry(&mut self) {
+        match self.capture.take() {
+            Some((TarCapture::Manifest, _, data)) => self.manifest = Some(data),
+            Some((TarCapture::LongName, _, data)) => self.next_name = Some(tar_str(&data)),
+            Some((TarCapture::Pax, _, data)) => {
+                for record in String::from_utf8_lossy(&data).split('\n') {
+                    if let Some(path) = record
+                        .splitn(2, ' ')
+                        .nth(1)
+                        .and_then(|kv| kv.strip_prefix("path="))
+                    {
+                        self.next_name = Some(path.to_owned());
+                    }
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// Where bytes may no longer be passed on to `docker load` before the archive is checked:
+    /// the end of archive marker, or the start of a header that has not been read in full.
+    fn hold_from(&self) -> u64 {
+        self.end
+            .unwrap_or_else(|| self.pos - self.header.len() as u64)
+    }
+
+    /// The tags `docker load` will apply, once the whole archive has been fed.
+    fn tags(&self) -> Result<Vec<String>, failure::Error> {
+        ensure!(
+            self.end.is_some() || (self.header.is_empty() && self.remaining == 0),
+            "Image Archive Is Truncated"
+        );
+        manifest_tags(
+            self.manifest
+                .as_ref()
+                .ok_or_else(|| format_err!("Image Archive Is Missing manifest.json"))?,
+        )
+    }
+}
+
+/// A NUL terminated string field of a tar header.
+fn tar_str(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// A numeric field of a tar header: octal, or big endian binary if the high bit is set.
+fn tar_octal(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut n: u64 = (field[0] & 0x7f) as u64;
+        for b in &field[1..] {
+            n = n.checked_mul(256)?.checked_add(*b as u64)?;
+        }
+        return Some(n);
+    }
+    let s = tar_str(field);
+    let s = s.trim_matches(|c| c == ' ');
+    if s.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(s, 8).ok()
+}
+
+/// Whether `docker load` extracts the entry `name` to `manifest.json`, at the root of the archive.
+fn is_manifest_json(name: &str) -> bool {
+    let mut path = Vec::new();
+    for component in Path::new(name).components() {
+        match component {
+            std::path::Component::Normal(c) => path.push(c),
+            std::path::Component::ParentDir => {
+                path.pop();
+            }
+            _ => (),
+        }
+    }
+    path == [std::ffi::OsStr::new("manifest.json")]
+}
+
+/// Passes a `docker save` archive through on its way to `docker load`, checking the tags it
+/// applies as it goes. `docker load` applies nothing before it reaches the end of the archive, so
+/// everything from the end of archive marker on is held back until `inner` has been read to its
+/// end, and the tags have passed `check_image_tags`. If they do not, or `inner` fails, the read
+/// fails instead, and the image is not loaded.
+pub struct CheckedImage<R> {
+    inner: R,
+    id: String,
+    scan: TarScan,
+    /// what has been read from `inner` and not yet passed on
+    buf: Vec<u8>,
+    chunk: Vec<u8>,
+    passed: u64,
+    eof: bool,
+    checked: bool,
+}
+impl<R: AsyncRead + Unpin> CheckedImage<R> {
+    pub fn new(inner: R, id: &str) -> Self {
+        CheckedImage {
+            inner,
+            id: id.to_owned(),
+            scan: TarScan::default(),
+            buf: Vec::new(),
+            chunk: vec![0; 64 * crate::BUFFER_SIZE],
+            passed: 0,
+            eof: false,
+            checked: false,
+        }
+    }
+
+    fn check(&self) -> Result<(), crate::Error> {
+        check_image_tags(&self.id, &self.scan.tags().no_code()?)
+    }
+}
+impl<R: AsyncRead + Unpin> AsyncRead for CheckedImage<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let available = if this.checked {
+                this.buf.len()
+            } else {
+                (this.scan.hold_from() - this.passed) as usize
+            };
+            if available > 0 {
+                let n = available.min(buf.remaining());
+                buf.put_slice(&this.buf[..n]);
+                this.buf.drain(..n);
+                this.passed += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+            if this.checked {
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                if let Err(e) = this.check() {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{}", e.failure),
+                    )));
+                }
+                this.checked = true;
+                continue;
+            }
+            if this.buf.len() as u64 > TAR_CAPTURE_LIMIT {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Image Archive Has Trailing Data",
+                )));
+            }
+            let mut chunk = ReadBuf::new(&mut this.chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(())) => (),
+                a => return a,
+            }
+            let read = chunk.filled();
+            if read.is_empty() {
+                this.eof = true;
+            } else {
+                if let Err(e) = this.scan.feed(read) {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{}", e),
+                    )));
+                }
+                this.buf.extend_from_slice(read);
+            }
+        }
+    }
+}
+
+/// Loading an image replaces whatever carried its tags before, so the image of `id` may only be
+/// tagged `start9/<id>`, and must be.
+pub fn check_image_tags(id: &str, tags: &[String]) -> Result<(), crate::Error> {
+    let image_name = format!("start9/{}", id);
+    crate::ensure_code!(
+        !tags.is_empty(),
+        crate::error::GENERAL_ERROR,
+        "Image Is Not Tagged {}",
+        image_name
+    );
+    for tag in tags {
+        crate::ensure_code!(
+            tag.rsplitn(2, ':').last() == Some(image_name.as_str()),
+            crate::error::GENERAL_ERROR,
+            "Image Contains Prohibited Tag: {}",
+            tag
+        );
+    }
+    Ok(())
+}
+
+/// `name` below `dir`, if it is a relative path that stays there.
+pub fn join_path<P: AsRef<Path>>(dir: &Path, name: P) -> Result<PathBuf, Error> {
+    validate_path(&name)?;
+    Ok(dir.join(name))
+}
+
 pub fn validate_path<P: AsRef<Path>>(p: P) -> Result<(), Error> {
     let path = p.as_ref();
     if path.is_absolute() {
@@ -176,26 +663,18 @@ pub async fn verify(path: &str) -> Result<(), failure::Error> {
             .and_then(|a| a.to_str())
             .ok_or_else(|| Error::InvalidFileName(format!("{}", path.display())))?,
     );
-    {}
     log::info!("Opening file.");
-    let r = tokio::fs::File::open(&path)
+    let mut f = tokio::fs::File::open(&path)
         .await
         .with_context(|e| format!("{}: {}", path.display(), e))?;
-    log::info!("Extracting archive.");
-    let mut pkg = tar::Archive::new(r);
-    let mut entries = pkg.entries()?;
-    log::info!("Opening manifest from archive.");
-    let manifest = entries
-        .next()
-        .await
-        .ok_or_else(|| format_err!("missing manifest"))??;
-    ensure!(
-        manifest.path()?.to_str() == Some("manifest.cbor"),
-        "Package File Invalid or Corrupted: expected manifest.cbor, got {}",
-        manifest.path()?.display()
-    );
-    log::trace!("Deserializing manifest.");
-    let manifest: Manifest = from_cbor_async_reader(manifest).await?;
+    log::info!("Verifying signature.");
+    crate::signing::verify(&mut f).await?;
+    let mut pkg = s9pk::Reader::new(f).await?;
+    log::info!("Verifying entry hashes.");
+    crate::integrity::check(&mut pkg).await?;
+    log::info!("Reading manifest from archive.");
+    let manifest = pkg.manifest().await?;
+    warn_unrecognized(&manifest);
     let manifest = manifest.into_latest();
     ensure!(
         crate::version::Current::new()
@@ -205,18 +684,53 @@ pub async fn verify(path: &str) -> Result<(), failure::Error> {
         manifest.os_version_required
     );
     ensure!(manifest.id == name, "Package Name Does Not Match Expected",);
-    if let (Some(public), Some(shared)) = (&manifest.public, &manifest.shared) {
+    if let (Some(public), Some(shared)) = (&manifest.volumes.public, &manifest.volumes.shared) {
         ensure!(
             !public.starts_with(shared) && !shared.starts_with(public),
             "Public Directory Conflicts With Shared Directory"
         )
     }
-    if let Some(public) = &manifest.public {
+    if let Some(public) = &manifest.volumes.public {
         validate_path(public)?;
     }
-    if let Some(shared) = &manifest.shared {
+    if let Some(shared) = &manifest.volumes.shared {
         validate_path(shared)?;
     }
+    for (name, volume) in &manifest.volumes.named {
+        ensure!(
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+            "Invalid Volume Name: {}",
+            name
+        );
+        ensure!(
+            volume.mount.is_absolute(),
+            "Volume Mount Path Must Be Absolute: {}",
+            name
+        );
+        ensure!(
+            volume.mount != manifest.volumes.mount
+                && manifest
+                    .volumes
+                    .named
+                    .iter()
+                    .all(|(other, v)| other == name || v.mount != volume.mount),
+            "Volume Mount Path Is Already Mounted: {}",
+            name
+        );
+        if volume.kind == VolumeKind::Tmpfs {
+            ensure!(
+                volume.backup != Some(BackupPolicy::Include),
+                "Tmpfs Volume Cannot Be Backed Up: {}",
+                name
+            );
+        } else {
+            ensure!(
+                volume.size_mb.is_none(),
+                "Only A Tmpfs Volume Can Have A Size: {}",
+                name
+            );
+        }
+    }
     for action in &manifest.actions {
         ensure!(
             !action.command.is_empty(),
@@ -224,34 +738,59 @@ pub async fn verify(path: &str) -> Result<(), failure::Error> {
             action.id
         );
     }
-    log::info!("Opening config spec from archive.");
-    let config_spec = entries
-        .next()
-        .await
-        .ok_or_else(|| format_err!("missing config spec"))??;
+    for (name, check) in &manifest.health_checks {
+        match &check.probe {
+            Probe::Command { command } => {
+                ensure!(!command.is_empty(), "Command Cannot Be Empty: {}", name)
+            }
+            Probe::Http { path, .. } => ensure!(
+                path.starts_with("/"),
+                "Health Check Path Must Be Absolute: {}",
+                name
+            ),
+            Probe::Tcp { .. } => (),
+        }
+        ensure!(
+            check.interval_secs > 0,
+            "Health Check Interval Cannot Be Zero: {}",
+            name
+        );
+    }
+    manifest.resources.validate()?;
+    manifest.security.validate()?;
+    warn_privileges(&manifest);
+    for key in manifest.env.keys() {
+        ensure!(
+            key.chars()
+                .next()
+                .map(|c| !c.is_ascii_digit())
+                .unwrap_or(false)
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "Invalid Environment Variable Name: {}",
+            key
+        );
+        ensure!(
+            !crate::env::RESERVED.contains(&key.as_str()),
+            "Environment Variable Is Set By AppMgr: {}",
+            key
+        );
+    }
     ensure!(
-        config_spec.path()?.to_str() == Some("config_spec.cbor"),
-        "Package File Invalid or Corrupted: expected config_rules.cbor, got {}",
-        config_spec.path()?.display()
+        manifest.restart_policy.backoff_initial_secs > 0
+            && manifest.restart_policy.backoff_initial_secs
+                <= manifest.restart_policy.backoff_max_secs,
+        "Restart Backoff Must Be Positive And No More Than Its Maximum"
     );
-    log::trace!("Deserializing config spec.");
-    let config_spec: ConfigSpec = from_cbor_async_reader(config_spec).await?;
+    log::info!("Reading config spec from archive.");
+    let config_spec: ConfigSpec =
+        from_cbor_async_reader(pkg.entry(s9pk::CONFIG_SPEC).await?).await?;
     log::trace!("Validating config spec.");
     config_spec.validate(&manifest)?;
     let config = config_spec.gen(&mut rand::rngs::StdRng::from_entropy(), &None)?;
     config_spec.matches(&config)?;
-    log::info!("Opening config rules from archive.");
-    let config_rules = entries
-        .next()
-        .await
-        .ok_or_else(|| format_err!("missing config rules"))??;
-    ensure!(
-        config_rules.path()?.to_str() == Some("config_rules.cbor"),
-        "Package File Invalid or Corrupted: expected config_rules.cbor, got {}",
-        config_rules.path()?.display()
-    );
-    log::trace!("Deserializing config rules.");
-    let config_rules: Vec<ConfigRuleEntry> = from_cbor_async_reader(config_rules).await?;
+    log::info!("Reading config rules from archive.");
+    let config_rules: Vec<ConfigRuleEntry> =
+        from_cbor_async_reader(pkg.entry(s9pk::CONFIG_RULES).await?).await?;
     log::trace!("Validating config rules against config spec.");
     let mut cfgs = LinearMap::new();
     cfgs.insert(name, Cow::Borrowed(&config));
@@ -260,129 +799,306 @@ pub async fn verify(path: &str) -> Result<(), failure::Error> {
             .with_context(|e| format!("Default Config does not satisfy: {}", e))?;
     }
     if manifest.has_instructions {
-        let instructions = entries
-            .next()
-            .await
-            .ok_or_else(|| format_err!("missing instructions"))??;
-        ensure!(
-            instructions.path()?.to_str() == Some("instructions.md"),
-            "Package File Invalid or Corrupted: expected instructions.md, got {}",
-            instructions.path()?.display()
-        );
+        pkg.get(s9pk::INSTRUCTIONS)?;
     }
-    for asset_info in manifest.assets {
+    for asset_info in &manifest.assets {
         validate_path(&asset_info.src)?;
         validate_path(&asset_info.dst)?;
-        let asset = entries
-            .next()
-            .await
-            .ok_or_else(|| format_err!("missing asset: {}", asset_info.src.display()))??;
-        if asset.header().entry_type().is_file() {
-            ensure!(
-                asset.path()?.to_str() == Some(&format!("{}", asset_info.src.display())),
-                "Package File Invalid or Corrupted: expected {}, got {}",
-                asset_info.src.display(),
-                asset.path()?.display()
-            );
-        } else if asset.header().entry_type().is_dir() {
-            ensure!(
-                asset.path()?.to_str() == Some(&format!("{}/", asset_info.src.display())),
-                "Package File Invalid or Corrupted: expected {}, got {}",
-                asset_info.src.display(),
-                asset.path()?.display()
-            );
-            loop {
-                let file = entries.next().await.ok_or_else(|| {
-                    format_err!(
-                        "missing directory end marker: APPMGR_DIR_END:{}",
-                        asset_info.src.display()
-                    )
-                })??;
-                if file
-                    .path()?
-                    .starts_with(format!("APPMGR_DIR_END:{}", asset_info.src.display()))
-                {
-                    break;
+        ensure!(
+            !pkg.toc
+                .files(&s9pk::asset_entry(&asset_info.src))
+                .is_empty(),
+            "Package File Invalid or Corrupted: missing asset: {}",
+            asset_info.src.display()
+        );
+    }
+    match &manifest.image {
+        ImageConfig::Tar { .. } => {
+            for arch in manifest.image.images() {
+                let name = ImageConfig::tar_name(arch);
+                log::info!("Verifying {}.", name);
+                check_image_tags(&manifest.id, &image_tags(pkg.entry(&name).await?).await?)?;
+            }
+        }
+        ImageConfig::Oci { .. } => {
+            log::info!("Verifying OCI layout.");
+            let index = crate::oci::verify(&mut pkg).await?;
+            for arch in manifest.image.images() {
+                index.image(arch)?;
+            }
+        }
+    };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const MANIFEST: &str = "compat: v0
+id: hello
+version: 0.1.0
+title: Hello
+description:
+  short: Says hello
+  long: Says hello over http
+release-notes: First release
+has-instructions: true
+ports: []
+image:
+  type: tar
+mount: /root
+assets:
+  - src: www
+    dst: .
+    overwrite: true
+  - src: instructions.md
+    dst: www
+    overwrite: true
+";
+
+    fn write(path: &Path, contents: &[u8], mode: u32) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    /// A package source directory, with files of differing modes in nested asset directories, and
+    /// an asset named like a reserved entry.
+    fn source(dir: &Path) {
+        write(&dir.join("manifest.yaml"), MANIFEST.as_bytes(), 0o644);
+        write(&dir.join("config_spec.yaml"), b"{}\n", 0o644);
+        write(&dir.join("config_rules.yaml"), b"[]\n", 0o644);
+        write(&dir.join("instructions.md"), b"# Hello\n", 0o644);
+        write(&dir.join("assets/www/index.html"), b"<p>hello</p>\n", 0o644);
+        write(&dir.join("assets/www/bin/run.sh"), b"#!/bin/sh\n", 0o755);
+        // named like a reserved entry, which it must not replace
+        write(&dir.join("assets/instructions.md"), b"# Help\n", 0o644);
+        write(&dir.join("image.tar"), &[7; 4096], 0o644);
+    }
+
+    async fn pack_dir(dir: &Path, out: &Path, key: &Path) -> Vec<u8> {
+        pack(
+            dir.to_str().unwrap(),
+            out.to_str().unwrap(),
+            key.to_str().unwrap(),
+            Compression::Gzip,
+            true,
+        )
+        .await
+        .unwrap();
+        std::fs::read(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_deterministic() {
+        let dir = crate::test_util::scratch_dir("pack-deterministic");
+        let key = dir.join("developer.key");
+        crate::signing::keygen(&key).await.unwrap();
+        let src = dir.join("hello");
+        source(&src);
+        let first = pack_dir(&src, &dir.join("first.s9pk"), &key).await;
+
+        // the same tree, written again with new timestamps and looser modes
+        std::fs::remove_dir_all(&src).unwrap();
+        source(&src);
+        std::fs::set_permissions(
+            src.join("assets/www/index.html"),
+            std::fs::Permissions::from_mode(0o664),
+        )
+        .unwrap();
+        std::fs::set_permissions(
+            src.join("assets/www/bin/run.sh"),
+            std::fs::Permissions::from_mode(0o775),
+        )
+        .unwrap();
+        let second = pack_dir(&src, &dir.join("second.s9pk"), &key).await;
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(first == second);
+    }
+
+    /// Every file below `dir`, relative to it, with its mode and contents.
+    fn tree(dir: &Path) -> Vec<(PathBuf, u32, Vec<u8>)> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir.to_owned()];
+        while let Some(d) = dirs.pop() {
+            for entry in std::fs::read_dir(&d).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
                 } else {
-                    ensure!(
-                        file.path()?
-                            .to_str()
-                            .map(|p| p.starts_with(&format!("{}/", asset_info.src.display())))
-                            .unwrap_or(false),
-                        "Package File Invalid or Corrupted: expected {}, got {}",
-                        asset_info.src.display(),
-                        asset.path()?.display()
-                    );
+                    let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+                    files.push((
+                        path.strip_prefix(dir).unwrap().to_owned(),
+                        mode,
+                        std::fs::read(&path).unwrap(),
+                    ));
                 }
             }
-        } else {
-            bail!("Asset Not Regular File: {}", asset_info.src.display());
         }
+        files.sort();
+        files
     }
-    match &manifest.image {
-        ImageConfig::Tar => {
-            #[derive(Clone, Debug, serde::Deserialize)]
-            #[serde(rename_all = "PascalCase")]
-            struct DockerManifest {
-                config: PathBuf,
-                repo_tags: Vec<String>,
-                layers: Vec<PathBuf>,
-            }
-            let image_name = format!("start9/{}", manifest.id);
-            log::debug!("Opening image.tar from archive.");
-            let image = entries
-                .next()
+
+    fn yaml<T: serde::de::DeserializeOwned + serde::Serialize>(path: &Path) -> serde_yaml::Value {
+        serde_yaml::to_value(serde_yaml::from_slice::<T>(&std::fs::read(path).unwrap()).unwrap())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unpack_round_trip() {
+        let dir = crate::test_util::scratch_dir("pack-unpack");
+        let key = dir.join("developer.key");
+        crate::signing::keygen(&key).await.unwrap();
+        let src = dir.join("hello");
+        source(&src);
+        let packed = pack_dir(&src, &dir.join("hello.s9pk"), &key).await;
+        let out = dir.join("unpacked");
+        unpack(
+            dir.join("hello.s9pk").to_str().unwrap(),
+            out.to_str().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // the yaml files are written back in canonical form, so only their values must match
+        assert_eq!(
+            yaml::<Manifest>(&src.join("manifest.yaml")),
+            yaml::<Manifest>(&out.join("manifest.yaml"))
+        );
+        assert_eq!(
+            yaml::<ConfigSpec>(&src.join("config_spec.yaml")),
+            yaml::<ConfigSpec>(&out.join("config_spec.yaml"))
+        );
+        assert_eq!(
+            yaml::<Vec<ConfigRuleEntry>>(&src.join("config_rules.yaml")),
+            yaml::<Vec<ConfigRuleEntry>>(&out.join("config_rules.yaml"))
+        );
+        let files = |dir: &Path| -> Vec<(PathBuf, u32, Vec<u8>)> {
+            tree(dir)
+                .into_iter()
+                .filter(|(path, ..)| path.extension().map(|ext| ext != "yaml").unwrap_or(true))
+                .collect()
+        };
+        assert_eq!(files(&src), files(&out));
+        let repacked = pack_dir(&out, &dir.join("repacked.s9pk"), &key).await;
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(packed == repacked);
+    }
+
+    #[tokio::test]
+    async fn test_unpack_unsafe_asset() {
+        let dir = crate::test_util::scratch_dir("pack-unsafe-asset");
+        let manifest: Manifest =
+            serde_yaml::from_str(&MANIFEST.replace("src: www", "src: /escape")).unwrap();
+        let config_spec: ConfigSpec = serde_yaml::from_str("{}").unwrap();
+        let mut writer = s9pk::Writer::new(dir.join("payload"), true).await.unwrap();
+        for (name, data) in vec![
+            (s9pk::MANIFEST, serde_cbor::to_vec(&manifest).unwrap()),
+            (s9pk::CONFIG_SPEC, serde_cbor::to_vec(&config_spec).unwrap()),
+            (
+                s9pk::CONFIG_RULES,
+                serde_cbor::to_vec(&Vec::<ConfigRuleEntry>::new()).unwrap(),
+            ),
+            (s9pk::INSTRUCTIONS, b"# Hello\n".to_vec()),
+            ("assets//escape", b"escaped\n".to_vec()),
+            ("assets/instructions.md", b"# Help\n".to_vec()),
+            ("image.tar", vec![7; 4096]),
+        ] {
+            writer
+                .append_bytes(name, &data, Compression::None)
                 .await
-                .ok_or_else(|| format_err!("missing image.tar"))??;
-            let image_path = image.path()?;
-            if image_path != Path::new("image.tar") {
-                return Err(format_err!(
-                    "Package File Invalid or Corrupted: expected image.tar, got {}",
-                    image_path.display()
-                ));
+                .unwrap();
+        }
+        let mut out = tokio::fs::File::create(dir.join("unsafe.s9pk"))
+            .await
+            .unwrap();
+        writer.finish(&mut out).await.unwrap();
+        drop(out);
+
+        let res = unpack(
+            dir.join("unsafe.s9pk").to_str().unwrap(),
+            dir.join("unpacked").to_str().unwrap(),
+        )
+        .await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_check_image_tags() {
+        let tags = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        check_image_tags("hello", &tags(&["start9/hello:latest"])).unwrap();
+        check_image_tags(
+            "hello",
+            &tags(&["start9/hello:latest", "start9/hello:0.1.0"]),
+        )
+        .unwrap();
+        assert!(check_image_tags("hello", &[]).is_err());
+        assert!(check_image_tags("hello", &tags(&["start9/bitcoind:latest"])).is_err());
+        assert!(check_image_tags("hello", &tags(&["start9/hello-world:latest"])).is_err());
+        assert!(check_image_tags("hello", &tags(&["debian:latest"])).is_err());
+        assert!(
+            check_image_tags("hello", &tags(&["start9/hello:latest", "nginx:latest"])).is_err()
+        );
+    }
+
+    async fn image_archive(path: &str, manifest: serde_json::Value) -> Vec<u8> {
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        crate::test_util::tar(&[("layer.tar", &[7; 4096]), (path, &manifest)]).await
+    }
+
+    async fn read_checked(archive: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        CheckedImage::new(archive, "hello")
+            .read_to_end(&mut out)
+            .await?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn test_checked_image() {
+        let archive = image_archive(
+            "manifest.json",
+            serde_json::json!([{ "RepoTags": ["start9/hello:latest"] }]),
+        )
+        .await;
+        assert_eq!(read_checked(&archive).await.unwrap(), archive);
+
+        let archive = image_archive(
+            "manifest.json",
+            serde_json::json!([{ "RepoTags": ["start9/bitcoind:latest"] }]),
+        )
+        .await;
+        let err = read_checked(&archive).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        // docker is never handed the end of the archive
+        let mut out = Vec::new();
+        let mut image = CheckedImage::new(&archive[..], "hello");
+        let mut buf = [0; 512];
+        while let Ok(n) = image.read(&mut buf).await {
+            if n == 0 {
+                break;
             }
-            log::info!("Verifying image.tar.");
-            let mut image_tar = tar::Archive::new(image);
-            let image_manifest = image_tar
-                .entries()?
-                .map(|e| {
-                    let e = e?;
-                    Ok((e.path()?.to_path_buf(), e))
-                })
-                .filter_map(|res: Result<(PathBuf, tar::Entry<_>), std::io::Error>| {
-                    futures::future::ready(match res {
-                        Ok((path, e)) => {
-                            if path == Path::new("manifest.json") {
-                                Some(Ok(e))
-                            } else {
-                                None
-                            }
-                        }
-                        Err(e) => Some(Err(e)),
-                    })
-                })
-                .next()
-                .await
-                .ok_or_else(|| format_err!("image.tar is missing manifest.json"))??;
-            let image_manifest: Vec<DockerManifest> =
-                from_json_async_reader(image_manifest).await?;
-            image_manifest
-                .into_iter()
-                .flat_map(|a| a.repo_tags)
-                .map(|t| {
-                    if t.starts_with("start9/") {
-                        if t.split(":").next().unwrap() != image_name {
-                            Err(format_err!("Contains prohibited image tag: {}", t))
-                        } else {
-                            Ok(())
-                        }
-                    } else {
-                        Ok(())
-                    }
-                })
-                .collect::<Result<_, _>>()?;
+            out.extend_from_slice(&buf[..n]);
         }
-    };
+        assert!(out.len() < archive.len());
 
-    Ok(())
+        // docker reads keys regardless of case, and paths as cleaned
+        let archive = image_archive(
+            "./manifest.json",
+            serde_json::json!([{ "repotags": ["start9/bitcoind:latest"] }]),
+        )
+        .await;
+        assert!(read_checked(&archive).await.is_err());
+
+        let archive = image_archive("layer.json", serde_json::json!([])).await;
+        assert!(read_checked(&archive).await.is_err());
+    }
 }
@@ -0,0 +1,176 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::Error;
+use crate::ResultExt as _;
+
+lazy_static::lazy_static! {
+    static ref OUTPUT: RwLock<Output> = RwLock::new(
+        std::env::var("APPMGR_PROGRESS")
+            .ok()
+            .and_then(|a| a.parse().ok())
+            .unwrap_or(Output::Text)
+    );
+}
+
+/// The long running operation a progress event belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    Download,
+    Install,
+    Update,
+    Backup,
+    Restore,
+}
+impl Phase {
+    fn verb(&self) -> &'static str {
+        match self {
+            Phase::Download => "Downloading",
+            Phase::Install => "Installing",
+            Phase::Update => "Updating",
+            Phase::Backup => "Backing up",
+            Phase::Restore => "Restoring",
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Progress {
+    pub phase: Phase,
+    /// what the operation is doing right now, e.g. "loading docker image"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_done: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_total: Option<u64>,
+    /// set on the last event of a phase
+    pub complete: bool,
+}
+
+/// Where progress events go. Text and JSON are written to stderr, leaving stdout to the result
+/// of the command.
+#[derive(Clone)]
+pub enum Output {
+    /// a percentage on the terminal, as `Downloading... 42%`
+    Text,
+    /// one JSON object per line
+    Json,
+    Callback(Arc<dyn Fn(&Progress) + Send + Sync>),
+}
+impl std::str::FromStr for Output {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(format_err!("Unknown Progress Output: {}", s)).no_code(),
+        }
+    }
+}
+
+pub fn set_output(output: Output) {
+    *OUTPUT.write().unwrap() = output;
+}
+
+pub async fn emit(progress: &Progress) {
+    let output = OUTPUT.read().unwrap().clone();
+    if let Output::Callback(f) = output {
+        return f(progress);
+    }
+    if *crate::QUIET.read().await {
+        return;
+    }
+    match output {
+        Output::Json => match serde_json::to_string(progress) {
+            Ok(line) => eprintln!("{}", line),
+            Err(e) => log::warn!("Failed to serialize progress: {}", e),
+        },
+        _ => match (progress.bytes_done, progress.bytes_total) {
+            (Some(_), _) if progress.complete => {
+                eprintln!("\r{}... 100%", progress.phase.verb())
+            }
+            (Some(done), Some(total)) if total > 0 => {
+                eprint!("\r{}... {}%", progress.phase.verb(), done * 100 / total)
+            }
+            (Some(done), _) => eprint!("\r{}... {}KiB", progress.phase.verb(), done / 1024),
+            // steps are already logged
+            (None, _) => (),
+        },
+    }
+}
+
+/// Reports that `phase` has moved on to `step`.
+pub async fn step<S: Into<String>>(phase: Phase, step: S) {
+    emit(&Progress {
+        phase,
+        step: Some(step.into()),
+        bytes_done: None,
+        bytes_total: None,
+        complete: false,
+    })
+    .await
+}
+
+/// Reports that `phase` is finished.
+pub async fn complete(phase: Phase) {
+    emit(&Progress {
+        phase,
+        step: None,
+        bytes_done: None,
+        bytes_total: None,
+        complete: true,
+    })
+    .await
+}
+
+/// Reports that `phase` is finished, having moved `bytes_done` of `bytes_total`.
+pub async fn complete_bytes(phase: Phase, bytes_done: Option<u64>, bytes_total: Option<u64>) {
+    emit(&Progress {
+        phase,
+        step: None,
+        bytes_done,
+        bytes_total,
+        complete: true,
+    })
+    .await
+}
+
+/// Reports `counter` bytes out of `total` until `done` is set, resolving to the last count
+/// reported. Whether `phase` is then complete is up to the caller: an attempt that failed, and
+/// may be retried, is not.
+pub fn track(
+    phase: Phase,
+    counter: Arc<AtomicU64>,
+    total: Option<u64>,
+    done: Arc<AtomicBool>,
+) -> JoinHandle<Option<u64>> {
+    tokio::spawn(async move {
+        let mut last = None;
+        loop {
+            let is_done = done.load(Ordering::SeqCst);
+            let bytes = counter.load(Ordering::SeqCst);
+            if last != Some(bytes) {
+                emit(&Progress {
+                    phase,
+                    step: None,
+                    bytes_done: Some(bytes),
+                    bytes_total: total,
+                    complete: false,
+                })
+                .await;
+                last = Some(bytes);
+            }
+            if is_done {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        last
+    })
+}
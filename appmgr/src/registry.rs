@@ -2,12 +2,20 @@ use emver::VersionRange;
 use tokio_compat_02::FutureExt;
 
 use crate::apps::AppConfig;
-use crate::manifest::ManifestLatest;
+use crate::manifest::{Manifest, ManifestLatest, ManifestV0};
 use crate::Error;
 use crate::ResultExt as _;
 
 pub async fn manifest(id: &str, version: &VersionRange) -> Result<ManifestLatest, Error> {
-    let manifest: ManifestLatest = reqwest::get(&format!(
+    /// The registry serves V0 manifests without a `compat` tag.
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum ManifestRes {
+        Tagged(Manifest),
+        V0(ManifestV0),
+    }
+
+    let manifest: ManifestRes = reqwest::get(&format!(
         "{}/manifest/{}?spec={}",
         &*crate::APP_REGISTRY_URL,
         id,
@@ -21,7 +29,10 @@ pub async fn manifest(id: &str, version: &VersionRange) -> Result<ManifestLatest
     .json()
     .await
     .with_code(crate::error::SERDE_ERROR)?;
-    Ok(manifest)
+    Ok(match manifest {
+        ManifestRes::Tagged(m) => m.into_latest(),
+        ManifestRes::V0(m) => m.into(),
+    })
 }
 
 pub async fn version(id: &str, version: &VersionRange) -> Result<emver::Version, Error> {
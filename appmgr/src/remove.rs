@@ -1,6 +1,4 @@
 use crate::failure::ResultExt;
-use std::path::Path;
-
 use linear_map::LinearMap;
 
 use crate::dependencies::{DependencyError, TaggedDependencyError};
@@ -29,35 +27,33 @@ pub async fn remove(
             LinearMap::new()
         });
     log::info!("Removing docker container.");
-    if !std::process::Command::new("docker")
-        .args(&["rm", name])
-        .stdout(std::process::Stdio::null())
-        .stderr(match log::max_level() {
-            log::LevelFilter::Error => std::process::Stdio::null(),
-            _ => std::process::Stdio::inherit(),
-        })
-        .status()?
-        .success()
-    {
-        log::error!("Failed to Remove Docker Container");
-    };
-    if !std::process::Command::new("docker")
-        .args(&["rmi", &image_name])
-        .stdout(std::process::Stdio::null())
-        .stderr(match log::max_level() {
-            log::LevelFilter::Error => std::process::Stdio::null(),
-            _ => std::process::Stdio::inherit(),
-        })
-        .status()?
-        .success()
-    {
-        log::error!("Failed to Remove Docker Image");
-    };
+    let runtime = crate::runtime::get();
+    if let Err(e) = runtime.rm(name, false).await {
+        log::error!("{}", e);
+    }
+    if let Err(e) = runtime.rmi(&image_name).await {
+        log::error!("{}", e);
+    }
+    if let Err(e) = crate::oci::prune().await {
+        log::warn!("Failed to prune image blobs: {}", e);
+    }
+    if let Err(e) = crate::install::clear_download_cache(name).await {
+        log::warn!("Failed to remove cached downloads: {}", e);
+    }
     if purge {
         log::info!("Removing tor hidden service.");
         crate::tor::rm_svc(name).await?;
         log::info!("Removing app metadata.");
-        let metadata_path = Path::new(crate::PERSISTENCE_DIR).join("apps").join(name);
+        let mut db = crate::db::transaction().await?;
+        db.remove(&crate::db::config_key(name));
+        db.remove(&crate::db::resources_key(name));
+        db.remove(&crate::db::env_key(name));
+        db.remove(&crate::db::health_key(name));
+        db.commit().await?;
+        let metadata_path = crate::context::get()
+            .persistence_dir
+            .join("apps")
+            .join(name);
         tokio::fs::remove_dir_all(&metadata_path)
             .await
             .with_context(|e| format!("rm {}: {}", metadata_path.display(), e))
@@ -65,7 +61,8 @@ pub async fn remove(
         log::info!("Unbinding shared filesystem.");
         let installed_apps = crate::apps::list_info().await?;
         for (dep, _) in manifest.dependencies.0.iter() {
-            let path = Path::new(crate::VOLUMES)
+            let path = crate::context::get()
+                .volumes
                 .join(name)
                 .join("start9")
                 .join("public")
@@ -75,7 +72,8 @@ pub async fn remove(
             } else {
                 log::warn!("{} does not exist, skipping...", path.display());
             }
-            let path = Path::new(crate::VOLUMES)
+            let path = crate::context::get()
+                .volumes
                 .join(name)
                 .join("start9")
                 .join("shared")
@@ -87,8 +85,12 @@ pub async fn remove(
             }
             if installed_apps.contains_key(dep) {
                 let dep_man = crate::apps::manifest(dep).await?;
-                if let Some(shared) = dep_man.shared {
-                    let path = Path::new(crate::VOLUMES).join(dep).join(&shared).join(name);
+                if let Some(shared) = dep_man.volumes.shared {
+                    let path = crate::context::get()
+                        .volumes
+                        .join(dep)
+                        .join(&shared)
+                        .join(name);
                     if path.exists() {
                         tokio::fs::remove_dir_all(&path)
                             .await
@@ -100,9 +102,10 @@ pub async fn remove(
                 log::warn!("{} is not installed, skipping...", dep);
             }
         }
-        if manifest.public.is_some() || manifest.shared.is_some() {
+        if manifest.volumes.public.is_some() || manifest.volumes.shared.is_some() {
             for dependent in crate::apps::dependents(name, false).await? {
-                let path = Path::new(crate::VOLUMES)
+                let path = crate::context::get()
+                    .volumes
                     .join(&dependent)
                     .join("start9")
                     .join("public")
@@ -112,7 +115,8 @@ pub async fn remove(
                 } else {
                     log::warn!("{} does not exist, skipping...", path.display());
                 }
-                let path = Path::new(crate::VOLUMES)
+                let path = crate::context::get()
+                    .volumes
                     .join(dependent)
                     .join("start9")
                     .join("shared")
@@ -125,26 +129,27 @@ pub async fn remove(
             }
         }
         log::info!("Destroying mounted volume.");
-        let volume_path = Path::new(crate::VOLUMES).join(name);
+        let volume_path = crate::context::get().volumes.join(name);
         tokio::fs::remove_dir_all(&volume_path)
             .await
             .with_context(|e| format!("rm {}: {}", volume_path.display(), e))
             .with_code(crate::error::FILESYSTEM_ERROR)?;
+        let named_volumes_path = crate::context::get().named_volumes(name);
+        if named_volumes_path.exists() {
+            tokio::fs::remove_dir_all(&named_volumes_path)
+                .await
+                .with_context(|e| format!("rm {}: {}", named_volumes_path.display(), e))
+                .with_code(crate::error::FILESYSTEM_ERROR)?;
+        }
         log::info!("Pruning unused docker images.");
-        crate::ensure_code!(
-            std::process::Command::new("docker")
-                .args(&["image", "prune", "-a", "-f"])
-                .stdout(std::process::Stdio::null())
-                .stderr(match log::max_level() {
-                    log::LevelFilter::Error => std::process::Stdio::null(),
-                    _ => std::process::Stdio::inherit(),
-                })
-                .status()?
-                .success(),
-            crate::error::DOCKER_ERROR,
-            "Failed to Prune Docker Images"
-        );
+        runtime.prune_images().await?;
     };
+    log::info!("Updating firewall.");
+    // the app is already gone, so this is not worth failing the remove over: the rules between
+    // the apps that are left are loaded again at boot
+    if let Err(e) = crate::firewall::reload().await {
+        log::error!("Failed to update firewall: {}", e);
+    }
 
     Ok(res)
 }
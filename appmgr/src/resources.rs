@@ -0,0 +1,88 @@
+use crate::manifest::Resources;
+use crate::Error;
+use crate::ResultExt as _;
+
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AppResources {
+    /// what the app runs with
+    pub limits: Resources,
+    /// the part of `limits` set by the user rather than the manifest
+    pub overrides: Resources,
+}
+
+/// The limits the user has set on `id`.
+pub async fn overrides(id: &str) -> Result<Resources, Error> {
+    crate::db::get_or_default(&crate::db::resources_key(id)).await
+}
+
+/// The limits a container for `id` is created with: those of `manifest`, with the user's
+/// overrides in their place. Overrides that no longer fit the manifest, e.g. a reservation above
+/// a new version's memory limit, are ignored.
+pub async fn effective(id: &str, manifest: &Resources) -> Result<Resources, Error> {
+    let limits = manifest.overlay(&overrides(id).await?);
+    match limits.validate() {
+        Ok(()) => Ok(limits),
+        Err(e) => {
+            log::warn!("Ignoring resource limits set on {}: {}", id, e);
+            Ok(manifest.clone())
+        }
+    }
+}
+
+pub async fn get(id: &str) -> Result<AppResources, Error> {
+    crate::ensure_code!(
+        crate::apps::list_info().await?.contains_key(id),
+        crate::error::NOT_FOUND,
+        "{} is not installed",
+        id
+    );
+    let manifest = crate::apps::manifest(id).await?;
+    let overrides = overrides(id).await?;
+    Ok(AppResources {
+        limits: manifest.resources.overlay(&overrides),
+        overrides,
+    })
+}
+
+/// Sets the limits in `overrides` on `id`, on top of those already set unless `reset`, and
+/// applies them to its container without recreating it.
+pub async fn set(id: &str, overrides: &Resources, reset: bool) -> Result<AppResources, Error> {
+    crate::ensure_code!(
+        overrides.shm_size_mb.is_none(),
+        crate::error::GENERAL_ERROR,
+        "Shared Memory Size Cannot Be Changed Without Reinstalling"
+    );
+    let prev = get(id).await?;
+    let manifest = crate::apps::manifest(id).await?;
+    let overrides = if reset {
+        overrides.clone()
+    } else {
+        prev.overrides.overlay(overrides)
+    };
+    let limits = manifest.resources.overlay(&overrides);
+    limits.validate().with_code(crate::error::GENERAL_ERROR)?;
+
+    let mut db = crate::db::transaction().await?;
+    log::info!("Updating resource limits of {}.", id);
+    crate::runtime::get().update(id, &limits).await?;
+    if (prev.limits.memory_limit_mb.is_some() && limits.memory_limit_mb.is_none())
+        || (prev.limits.memory_reservation_mb.is_some() && limits.memory_reservation_mb.is_none())
+        || (prev.limits.cpu_shares.is_some() && limits.cpu_shares.is_none())
+        || (prev.limits.cpus.is_some() && limits.cpus.is_none())
+        || (prev.limits.pids_limit.is_some() && limits.pids_limit.is_none())
+    {
+        log::warn!(
+            "Limits lifted from {} stay in place until it is next installed.",
+            id
+        );
+    }
+    let key = crate::db::resources_key(id);
+    if overrides == Resources::default() {
+        db.remove(&key);
+    } else {
+        db.put(&key, &overrides)?;
+    }
+    db.commit().await?;
+    Ok(AppResources { limits, overrides })
+}
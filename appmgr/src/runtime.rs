@@ -0,0 +1,873 @@
+use std::ffi::OsString;
+use std::net::Ipv4Addr;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use futures::stream::TryStreamExt;
+use linear_map::set::LinearSet;
+use linear_map::LinearMap;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_tar as tar;
+
+use crate::logs::LogOptions;
+use crate::manifest::{Resources, Security};
+use crate::Error;
+use crate::ResultExt as _;
+
+lazy_static::lazy_static! {
+    static ref RUNTIME: RwLock<Arc<dyn Runtime>> = RwLock::new(Arc::new(Docker));
+}
+
+/// The runtime apps are run with, docker unless replaced with `set`.
+pub fn get() -> Arc<dyn Runtime> {
+    RUNTIME.read().unwrap().clone()
+}
+
+pub fn set(runtime: Arc<dyn Runtime>) {
+    *RUNTIME.write().unwrap() = runtime;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContainerStatus {
+    Created,
+    Running,
+    Paused,
+    Restarting,
+    Removing,
+    Exited,
+    Dead,
+}
+impl std::str::FromStr for ContainerStatus {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "created" => ContainerStatus::Created,
+            "running" => ContainerStatus::Running,
+            "paused" => ContainerStatus::Paused,
+            "restarting" => ContainerStatus::Restarting,
+            "removing" => ContainerStatus::Removing,
+            "exited" => ContainerStatus::Exited,
+            "dead" => ContainerStatus::Dead,
+            _ => return Err(format_err!("unknown status: {}", s)).no_code(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ContainerState {
+    pub status: ContainerStatus,
+    /// opaque, but different for every run of the container
+    pub started_at: String,
+    /// opaque, but different for every exit of the container
+    pub finished_at: String,
+    pub exit_code: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Mount {
+    pub src: PathBuf,
+    pub dst: PathBuf,
+    pub readonly: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Tmpfs {
+    pub dst: PathBuf,
+    pub size_mb: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CreateOptions {
+    pub name: String,
+    pub image: String,
+    pub mounts: Vec<Mount>,
+    pub tmpfs: Vec<Tmpfs>,
+    /// the network to join, and the container's address on it
+    pub network: Option<(String, Ipv4Addr)>,
+    pub env: LinearMap<String, String>,
+    pub resources: Resources,
+    pub security: Security,
+    /// overrides the image's entrypoint
+    pub entrypoint: Option<String>,
+    pub command: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ExecOutput {
+    /// the exit code, or 128 plus the signal that killed the process
+    pub code: i32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+impl ExecOutput {
+    pub fn success(&self) -> bool {
+        self.code == 0
+    }
+}
+
+/// Everything appmgr asks of the container engine its apps run in.
+#[async_trait]
+pub trait Runtime: Send + Sync {
+    async fn create(&self, options: &CreateOptions) -> Result<(), Error>;
+    async fn start(&self, name: &str) -> Result<(), Error>;
+    async fn stop(&self, name: &str, timeout_secs: u64) -> Result<(), Error>;
+    async fn pause(&self, name: &str) -> Result<(), Error>;
+    async fn resume(&self, name: &str) -> Result<(), Error>;
+    async fn inspect(&self, name: &str) -> Result<ContainerState, Error>;
+    async fn rename(&self, name: &str, new_name: &str) -> Result<(), Error>;
+    /// Changes the limits of a container, running or not. The shared memory size cannot be
+    /// changed, nor can a limit be lifted: those left unset in `resources` are kept.
+    async fn update(&self, name: &str, resources: &Resources) -> Result<(), Error>;
+    /// `force` removes the container even if it is running
+    async fn rm(&self, name: &str, force: bool) -> Result<(), Error>;
+    /// Runs `command` in a running container.
+    async fn exec(&self, name: &str, command: &[String]) -> Result<ExecOutput, Error>;
+    /// Runs a one-off container, removed when it exits.
+    async fn run(&self, options: &CreateOptions) -> Result<ExecOutput, Error>;
+    /// Writes the logs of a container to stdout.
+    async fn logs(&self, name: &str, options: &LogOptions<&str, &str>) -> Result<(), Error>;
+    /// Loads images from a tar archive in the format of `docker save`, or an OCI layout with a
+    /// `manifest.json`. Nothing is loaded if reading `archive` fails.
+    async fn load(&self, archive: &mut (dyn AsyncRead + Unpin + Send)) -> Result<(), Error>;
+    async fn image_exists(&self, image: &str) -> Result<bool, Error>;
+    async fn tag(&self, image: &str, tag: &str) -> Result<(), Error>;
+    async fn rmi(&self, image: &str) -> Result<(), Error>;
+    /// Removes every image no container uses.
+    async fn prune_images(&self) -> Result<(), Error>;
+    /// The name of every container, running or not, with the image it was created from.
+    async fn list_containers(&self) -> Result<Vec<(String, String)>, Error>;
+    /// Every tagged image, as `repository:tag`.
+    async fn list_images(&self) -> Result<Vec<String>, Error>;
+}
+
+fn stderr() -> Stdio {
+    match log::max_level() {
+        log::LevelFilter::Error => Stdio::null(),
+        _ => Stdio::inherit(),
+    }
+}
+
+fn exec_output(output: std::process::Output) -> ExecOutput {
+    ExecOutput {
+        code: output
+            .status
+            .code()
+            .unwrap_or_else(|| output.status.signal().unwrap_or(0) + 128),
+        stdout: output.stdout,
+        stderr: output.stderr,
+    }
+}
+
+fn container_args(options: &CreateOptions) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![
+        "--restart".into(),
+        "no".into(),
+        "--name".into(),
+        options.name.clone().into(),
+    ];
+    for mount in &options.mounts {
+        args.push("--mount".into());
+        args.push(
+            format!(
+                "type=bind,src={},dst={}{}",
+                mount.src.display(),
+                mount.dst.display(),
+                if mount.readonly { ",readonly" } else { "" }
+            )
+            .into(),
+        );
+    }
+    for tmpfs in &options.tmpfs {
+        args.push("--mount".into());
+        args.push(
+            format!(
+                "type=tmpfs,dst={}{}",
+                tmpfs.dst.display(),
+                match tmpfs.size_mb {
+                    Some(size_mb) => format!(",tmpfs-size={}", size_mb * 1024 * 1024),
+                    None => String::new(),
+                }
+            )
+            .into(),
+        );
+    }
+    if let Some((network, ip)) = &options.network {
+        args.push("--net".into());
+        args.push(network.into());
+        args.push("--ip".into());
+        args.push(format!("{}", ip).into());
+    }
+    for (key, value) in &options.env {
+        args.push("--env".into());
+        args.push(format!("{}={}", key, value).into());
+    }
+    if let Some(shm_size_mb) = options.resources.shm_size_mb {
+        args.push("--shm-size".into());
+        args.push(format!("{}m", shm_size_mb).into());
+    }
+    args.extend(limit_args(&options.resources));
+    if let Some(user) = options.security.user_spec() {
+        args.push("--user".into());
+        args.push(user.into());
+    }
+    for cap in &options.security.cap_drop {
+        args.push("--cap-drop".into());
+        args.push(cap.into());
+    }
+    for cap in &options.security.cap_add {
+        args.push("--cap-add".into());
+        args.push(cap.into());
+    }
+    if options.security.read_only_rootfs {
+        args.push("--read-only".into());
+    }
+    if options.security.no_new_privileges {
+        args.push("--security-opt".into());
+        args.push("no-new-privileges".into());
+    }
+    if let Some(entrypoint) = &options.entrypoint {
+        args.push("--entrypoint".into());
+        args.push(entrypoint.into());
+    }
+    args.push(options.image.clone().into());
+    args.extend(options.command.iter().map(OsString::from));
+    args
+}
+
+/// The arguments of `docker create` and `docker update` that set `resources`, but for the shared
+/// memory size, which only `docker create` takes.
+fn limit_args(resources: &Resources) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+    if let Some(memory_limit_mb) = resources.memory_limit_mb {
+        args.push("--memory".into());
+        args.push(format!("{}m", memory_limit_mb).into());
+        // docker's default, given explicitly so that a raised limit does not exceed the swap
+        // limit the container was created with
+        args.push("--memory-swap".into());
+        args.push(format!("{}m", memory_limit_mb * 2).into());
+    }
+    if let Some(memory_reservation_mb) = resources.memory_reservation_mb {
+        args.push("--memory-reservation".into());
+        args.push(format!("{}m", memory_reservation_mb).into());
+    }
+    if let Some(cpu_shares) = resources.cpu_shares {
+        args.push("--cpu-shares".into());
+        args.push(format!("{}", cpu_shares).into());
+    }
+    if let Some(cpus) = resources.cpus {
+        args.push("--cpus".into());
+        args.push(format!("{}", cpus).into());
+    }
+    if let Some(pids_limit) = resources.pids_limit {
+        args.push("--pids-limit".into());
+        args.push(format!("{}", pids_limit).into());
+    }
+    args
+}
+
+/// The docker CLI.
+pub struct Docker;
+impl Docker {
+    async fn docker<I: IntoIterator<Item = S>, S: AsRef<std::ffi::OsStr>>(
+        args: I,
+        what: String,
+    ) -> Result<(), Error> {
+        let output = tokio::process::Command::new("docker")
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+        crate::ensure_code!(
+            output.status.success(),
+            crate::error::DOCKER_ERROR,
+            "Failed to {}: {}",
+            what,
+            std::str::from_utf8(&output.stderr)
+                .unwrap_or("Unknown Error")
+                .trim()
+        );
+        Ok(())
+    }
+}
+#[async_trait]
+impl Runtime for Docker {
+    async fn create(&self, options: &CreateOptions) -> Result<(), Error> {
+        let mut args = vec![OsString::from("create")];
+        args.extend(container_args(options));
+        Docker::docker(args, format!("Create Container {}", options.name)).await
+    }
+    async fn start(&self, name: &str) -> Result<(), Error> {
+        Docker::docker(&["start", name], format!("Start {}", name)).await
+    }
+    async fn stop(&self, name: &str, timeout_secs: u64) -> Result<(), Error> {
+        Docker::docker(
+            &["stop", "-t", &format!("{}", timeout_secs), name],
+            format!("Stop {}", name),
+        )
+        .await
+    }
+    async fn pause(&self, name: &str) -> Result<(), Error> {
+        Docker::docker(&["pause", name], format!("Pause {}", name)).await
+    }
+    async fn resume(&self, name: &str) -> Result<(), Error> {
+        Docker::docker(&["unpause", name], format!("Resume {}", name)).await
+    }
+    async fn inspect(&self, name: &str) -> Result<ContainerState, Error> {
+        let output = tokio::process::Command::new("docker")
+            .args(&[
+                "inspect",
+                name,
+                "--format",
+                "{{.State.Status}} {{.State.StartedAt}} {{.State.FinishedAt}} {{.State.ExitCode}}",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+        crate::ensure_code!(
+            output.status.success(),
+            crate::error::DOCKER_ERROR,
+            "{}: Docker Error: {}",
+            name,
+            std::str::from_utf8(&output.stderr)
+                .unwrap_or("Unknown Error")
+                .trim()
+        );
+        let mut state = std::str::from_utf8(&output.stdout)
+            .no_code()?
+            .split_whitespace();
+        Ok(ContainerState {
+            status: state.next().unwrap_or_default().parse()?,
+            started_at: state.next().unwrap_or_default().to_owned(),
+            finished_at: state.next().unwrap_or_default().to_owned(),
+            exit_code: state.next().and_then(|a| a.parse().ok()).unwrap_or(-1),
+        })
+    }
+    async fn rename(&self, name: &str, new_name: &str) -> Result<(), Error> {
+        Docker::docker(
+            &["rename", name, new_name],
+            format!("Rename {} to {}", name, new_name),
+        )
+        .await
+    }
+    async fn update(&self, name: &str, resources: &Resources) -> Result<(), Error> {
+        let mut args = vec![OsString::from("update")];
+        args.extend(limit_args(resources));
+        args.push(name.into());
+        Docker::docker(args, format!("Update {}", name)).await
+    }
+    async fn rm(&self, name: &str, force: bool) -> Result<(), Error> {
+        if force {
+            Docker::docker(&["rm", "-f", name], format!("Remove {}", name)).await
+        } else {
+            Docker::docker(&["rm", name], format!("Remove {}", name)).await
+        }
+    }
+    async fn exec(&self, name: &str, command: &[String]) -> Result<ExecOutput, Error> {
+        let output = tokio::process::Command::new("docker")
+            .arg("exec")
+            .arg(name)
+            .args(command)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        Ok(exec_output(output))
+    }
+    async fn run(&self, options: &CreateOptions) -> Result<ExecOutput, Error> {
+        let output = tokio::process::Command::new("docker")
+            .arg("run")
+            .arg("--rm")
+            .args(container_args(options))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        Ok(exec_output(output))
+    }
+    async fn logs(&self, name: &str, options: &LogOptions<&str, &str>) -> Result<(), Error> {
+        let mut args = vec![OsString::from("logs")];
+        if options.details {
+            args.push("--details".into());
+        }
+        if options.follow {
+            args.push("-f".into());
+        }
+        if let Some(since) = options.since {
+            args.push("--since".into());
+            args.push(since.into());
+        }
+        if let Some(until) = options.until {
+            args.push("--until".into());
+            args.push(until.into());
+        }
+        if let Some(tail) = options.tail {
+            args.push("--tail".into());
+            args.push(format!("{}", tail).into());
+        }
+        if options.timestamps {
+            args.push("-t".into());
+        }
+        args.push(name.into());
+        crate::ensure_code!(
+            tokio::process::Command::new("docker")
+                .args(args)
+                .status()
+                .await?
+                .success(),
+            crate::error::DOCKER_ERROR,
+            "Failed to Collect Logs from Docker"
+        );
+        Ok(())
+    }
+    async fn load(&self, archive: &mut (dyn AsyncRead + Unpin + Send)) -> Result<(), Error> {
+        let mut child = tokio::process::Command::new("docker")
+            .arg("load")
+            .stdin(Stdio::piped())
+            // stdout carries progress events
+            .stdout(Stdio::null())
+            .stderr(stderr())
+            .spawn()?;
+        let mut child_in = child.stdin.take().unwrap();
+        if let Err(e) = tokio::io::copy(archive, &mut child_in).await {
+            // docker would take the end of its input for the end of the archive, and load what it
+            // has been given so far
+            child.kill().await?;
+            return Err(e.into());
+        }
+        drop(child_in);
+        crate::ensure_code!(
+            child.wait().await?.success(),
+            crate::error::DOCKER_ERROR,
+            "Failed to Load Docker Image"
+        );
+        Ok(())
+    }
+    async fn image_exists(&self, image: &str) -> Result<bool, Error> {
+        Ok(tokio::process::Command::new("docker")
+            .args(&["images", "-q", image])
+            .stderr(stderr())
+            .output()
+            .await?
+            .stdout
+            .len()
+            > 0)
+    }
+    async fn tag(&self, image: &str, tag: &str) -> Result<(), Error> {
+        Docker::docker(&["tag", image, tag], format!("Tag {} as {}", image, tag)).await
+    }
+    async fn rmi(&self, image: &str) -> Result<(), Error> {
+        Docker::docker(&["rmi", image], format!("Remove Image {}", image)).await
+    }
+    async fn prune_images(&self) -> Result<(), Error> {
+        Docker::docker(
+            &["image", "prune", "-a", "-f"],
+            format!("Prune Docker Images"),
+        )
+        .await
+    }
+    async fn list_containers(&self) -> Result<Vec<(String, String)>, Error> {
+        let output = tokio::process::Command::new("docker")
+            .args(&["ps", "-a", "--format", "{{.Names}}\t{{.Image}}"])
+            .stderr(stderr())
+            .output()
+            .await?;
+        crate::ensure_code!(
+            output.status.success(),
+            crate::error::DOCKER_ERROR,
+            "Failed to List Containers"
+        );
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut split = line.splitn(2, '\t');
+                Some((split.next()?.to_owned(), split.next()?.to_owned()))
+            })
+            .collect())
+    }
+    async fn list_images(&self) -> Result<Vec<String>, Error> {
+        let output = tokio::process::Command::new("docker")
+            .args(&["images", "--format", "{{.Repository}}:{{.Tag}}"])
+            .stderr(stderr())
+            .output()
+            .await?;
+        crate::ensure_code!(
+            output.status.success(),
+            crate::error::DOCKER_ERROR,
+            "Failed to List Images"
+        );
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.contains("<none>"))
+            .map(|line| line.to_owned())
+            .collect())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FakeContainer {
+    pub options: CreateOptions,
+    pub state: ContainerState,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    containers: LinearMap<String, FakeContainer>,
+    images: LinearSet<String>,
+    execs: Vec<(String, Vec<String>)>,
+}
+
+/// A runtime that only keeps track of what it was asked to do, so that flows which manage
+/// containers can be tested without docker. Commands "run" in it succeed without output.
+#[derive(Debug, Default)]
+pub struct FakeRuntime {
+    state: Mutex<FakeState>,
+}
+impl FakeRuntime {
+    pub fn containers(&self) -> LinearMap<String, FakeContainer> {
+        self.state.lock().unwrap().containers.clone()
+    }
+    pub fn images(&self) -> LinearSet<String> {
+        self.state.lock().unwrap().images.clone()
+    }
+    /// Every command passed to `exec` so far, with the container it was run in.
+    pub fn execs(&self) -> Vec<(String, Vec<String>)> {
+        self.state.lock().unwrap().execs.clone()
+    }
+    pub fn add_image(&self, image: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .images
+            .insert(Self::qualify(image));
+    }
+    /// Makes a running container exit on its own with `exit_code`.
+    pub fn kill(&self, name: &str, exit_code: i32) -> Result<(), Error> {
+        self.with_container(name, |c| {
+            c.state.status = ContainerStatus::Exited;
+            c.state.finished_at = Self::now();
+            c.state.exit_code = exit_code;
+            Ok(())
+        })
+    }
+
+    fn qualify(image: &str) -> String {
+        if image.contains(':') {
+            image.to_owned()
+        } else {
+            format!("{}:latest", image)
+        }
+    }
+    fn now() -> String {
+        format!(
+            "{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|a| a.as_nanos())
+                .unwrap_or(0)
+        )
+    }
+    fn with_container<T, F: FnOnce(&mut FakeContainer) -> Result<T, Error>>(
+        &self,
+        name: &str,
+        f: F,
+    ) -> Result<T, Error> {
+        match self.state.lock().unwrap().containers.get_mut(name) {
+            Some(c) => f(c),
+            None => Err(format_err!("No such container: {}", name))
+                .with_code(crate::error::DOCKER_ERROR),
+        }
+    }
+    fn set_status(
+        &self,
+        name: &str,
+        from: &[ContainerStatus],
+        to: ContainerStatus,
+    ) -> Result<(), Error> {
+        self.with_container(name, |c| {
+            crate::ensure_code!(
+                from.contains(&c.state.status),
+                crate::error::DOCKER_ERROR,
+                "{} is {:?}",
+                name,
+                c.state.status
+            );
+            c.state.status = to;
+            Ok(())
+        })
+    }
+}
+#[async_trait]
+impl Runtime for FakeRuntime {
+    async fn create(&self, options: &CreateOptions) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        crate::ensure_code!(
+            !state.containers.contains_key(&options.name),
+            crate::error::DOCKER_ERROR,
+            "Container Already Exists: {}",
+            options.name
+        );
+        crate::ensure_code!(
+            state.images.contains(&Self::qualify(&options.image)),
+            crate::error::DOCKER_ERROR,
+            "No such image: {}",
+            options.image
+        );
+        state.containers.insert(
+            options.name.clone(),
+            FakeContainer {
+                options: options.clone(),
+                state: ContainerState {
+                    status: ContainerStatus::Created,
+                    started_at: String::new(),
+                    finished_at: String::new(),
+                    exit_code: 0,
+                },
+            },
+        );
+        Ok(())
+    }
+    async fn start(&self, name: &str) -> Result<(), Error> {
+        self.with_container(name, |c| {
+            if c.state.status != ContainerStatus::Running {
+                c.state.status = ContainerStatus::Running;
+                c.state.started_at = Self::now();
+            }
+            Ok(())
+        })
+    }
+    async fn stop(&self, name: &str, _timeout_secs: u64) -> Result<(), Error> {
+        self.with_container(name, |c| {
+            if c.state.status == ContainerStatus::Running
+                || c.state.status == ContainerStatus::Paused
+            {
+                c.state.status = ContainerStatus::Exited;
+                c.state.finished_at = Self::now();
+                c.state.exit_code = 0;
+            }
+            Ok(())
+        })
+    }
+    async fn pause(&self, name: &str) -> Result<(), Error> {
+        self.set_status(name, &[ContainerStatus::Running], ContainerStatus::Paused)
+    }
+    async fn resume(&self, name: &str) -> Result<(), Error> {
+        self.set_status(name, &[ContainerStatus::Paused], ContainerStatus::Running)
+    }
+    async fn inspect(&self, name: &str) -> Result<ContainerState, Error> {
+        self.with_container(name, |c| Ok(c.state.clone()))
+    }
+    async fn rename(&self, name: &str, new_name: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        crate::ensure_code!(
+            !state.containers.contains_key(new_name),
+            crate::error::DOCKER_ERROR,
+            "Container Already Exists: {}",
+            new_name
+        );
+        let mut container = state
+            .containers
+            .remove(name)
+            .ok_or_else(|| format_err!("No such container: {}", name))
+            .with_code(crate::error::DOCKER_ERROR)?;
+        container.options.name = new_name.to_owned();
+        state.containers.insert(new_name.to_owned(), container);
+        Ok(())
+    }
+    async fn update(&self, name: &str, resources: &Resources) -> Result<(), Error> {
+        self.with_container(name, |c| {
+            c.options.resources = Resources {
+                shm_size_mb: c.options.resources.shm_size_mb,
+                ..c.options.resources.overlay(resources)
+            };
+            Ok(())
+        })
+    }
+    async fn rm(&self, name: &str, force: bool) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        match state.containers.get(name) {
+            Some(c) => crate::ensure_code!(
+                force || c.state.status != ContainerStatus::Running,
+                crate::error::DOCKER_ERROR,
+                "Cannot Remove Running Container: {}",
+                name
+            ),
+            None => {
+                return Err(format_err!("No such container: {}", name))
+                    .with_code(crate::error::DOCKER_ERROR)
+            }
+        }
+        state.containers.remove(name);
+        Ok(())
+    }
+    async fn exec(&self, name: &str, command: &[String]) -> Result<ExecOutput, Error> {
+        self.with_container(name, |c| {
+            crate::ensure_code!(
+                c.state.status == ContainerStatus::Running,
+                crate::error::DOCKER_ERROR,
+                "Container {} is not running",
+                name
+            );
+            Ok(())
+        })?;
+        self.state
+            .lock()
+            .unwrap()
+            .execs
+            .push((name.to_owned(), command.to_vec()));
+        Ok(ExecOutput {
+            code: 0,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        })
+    }
+    async fn run(&self, options: &CreateOptions) -> Result<ExecOutput, Error> {
+        let mut state = self.state.lock().unwrap();
+        crate::ensure_code!(
+            state.images.contains(&Self::qualify(&options.image)),
+            crate::error::DOCKER_ERROR,
+            "No such image: {}",
+            options.image
+        );
+        let mut command: Vec<String> = options.entrypoint.iter().cloned().collect();
+        command.extend(options.command.iter().cloned());
+        state.execs.push((options.name.clone(), command));
+        Ok(ExecOutput {
+            code: 0,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        })
+    }
+    async fn logs(&self, name: &str, _options: &LogOptions<&str, &str>) -> Result<(), Error> {
+        self.with_container(name, |_| Ok(()))
+    }
+    async fn load(&self, archive: &mut (dyn AsyncRead + Unpin + Send)) -> Result<(), Error> {
+        #[derive(serde::Deserialize)]
+        struct ManifestEntry {
+            #[serde(rename = "RepoTags")]
+            #[serde(default)]
+            repo_tags: Vec<String>,
+        }
+        let mut tags = Vec::new();
+        let mut entries = tar::Archive::new(archive).entries()?;
+        while let Some(mut entry) = entries.try_next().await? {
+            if entry.path()?.to_str() == Some("manifest.json") {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).await?;
+                let manifest: Vec<ManifestEntry> =
+                    serde_json::from_slice(&data).with_code(crate::error::SERDE_ERROR)?;
+                tags.extend(manifest.into_iter().flat_map(|m| m.repo_tags));
+            }
+        }
+        crate::ensure_code!(
+            !tags.is_empty(),
+            crate::error::DOCKER_ERROR,
+            "Failed to Load Docker Image: no tagged image in archive"
+        );
+        let mut state = self.state.lock().unwrap();
+        for tag in tags {
+            state.images.insert(Self::qualify(&tag));
+        }
+        Ok(())
+    }
+    async fn image_exists(&self, image: &str) -> Result<bool, Error> {
+        let images = &self.state.lock().unwrap().images;
+        Ok(if image.contains(':') {
+            images.contains(image)
+        } else {
+            images
+                .iter()
+                .any(|i| i.rsplitn(2, ':').nth(1) == Some(image))
+        })
+    }
+    async fn tag(&self, image: &str, tag: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        crate::ensure_code!(
+            state.images.contains(&Self::qualify(image)),
+            crate::error::DOCKER_ERROR,
+            "No such image: {}",
+            image
+        );
+        state.images.insert(Self::qualify(tag));
+        Ok(())
+    }
+    async fn rmi(&self, image: &str) -> Result<(), Error> {
+        crate::ensure_code!(
+            self.state
+                .lock()
+                .unwrap()
+                .images
+                .remove(&Self::qualify(image)),
+            crate::error::DOCKER_ERROR,
+            "No such image: {}",
+            image
+        );
+        Ok(())
+    }
+    async fn prune_images(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let used: LinearSet<String> = state
+            .containers
+            .values()
+            .map(|c| Self::qualify(&c.options.image))
+            .collect();
+        state.images.retain(|i| used.contains(i));
+        Ok(())
+    }
+    async fn list_containers(&self) -> Result<Vec<(String, String)>, Error> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .containers
+            .iter()
+            .map(|(name, c)| (name.clone(), c.options.image.clone()))
+            .collect())
+    }
+    async fn list_images(&self) -> Result<Vec<String>, Error> {
+        Ok(self.state.lock().unwrap().images.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::image_archive;
+
+    #[tokio::test]
+    async fn test_fake_lifecycle() {
+        let runtime = FakeRuntime::default();
+        let archive = image_archive("start9/foo:latest").await;
+        runtime
+            .load(&mut std::io::Cursor::new(archive))
+            .await
+            .unwrap();
+        assert!(runtime.image_exists("start9/foo").await.unwrap());
+        runtime
+            .create(&CreateOptions {
+                name: "foo".to_owned(),
+                image: "start9/foo:latest".to_owned(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        runtime.start("foo").await.unwrap();
+        let command = vec!["true".to_owned()];
+        assert!(runtime.exec("foo", &command).await.unwrap().success());
+        assert_eq!(runtime.execs(), vec![("foo".to_owned(), command)]);
+        runtime.kill("foo", 137).unwrap();
+        let state = runtime.inspect("foo").await.unwrap();
+        assert_eq!(state.status, ContainerStatus::Exited);
+        assert_eq!(state.exit_code, 137);
+        runtime.rm("foo", false).await.unwrap();
+        runtime.prune_images().await.unwrap();
+        assert!(runtime.images().is_empty());
+    }
+}
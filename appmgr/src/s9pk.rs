@@ -0,0 +1,704 @@
+use std::io::SeekFrom;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use failure::ResultExt as _;
+use futures::future::{BoxFuture, FutureExt};
+use linear_map::{set::LinearSet, LinearMap};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf, Take,
+};
+
+use crate::compression::Compression;
+use crate::manifest::Manifest;
+use crate::signing::{SIGNATURE_MAGIC, SIGNATURE_SECTION_LEN};
+use crate::util::from_cbor_async_reader;
+use crate::Error;
+use crate::ResultExt as _;
+
+pub const MAGIC: &'static [u8; 4] = b"S9PK";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: u64 = 9;
+/// Bounds the table of contents so a corrupted header cannot make readers allocate without
+/// limit.
+pub const MAX_TOC_LEN: u32 = 16 * 1024 * 1024;
+
+pub const MANIFEST: &'static str = "manifest.cbor";
+pub const CONFIG_SPEC: &'static str = "config_spec.cbor";
+pub const CONFIG_RULES: &'static str = "config_rules.cbor";
+pub const INSTRUCTIONS: &'static str = "instructions.md";
+/// Assets are stored below this directory, so their names cannot collide with the entries above.
+pub const ASSETS: &'static str = "assets";
+
+/// The name of the entry, or directory of entries, the asset `src` is stored as.
+pub fn asset_entry<P: AsRef<Path>>(src: P) -> String {
+    format!("{}/{}", ASSETS, src.as_ref().display())
+}
+
+/// Where an entry's stored bytes live in the payload, and how to read them back.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TocEntry {
+    pub offset: u64,
+    pub length: u64,
+    /// hex encoded SHA-256 of the stored, possibly compressed, bytes
+    pub sha256: String,
+    #[serde(default)]
+    pub compression: Compression,
+    pub mode: u32,
+}
+
+/// An s9pk is laid out as a fixed size header, the table of contents, and the payload: the
+/// stored bytes of every entry, back to back, in table of contents order. The header is
+/// `S9PK`, the format version, and the big endian `u32` length of the CBOR encoded table of
+/// contents. Entry offsets are relative to the start of the payload. The signature section
+/// follows the payload.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct Toc(pub LinearMap<String, TocEntry>);
+impl Toc {
+    /// The entries below the directory `dir`, in payload order.
+    pub fn dir<'a>(&'a self, dir: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.0.keys().filter(move |name| {
+            name.strip_prefix(dir)
+                .map(|rest| rest.starts_with("/"))
+                .unwrap_or(false)
+        })
+    }
+
+    /// The entry `path`, or the entries below it if it was packed from a directory.
+    pub fn files(&self, path: &str) -> Vec<String> {
+        if self.0.contains_key(path) {
+            vec![path.to_owned()]
+        } else {
+            self.dir(path).cloned().collect()
+        }
+    }
+}
+
+/// Builds an s9pk. Entries are appended to a scratch payload file, and `finish` writes them out
+/// behind the header and table of contents. Directories are always walked in name order. Entries
+/// record no timestamps or owners, only a file mode, which a deterministic writer reduces to
+/// `0644` or `0755`, so its output depends only on the contents of the source tree.
+pub struct Writer {
+    payload_path: PathBuf,
+    payload: tokio::fs::File,
+    toc: Toc,
+    len: u64,
+    deterministic: bool,
+}
+impl Writer {
+    pub async fn new<P: AsRef<Path>>(payload_path: P, deterministic: bool) -> Result<Self, Error> {
+        let payload_path = payload_path.as_ref().to_owned();
+        let payload = tokio::fs::File::create(&payload_path)
+            .await
+            .with_context(|e| format!("{}: {}", payload_path.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        Ok(Writer {
+            payload_path,
+            payload,
+            toc: Toc::default(),
+            len: 0,
+            deterministic,
+        })
+    }
+
+    pub async fn append<R: AsyncRead + Unpin + Send>(
+        &mut self,
+        name: &str,
+        r: R,
+        mode: u32,
+        compression: Compression,
+    ) -> Result<(), Error> {
+        crate::pack::validate_path(name).no_code()?;
+        crate::ensure_code!(
+            !self.toc.0.contains_key(name),
+            crate::error::GENERAL_ERROR,
+            "Duplicate Entry: {}",
+            name
+        );
+        log::trace!("Appending {} to payload.", name);
+        let mut r = compression.encode(r);
+        let mut hasher = Sha256::new();
+        let mut length = 0;
+        let mut buf = vec![0; 64 * crate::BUFFER_SIZE];
+        loop {
+            let n = r.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            self.payload.write_all(&buf[..n]).await?;
+            length += n as u64;
+        }
+        self.toc.0.insert(
+            name.to_owned(),
+            TocEntry {
+                offset: self.len,
+                length,
+                sha256: format!("{:x}", hasher.finalize()),
+                compression,
+                mode,
+            },
+        );
+        self.len += length;
+        Ok(())
+    }
+
+    pub async fn append_bytes(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        compression: Compression,
+    ) -> Result<(), Error> {
+        self.append(name, data, 0o644, compression).await
+    }
+
+    pub async fn append_file<P: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        src: P,
+        compression: Compression,
+    ) -> Result<(), Error> {
+        let src = src.as_ref();
+        let f = tokio::fs::File::open(src)
+            .await
+            .with_context(|e| format!("{}: {}", src.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        let mut mode = f.metadata().await?.permissions().mode() & 0o7777;
+        if self.deterministic {
+            mode = if mode & 0o111 != 0 { 0o755 } else { 0o644 };
+        }
+        self.append(name, f, mode, compression).await
+    }
+
+    /// Appends every file below `src` as `name/<relative path>`.
+    pub fn append_dir_all<'a>(
+        &'a mut self,
+        name: PathBuf,
+        src: PathBuf,
+        compression: Compression,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut read_dir = tokio::fs::read_dir(&src)
+                .await
+                .with_context(|e| format!("{}: {}", src.display(), e))
+                .with_code(crate::error::FILESYSTEM_ERROR)?;
+            let mut entries = Vec::new();
+            while let Some(entry) = read_dir.next_entry().await? {
+                entries.push(entry.file_name());
+            }
+            entries.sort();
+            for entry in entries {
+                let dest = name.join(&entry);
+                let entry = src.join(&entry);
+                if tokio::fs::metadata(&entry).await?.is_dir() {
+                    self.append_dir_all(dest, entry, compression).await?;
+                } else {
+                    self.append_file(&format!("{}", dest.display()), entry, compression)
+                        .await?;
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    pub async fn finish<W: AsyncWrite + Unpin>(mut self, out: &mut W) -> Result<(), Error> {
+        self.payload.flush().await?;
+        let Writer {
+            payload_path,
+            payload,
+            toc,
+            ..
+        } = self;
+        drop(payload);
+        let toc = serde_cbor::to_vec(&toc).with_code(crate::error::SERDE_ERROR)?;
+        crate::ensure_code!(
+            toc.len() <= MAX_TOC_LEN as usize,
+            crate::error::GENERAL_ERROR,
+            "Table of Contents Too Large: {} bytes",
+            toc.len()
+        );
+        out.write_all(MAGIC).await?;
+        out.write_all(&[VERSION]).await?;
+        out.write_all(&(toc.len() as u32).to_be_bytes()).await?;
+        out.write_all(&toc).await?;
+        let mut payload = tokio::fs::File::open(&payload_path)
+            .await
+            .with_context(|e| format!("{}: {}", payload_path.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        tokio::io::copy(&mut payload, out).await?;
+        out.flush().await?;
+        drop(payload);
+        tokio::fs::remove_file(&payload_path)
+            .await
+            .with_context(|e| format!("{}: {}", payload_path.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        Ok(())
+    }
+}
+
+/// An entry's stored bytes. The hash is checked as they are read: the read that reaches the end
+/// of the entry fails if the contents do not match the table of contents.
+pub struct EntryReader<'a, R> {
+    name: String,
+    inner: Take<&'a mut R>,
+    hasher: Option<Sha256>,
+    sha256: String,
+    unverified: &'a mut LinearSet<String>,
+}
+impl<'a, R: AsyncRead + Unpin> AsyncRead for EntryReader<'a, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => (),
+            a => return a,
+        }
+        let read = &buf.filled()[start..];
+        if !read.is_empty() {
+            if let Some(hasher) = &mut this.hasher {
+                hasher.update(read);
+            }
+        } else if let Some(hasher) = this.hasher.take() {
+            if this.inner.limit() > 0 {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("Package File Invalid or Corrupted: {} truncated", this.name),
+                )));
+            }
+            if format!("{:x}", hasher.finalize()) != this.sha256 {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Package File Invalid or Corrupted: hash mismatch for {}",
+                        this.name
+                    ),
+                )));
+            }
+            this.unverified.remove(&this.name);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct Reader<R> {
+    inner: R,
+    pub toc: Toc,
+    payload_start: u64,
+    payload_len: u64,
+    digest: Vec<u8>,
+    /// entries that have been opened, but not yet read to the end and found to match
+    unverified: LinearSet<String>,
+}
+impl<R: AsyncRead + AsyncSeek + Unpin + Send> Reader<R> {
+    pub async fn new(mut inner: R) -> Result<Self, Error> {
+        let mut end = inner.seek(SeekFrom::End(0)).await?;
+        if end >= SIGNATURE_SECTION_LEN {
+            inner
+                .seek(SeekFrom::End(-(SIGNATURE_MAGIC.len() as i64)))
+                .await?;
+            let mut magic = [0; 8];
+            inner.read_exact(&mut magic).await?;
+            if &magic == SIGNATURE_MAGIC {
+                end -= SIGNATURE_SECTION_LEN;
+            }
+        }
+        crate::ensure_code!(
+            end >= HEADER_LEN,
+            crate::error::GENERAL_ERROR,
+            "Package File Invalid or Corrupted: missing header"
+        );
+        inner.seek(SeekFrom::Start(0)).await?;
+        Self::read_header(inner, end).await
+    }
+
+    /// Reads a package that can only be read front to back, and whose payload ends at `end`.
+    /// Entries must be read in payload order.
+    pub async fn from_stream(inner: R, end: u64) -> Result<Self, Error> {
+        Self::read_header(inner, end).await
+    }
+
+    async fn read_header(mut inner: R, end: u64) -> Result<Self, Error> {
+        let mut header = [0; HEADER_LEN as usize];
+        inner.read_exact(&mut header).await?;
+        crate::ensure_code!(
+            &header[..4] == MAGIC,
+            crate::error::GENERAL_ERROR,
+            "Package File Invalid or Corrupted: not an s9pk"
+        );
+        crate::ensure_code!(
+            header[4] == VERSION,
+            crate::error::VERSION_INCOMPATIBLE,
+            "Unsupported Package Format Version: {}",
+            header[4]
+        );
+        let mut toc_len = [0; 4];
+        toc_len.copy_from_slice(&header[5..]);
+        let toc_len = u32::from_be_bytes(toc_len);
+        let payload_start = HEADER_LEN + toc_len as u64;
+        crate::ensure_code!(
+            toc_len <= MAX_TOC_LEN && payload_start <= end,
+            crate::error::GENERAL_ERROR,
+            "Package File Invalid or Corrupted: invalid table of contents length"
+        );
+        let mut toc = vec![0; toc_len as usize];
+        inner.read_exact(&mut toc).await?;
+        let mut hasher = Sha512::new();
+        hasher.update(&header);
+        hasher.update(&toc);
+        let toc = serde_cbor::from_slice(&toc).with_code(crate::error::SERDE_ERROR)?;
+        Ok(Reader {
+            inner,
+            toc,
+            payload_start,
+            payload_len: end - payload_start,
+            digest: hasher.finalize().to_vec(),
+            unverified: LinearSet::new(),
+        })
+    }
+
+    /// The SHA-512 digest of the header and table of contents, which is what a package's
+    /// signature covers.
+    pub fn digest(&self) -> &[u8] {
+        &self.digest
+    }
+
+    /// The length of the header and table of contents.
+    pub fn payload_start(&self) -> u64 {
+        self.payload_start
+    }
+
+    pub fn payload_len(&self) -> u64 {
+        self.payload_len
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.toc.0.contains_key(name)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&TocEntry, Error> {
+        self.toc
+            .0
+            .get(name)
+            .ok_or_else(|| format_err!("Package File Invalid or Corrupted: missing {}", name))
+            .with_code(crate::error::GENERAL_ERROR)
+    }
+
+    /// The stored bytes of `name`, as they appear in the payload.
+    pub async fn raw(&mut self, name: &str) -> Result<EntryReader<'_, R>, Error> {
+        let entry = self.get(name)?.clone();
+        crate::ensure_code!(
+            entry
+                .offset
+                .checked_add(entry.length)
+                .map(|end| end <= self.payload_len)
+                .unwrap_or(false),
+            crate::error::GENERAL_ERROR,
+            "Package File Invalid or Corrupted: {} out of bounds",
+            name
+        );
+        self.inner
+            .seek(SeekFrom::Start(self.payload_start + entry.offset))
+            .await?;
+        self.unverified.insert(name.to_owned());
+        Ok(EntryReader {
+            name: name.to_owned(),
+            inner: (&mut self.inner).take(entry.length),
+            hasher: Some(Sha256::new()),
+            sha256: entry.sha256,
+            unverified: &mut self.unverified,
+        })
+    }
+
+    /// The contents of `name`, decompressed as they are read.
+    pub async fn entry(&mut self, name: &str) -> Result<crate::compression::Reader<'_>, Error> {
+        let compression = self.get(name)?.compression;
+        Ok(compression.decode(self.raw(name).await?))
+    }
+
+    pub async fn manifest(&mut self) -> Result<Manifest, Error> {
+        from_cbor_async_reader(self.entry(MANIFEST).await?).await
+    }
+
+    /// Writes the contents of `name` to `dst`, with the mode it was packed with.
+    pub async fn unpack<P: AsRef<Path>>(&mut self, name: &str, dst: P) -> Result<(), Error> {
+        crate::pack::validate_path(name).no_code()?;
+        let dst = dst.as_ref();
+        let mode = self.get(name)?.mode;
+        if let Some(parent) = dst.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|e| format!("{}: {}", parent.display(), e))
+                .with_code(crate::error::FILESYSTEM_ERROR)?;
+        }
+        let mut f = tokio::fs::File::create(dst)
+            .await
+            .with_context(|e| format!("{}: {}", dst.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        tokio::io::copy(&mut self.entry(name).await?, &mut f).await?;
+        f.flush().await?;
+        drop(f);
+        tokio::fs::set_permissions(dst, std::fs::Permissions::from_mode(mode))
+            .await
+            .with_context(|e| format!("{}: {}", dst.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?;
+        Ok(())
+    }
+}
+
+impl<R: Source> Reader<R> {
+    /// Completes reading the package, once everything needed from it has been read. Fails if an
+    /// entry was not read to the end, so was never checked against the table of contents, and
+    /// then finishes the source: see `Source::finish`.
+    pub async fn finish(&mut self) -> Result<(), Error> {
+        if let Some(name) = self.unverified.iter().next() {
+            return Err(format_err!(
+                "Package File Invalid or Corrupted: {} was not read to the end",
+                name
+            ))
+            .with_code(crate::error::GENERAL_ERROR);
+        }
+        self.inner.finish().await
+    }
+}
+
+/// Where a package is read from.
+#[async_trait]
+pub trait Source: AsyncRead + AsyncSeek + Unpin + Send {
+    /// Called once everything needed from the package has been read.
+    async fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+impl Source for tokio::fs::File {}
+impl<T: AsRef<[u8]> + Unpin + Send> Source for std::io::Cursor<T> {}
+
+/// A package of `len` bytes arriving over the network, whose signature section has already been
+/// fetched on its own. It can be read front to back, skipping ahead, but not rewound. The
+/// signature section is held back from readers, and `finish` reads what is left of the package
+/// and checks that it ends with the section that was fetched.
+pub struct Stream<R> {
+    inner: R,
+    len: u64,
+    section: [u8; SIGNATURE_SECTION_LEN as usize],
+    chunk: Vec<u8>,
+    pos: u64,
+    target: Option<u64>,
+    interrupted: Arc<AtomicBool>,
+}
+impl<R: AsyncRead + Unpin + Send> Stream<R> {
+    pub fn new(inner: R, len: u64, section: [u8; SIGNATURE_SECTION_LEN as usize]) -> Self {
+        Stream {
+            inner,
+            len,
+            section,
+            chunk: vec![0; 64 * crate::BUFFER_SIZE],
+            pos: 0,
+            target: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Where the payload ends and the signature section begins.
+    pub fn end(&self) -> u64 {
+        self.len - SIGNATURE_SECTION_LEN
+    }
+
+    /// Set if reading from the underlying stream failed, or it ended early.
+    pub fn interrupted(&self) -> Arc<AtomicBool> {
+        self.interrupted.clone()
+    }
+
+    /// Reads up to `max` bytes from the underlying stream into `chunk`.
+    fn poll_chunk(&mut self, cx: &mut Context<'_>, max: u64) -> Poll<std::io::Result<usize>> {
+        if max == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        let len = (max.min(self.chunk.len() as u64)) as usize;
+        let mut chunk = ReadBuf::new(&mut self.chunk[..len]);
+        match Pin::new(&mut self.inner).poll_read(cx, &mut chunk) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => {
+                self.interrupted.store(true, Ordering::SeqCst);
+                return Poll::Ready(Err(e));
+            }
+            Poll::Pending => return Poll::Pending,
+        }
+        let n = chunk.filled().len();
+        if n == 0 {
+            self.interrupted.store(true, Ordering::SeqCst);
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Package Stream Ended Early",
+            )));
+        }
+        self.pos += n as u64;
+        Poll::Ready(Ok(n))
+    }
+}
+impl<R: AsyncRead + Unpin + Send> AsyncRead for Stream<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let max = (this.end() - this.pos).min(buf.remaining() as u64);
+        match this.poll_chunk(cx, max) {
+            Poll::Ready(Ok(n)) => {
+                buf.put_slice(&this.chunk[..n]);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+impl<R: AsyncRead + Unpin + Send> AsyncSeek for Stream<R> {
+    fn start_seek(self: Pin<&mut Self>, pos: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) if n >= 0 => Some(this.pos + n as u64),
+            _ => None,
+        };
+        match target {
+            Some(target) if target >= this.pos && target <= this.end() => {
+                this.target = Some(target);
+                Ok(())
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Package Stream Must Be Read In Order",
+            )),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        let this = self.get_mut();
+        while let Some(target) = this.target {
+            if this.pos >= target {
+                this.target = None;
+                break;
+            }
+            match this.poll_chunk(cx, target - this.pos) {
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(this.pos))
+    }
+}
+#[async_trait]
+impl<R: AsyncRead + Unpin + Send> Source for Stream<R> {
+    async fn finish(&mut self) -> Result<(), Error> {
+        let end = self.end();
+        self.seek(SeekFrom::Start(end)).await?;
+        let mut section = [0; SIGNATURE_SECTION_LEN as usize];
+        self.inner.read_exact(&mut section).await.map_err(|e| {
+            self.interrupted.store(true, Ordering::SeqCst);
+            e
+        })?;
+        crate::ensure_code!(
+            section == self.section && self.inner.read(&mut [0]).await? == 0,
+            crate::error::SIGNATURE_ERROR,
+            "Package Changed While Downloading"
+        );
+        Ok(())
+    }
+}
+
+pub async fn open<P: AsRef<Path>>(path: P) -> Result<Reader<tokio::fs::File>, Error> {
+    let path = path.as_ref();
+    let f = tokio::fs::File::open(path)
+        .await
+        .with_context(|e| format!("{}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    Reader::new(f).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_util::package;
+
+    async fn read_entry<R: AsyncRead + AsyncSeek + Unpin + Send>(
+        pkg: &mut Reader<R>,
+        name: &str,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let mut data = Vec::new();
+        pkg.entry(name)
+            .await
+            .unwrap()
+            .read_to_end(&mut data)
+            .await?;
+        Ok(data)
+    }
+
+    const ENTRIES: &[(&str, &[u8], Compression)] = &[
+        ("instructions.md", b"# Instructions", Compression::None),
+        (
+            "assets/a.txt",
+            b"aaaaaaaaaaaaaaaaaaaaaaaa",
+            Compression::Gzip,
+        ),
+        ("assets/b/c.txt", b"cccccccccccccccc", Compression::Zstd),
+    ];
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let bytes = package("s9pk-round-trip", ENTRIES).await;
+        let mut pkg = Reader::new(Cursor::new(bytes)).await.unwrap();
+        assert_eq!(
+            pkg.toc.0.keys().collect::<Vec<_>>(),
+            ENTRIES.iter().map(|(name, ..)| name).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            pkg.toc.files("assets"),
+            vec!["assets/a.txt".to_owned(), "assets/b/c.txt".to_owned()]
+        );
+        for (name, data, compression) in ENTRIES.iter().rev() {
+            assert_eq!(pkg.get(name).unwrap().compression, *compression);
+            assert_eq!(read_entry(&mut pkg, name).await.unwrap(), *data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_magic() {
+        let mut bytes = package("s9pk-bad-magic", ENTRIES).await;
+        bytes[0] = b'X';
+        assert!(Reader::new(Cursor::new(bytes)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_toc() {
+        let bytes = package("s9pk-truncated-toc", ENTRIES).await;
+        let toc_len = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]) as usize;
+        let truncated = bytes[..HEADER_LEN as usize + toc_len / 2].to_vec();
+        assert!(Reader::new(Cursor::new(truncated)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_entry() {
+        let mut bytes = package("s9pk-truncated-entry", ENTRIES).await;
+        bytes.truncate(bytes.len() - 4);
+        let last = ENTRIES[ENTRIES.len() - 1].0;
+
+        let mut pkg = Reader::new(Cursor::new(bytes)).await.unwrap();
+        assert!(pkg.raw(last).await.is_err());
+    }
+}
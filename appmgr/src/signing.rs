@@ -0,0 +1,321 @@
+use std::convert::TryFrom;
+use std::io::SeekFrom;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use failure::ResultExt as _;
+use linear_map::LinearMap;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::util::{from_yaml_async_reader, PersistencePath, YamlUpdateHandle};
+use crate::Error;
+use crate::ResultExt as _;
+
+pub const SIGNATURE_MAGIC: &'static [u8; 8] = b"S9PKSIG1";
+pub const SIGNATURE_SECTION_LEN: u64 =
+    (ed25519_dalek::PUBLIC_KEY_LENGTH + ed25519_dalek::SIGNATURE_LENGTH + 8) as u64;
+
+/// The signature section is appended after the payload of the package. It is laid out as
+/// `pubkey || signature || magic`, where the signature covers the SHA-512 digest of the header
+/// and table of contents. The table of contents records the SHA-256 of every entry, so a package
+/// is only authentic once its entries are found to match it: see `integrity::check`. This lets a
+/// package be verified before its payload has been read, as it streams in.
+#[derive(Clone, Debug)]
+pub struct PackageSignature {
+    pub pubkey: PublicKey,
+    pub signature: Signature,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct TrustStore(pub LinearMap<String, String>);
+impl TrustStore {
+    pub fn get_name(&self, key: &PublicKey) -> Option<&str> {
+        let encoded = encode_key(key);
+        self.0
+            .iter()
+            .find(|(_, k)| k.as_str() == encoded)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+pub fn encode_key(key: &PublicKey) -> String {
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, key.as_bytes()).to_lowercase()
+}
+
+pub fn decode_key(key: &str) -> Result<PublicKey, Error> {
+    let bytes = base32::decode(
+        base32::Alphabet::RFC4648 { padding: false },
+        &key.trim().to_uppercase(),
+    )
+    .ok_or_else(|| format_err!("Invalid Public Key: {}", key))
+    .no_code()?;
+    PublicKey::from_bytes(&bytes)
+        .with_context(|e| format!("Invalid Public Key: {}: {}", key, e))
+        .no_code()
+}
+
+pub async fn keygen<P: AsRef<Path>>(path: P) -> Result<PublicKey, Error> {
+    let path = path.as_ref();
+    crate::ensure_code!(
+        !path.exists(),
+        crate::error::FILESYSTEM_ERROR,
+        "{} Already Exists",
+        path.display()
+    );
+    let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+    let mut f = tokio::fs::File::from_std(
+        std::fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|e| format!("{}: {}", path.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?,
+    );
+    f.write_all(&keypair.to_bytes()).await?;
+    f.flush().await?;
+    f.sync_all().await?;
+    Ok(keypair.public)
+}
+
+pub async fn load_keypair<P: AsRef<Path>>(path: P) -> Result<Keypair, Error> {
+    let path = path.as_ref();
+    let bytes = tokio::fs::read(path)
+        .await
+        .with_context(|e| format!("{}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    Keypair::from_bytes(&bytes)
+        .with_context(|e| format!("{}: Invalid Developer Key: {}", path.display(), e))
+        .with_code(crate::error::SIGNATURE_ERROR)
+}
+
+/// The digest the signature of the package open as `f` covers.
+async fn digest(f: &mut tokio::fs::File) -> Result<Vec<u8>, Error> {
+    f.seek(SeekFrom::Start(0)).await?;
+    Ok(crate::s9pk::Reader::new(&mut *f).await?.digest().to_vec())
+}
+
+pub async fn sign_file<P: AsRef<Path>>(path: P, keypair: &Keypair) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut f = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .await
+        .with_context(|e| format!("{}: {}", path.display(), e))
+        .with_code(crate::error::FILESYSTEM_ERROR)?;
+    let signature = keypair.sign(&digest(&mut f).await?);
+    f.seek(SeekFrom::End(0)).await?;
+    f.write_all(keypair.public.as_bytes()).await?;
+    f.write_all(&signature.to_bytes()).await?;
+    f.write_all(SIGNATURE_MAGIC).await?;
+    f.flush().await?;
+    f.sync_all().await?;
+    Ok(())
+}
+
+pub async fn read_signature(f: &mut tokio::fs::File) -> Result<Option<PackageSignature>, Error> {
+    let len = f.metadata().await?.len();
+    if len < SIGNATURE_SECTION_LEN {
+        return Ok(None);
+    }
+    f.seek(SeekFrom::Start(len - SIGNATURE_SECTION_LEN)).await?;
+    let mut section = [0; SIGNATURE_SECTION_LEN as usize];
+    f.read_exact(&mut section).await?;
+    parse_signature(&section)
+}
+
+pub fn parse_signature(
+    section: &[u8; SIGNATURE_SECTION_LEN as usize],
+) -> Result<Option<PackageSignature>, Error> {
+    let (pubkey, rest) = section.split_at(ed25519_dalek::PUBLIC_KEY_LENGTH);
+    let (signature, magic) = rest.split_at(ed25519_dalek::SIGNATURE_LENGTH);
+    if magic != SIGNATURE_MAGIC {
+        return Ok(None);
+    }
+    Ok(Some(PackageSignature {
+        pubkey: PublicKey::from_bytes(pubkey)
+            .with_context(|e| format!("Invalid Signing Key: {}", e))
+            .with_code(crate::error::SIGNATURE_ERROR)?,
+        signature: Signature::try_from(signature)
+            .with_context(|e| format!("Invalid Signature: {}", e))
+            .with_code(crate::error::SIGNATURE_ERROR)?,
+    }))
+}
+
+/// Checks that the table of contents of the package open as `f` is signed by a key in the trust
+/// store, and leaves `f` at its start. Callers go on to check the entries against the table of
+/// contents through the same handle, so what they read is what was verified. Returns the name
+/// the signing key is trusted under.
+pub async fn verify(f: &mut tokio::fs::File) -> Result<String, Error> {
+    let sig = read_signature(f)
+        .await?
+        .ok_or_else(|| format_err!("Package Is Not Signed"))
+        .with_code(crate::error::SIGNATURE_ERROR)?;
+    let digest = digest(f).await?;
+    f.seek(SeekFrom::Start(0)).await?;
+    verify_digest(&sig, &digest).await
+}
+
+/// Checks `sig` against the SHA-512 `digest` of the header and table of contents of a package,
+/// returning the name the signing key is trusted under.
+pub async fn verify_digest(sig: &PackageSignature, digest: &[u8]) -> Result<String, Error> {
+    let signer = check(&trust_store().await?, sig, digest)?;
+    log::info!("Package signed by {}.", signer);
+    Ok(signer)
+}
+
+fn check(trust_store: &TrustStore, sig: &PackageSignature, digest: &[u8]) -> Result<String, Error> {
+    let signer = trust_store
+        .get_name(&sig.pubkey)
+        .ok_or_else(|| format_err!("Untrusted Signing Key: {}", encode_key(&sig.pubkey)))
+        .with_code(crate::error::SIGNATURE_ERROR)?
+        .to_owned();
+    sig.pubkey
+        .verify(digest, &sig.signature)
+        .with_context(|e| format!("Invalid Signature: {}", e))
+        .with_code(crate::error::SIGNATURE_ERROR)?;
+    Ok(signer)
+}
+
+/// The keys trusted to sign packages. The registry's key is always trusted, under
+/// `REGISTRY_KEY_NAME`, unless the store names another key that way.
+pub async fn trust_store() -> Result<TrustStore, Error> {
+    let path = PersistencePath::from_ref(crate::TRUSTED_KEYS_YAML);
+    let mut store: TrustStore = if let Some(mut f) = path.maybe_read(false).await.transpose()? {
+        from_yaml_async_reader(&mut *f).await?
+    } else {
+        TrustStore::default()
+    };
+    if !store.0.contains_key(crate::REGISTRY_KEY_NAME) {
+        store.0.insert(
+            crate::REGISTRY_KEY_NAME.to_owned(),
+            crate::REGISTRY_KEY.to_owned(),
+        );
+    }
+    Ok(store)
+}
+
+pub async fn trust_store_mut() -> Result<YamlUpdateHandle<TrustStore>, Error> {
+    YamlUpdateHandle::new_or_default(PersistencePath::from_ref(crate::TRUSTED_KEYS_YAML)).await
+}
+
+pub async fn trust(name: &str, key: &PublicKey) -> Result<(), Error> {
+    let mut store = trust_store_mut().await?;
+    store.0.insert(name.to_owned(), encode_key(key));
+    store.commit().await
+}
+
+pub async fn distrust(name: &str) -> Result<(), Error> {
+    let mut store = trust_store_mut().await?;
+    crate::ensure_code!(
+        name != crate::REGISTRY_KEY_NAME,
+        crate::error::GENERAL_ERROR,
+        "The Registry Key Cannot Be Distrusted"
+    );
+    crate::ensure_code!(
+        store.0.remove(name).is_some(),
+        crate::error::NOT_FOUND,
+        "No Trusted Key Named {}",
+        name
+    );
+    store.commit().await
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    async fn signed_package(name: &str, keypair: &Keypair) -> std::path::PathBuf {
+        let path =
+            crate::test_util::scratch_path(&format!("signing-{}", name)).with_extension("s9pk");
+        let bytes = crate::test_util::package(
+            &format!("signing-{}", name),
+            &[(
+                crate::s9pk::INSTRUCTIONS,
+                b"# Instructions",
+                crate::compression::Compression::None,
+            )],
+        )
+        .await;
+        std::fs::write(&path, bytes).unwrap();
+        sign_file(&path, keypair).await.unwrap();
+        path
+    }
+
+    async fn signature_and_digest(path: &Path) -> (PackageSignature, Vec<u8>) {
+        let mut f = tokio::fs::File::open(path).await.unwrap();
+        let sig = read_signature(&mut f).await.unwrap().unwrap();
+        (sig, digest(&mut f).await.unwrap())
+    }
+
+    /// Changes a byte of the package at `path`, at the first occurrence of `needle`.
+    async fn tamper(path: &Path, needle: &[u8]) {
+        let mut contents = tokio::fs::read(path).await.unwrap();
+        let pos = contents
+            .windows(needle.len())
+            .position(|w| w == needle)
+            .unwrap();
+        contents[pos] = if contents[pos] == b'0' { b'1' } else { b'0' };
+        tokio::fs::write(path, &contents).await.unwrap();
+    }
+
+    fn store(name: &str, key: &PublicKey) -> TrustStore {
+        let mut store = TrustStore::default();
+        store.0.insert(name.to_owned(), encode_key(key));
+        store
+    }
+
+    #[tokio::test]
+    async fn test_sign_verify() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+        let path = signed_package("valid", &keypair).await;
+        let (sig, digest) = signature_and_digest(&path).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(
+            check(&store("dev", &keypair.public), &sig, &digest).unwrap(),
+            "dev"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tampered() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+        let path = signed_package("tampered", &keypair).await;
+        let mut sha256 = Sha256::new();
+        sha256.update(b"# Instructions");
+        // an entry hash in the table of contents is covered by the signature
+        tamper(&path, format!("{:x}", sha256.finalize()).as_bytes()).await;
+        let (sig, digest) = signature_and_digest(&path).await;
+        let err = check(&store("dev", &keypair.public), &sig, &digest).unwrap_err();
+        assert_eq!(err.code, Some(crate::error::SIGNATURE_ERROR));
+
+        // the payload is not, but no longer matches the table of contents
+        let path = signed_package("tampered", &keypair).await;
+        tamper(&path, b"# Instructions").await;
+        let (sig, digest) = signature_and_digest(&path).await;
+        check(&store("dev", &keypair.public), &sig, &digest).unwrap();
+        let mut pkg = crate::s9pk::open(&path).await.unwrap();
+        assert!(crate::integrity::check(&mut pkg).await.is_err());
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_untrusted() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+        let other = Keypair::generate(&mut rand::rngs::OsRng);
+        let path = signed_package("untrusted", &keypair).await;
+        let (sig, digest) = signature_and_digest(&path).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        let err = check(&store("other", &other.public), &sig, &digest).unwrap_err();
+        assert_eq!(err.code, Some(crate::error::SIGNATURE_ERROR));
+    }
+
+    #[test]
+    fn test_registry_key() {
+        decode_key(crate::REGISTRY_KEY).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+use std::time::Duration;
+
+use linear_map::set::LinearSet;
+use linear_map::LinearMap;
+use tokio::io::AsyncBufReadExt;
+use tokio::time::Instant;
+
+use crate::manifest::{ManifestLatest, RestartCondition, RestartPolicy};
+use crate::runtime::ContainerStatus;
+use crate::util::{from_yaml_async_reader, PersistencePath, YamlUpdateHandle};
+use crate::Error;
+use crate::ResultExt as _;
+
+/// How an app has stopped without being asked to, persisted in `apps/<id>/crashes.yaml`.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CrashRecord {
+    pub crash_count: u64,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_exit_code: Option<i32>,
+    /// docker's finish time of the last recorded crash, so that none is counted twice
+    #[serde(default)]
+    last_finished_at: String,
+    /// restarts since the app last stayed up for its policy's `reset-after-secs`
+    #[serde(default)]
+    consecutive: u32,
+}
+
+fn crashes_path(id: &str) -> PersistencePath {
+    PersistencePath::from_ref("apps")
+        .join(id)
+        .join("crashes.yaml")
+}
+
+pub async fn crash_record(id: &str) -> Result<CrashRecord, Error> {
+    if let Some(mut f) = crashes_path(id).maybe_read(false).await.transpose()? {
+        from_yaml_async_reader(&mut *f).await
+    } else {
+        Ok(CrashRecord::default())
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Actor {
+    #[serde(rename = "Attributes")]
+    attributes: LinearMap<String, String>,
+}
+
+/// A line of `docker events --format '{{json .}}'`.
+#[derive(serde::Deserialize)]
+struct Event {
+    #[serde(rename = "Action")]
+    action: String,
+    #[serde(rename = "Actor")]
+    actor: Actor,
+}
+
+/// Counts a restart against `policy`, returning how long to wait before it, or `None` if the
+/// app has run out of retries.
+fn backoff(policy: &RestartPolicy, record: &mut CrashRecord) -> Option<Duration> {
+    if let Some(max) = policy.max_retries {
+        if record.consecutive >= max {
+            return None;
+        }
+    }
+    let factor = 1_u64.checked_shl(record.consecutive).unwrap_or(u64::MAX);
+    record.consecutive += 1;
+    Some(Duration::from_secs(
+        policy
+            .backoff_initial_secs
+            .saturating_mul(factor)
+            .min(policy.backoff_max_secs),
+    ))
+}
+
+/// The apps that should be running.
+async fn running() -> Result<LinearSet<String>, Error> {
+    crate::db::get_or_default(crate::db::RUNNING).await
+}
+
+/// Takes `id` out of the running apps, so that it is reported as stopped instead of restarting.
+async fn give_up(id: &str) -> Result<(), Error> {
+    let mut db = crate::db::transaction().await?;
+    let mut running: LinearSet<String> = db.get_or_default(crate::db::RUNNING)?;
+    running.remove(id);
+    db.put(crate::db::RUNNING, &running)?;
+    db.commit().await
+}
+
+struct Pending {
+    due: Instant,
+    /// not restarted while any of these is waiting to be
+    dependencies: Vec<String>,
+}
+
+#[derive(Default)]
+struct Supervisor {
+    pending: LinearMap<String, Pending>,
+    /// when each running app was first seen up
+    started: LinearMap<String, Instant>,
+}
+impl Supervisor {
+    fn queue(&mut self, id: &str, manifest: &ManifestLatest, delay: Duration) {
+        log::info!("Restarting {} in {}s.", id, delay.as_secs());
+        self.pending.insert(
+            id.to_owned(),
+            Pending {
+                due: Instant::now() + delay,
+                dependencies: manifest.dependencies.0.keys().cloned().collect(),
+            },
+        );
+    }
+
+    fn is_blocked(&self, pending: &Pending) -> bool {
+        pending
+            .dependencies
+            .iter()
+            .any(|dep| self.pending.contains_key(dep))
+    }
+
+    /// The earliest time a pending restart can happen. Restarts waiting on a dependency are
+    /// left out: they are unblocked by that dependency's restart.
+    fn next_due(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .filter(|p| !self.is_blocked(p))
+            .map(|p| p.due)
+            .min()
+    }
+
+    /// Queues restarts for every app that should be running but is not.
+    async fn reconcile(&mut self) -> Result<(), Error> {
+        let installed = crate::apps::list_info().await?;
+        let running = running().await?;
+        for id in running {
+            if !installed.contains_key(&id) || self.pending.contains_key(&id) {
+                continue;
+            }
+            match crate::runtime::get().inspect(&id).await?.status {
+                ContainerStatus::Created | ContainerStatus::Exited | ContainerStatus::Dead => {
+                    self.stopped(&id).await?
+                }
+                _ => {
+                    self.started.entry(id).or_insert_with(Instant::now);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, line: &str) -> Result<(), Error> {
+        let event: Event = serde_json::from_str(line).no_code()?;
+        let id = match event.actor.attributes.get("name") {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        if !crate::apps::list_info().await?.contains_key(id) {
+            return Ok(());
+        }
+        match event.action.as_str() {
+            "start" => {
+                self.started.insert(id.clone(), Instant::now());
+                self.pending.remove(id);
+            }
+            "die" => self.stopped(id).await?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Records the exit of `id` and queues its restart if it was not stopped on purpose.
+    async fn stopped(&mut self, id: &str) -> Result<(), Error> {
+        // held by `control::stop_app` until `id` is out of the running apps
+        let lock = crate::util::lock_file(crate::context::get().control_lock(id), true).await?;
+        let running = running().await?.contains(id);
+        let state = crate::runtime::get().inspect(id).await?;
+        if !running || state.status == ContainerStatus::Running {
+            crate::util::unlock(lock).await?;
+            return Ok(());
+        }
+        let manifest = crate::apps::manifest(id).await?;
+        let policy = &manifest.restart_policy;
+        let mut record = YamlUpdateHandle::<CrashRecord>::new_or_default(crashes_path(id)).await?;
+        // an exit already recorded was seen by an earlier supervisor that did not get to
+        // restart the app, so its backoff has most likely passed
+        let delay = if record.last_finished_at == state.finished_at {
+            Some(Duration::from_secs(0))
+        } else {
+            log::warn!("{} exited with code {}.", id, state.exit_code);
+            record.crash_count += 1;
+            record.last_exit_code = Some(state.exit_code);
+            record.last_finished_at = state.finished_at;
+            if self
+                .started
+                .remove(id)
+                .map(|t| t.elapsed() >= Duration::from_secs(policy.reset_after_secs))
+                .unwrap_or(false)
+            {
+                record.consecutive = 0;
+            }
+            let restart = match policy.condition {
+                RestartCondition::Never => false,
+                RestartCondition::OnFailure => state.exit_code != 0,
+                RestartCondition::Always => true,
+            };
+            if !restart {
+                log::info!("Not restarting {}: its restart policy forbids it.", id);
+                None
+            } else if let Some(delay) = backoff(policy, &mut record) {
+                Some(delay)
+            } else {
+                log::error!(
+                    "Giving up on {} after {} consecutive restarts.",
+                    id,
+                    record.consecutive
+                );
+                None
+            }
+        };
+        match delay {
+            Some(delay) => self.queue(id, &manifest, delay),
+            None => {
+                record.consecutive = 0;
+                give_up(id).await?;
+            }
+        }
+        record.commit().await?;
+        crate::util::unlock(lock).await?;
+        Ok(())
+    }
+
+    /// Restarts the apps whose backoff has passed, dependencies first.
+    async fn restart_due(&mut self) {
+        let now = Instant::now();
+        let due: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.due <= now && !self.is_blocked(p))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in due {
+            self.pending.remove(&id);
+            if let Err(e) = self.restart(&id).await {
+                log::error!("Failed to restart {}: {}", id, e);
+                if let Err(e) = self.retry(&id).await {
+                    log::error!("Failed to queue restart of {}: {}", id, e);
+                }
+            }
+        }
+    }
+
+    async fn restart(&mut self, id: &str) -> Result<(), Error> {
+        let running = running().await?.contains(id);
+        if !running || !crate::apps::list_info().await?.contains_key(id) {
+            // stopped or removed while waiting
+            return Ok(());
+        }
+        log::info!("Restarting {}.", id);
+        crate::control::start_app(id, true).await
+    }
+
+    /// Runs the due health checks of every running app, storing the results for `apps::status`.
+    async fn check_health(&mut self) {
+        let running = match running().await {
+            Ok(a) => a,
+            Err(e) => {
+                log::error!("Failed to list running apps: {}", e);
+                return;
+            }
+        };
+        for id in running {
+            if self.pending.contains_key(&id) {
+                continue;
+            }
+            let res = async {
+                let state = crate::runtime::get().inspect(&id).await?;
+                if state.status == ContainerStatus::Running {
+                    crate::health::check(&id, &state.started_at).await?;
+                }
+                Ok::<_, Error>(())
+            }
+            .await;
+            if let Err(e) = res {
+                log::error!("Failed to check health of {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Queues another restart after one failed.
+    async fn retry(&mut self, id: &str) -> Result<(), Error> {
+        let manifest = crate::apps::manifest(id).await?;
+        let mut record = YamlUpdateHandle::<CrashRecord>::new_or_default(crashes_path(id)).await?;
+        match backoff(&manifest.restart_policy, &mut record) {
+            Some(delay) => self.queue(id, &manifest, delay),
+            None => {
+                log::error!(
+                    "Giving up on {} after {} consecutive restarts.",
+                    id,
+                    record.consecutive
+                );
+                record.consecutive = 0;
+                give_up(id).await?;
+            }
+        }
+        record.commit().await
+    }
+}
+
+/// How often the supervisor looks for health checks that are due. Each check still only runs
+/// once per its own interval.
+const HEALTH_TICK: Duration = Duration::from_secs(5);
+
+/// Watches docker for apps that stop on their own and restarts them according to their restart
+/// policies, and runs the health checks of the apps that are up. Runs until killed.
+pub async fn run() -> Result<(), Error> {
+    let mut supervisor = Supervisor::default();
+    let mut health_tick = tokio::time::interval(HEALTH_TICK);
+    loop {
+        let mut events = tokio::process::Command::new("docker")
+            .args(&[
+                "events",
+                "--filter",
+                "type=container",
+                "--filter",
+                "event=start",
+                "--filter",
+                "event=die",
+                "--format",
+                "{{json .}}",
+            ])
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut lines = tokio::io::BufReader::new(events.stdout.take().unwrap()).lines();
+        // only after subscribing, so that no exit falls between the two
+        if let Err(e) = supervisor.reconcile().await {
+            log::error!("Failed to check app status: {}", e);
+        }
+        loop {
+            let wake = supervisor
+                .next_due()
+                .unwrap_or_else(|| Instant::now() + Duration::from_secs(3600));
+            tokio::select! {
+                line = lines.next_line() => match line? {
+                    Some(line) => {
+                        if let Err(e) = supervisor.handle_event(&line).await {
+                            log::error!("Failed to handle docker event: {}", e);
+                        }
+                    }
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(wake) => supervisor.restart_due().await,
+                // probes are bounded by their timeouts, and docker events wait in the pipe
+                _ = health_tick.tick() => supervisor.check_health().await,
+            }
+        }
+        log::warn!("Docker event stream ended, resubscribing.");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
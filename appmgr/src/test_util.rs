@@ -0,0 +1,103 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use tokio_tar as tar;
+
+use crate::compression::Compression;
+use crate::context::Context;
+
+lazy_static::lazy_static! {
+    static ref SANDBOX: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+/// A path in the temp dir named after `name`, unique to this test process.
+pub fn scratch_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("appmgr-{}-{}", name, std::process::id()))
+}
+
+/// A fresh, empty directory named after `name`.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = scratch_path(name);
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Points the context at a fresh directory named after `name` until the guard is dropped. Tests
+/// that touch the state hold it, since the context is shared by every test.
+pub async fn sandbox(name: &str) -> (tokio::sync::MutexGuard<'static, ()>, PathBuf) {
+    let guard = SANDBOX.lock().await;
+    let root = scratch_path(name);
+    if root.exists() {
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+    let context = Context::default().under(&root);
+    std::fs::create_dir_all(&context.persistence_dir).unwrap();
+    crate::context::set(context);
+    (guard, root)
+}
+
+/// Stands in for `service` and `nft`, which installs and migrations call to reload tor, nginx and the
+/// firewall. Reloading tor writes the address and key of every hidden service in the torrc,
+/// as tor would.
+pub fn fake_system(root: &Path) {
+    let ctx = crate::context::get();
+    for dir in &[
+        ctx.tor_rc().parent().unwrap(),
+        ctx.etc_tor_rc.parent().unwrap(),
+        ctx.nginx_services_conf.parent().unwrap(),
+    ] {
+        std::fs::create_dir_all(dir).unwrap();
+    }
+    std::fs::write(ctx.tor_rc(), "").unwrap();
+    let service = format!(
+        "#!/bin/sh\n\
+         [ \"$1\" = tor ] || exit 0\n\
+         grep '^HiddenServiceDir' '{}' | while read key dir; do\n\
+         \tmkdir -p \"$dir\"\n\
+         \techo \"$(basename \"$dir\").onion\" > \"$dir/hostname\"\n\
+         \thead -c 96 /dev/zero > \"$dir/hs_ed25519_secret_key\"\n\
+         done\n",
+        ctx.etc_tor_rc.display()
+    );
+    let bin = root.join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    for (name, script) in &[("service", service.as_str()), ("nft", "#!/bin/sh\n")] {
+        let path = bin.join(name);
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let path = std::env::var("PATH").unwrap_or_default();
+    std::env::set_var("PATH", format!("{}:{}", bin.display(), path));
+}
+
+/// An unsigned package of `entries`, in order.
+pub async fn package(name: &str, entries: &[(&str, &[u8], Compression)]) -> Vec<u8> {
+    let payload = scratch_path(name).with_extension("payload");
+    let mut writer = crate::s9pk::Writer::new(&payload, true).await.unwrap();
+    for (name, data, compression) in entries {
+        writer.append_bytes(name, data, *compression).await.unwrap();
+    }
+    let mut out = Vec::new();
+    writer.finish(&mut out).await.unwrap();
+    out
+}
+
+/// A tar archive of `entries`, in order.
+pub async fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut out = tar::Builder::new(Vec::new());
+    for (path, data) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        out.append_data(&mut header, path, *data).await.unwrap();
+    }
+    out.into_inner().await.unwrap()
+}
+
+/// A docker image archive tagged `tag`, as far as `docker load` reads its tags.
+pub async fn image_archive(tag: &str) -> Vec<u8> {
+    let manifest = serde_json::to_vec(&serde_json::json!([{ "RepoTags": [tag] }])).unwrap();
+    tar(&[("manifest.json", &manifest)]).await
+}
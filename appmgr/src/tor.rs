@@ -1,14 +1,13 @@
 use std::collections::{BTreeSet, HashMap};
 use std::net::Ipv4Addr;
 use std::os::unix::process::ExitStatusExt;
-use std::path::Path;
 use std::time::{Duration, Instant};
 
 use failure::ResultExt as _;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 
-use crate::util::{Invoke, PersistencePath, YamlUpdateHandle};
+use crate::util::{Invoke, PersistencePath};
 use crate::{Error, ResultExt as _};
 
 #[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
@@ -60,10 +59,10 @@ impl<'de> serde::de::Deserialize<'de> for PortMapping {
     }
 }
 
-pub const ETC_TOR_RC: &'static str = "/etc/tor/torrc";
-pub const HIDDEN_SERVICE_DIR_ROOT: &'static str = "/var/lib/tor";
 pub const ETC_HOSTNAME: &'static str = "/etc/hostname";
-pub const ETC_NGINX_SERVICES_CONF: &'static str = "/etc/nginx/sites-available/start9-services.conf";
+/// A copy of the services map, relative to the persistence directory, kept for the agent to read
+/// the IPs of apps from. appmgr itself only reads the services map from the db.
+pub const SERVICES_YAML: &'static str = "tor/services.yaml";
 
 #[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -167,29 +166,30 @@ impl ServicesMap {
     }
 }
 
-pub async fn services_map(path: &PersistencePath) -> Result<ServicesMap, Error> {
-    let f = path.maybe_read(false).await.transpose()?;
-    if let Some(mut f) = f {
-        crate::util::from_yaml_async_reader(&mut *f).await
-    } else {
-        Ok(Default::default())
-    }
-}
-
-pub async fn services_map_mut(
-    path: PersistencePath,
-) -> Result<YamlUpdateHandle<ServicesMap>, Error> {
-    YamlUpdateHandle::new_or_default(path).await
+pub async fn services_map() -> Result<ServicesMap, Error> {
+    crate::db::get_or_default(crate::db::SERVICES).await
 }
 
 pub async fn write_services(hidden_services: &ServicesMap) -> Result<(), Error> {
-    tokio::fs::copy(crate::TOR_RC, ETC_TOR_RC)
+    let mut export = PersistencePath::from_ref(SERVICES_YAML).write(None).await?;
+    crate::util::to_yaml_async_writer(&mut *export, hidden_services).await?;
+    export.commit().await?;
+    let ctx = crate::context::get();
+    let tor_rc = ctx.tor_rc();
+    tokio::fs::copy(&tor_rc, &ctx.etc_tor_rc)
         .await
-        .with_context(|e| format!("{} -> {}: {}", crate::TOR_RC, ETC_TOR_RC, e))
+        .with_context(|e| {
+            format!(
+                "{} -> {}: {}",
+                tor_rc.display(),
+                ctx.etc_tor_rc.display(),
+                e
+            )
+        })
         .with_code(crate::error::FILESYSTEM_ERROR)?;
     let mut f = tokio::fs::OpenOptions::new()
         .append(true)
-        .open(ETC_TOR_RC)
+        .open(&ctx.etc_tor_rc)
         .await?;
     f.write_all(b"\n").await?;
     for (name, service) in &hidden_services.map {
@@ -201,8 +201,8 @@ pub async fn write_services(hidden_services: &ServicesMap) -> Result<(), Error>
             .await?;
         f.write_all(
             format!(
-                "HiddenServiceDir {}/app-{}/\n",
-                HIDDEN_SERVICE_DIR_ROOT, name
+                "HiddenServiceDir {}/\n",
+                ctx.hidden_service_dir(name).display()
             )
             .as_bytes(),
         )
@@ -225,16 +225,20 @@ pub async fn write_services(hidden_services: &ServicesMap) -> Result<(), Error>
 }
 
 pub async fn write_lan_services(hidden_services: &ServicesMap) -> Result<(), Error> {
-    let mut f = tokio::fs::File::create(ETC_NGINX_SERVICES_CONF).await?;
+    let ctx = crate::context::get();
+    let int_ca_cert = ctx
+        .ca_dir
+        .join("intermediate")
+        .join("certs")
+        .join("embassy-int-ca.crt.pem");
+    let root_ca_cert = ctx.ca_dir.join("certs").join("embassy-root-ca.cert.pem");
+    let mut f = tokio::fs::File::create(&ctx.nginx_services_conf).await?;
     for (app_id, service) in &hidden_services.map {
-        let hostname = tokio::fs::read_to_string(
-            Path::new(HIDDEN_SERVICE_DIR_ROOT)
-                .join(format!("app-{}", app_id))
-                .join("hostname"),
-        )
-        .await
-        .with_context(|e| format!("{}/app-{}/hostname: {}", HIDDEN_SERVICE_DIR_ROOT, app_id, e))
-        .with_code(crate::error::FILESYSTEM_ERROR)?;
+        let hostname_path = ctx.hidden_service_dir(app_id).join("hostname");
+        let hostname = tokio::fs::read_to_string(&hostname_path)
+            .await
+            .with_context(|e| format!("{}: {}", hostname_path.display(), e))
+            .with_code(crate::error::FILESYSTEM_ERROR)?;
         let hostname_str = hostname
             .trim()
             .strip_suffix(".onion")
@@ -292,12 +296,17 @@ pub async fn write_lan_services(hidden_services: &ServicesMap) -> Result<(), Err
                             .arg("ca")
                             .arg("-batch")
                             .arg("-config")
-                            .arg("/root/agent/ca/intermediate/openssl.conf")
+                            .arg(ctx.ca_dir.join("intermediate").join("openssl.conf"))
                             .arg("-rand_serial")
                             .arg("-keyfile")
-                            .arg("/root/agent/ca/intermediate/private/embassy-int-ca.key.pem")
+                            .arg(
+                                ctx.ca_dir
+                                    .join("intermediate")
+                                    .join("private")
+                                    .join("embassy-int-ca.key.pem"),
+                            )
                             .arg("-cert")
-                            .arg("/root/agent/ca/intermediate/certs/embassy-int-ca.crt.pem")
+                            .arg(&int_ca_cert)
                             .arg("-extensions")
                             .arg("server_cert")
                             .arg("-days")
@@ -316,29 +325,18 @@ pub async fn write_lan_services(hidden_services: &ServicesMap) -> Result<(), Err
                         )
                         .await?;
                         tokio::io::copy(
-                            &mut tokio::fs::File::open(
-                                "/root/agent/ca/intermediate/certs/embassy-int-ca.crt.pem",
-                            )
-                            .await
-                            .with_context(|e| {
-                                format!(
-                                    "{}: /root/agent/ca/intermediate/certs/embassy-int-ca.crt.pem",
-                                    e
-                                )
-                            })
-                            .with_code(crate::error::FILESYSTEM_ERROR)?,
+                            &mut tokio::fs::File::open(&int_ca_cert)
+                                .await
+                                .with_context(|e| format!("{}: {}", e, int_ca_cert.display()))
+                                .with_code(crate::error::FILESYSTEM_ERROR)?,
                             &mut *fullchain_file,
                         )
                         .await?;
                         tokio::io::copy(
-                            &mut tokio::fs::File::open(
-                                "/root/agent/ca/certs/embassy-root-ca.cert.pem",
-                            )
-                            .await
-                            .with_context(|e| {
-                                format!("{}: /root/agent/ca/certs/embassy-root-ca.cert.pem", e)
-                            })
-                            .with_code(crate::error::FILESYSTEM_ERROR)?,
+                            &mut tokio::fs::File::open(&root_ca_cert)
+                                .await
+                                .with_context(|e| format!("{}: {}", e, root_ca_cert.display()))
+                                .with_code(crate::error::FILESYSTEM_ERROR)?,
                             &mut *fullchain_file,
                         )
                         .await?;
@@ -381,8 +379,8 @@ pub async fn write_lan_services(hidden_services: &ServicesMap) -> Result<(), Err
 
 pub async fn read_tor_address(name: &str, timeout: Option<Duration>) -> Result<String, Error> {
     log::info!("Retrieving Tor hidden service address for {}.", name);
-    let addr_path = Path::new(HIDDEN_SERVICE_DIR_ROOT)
-        .join(format!("app-{}", name))
+    let addr_path = crate::context::get()
+        .hidden_service_dir(name)
         .join("hostname");
     if let Some(timeout) = timeout {
         let start = Instant::now();
@@ -418,8 +416,8 @@ pub async fn read_tor_key(
     timeout: Option<Duration>,
 ) -> Result<String, Error> {
     log::info!("Retrieving Tor hidden service key for {}.", name);
-    let addr_path = Path::new(HIDDEN_SERVICE_DIR_ROOT)
-        .join(format!("app-{}", name))
+    let addr_path = crate::context::get()
+        .hidden_service_dir(name)
         .join(match version {
             HiddenServiceVersion::V3 => "hs_ed25519_secret_key",
             _ => "private_key",
@@ -465,20 +463,20 @@ pub async fn set_svc(
     name: &str,
     service: NewService,
 ) -> Result<(Ipv4Addr, Option<String>, Option<String>), Error> {
+    log::info!("Adding Tor hidden service {} to {}.", name, crate::db::DB);
+    let is_listening = !service.ports.is_empty();
+    let mut db = crate::db::transaction().await?;
+    let mut hidden_services: ServicesMap = db.get_or_default(crate::db::SERVICES)?;
+    let ver = service.hidden_service_version;
+    let ip = hidden_services.add(name.to_owned(), service);
     log::info!(
         "Adding Tor hidden service {} to {}.",
         name,
-        crate::SERVICES_YAML
+        crate::context::get().etc_tor_rc.display()
     );
-    let is_listening = !service.ports.is_empty();
-    let path = PersistencePath::from_ref(crate::SERVICES_YAML);
-    let mut hidden_services = services_map_mut(path).await?;
-    let ver = service.hidden_service_version;
-    let ip = hidden_services.add(name.to_owned(), service);
-    log::info!("Adding Tor hidden service {} to {}.", name, ETC_TOR_RC);
     write_services(&hidden_services).await?;
-    let addr_path = Path::new(HIDDEN_SERVICE_DIR_ROOT)
-        .join(format!("app-{}", name))
+    let addr_path = crate::context::get()
+        .hidden_service_dir(name)
         .join("hostname");
     tokio::fs::remove_file(addr_path).await.or_else(|e| {
         if e.kind() == std::io::ErrorKind::NotFound {
@@ -526,7 +524,8 @@ pub async fn set_svc(
             .or_else(|| { svc_exit.signal().map(|a| 128 + a) })
             .unwrap_or(0)
     );
-    hidden_services.commit().await?;
+    db.put(crate::db::SERVICES, &hidden_services)?;
+    db.commit().await?;
     Ok((ip, addr, key))
 }
 
@@ -534,17 +533,21 @@ pub async fn rm_svc(name: &str) -> Result<(), Error> {
     log::info!(
         "Removing Tor hidden service {} from {}.",
         name,
-        crate::SERVICES_YAML
+        crate::db::DB
     );
-    let path = PersistencePath::from_ref(crate::SERVICES_YAML);
-    let mut hidden_services = services_map_mut(path).await?;
+    let mut db = crate::db::transaction().await?;
+    let mut hidden_services: ServicesMap = db.get_or_default(crate::db::SERVICES)?;
     hidden_services.remove(name);
-    let hidden_service_path = Path::new(HIDDEN_SERVICE_DIR_ROOT).join(format!("app-{}", name));
+    let hidden_service_path = crate::context::get().hidden_service_dir(name);
     log::info!("Removing {}", hidden_service_path.display());
     if hidden_service_path.exists() {
         tokio::fs::remove_dir_all(hidden_service_path).await?;
     }
-    log::info!("Removing Tor hidden service {} from {}.", name, ETC_TOR_RC);
+    log::info!(
+        "Removing Tor hidden service {} from {}.",
+        name,
+        crate::context::get().etc_tor_rc.display()
+    );
     write_services(&hidden_services).await?;
     log::info!("Reloading Tor.");
     let svc_exit = std::process::Command::new("service")
@@ -570,7 +573,8 @@ pub async fn rm_svc(name: &str) -> Result<(), Error> {
             .or_else(|| { svc_exit.signal().map(|a| 128 + a) })
             .unwrap_or(0)
     );
-    hidden_services.commit().await?;
+    db.put(crate::db::SERVICES, &hidden_services)?;
+    db.commit().await?;
     Ok(())
 }
 
@@ -578,7 +582,7 @@ pub async fn change_key(
     name: &str,
     key: Option<&ed25519_dalek::ExpandedSecretKey>,
 ) -> Result<(), Error> {
-    let hidden_service_path = Path::new(HIDDEN_SERVICE_DIR_ROOT).join(format!("app-{}", name));
+    let hidden_service_path = crate::context::get().hidden_service_dir(name);
     log::info!("Removing {}", hidden_service_path.display());
     if hidden_service_path.exists() {
         tokio::fs::remove_dir_all(&hidden_service_path)
@@ -606,20 +610,27 @@ pub async fn change_key(
         "Failed to Reload Tor: {}",
         svc_exit.code().unwrap_or(0)
     );
-    let mut info = crate::apps::list_info_mut().await?;
-    if let Some(mut i) = info.get_mut(name) {
-        if i.tor_address.is_some() {
-            i.tor_address = Some(read_tor_address(name, Some(Duration::from_secs(30))).await?);
+    if let Some(info) = crate::apps::list_info().await?.get(name) {
+        if info.tor_address.is_some() {
+            let addr = read_tor_address(name, Some(Duration::from_secs(30))).await?;
+            let mut db = crate::db::transaction().await?;
+            crate::apps::update_info(&mut db, name, |app| app.tor_address = Some(addr))?;
+            db.commit().await?;
         }
     }
     Ok(())
 }
 
 pub async fn reload() -> Result<(), Error> {
-    let path = PersistencePath::from_ref(crate::SERVICES_YAML);
-    let hidden_services = services_map(&path).await?;
-    log::info!("Syncing Tor hidden services to {}.", ETC_TOR_RC);
-    write_services(&hidden_services).await?;
+    reload_services(&services_map().await?).await
+}
+
+pub async fn reload_services(hidden_services: &ServicesMap) -> Result<(), Error> {
+    log::info!(
+        "Syncing Tor hidden services to {}.",
+        crate::context::get().etc_tor_rc.display()
+    );
+    write_services(hidden_services).await?;
     log::info!("Reloading Tor.");
     let svc_exit = std::process::Command::new("service")
         .args(&["tor", "reload"])
@@ -634,9 +645,11 @@ pub async fn reload() -> Result<(), Error> {
 }
 
 pub async fn restart() -> Result<(), Error> {
-    let path = PersistencePath::from_ref(crate::SERVICES_YAML);
-    let hidden_services = services_map(&path).await?;
-    log::info!("Syncing Tor hidden services to {}.", ETC_TOR_RC);
+    let hidden_services = services_map().await?;
+    log::info!(
+        "Syncing Tor hidden services to {}.",
+        crate::context::get().etc_tor_rc.display()
+    );
     write_services(&hidden_services).await?;
     log::info!("Restarting Tor.");
     let svc_exit = std::process::Command::new("service")
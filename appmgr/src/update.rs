@@ -1,6 +1,7 @@
 use linear_map::LinearMap;
 
 use crate::dependencies::{DependencyError, TaggedDependencyError};
+use crate::progress::Phase;
 use crate::Error;
 use crate::ResultExt as _;
 
@@ -18,6 +19,9 @@ pub async fn update(
         .unwrap_or_else(emver::VersionRange::any);
     let version = crate::registry::version(name, &version_req).await?;
     let mut res = LinearMap::new();
+    if !dry_run {
+        crate::progress::step(Phase::Update, "stopping dependents").await;
+    }
     for dependent in crate::apps::dependents(name, false).await? {
         if crate::apps::status(&dependent, false).await?.status
             != crate::apps::DockerStatus::Stopped
@@ -75,10 +79,20 @@ pub async fn update(
     if dry_run {
         return Ok(res);
     }
-    let download_path = crate::install::download_name(name_version).await?;
-    crate::remove::remove(name, false, false).await?;
+    let installed = crate::apps::list_info()
+        .await?
+        .get(name)
+        .map(|info| info.version.clone())
+        .ok_or_else(|| failure::format_err!("App Not Installed: {}", name))
+        .with_code(crate::error::NOT_FOUND)?;
+    crate::progress::step(Phase::Update, "downloading").await;
+    let download_path = crate::install::download_update(name, &installed, &version).await?;
+    // The install sets the previous image and container aside and only discards them once the
+    // new version is in place, so a failed install leaves the previous version as it was.
+    crate::progress::step(Phase::Update, "installing").await;
     crate::install::install_path(download_path, Some(name)).await?;
     crate::apps::set_recoverable(name, false).await?;
+    crate::progress::complete(Phase::Update).await;
 
     Ok(res)
 }
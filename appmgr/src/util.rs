@@ -35,11 +35,11 @@ impl PersistencePath {
     }
 
     pub fn tmp(&self) -> PathBuf {
-        Path::new(crate::TMP_DIR).join(&self.0)
+        crate::context::get().tmp_dir.join(&self.0)
     }
 
     pub fn path(&self) -> PathBuf {
-        Path::new(crate::PERSISTENCE_DIR).join(&self.0)
+        crate::context::get().persistence_dir.join(&self.0)
     }
 
     pub async fn lock(&self, for_update: bool) -> Result<FileLock, Error> {
@@ -30,8 +30,12 @@ mod v0_2_10;
 mod v0_2_11;
 mod v0_2_12;
 mod v0_2_13;
+mod v0_2_14;
 
-pub use v0_2_13::Version as Current;
+pub use v0_2_14::Version as Current;
+
+/// Where the version was kept before the database.
+const VERSION_FILE: &'static str = "version";
 
 #[derive(serde::Serialize, serde::Deserialize)]
 #[serde(untagged)]
@@ -57,6 +61,7 @@ enum Version {
     V0_2_11(Wrapper<v0_2_11::Version>),
     V0_2_12(Wrapper<v0_2_12::Version>),
     V0_2_13(Wrapper<v0_2_13::Version>),
+    V0_2_14(Wrapper<v0_2_14::Version>),
     Other(emver::Version),
 }
 
@@ -71,7 +76,15 @@ where
     async fn up(&self) -> Result<(), Error>;
     async fn down(&self) -> Result<(), Error>;
     async fn commit(&self) -> Result<(), Error> {
-        let mut out = PersistencePath::from_ref("version").write(None).await?;
+        if crate::db::get::<emver::Version>(crate::db::VERSION)
+            .await?
+            .is_some()
+        {
+            let mut db = crate::db::transaction().await?;
+            db.put(crate::db::VERSION, self.semver())?;
+            return db.commit().await;
+        }
+        let mut out = PersistencePath::from_ref(VERSION_FILE).write(None).await?;
         to_yaml_async_writer(out.as_mut(), &self.semver()).await?;
         out.commit().await?;
         Ok(())
@@ -147,9 +160,11 @@ impl VersionT for () {
 
 pub async fn init() -> Result<(), failure::Error> {
     let _lock = PersistencePath::from_ref("").lock(true).await?;
-    let vpath = PersistencePath::from_ref("version");
-    if let Some(mut f) = vpath.maybe_read(false).await.transpose()? {
-        let v: Version = crate::util::from_yaml_async_reader(&mut *f).await?;
+    let version: Option<Version> = match crate::db::get(crate::db::VERSION).await? {
+        Some(v) => Some(v),
+        None => v0_2_14::legacy::read(&PersistencePath::from_ref(VERSION_FILE)).await?,
+    };
+    if let Some(v) = version {
         match v {
             Version::V0_0_0(v) => v.0.migrate_to(&Current::new()).await?,
             Version::V0_1_0(v) => v.0.migrate_to(&Current::new()).await?,
@@ -172,6 +187,7 @@ pub async fn init() -> Result<(), failure::Error> {
             Version::V0_2_11(v) => v.0.migrate_to(&Current::new()).await?,
             Version::V0_2_12(v) => v.0.migrate_to(&Current::new()).await?,
             Version::V0_2_13(v) => v.0.migrate_to(&Current::new()).await?,
+            Version::V0_2_14(v) => v.0.migrate_to(&Current::new()).await?,
             Version::Other(_) => (),
             // TODO find some way to automate this?
         }
@@ -266,6 +282,7 @@ pub async fn self_update(requirement: emver::VersionRange) -> Result<(), Error>
         Version::V0_2_11(v) => Current::new().migrate_to(&v.0).await?,
         Version::V0_2_12(v) => Current::new().migrate_to(&v.0).await?,
         Version::V0_2_13(v) => Current::new().migrate_to(&v.0).await?,
+        Version::V0_2_14(v) => Current::new().migrate_to(&v.0).await?,
         Version::Other(_) => (),
         // TODO find some way to automate this?
     };
@@ -284,3 +301,78 @@ pub async fn self_update(requirement: emver::VersionRange) -> Result<(), Error>
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use linear_map::{set::LinearSet, LinearMap};
+
+    use super::*;
+
+    fn yaml(s: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_init() {
+        let (_guard, root) = crate::test_util::sandbox("version-init").await;
+        crate::test_util::fake_system(&root);
+        // as 0.2.10 left it, before the database
+        for (path, value) in &[
+            (VERSION_FILE, "0.2.10"),
+            (
+                v0_2_14::legacy::APPS_YAML,
+                "{bitcoind: {title: Bitcoin Core, version: 0.21.0, tor-address: ~, \
+                 configured: true, recoverable: false, needs-restart: false}}",
+            ),
+            (v0_2_14::legacy::RUNNING_YAML, "[bitcoind]"),
+            (
+                v0_2_14::legacy::SERVICES_YAML,
+                "{map: {bitcoind: {ip: 172.18.0.2, ports: []}}, ips: [172.18.0.2]}",
+            ),
+        ] {
+            v0_2_14::legacy::write(&PersistencePath::from_ref(path), &yaml(value))
+                .await
+                .unwrap();
+        }
+        v0_2_14::legacy::write(&v0_2_14::legacy::config_path("bitcoind"), &yaml("{a: 1}"))
+            .await
+            .unwrap();
+        let hidden_service = crate::context::get().hidden_service_dir("bitcoind");
+        std::fs::create_dir_all(&hidden_service).unwrap();
+        std::fs::write(hidden_service.join("hostname"), "bitcoind.onion\n").unwrap();
+
+        init().await.unwrap();
+        assert_eq!(
+            crate::db::get::<emver::Version>(crate::db::VERSION)
+                .await
+                .unwrap()
+                .as_ref(),
+            Some(Current::new().semver())
+        );
+        assert!(!PersistencePath::from_ref(VERSION_FILE).exists().await);
+        let apps: LinearMap<String, crate::apps::AppInfo> =
+            crate::db::get_or_default(crate::db::APPS).await.unwrap();
+        assert_eq!(apps["bitcoind"].title, "Bitcoin Core");
+        let running: LinearSet<String> =
+            crate::db::get_or_default(crate::db::RUNNING).await.unwrap();
+        assert!(running.contains("bitcoind"));
+        assert_eq!(
+            crate::db::get::<serde_yaml::Value>(&crate::db::config_key("bitcoind"))
+                .await
+                .unwrap(),
+            Some(yaml("{a: 1}"))
+        );
+        // the nginx config of earlier migrations was written from the files they had
+        assert!(crate::context::get().nginx_services_conf.exists());
+
+        // nothing left to migrate
+        let db = std::fs::read(PersistencePath::from_ref(crate::db::DB).path()).unwrap();
+        init().await.unwrap();
+        assert_eq!(
+            std::fs::read(PersistencePath::from_ref(crate::db::DB).path()).unwrap(),
+            db
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,5 +1,3 @@
-use std::path::Path;
-
 use super::*;
 
 const V0_1_0: emver::Version = emver::Version::new(0, 1, 0, 0);
@@ -15,10 +13,10 @@ impl VersionT for Version {
         &V0_1_0
     }
     async fn up(&self) -> Result<(), Error> {
-        tokio::fs::create_dir_all(Path::new(crate::PERSISTENCE_DIR).join("tor")).await?;
-        tokio::fs::create_dir_all(Path::new(crate::PERSISTENCE_DIR).join("apps")).await?;
-        tokio::fs::create_dir_all(Path::new(crate::TMP_DIR).join("tor")).await?;
-        tokio::fs::create_dir_all(Path::new(crate::TMP_DIR).join("apps")).await?;
+        tokio::fs::create_dir_all(crate::context::get().persistence_dir.join("tor")).await?;
+        tokio::fs::create_dir_all(crate::context::get().persistence_dir.join("apps")).await?;
+        tokio::fs::create_dir_all(crate::context::get().tmp_dir.join("tor")).await?;
+        tokio::fs::create_dir_all(crate::context::get().tmp_dir.join("apps")).await?;
         let mut outfile = legacy::util::PersistencePath::from_ref("tor/torrc")
             .write()
             .await?;
@@ -92,9 +90,10 @@ mod legacy {
         pub async fn write_services(
             hidden_services: &LinearMap<String, Service>,
         ) -> Result<(), Error> {
-            tokio::fs::copy(crate::TOR_RC, ETC_TOR_RC)
+            let tor_rc = crate::context::get().tor_rc();
+            tokio::fs::copy(&tor_rc, ETC_TOR_RC)
                 .await
-                .with_context(|e| format!("{} -> {}: {}", crate::TOR_RC, ETC_TOR_RC, e))?;
+                .with_context(|e| format!("{} -> {}: {}", tor_rc.display(), ETC_TOR_RC, e))?;
             let mut f = tokio::fs::OpenOptions::new()
                 .append(true)
                 .open(ETC_TOR_RC)
@@ -127,9 +126,9 @@ mod legacy {
             log::info!(
                 "Adding Tor hidden service {} to {}.",
                 name,
-                crate::SERVICES_YAML
+                crate::version::v0_2_14::legacy::SERVICES_YAML
             );
-            let path = PersistencePath::from_ref(crate::SERVICES_YAML);
+            let path = PersistencePath::from_ref(crate::version::v0_2_14::legacy::SERVICES_YAML);
             let mut hidden_services = services_map(&path).await?;
             hidden_services.insert(name.to_owned(), service);
             let mut services_yaml = path.write().await?;
@@ -172,11 +171,11 @@ mod legacy {
             }
 
             pub fn tmp(&self) -> PathBuf {
-                Path::new(crate::TMP_DIR).join(&self.0)
+                crate::context::get().tmp_dir.join(&self.0)
             }
 
             pub fn path(&self) -> PathBuf {
-                Path::new(crate::PERSISTENCE_DIR).join(&self.0)
+                crate::context::get().persistence_dir.join(&self.0)
             }
 
             pub async fn maybe_read(&self) -> Option<Result<File, Error>> {
@@ -1,5 +1,3 @@
-use std::path::Path;
-
 use super::*;
 
 const V0_1_1: emver::Version = emver::Version::new(0, 1, 1, 0);
@@ -52,18 +50,29 @@ impl VersionT for Version {
             log::warn!("Failed to Create Network")
         }
 
-        match tokio::fs::remove_file(Path::new(crate::PERSISTENCE_DIR).join(crate::SERVICES_YAML))
-            .await
+        match tokio::fs::remove_file(
+            crate::context::get()
+                .persistence_dir
+                .join(v0_2_14::legacy::SERVICES_YAML),
+        )
+        .await
         {
             Ok(_) => Ok(()),
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
             Err(e) => Err(e),
         }
-        .with_context(|e| format!("{}/{}: {}", crate::PERSISTENCE_DIR, crate::SERVICES_YAML, e))
+        .with_context(|e| {
+            format!(
+                "{}/{}: {}",
+                crate::context::get().persistence_dir.display(),
+                v0_2_14::legacy::SERVICES_YAML,
+                e
+            )
+        })
         .with_code(crate::error::FILESYSTEM_ERROR)?;
-        crate::tor::reload().await?;
+        crate::tor::reload_services(&v0_2_14::legacy::services_map().await?).await?;
 
-        for app in crate::apps::list_info().await? {
+        for app in v0_2_14::legacy::list_info().await? {
             legacy::update::update(&app.0).await?;
         }
 
@@ -94,10 +103,11 @@ impl VersionT for Version {
         .with_code(crate::error::FILESYSTEM_ERROR)?;
         outfile.commit().await?;
 
-        for app in crate::apps::list_info().await? {
+        for app in v0_2_14::legacy::list_info().await? {
             legacy::remove::remove(&app.0, false).await?;
         }
-        let tor_svcs = crate::util::PersistencePath::from_ref(crate::SERVICES_YAML).path();
+        let tor_svcs =
+            crate::util::PersistencePath::from_ref(v0_2_14::legacy::SERVICES_YAML).path();
         if tor_svcs.exists() {
             tokio::fs::remove_file(&tor_svcs)
                 .await
@@ -121,8 +131,6 @@ impl VersionT for Version {
 
 mod legacy {
     pub mod remove {
-        use std::path::Path;
-
         use crate::Error;
 
         pub async fn remove(name: &str, purge: bool) -> Result<(), Error> {
@@ -160,9 +168,14 @@ mod legacy {
                 log::info!("Removing tor hidden service.");
                 crate::tor::rm_svc(name).await?;
                 log::info!("Removing app metadata.");
-                std::fs::remove_dir_all(Path::new(crate::PERSISTENCE_DIR).join("apps").join(name))?;
+                std::fs::remove_dir_all(
+                    crate::context::get()
+                        .persistence_dir
+                        .join("apps")
+                        .join(name),
+                )?;
                 log::info!("Destroying mounted volume.");
-                std::fs::remove_dir_all(Path::new(crate::VOLUMES).join(name))?;
+                std::fs::remove_dir_all(crate::context::get().volumes.join(name))?;
                 log::info!("Pruning unused docker images.");
                 crate::ensure_code!(
                     std::process::Command::new("docker")
@@ -189,7 +202,7 @@ mod legacy {
                 .split("@")
                 .next()
                 .ok_or_else(|| failure::format_err!("invalid app id"))?;
-            crate::install::download_name(name_version).await?;
+            crate::install::download_name(name_version, false).await?;
             super::remove::remove(name, false).await?;
             crate::install::install_name(name_version, true).await?;
             let config = crate::apps::config(name).await?;
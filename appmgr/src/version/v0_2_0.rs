@@ -40,7 +40,7 @@ impl VersionT for Version {
         Ok(())
     }
     async fn down(&self) -> Result<(), Error> {
-        let app_info: LinearMap<String, legacy::apps::AppInfo> = crate::apps::list_info()
+        let app_info: LinearMap<String, legacy::apps::AppInfo> = v0_2_14::legacy::list_info()
             .await?
             .into_iter()
             .map(|(id, ai)| {
@@ -14,10 +14,7 @@ impl VersionT for Version {
         &V0_2_11
     }
     async fn up(&self) -> Result<(), Error> {
-        crate::tor::write_lan_services(
-            &crate::tor::services_map(&PersistencePath::from_ref(crate::SERVICES_YAML)).await?,
-        )
-        .await?;
+        crate::tor::write_lan_services(&v0_2_14::legacy::services_map().await?).await?;
         let svc_exit = std::process::Command::new("service")
             .args(&["nginx", "reload"])
             .status()?;
@@ -0,0 +1,280 @@
+use linear_map::{set::LinearSet, LinearMap};
+
+use super::*;
+
+const V0_2_14: emver::Version = emver::Version::new(0, 2, 14, 0);
+
+pub struct Version;
+#[async_trait]
+impl VersionT for Version {
+    type Previous = v0_2_13::Version;
+    fn new() -> Self {
+        Version
+    }
+    fn semver(&self) -> &'static emver::Version {
+        &V0_2_14
+    }
+    async fn up(&self) -> Result<(), Error> {
+        // earlier migrations reinstall apps, which puts them in the db already: what they put
+        // there is newer than the files, so the files only fill in what the db does not have
+        let mut db = crate::db::transaction().await?;
+        let legacy_apps: LinearMap<String, serde_yaml::Value> =
+            legacy::read(&PersistencePath::from_ref(legacy::APPS_YAML))
+                .await?
+                .unwrap_or_default();
+        let mut apps: LinearMap<String, serde_yaml::Value> = db.get_or_default(crate::db::APPS)?;
+        for (id, info) in &legacy_apps {
+            if !apps.contains_key(id) {
+                apps.insert(id.clone(), info.clone());
+            }
+        }
+        for id in legacy_apps.keys() {
+            if db
+                .get::<serde_yaml::Value>(&crate::db::config_key(id))?
+                .is_some()
+            {
+                continue;
+            }
+            if let Some(config) =
+                legacy::read::<serde_yaml::Value>(&legacy::config_path(id)).await?
+            {
+                db.put(&crate::db::config_key(id), &config)?;
+            }
+        }
+        db.put(crate::db::APPS, &apps)?;
+        let mut running: LinearSet<String> = db.get_or_default(crate::db::RUNNING)?;
+        let legacy_running: LinearSet<String> =
+            legacy::read(&PersistencePath::from_ref(legacy::RUNNING_YAML))
+                .await?
+                .unwrap_or_default();
+        running.extend(legacy_running);
+        db.put(crate::db::RUNNING, &running)?;
+        let mut services: crate::tor::ServicesMap = db.get_or_default(crate::db::SERVICES)?;
+        for (id, svc) in legacy::services_map().await?.map {
+            if services.map.contains_key(&id) {
+                continue;
+            }
+            if services.ips.contains(&svc.ip) {
+                services.add(
+                    id,
+                    crate::tor::NewService {
+                        ports: svc.ports,
+                        hidden_service_version: svc.hidden_service_version,
+                    },
+                );
+            } else {
+                services.ips.insert(svc.ip);
+                services.map.insert(id, svc);
+            }
+        }
+        db.put(crate::db::SERVICES, &services)?;
+        db.put(crate::db::VERSION, self.semver())?;
+        db.commit().await?;
+        legacy::write(&PersistencePath::from_ref(legacy::SERVICES_YAML), &services).await?;
+
+        // only once the database has everything they held. `tor/services.yaml` stays: appmgr
+        // keeps it up to date from the db for the agent.
+        for id in legacy_apps.keys() {
+            legacy::config_path(id).delete().await?;
+        }
+        for path in &[legacy::APPS_YAML, legacy::RUNNING_YAML, VERSION_FILE] {
+            PersistencePath::from_ref(path).delete().await?;
+        }
+        Ok(())
+    }
+    async fn down(&self) -> Result<(), Error> {
+        let db = crate::db::transaction().await?;
+        let apps: LinearMap<String, serde_yaml::Value> = db.get_or_default(crate::db::APPS)?;
+        for id in apps.keys() {
+            if let Some(config) = db.get::<serde_yaml::Value>(&crate::db::config_key(id))? {
+                legacy::write(&legacy::config_path(id), &config).await?;
+            }
+        }
+        legacy::write(&PersistencePath::from_ref(legacy::APPS_YAML), &apps).await?;
+        let running: LinearSet<String> = db.get_or_default(crate::db::RUNNING)?;
+        legacy::write(&PersistencePath::from_ref(legacy::RUNNING_YAML), &running).await?;
+        let services: crate::tor::ServicesMap = db.get_or_default(crate::db::SERVICES)?;
+        legacy::write(&PersistencePath::from_ref(legacy::SERVICES_YAML), &services).await?;
+        drop(db);
+
+        PersistencePath::from_ref(crate::db::DB).delete().await?;
+        Ok(())
+    }
+}
+
+/// The files the state was kept in before the database, which earlier migrations work on.
+pub mod legacy {
+    use linear_map::LinearMap;
+
+    use crate::util::{from_yaml_async_reader, to_yaml_async_writer, PersistencePath};
+    use crate::Error;
+
+    pub const APPS_YAML: &'static str = "apps.yaml";
+    pub const RUNNING_YAML: &'static str = "running.yaml";
+    pub const SERVICES_YAML: &'static str = crate::tor::SERVICES_YAML;
+
+    pub fn config_path(id: &str) -> PersistencePath {
+        PersistencePath::from_ref("apps")
+            .join(id)
+            .join("config.yaml")
+    }
+
+    pub async fn read<T: for<'de> serde::Deserialize<'de>>(
+        path: &PersistencePath,
+    ) -> Result<Option<T>, Error> {
+        match path.maybe_read(false).await.transpose()? {
+            Some(mut f) => Ok(Some(from_yaml_async_reader(&mut *f).await?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn write<T: serde::Serialize>(
+        path: &PersistencePath,
+        value: &T,
+    ) -> Result<(), Error> {
+        let mut f = path.write(None).await?;
+        to_yaml_async_writer(&mut *f, value).await?;
+        f.commit().await
+    }
+
+    pub async fn list_info() -> Result<LinearMap<String, crate::apps::AppInfo>, Error> {
+        Ok(read(&PersistencePath::from_ref(APPS_YAML))
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn services_map() -> Result<crate::tor::ServicesMap, Error> {
+        Ok(read(&PersistencePath::from_ref(SERVICES_YAML))
+            .await?
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn yaml(s: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    async fn put(key: &str, value: serde_yaml::Value) {
+        let mut db = crate::db::transaction().await.unwrap();
+        db.put(key, &value).unwrap();
+        db.commit().await.unwrap();
+    }
+
+    async fn read(path: &PersistencePath) -> serde_yaml::Value {
+        legacy::read(path).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_up_down() {
+        let (_guard, root) = crate::test_util::sandbox("v0_2_14").await;
+        legacy::write(
+            &PersistencePath::from_ref(legacy::APPS_YAML),
+            &yaml("{bitcoind: {title: Old Bitcoin}, lnd: {title: LND}}"),
+        )
+        .await
+        .unwrap();
+        legacy::write(&legacy::config_path("bitcoind"), &yaml("{a: 1}"))
+            .await
+            .unwrap();
+        legacy::write(&legacy::config_path("lnd"), &yaml("{b: 2}"))
+            .await
+            .unwrap();
+        legacy::write(
+            &PersistencePath::from_ref(legacy::RUNNING_YAML),
+            &yaml("[lnd]"),
+        )
+        .await
+        .unwrap();
+        legacy::write(
+            &PersistencePath::from_ref(legacy::SERVICES_YAML),
+            &yaml(
+                "{map: {bitcoind: {ip: 172.18.0.2, ports: []}, lnd: {ip: 172.18.0.3, ports: []}}, \
+                 ips: [172.18.0.2, 172.18.0.3]}",
+            ),
+        )
+        .await
+        .unwrap();
+        // as reinstalled by an earlier migration
+        put(
+            crate::db::APPS,
+            yaml("{bitcoind: {title: Bitcoin}, electrs: {title: Electrs}}"),
+        )
+        .await;
+        put(&crate::db::config_key("bitcoind"), yaml("{a: 3}")).await;
+        put(crate::db::RUNNING, yaml("[bitcoind]")).await;
+        put(
+            crate::db::SERVICES,
+            yaml(
+                "{map: {bitcoind: {ip: 172.18.0.2, ports: []}, electrs: {ip: 172.18.0.3, ports: []}}, \
+                 ips: [172.18.0.2, 172.18.0.3]}",
+            ),
+        )
+        .await;
+
+        Version.up().await.unwrap();
+        let apps: LinearMap<String, serde_yaml::Value> =
+            crate::db::get_or_default(crate::db::APPS).await.unwrap();
+        assert_eq!(apps.len(), 3);
+        assert_eq!(apps["bitcoind"], yaml("{title: Bitcoin}"));
+        assert_eq!(apps["lnd"], yaml("{title: LND}"));
+        assert_eq!(
+            crate::db::get::<serde_yaml::Value>(&crate::db::config_key("bitcoind"))
+                .await
+                .unwrap(),
+            Some(yaml("{a: 3}"))
+        );
+        assert_eq!(
+            crate::db::get::<serde_yaml::Value>(&crate::db::config_key("lnd"))
+                .await
+                .unwrap(),
+            Some(yaml("{b: 2}"))
+        );
+        let running: LinearSet<String> =
+            crate::db::get_or_default(crate::db::RUNNING).await.unwrap();
+        assert!(running.contains("bitcoind") && running.contains("lnd"));
+        let services: crate::tor::ServicesMap = crate::db::get_or_default(crate::db::SERVICES)
+            .await
+            .unwrap();
+        assert_eq!(
+            services.map["electrs"].ip,
+            std::net::Ipv4Addr::new(172, 18, 0, 3)
+        );
+        // its address was taken
+        assert_eq!(
+            services.map["lnd"].ip,
+            std::net::Ipv4Addr::new(172, 18, 0, 4)
+        );
+        assert_eq!(services.ips.len(), 3);
+        assert!(!PersistencePath::from_ref(legacy::APPS_YAML).exists().await);
+        assert!(
+            !PersistencePath::from_ref(legacy::RUNNING_YAML)
+                .exists()
+                .await
+        );
+        assert!(!legacy::config_path("lnd").exists().await);
+        assert_eq!(
+            legacy::services_map().await.unwrap().map["lnd"].ip,
+            std::net::Ipv4Addr::new(172, 18, 0, 4)
+        );
+
+        Version.down().await.unwrap();
+        assert!(!crate::db::exists().await);
+        let apps = read(&PersistencePath::from_ref(legacy::APPS_YAML)).await;
+        assert_eq!(apps["bitcoind"], yaml("{title: Bitcoin}"));
+        assert_eq!(apps["electrs"], yaml("{title: Electrs}"));
+        assert_eq!(apps["lnd"], yaml("{title: LND}"));
+        assert_eq!(read(&legacy::config_path("bitcoind")).await, yaml("{a: 3}"));
+        assert_eq!(read(&legacy::config_path("lnd")).await, yaml("{b: 2}"));
+        assert_eq!(
+            read(&PersistencePath::from_ref(legacy::RUNNING_YAML)).await,
+            yaml("[bitcoind, lnd]")
+        );
+        assert_eq!(legacy::services_map().await.unwrap().map.len(), 3);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -14,7 +14,7 @@ impl VersionT for Version {
         &V0_2_8
     }
     async fn up(&self) -> Result<(), Error> {
-        for (app_id, _) in crate::apps::list_info().await? {
+        for (app_id, _) in v0_2_14::legacy::list_info().await? {
             tokio::process::Command::new("docker")
                 .arg("stop")
                 .arg(&app_id)
@@ -15,12 +15,9 @@ impl VersionT for Version {
         &V0_2_9
     }
     async fn up(&self) -> Result<(), Error> {
-        crate::tor::write_lan_services(
-            &crate::tor::services_map(&PersistencePath::from_ref(crate::SERVICES_YAML)).await?,
-        )
-        .await?;
+        crate::tor::write_lan_services(&v0_2_14::legacy::services_map().await?).await?;
         tokio::fs::os::unix::symlink(
-            crate::tor::ETC_NGINX_SERVICES_CONF,
+            &crate::context::get().nginx_services_conf,
             "/etc/nginx/sites-enabled/start9-services.conf",
         )
         .await
@@ -52,7 +49,7 @@ impl VersionT for Version {
                 e if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
                 e => Err(e),
             })?;
-        tokio::fs::remove_file(crate::tor::ETC_NGINX_SERVICES_CONF)
+        tokio::fs::remove_file(&crate::context::get().nginx_services_conf)
             .await
             .or_else(|e| match e {
                 e if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
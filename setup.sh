@@ -10,6 +10,7 @@ apt-get install -y bmon
 apt-get install -y libavahi-client3
 apt-get install -y libsecp256k1-0
 apt-get install -y docker.io needrestart-
+apt-get install -y nftables
 mv /root/setup.sh /root/setup-s1.sh.done
 cat <<EOT >> /root/setup-s2.sh
 #!/bin/bash
@@ -20,6 +21,7 @@ apt-get install -y bmon
 apt-get install -y libavahi-client3
 apt-get install -y libsecp256k1-0
 apt-get install -y docker.io needrestart-
+apt-get install -y nftables
 apt-get autoremove -y
 systemctl enable lifeline
 systemctl enable agent